@@ -0,0 +1,261 @@
+use std::future::Future;
+
+use anyhow::{bail, Result};
+use erpy_types::hash::fnv1a;
+use log::info;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+/// Turns texts into vectors, so that texts with similar meaning can be found.
+pub trait EmbeddingApi {
+    /// One vector for every text, in the same order.
+    fn embed(&self, texts: &[String]) -> impl Future<Output = Result<Vec<Vec<f32>>>> + Send;
+
+    /// Identifies the model. Vectors of different models can't be compared.
+    fn model_id(&self) -> String;
+}
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct OpenAiEmbeddingResponse {
+    data: Vec<OpenAiEmbedding>,
+}
+
+#[derive(Deserialize)]
+struct OpenAiEmbedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[derive(Deserialize)]
+struct OllamaEmbeddingResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+/// An OpenAI-compatible `/embeddings` endpoint.
+pub struct OpenAiEmbeddings {
+    base_url: String,
+    api_key: Option<String>,
+    client: Client,
+    model: String,
+}
+
+impl OpenAiEmbeddings {
+    pub fn new(api_url: String, api_key: Option<String>, model: String) -> Self {
+        OpenAiEmbeddings {
+            base_url: api_url,
+            api_key,
+            client: Client::new(),
+            model,
+        }
+    }
+}
+
+impl EmbeddingApi for OpenAiEmbeddings {
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let url = format!("{}/embeddings", self.base_url);
+        info!(
+            "Embedding {} texts with {url} and model {}",
+            texts.len(),
+            self.model
+        );
+        let mut request = self.client.post(&url).json(&EmbeddingRequest {
+            model: &self.model,
+            input: texts,
+        });
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await?;
+            bail!("Request failed with status {status} and response '{text}'");
+        }
+
+        let mut response = response.json::<OpenAiEmbeddingResponse>().await?;
+        response.data.sort_by_key(|embedding| embedding.index);
+        check_count(
+            texts,
+            response.data.into_iter().map(|e| e.embedding).collect(),
+        )
+    }
+
+    fn model_id(&self) -> String {
+        format!("open-ai:{}", self.model)
+    }
+}
+
+/// The `/api/embed` endpoint of an Ollama server.
+pub struct OllamaEmbeddings {
+    base_url: String,
+    client: Client,
+    model: String,
+}
+
+impl OllamaEmbeddings {
+    pub fn new(api_url: String, model: String) -> Self {
+        OllamaEmbeddings {
+            base_url: api_url,
+            client: Client::new(),
+            model,
+        }
+    }
+}
+
+impl EmbeddingApi for OllamaEmbeddings {
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let url = format!("{}/api/embed", self.base_url);
+        info!(
+            "Embedding {} texts with {url} and model {}",
+            texts.len(),
+            self.model
+        );
+        let response = self
+            .client
+            .post(&url)
+            .json(&EmbeddingRequest {
+                model: &self.model,
+                input: texts,
+            })
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await?;
+            bail!("Request failed with status {status} and response '{text}'");
+        }
+
+        let response = response.json::<OllamaEmbeddingResponse>().await?;
+        check_count(texts, response.embeddings)
+    }
+
+    fn model_id(&self) -> String {
+        format!("ollama:{}", self.model)
+    }
+}
+
+fn check_count(texts: &[String], vectors: Vec<Vec<f32>>) -> Result<Vec<Vec<f32>>> {
+    if vectors.len() != texts.len() {
+        bail!(
+            "expected {} embeddings, received {}",
+            texts.len(),
+            vectors.len()
+        );
+    }
+    Ok(vectors)
+}
+
+/// Embeddings of the words in a text, hashed into a fixed number of dimensions.
+///
+/// Needs no model and gives the same vectors everywhere, so texts that share words are
+/// found without any setup. It doesn't know synonyms, unlike a real embedding model.
+pub struct HashingEmbeddings {
+    dimensions: usize,
+}
+
+impl HashingEmbeddings {
+    pub fn new(dimensions: usize) -> Self {
+        HashingEmbeddings {
+            dimensions: dimensions.max(1),
+        }
+    }
+
+    pub fn embed_text(&self, text: &str) -> Vec<f32> {
+        let mut vector = vec![0.0; self.dimensions];
+        let words = text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase);
+        for word in words {
+            let hash = fnv1a(word.as_bytes());
+            let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+            vector[(hash % self.dimensions as u64) as usize] += sign;
+        }
+
+        let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            vector.iter_mut().for_each(|x| *x /= norm);
+        }
+        vector
+    }
+}
+
+impl Default for HashingEmbeddings {
+    fn default() -> Self {
+        HashingEmbeddings::new(256)
+    }
+}
+
+impl EmbeddingApi for HashingEmbeddings {
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        Ok(texts.iter().map(|text| self.embed_text(text)).collect())
+    }
+
+    fn model_id(&self) -> String {
+        format!("hashing:{}", self.dimensions)
+    }
+}
+
+pub enum EmbeddingApis {
+    OpenAi(OpenAiEmbeddings),
+    Ollama(OllamaEmbeddings),
+    Hashing(HashingEmbeddings),
+}
+
+impl EmbeddingApi for EmbeddingApis {
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        match self {
+            EmbeddingApis::OpenAi(api) => api.embed(texts).await,
+            EmbeddingApis::Ollama(api) => api.embed(texts).await,
+            EmbeddingApis::Hashing(api) => api.embed(texts).await,
+        }
+    }
+
+    fn model_id(&self) -> String {
+        match self {
+            EmbeddingApis::OpenAi(api) => api.model_id(),
+            EmbeddingApis::Ollama(api) => api.model_id(),
+            EmbeddingApis::Hashing(api) => api.model_id(),
+        }
+    }
+}
+
+/// The cosine similarity of two vectors, 0 if either is empty or zero.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cosine_similarity, HashingEmbeddings};
+
+    #[test]
+    fn test_hashing_embeddings() {
+        let embeddings = HashingEmbeddings::new(64);
+        let sword = embeddings.embed_text("The knight drew his sword.");
+        assert_eq!(sword, embeddings.embed_text("the KNIGHT drew his sword"));
+        assert!((cosine_similarity(&sword, &sword) - 1.0).abs() < 1e-6);
+
+        let similar = embeddings.embed_text("A sword for the knight");
+        let other = embeddings.embed_text("Tea is served at noon");
+        assert!(cosine_similarity(&sword, &similar) > cosine_similarity(&sword, &other));
+        assert_eq!(embeddings.embed_text("").iter().sum::<f32>(), 0.0);
+        assert_eq!(cosine_similarity(&sword, &[]), 0.0);
+    }
+}
@@ -1,9 +1,9 @@
 use std::{future::Future, pin::Pin};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 
 use camino::Utf8PathBuf;
-use erpy_types::MessageRole;
+use erpy_types::{MessageRole, SamplerSettings};
 use serde::{Deserialize, Serialize};
 use tokio_stream::Stream;
 
@@ -13,7 +13,9 @@ pub mod llama;
 #[cfg(feature = "mistral")]
 pub mod mistral;
 
+pub mod embeddings;
 pub mod open_ai;
+pub mod template;
 
 #[derive(Debug, Deserialize)]
 pub struct ModelsResponse {
@@ -25,7 +27,7 @@ pub struct Model {
     pub id: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MessageHistoryItem {
     pub role: MessageRole,
@@ -58,9 +60,81 @@ pub struct CompletionRequest {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub seed: Option<i64>,
+
+    /// Number of alternative answers to generate for the same prompt.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<usize>,
+
+    /// Keep writing the last (assistant) message instead of starting a new turn.
+    #[serde(
+        rename = "continue_final_message",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub continue_final_message: bool,
+
+    #[serde(
+        rename = "add_generation_prompt",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub add_generation_prompt: Option<bool>,
+
+    #[serde(rename = "stream_options", skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StreamOptions {
+    /// Ask for a last chunk with the token counts of the whole request.
+    pub include_usage: bool,
 }
 
 impl CompletionRequest {
+    pub fn choice_count(&self) -> usize {
+        self.n.unwrap_or(1).max(1)
+    }
+
+    /// Turns the request into one that continues the last assistant message. For
+    /// OpenAI-compatible servers this asks for the message to be used as a prefill.
+    pub fn continue_last_message(self) -> Result<Self> {
+        match self.messages.last() {
+            Some(message) if message.role == MessageRole::Assistant => Ok(CompletionRequest {
+                continue_final_message: true,
+                add_generation_prompt: Some(false),
+                ..self
+            }),
+            Some(message) => bail!(
+                "only assistant messages can be continued, found {}",
+                message.role
+            ),
+            None => bail!("cannot continue an empty conversation"),
+        }
+    }
+
+    pub fn sampler(&self) -> SamplerSettings {
+        SamplerSettings {
+            max_tokens: self.max_tokens,
+            temperature: self.temperature,
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            repeat_penalty: self.repeat_penalty,
+            top_p: self.top_p,
+        }
+    }
+
+    /// Uses the sampler settings and seed of an earlier request, e.g. to regenerate a reply.
+    pub fn with_sampler(self, sampler: &SamplerSettings, seed: Option<i64>) -> Self {
+        CompletionRequest {
+            max_tokens: sampler.max_tokens,
+            temperature: sampler.temperature,
+            frequency_penalty: sampler.frequency_penalty,
+            presence_penalty: sampler.presence_penalty,
+            repeat_penalty: sampler.repeat_penalty,
+            top_p: sampler.top_p,
+            seed,
+            ..self
+        }
+    }
+
     pub fn estimated_tokens(&self) -> usize {
         estimate_tokens(&self.messages)
     }
@@ -93,21 +167,49 @@ impl CompletionRequest {
 #[serde(rename_all = "camelCase")]
 pub struct StreamingCompletionResponse {
     pub choices: Vec<StreamingCompletionChoice>,
+    /// Only sent with the last chunk, and only by backends that count tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+impl StreamingCompletionResponse {
+    /// Splits a chunk into one response per choice, so that every choice can be
+    /// forwarded on its own when multiple answers are streamed at once.
+    pub fn split_choices(self) -> impl Iterator<Item = StreamingCompletionResponse> {
+        self.choices
+            .into_iter()
+            .map(|choice| StreamingCompletionResponse {
+                choices: vec![choice],
+                usage: None,
+            })
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct StreamingCompletionChoice {
+    #[serde(default)]
+    pub index: usize,
     pub delta: DeltaContent,
+    #[serde(rename(deserialize = "finish_reason"))]
     pub finish_reason: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DeltaContent {
+    /// Missing in the chunk that only finishes a choice.
+    #[serde(default)]
     pub content: String,
 }
 
+/// The token counts of a whole request, summed over all of its choices.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CompletionResponse {
     pub id: String,
@@ -159,6 +261,17 @@ pub enum CompletionApis {
 }
 
 impl CompletionApis {
+    /// The kind of backend, as it is called in the settings.
+    pub fn backend_name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "llama")]
+            CompletionApis::Llama(_) => "llama",
+            #[cfg(feature = "mistral")]
+            CompletionApis::Mistral(_) => "mistral",
+            CompletionApis::OpenAi(_) => "open-ai",
+        }
+    }
+
     pub async fn get_completions_stream<'a>(
         &'a self,
         request: CompletionRequest,
@@ -178,6 +291,15 @@ impl CompletionApis {
         Ok(stream)
     }
 
+    /// Streams a continuation of the last assistant message in `request`.
+    pub async fn continue_completion<'a>(
+        &'a self,
+        request: CompletionRequest,
+    ) -> Result<Pin<Box<dyn Stream<Item = StreamingCompletionResponse> + Send + 'a>>> {
+        let request = request.continue_last_message()?;
+        self.get_completions_stream(request).await
+    }
+
     pub async fn list_models(&self) -> Result<Vec<String>> {
         match self {
             #[cfg(feature = "llama")]
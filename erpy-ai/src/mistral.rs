@@ -3,23 +3,24 @@ use std::{
     sync::{Arc, LazyLock},
 };
 
-use crate::{CompletionChoice, CompletionMessage, MessageRole, ModelInfo};
+use crate::{template::ChatTemplate, CompletionChoice, CompletionMessage, MessageRole, ModelInfo};
 
 use super::{
     CompletionApi, CompletionRequest, CompletionResponse, DeltaContent, MessageHistoryItem,
-    StreamingCompletionChoice, StreamingCompletionResponse,
+    StreamingCompletionChoice, StreamingCompletionResponse, Usage,
 };
-use anyhow::Result;
+use anyhow::{bail, Result};
 use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
 use either::Either;
 use indexmap::IndexMap;
 
 use log::info;
 use mistralrs::{
-    best_device, ChatCompletionChunkResponse, Constraint, DefaultSchedulerMethod, DeviceMapSetting,
-    GGUFLoaderBuilder, GGUFSpecificConfig, MemoryGpuConfig, MistralRs, MistralRsBuilder,
-    ModelDType, NormalRequest, PagedAttentionMetaBuilder, Request, RequestMessage, Response,
-    SamplingParams, SchedulerConfig, TokenSource,
+    best_device, ChatCompletionChunkResponse, CompletionChunkResponse, Constraint,
+    DefaultSchedulerMethod, DeviceMapSetting, GGUFLoaderBuilder, GGUFSpecificConfig,
+    MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelDType, NormalRequest,
+    PagedAttentionMetaBuilder, Request, RequestMessage, Response, SamplingParams, SchedulerConfig,
+    TokenSource,
 };
 use tokio::sync::mpsc::Receiver;
 use tokio_stream::{Stream, StreamExt};
@@ -29,6 +30,7 @@ use uuid::Uuid;
 pub struct MistralRsCompletions {
     runner: Arc<MistralRs>,
     model_id: String,
+    chat_template: Option<ChatTemplate>,
     // file_name: String,
 }
 
@@ -38,6 +40,11 @@ impl MistralRsCompletions {
         chat_template: Option<String>,
         files: Vec<String>,
     ) -> Result<Self> {
+        let template = chat_template
+            .as_deref()
+            .map(ChatTemplate::from_file)
+            .transpose()?;
+
         let config = GGUFSpecificConfig {
             prompt_chunksize: None,
             topology: None,
@@ -95,6 +102,7 @@ impl MistralRsCompletions {
         Ok(Self {
             runner,
             model_id,
+            chat_template: template,
             // file_name,
         })
     }
@@ -105,11 +113,26 @@ impl MistralRsCompletions {
     ) -> Result<Receiver<Response>> {
         use tokio::sync::mpsc::channel;
 
+        let messages = if request.continue_final_message {
+            // mistral.rs always closes the last turn of a chat request, so the prompt for
+            // a continuation has to be rendered here and sent as a raw completion.
+            let Some(template) = &self.chat_template else {
+                bail!("continuing a message requires a chat template");
+            };
+            RequestMessage::Completion {
+                text: template.render_continuation(&request.messages)?,
+                echo_prompt: false,
+                best_of: None,
+            }
+        } else {
+            convert_messages(&request.messages)
+        };
+
         let (tx, rx) = channel(10_000);
         let id = self.runner.next_request_id();
         let request = Request::Normal(Box::new(NormalRequest {
             id,
-            messages: convert_messages(&request.messages),
+            messages,
             web_search_options: None,
             sampling_params: SamplingParams {
                 temperature: request.temperature,
@@ -122,7 +145,7 @@ impl MistralRsCompletions {
                 max_len: request.max_tokens,
                 stop_toks: None,
                 logits_bias: None,
-                n_choices: 1,
+                n_choices: request.choice_count(),
                 dry_params: None,
             },
             response: tx,
@@ -168,6 +191,7 @@ impl From<ChatCompletionChunkResponse> for StreamingCompletionResponse {
             .choices
             .into_iter()
             .map(|c| StreamingCompletionChoice {
+                index: c.index,
                 delta: DeltaContent {
                     content: c.delta.content.unwrap_or_default(),
                 },
@@ -176,6 +200,28 @@ impl From<ChatCompletionChunkResponse> for StreamingCompletionResponse {
 
         Self {
             choices: choices.collect(),
+            usage: chunk.usage.map(|usage| Usage {
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+            }),
+        }
+    }
+}
+
+impl From<CompletionChunkResponse> for StreamingCompletionResponse {
+    fn from(chunk: CompletionChunkResponse) -> Self {
+        let choices = chunk
+            .choices
+            .into_iter()
+            .map(|c| StreamingCompletionChoice {
+                index: c.index,
+                delta: DeltaContent { content: c.text },
+                finish_reason: c.finish_reason,
+            });
+
+        Self {
+            choices: choices.collect(),
+            usage: None,
         }
     }
 }
@@ -196,36 +242,52 @@ impl CompletionApi for MistralRsCompletions {
     ) -> Result<impl Stream<Item = StreamingCompletionResponse>> {
         let rx = self.completions_receiver(&request).await?;
         let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
+        let choice_count = request.choice_count();
+        let mut finished_choices = 0;
 
-        Ok(stream.map_while(|response| match response {
-            Response::Chunk(chunk) => {
-                let is_finished = chunk.choices.iter().all(|x| x.finish_reason.is_some());
-                log::debug!("got chunk: {:#?}", chunk);
-                if is_finished {
-                    None
-                } else {
-                    Some(chunk.into())
-                }
-            }
-            Response::InternalError(error) => {
-                log::error!("internal error: {:#?}", error);
-                None
-            }
-            Response::ValidationError(error) => {
-                log::error!("validation error: {:#?}", error);
-                None
+        Ok(stream.map_while(move |response| {
+            // the chunk that finished the last choice was sent, the stream ends after it
+            if finished_choices >= choice_count {
+                return None;
             }
-            Response::ModelError(e, _) => {
-                log::error!("model error: {:#?}", e);
-                None
-            }
-            Response::Done(_) => None,
-            Response::CompletionModelError(_, _) => None,
-            Response::CompletionDone(_) => None,
-            Response::CompletionChunk(_) => None,
-            Response::ImageGeneration(_) => None,
-            Response::Raw { .. } => None,
-            Response::Speech { .. } => None,
+            let chunk: StreamingCompletionResponse = match response {
+                Response::Chunk(chunk) => {
+                    log::debug!("got chunk: {:#?}", chunk);
+                    chunk.into()
+                }
+                Response::CompletionChunk(chunk) => {
+                    log::debug!("got completion chunk: {:#?}", chunk);
+                    chunk.into()
+                }
+                Response::InternalError(error) => {
+                    log::error!("internal error: {:#?}", error);
+                    return None;
+                }
+                Response::ValidationError(error) => {
+                    log::error!("validation error: {:#?}", error);
+                    return None;
+                }
+                Response::ModelError(e, _) => {
+                    log::error!("model error: {:#?}", e);
+                    return None;
+                }
+                Response::CompletionModelError(e, _) => {
+                    log::error!("completion model error: {:#?}", e);
+                    return None;
+                }
+                Response::Done(_) => return None,
+                Response::CompletionDone(_) => return None,
+                Response::ImageGeneration(_) => return None,
+                Response::Raw { .. } => return None,
+                Response::Speech { .. } => return None,
+            };
+
+            finished_choices += chunk
+                .choices
+                .iter()
+                .filter(|x| x.finish_reason.is_some())
+                .count();
+            Some(chunk)
         }))
     }
 
@@ -234,27 +296,43 @@ impl CompletionApi for MistralRsCompletions {
     }
 
     async fn get_completions(&self, request: CompletionRequest) -> Result<CompletionResponse> {
-        let chunks: Vec<_> = self.get_completions_stream(request).await?.collect().await;
-        let finish_reason = chunks
-            .last()
-            .and_then(|c| c.choices[0].finish_reason.clone());
-        let message = chunks
+        let choice_count = request.choice_count();
+        let mut messages = vec![String::new(); choice_count];
+        let mut finish_reasons = vec![None; choice_count];
+
+        let mut stream = Box::pin(self.get_completions_stream(request).await?);
+        while let Some(chunk) = stream.next().await {
+            for choice in chunk.choices {
+                if let Some(message) = messages.get_mut(choice.index) {
+                    message.push_str(&choice.delta.content);
+                }
+                if choice.finish_reason.is_some() {
+                    if let Some(reason) = finish_reasons.get_mut(choice.index) {
+                        *reason = choice.finish_reason;
+                    }
+                }
+            }
+        }
+
+        let choices = messages
             .into_iter()
-            .map(|mut c| c.choices.remove(0).delta.content)
+            .zip(finish_reasons)
+            .enumerate()
+            .map(|(index, (content, finish_reason))| CompletionChoice {
+                index: index as i32,
+                finish_reason,
+                message: CompletionMessage {
+                    role: MessageRole::Assistant,
+                    content,
+                },
+            })
             .collect();
 
         Ok(CompletionResponse {
             id: Uuid::new_v4().to_string(),
             created: timestamp(),
             model: self.model_id.clone(),
-            choices: vec![CompletionChoice {
-                index: 0,
-                finish_reason,
-                message: CompletionMessage {
-                    role: MessageRole::Assistant,
-                    content: message,
-                },
-            }],
+            choices,
         })
     }
 }
@@ -413,6 +491,10 @@ mod tests {
             repeat_penalty: None,
             top_p: None,
             seed: None,
+            n: None,
+            continue_final_message: false,
+            add_generation_prompt: None,
+            stream_options: None,
         };
 
         let mut stream = mistral.get_completions_stream(request).await.unwrap();
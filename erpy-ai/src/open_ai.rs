@@ -1,13 +1,13 @@
 use std::time::Duration;
 
 use anyhow::{bail, Result};
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 use reqwest::Client;
 use reqwest_eventsource::{Event, EventSource};
 use tokio_stream::{Stream, StreamExt};
 
 use super::{
-    CompletionApi, CompletionRequest, CompletionResponse, ModelsResponse,
+    CompletionApi, CompletionRequest, CompletionResponse, ModelsResponse, StreamOptions,
     StreamingCompletionResponse,
 };
 pub struct OpenAiCompletions {
@@ -21,7 +21,7 @@ impl OpenAiCompletions {
     pub fn new(api_url: String, api_key: Option<String>, model: String) -> Self {
         OpenAiCompletions {
             base_url: api_url,
-            api_key: api_key,
+            api_key,
             client: Client::new(),
             model,
         }
@@ -38,6 +38,9 @@ impl CompletionApi for OpenAiCompletions {
         }
 
         request.model = self.model.clone();
+        request.stream_options = Some(StreamOptions {
+            include_usage: true,
+        });
 
         let url = format!("{}/chat/completions", self.base_url);
         info!(
@@ -58,11 +61,18 @@ impl CompletionApi for OpenAiCompletions {
                 debug!("received event: {:?}", event);
                 let event = event.ok()?;
                 match event {
+                    Event::Message(msg) if msg.data == "[DONE]" => None,
                     Event::Message(msg) => {
-                        let response: StreamingCompletionResponse =
-                            serde_json::from_str(&msg.data).ok()?;
-                        trace!("parsed response: {:#?}", response);
-                        Some(response)
+                        match serde_json::from_str::<StreamingCompletionResponse>(&msg.data) {
+                            Ok(response) => {
+                                trace!("parsed response: {:#?}", response);
+                                Some(response)
+                            }
+                            Err(e) => {
+                                warn!("failed to parse completion chunk {:?}: {e}", msg.data);
+                                None
+                            }
+                        }
                     }
                     Event::Open => None,
                 }
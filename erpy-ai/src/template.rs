@@ -0,0 +1,219 @@
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+use erpy_types::MessageRole;
+use minijinja::{context, Environment, Error, ErrorKind};
+use serde::Deserialize;
+
+use crate::MessageHistoryItem;
+
+/// Appended to the message that should be continued, so that the rendered prompt can be
+/// cut off right after its content, before any end-of-turn tokens the template adds.
+const OPEN_MESSAGE_MARKER: &str = "\u{E000}erpy-open-message\u{E000}";
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SpecialToken {
+    Plain(String),
+    Added { content: String },
+}
+
+impl SpecialToken {
+    fn into_content(self) -> String {
+        match self {
+            SpecialToken::Plain(content) => content,
+            SpecialToken::Added { content } => content,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TemplateFile {
+    chat_template: String,
+    bos_token: Option<SpecialToken>,
+    eos_token: Option<SpecialToken>,
+}
+
+/// A Hugging Face style chat template, as found in the `chat_templates` directory.
+#[derive(Debug, Clone)]
+pub struct ChatTemplate {
+    source: String,
+    bos_token: String,
+    eos_token: String,
+}
+
+impl ChatTemplate {
+    pub fn from_json(json: &str) -> Result<Self> {
+        let file: TemplateFile = serde_json::from_str(json)?;
+
+        Ok(ChatTemplate {
+            source: file.chat_template,
+            bos_token: file
+                .bos_token
+                .map(SpecialToken::into_content)
+                .unwrap_or_default(),
+            eos_token: file
+                .eos_token
+                .map(SpecialToken::into_content)
+                .unwrap_or_default(),
+        })
+    }
+
+    pub fn from_file(path: impl AsRef<Utf8Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read chat template {path}"))?;
+
+        Self::from_json(&json)
+    }
+
+    pub fn render(
+        &self,
+        messages: &[MessageHistoryItem],
+        add_generation_prompt: bool,
+    ) -> Result<String> {
+        let mut env = Environment::new();
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function(
+            "raise_exception",
+            |message: String| -> Result<String, Error> {
+                Err(Error::new(ErrorKind::InvalidOperation, message))
+            },
+        );
+
+        let template = env.template_from_str(&self.source)?;
+        let prompt = template.render(context! {
+            messages => messages,
+            bos_token => &self.bos_token,
+            eos_token => &self.eos_token,
+            add_generation_prompt => add_generation_prompt,
+        })?;
+
+        Ok(prompt)
+    }
+
+    /// Renders the history with the last assistant message left open, so that the model
+    /// keeps writing it instead of starting a new turn.
+    pub fn render_continuation(&self, messages: &[MessageHistoryItem]) -> Result<String> {
+        let Some((last, history)) = messages.split_last() else {
+            bail!("cannot continue an empty conversation");
+        };
+        if last.role != MessageRole::Assistant {
+            bail!(
+                "only assistant messages can be continued, found {}",
+                last.role
+            );
+        }
+
+        let mut messages = history.to_vec();
+        messages.push(MessageHistoryItem {
+            role: last.role,
+            content: format!("{}{OPEN_MESSAGE_MARKER}", last.content),
+        });
+
+        let prompt = self.render(&messages, false)?;
+        let end = prompt
+            .rfind(OPEN_MESSAGE_MARKER)
+            .context("chat template did not render the message to continue")?;
+
+        Ok(prompt[..end].to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use erpy_types::MessageRole;
+
+    use super::ChatTemplate;
+    use crate::MessageHistoryItem;
+
+    fn message(role: MessageRole, content: &str) -> MessageHistoryItem {
+        MessageHistoryItem {
+            role,
+            content: content.into(),
+        }
+    }
+
+    fn history() -> Vec<MessageHistoryItem> {
+        vec![
+            message(MessageRole::System, "You are Alice."),
+            message(MessageRole::User, "Hi!"),
+            message(MessageRole::Assistant, "Hello, I was just about to"),
+        ]
+    }
+
+    #[test]
+    fn test_render_generation_prompt() {
+        let template =
+            ChatTemplate::from_json(include_str!("../../src-tauri/chat_templates/chatml.json"))
+                .unwrap();
+        let prompt = template.render(&history()[..2], true).unwrap();
+
+        assert_eq!(
+            prompt,
+            "<|im_start|>system\nYou are Alice.<|im_end|>\n<|im_start|>user\nHi!<|im_end|>\n<|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn test_continuation_chatml() {
+        let template =
+            ChatTemplate::from_json(include_str!("../../src-tauri/chat_templates/chatml.json"))
+                .unwrap();
+        let prompt = template.render_continuation(&history()).unwrap();
+
+        assert!(prompt.ends_with("<|im_start|>assistant\nHello, I was just about to"));
+    }
+
+    #[test]
+    fn test_continuation_llama3() {
+        let template =
+            ChatTemplate::from_json(include_str!("../../src-tauri/chat_templates/llama3.json"))
+                .unwrap();
+        let prompt = template.render_continuation(&history()).unwrap();
+
+        assert!(prompt.ends_with(
+            "<|start_header_id|>assistant<|end_header_id|>\n\nHello, I was just about to"
+        ));
+        assert_eq!(prompt.matches("<|eot_id|>").count(), 2);
+    }
+
+    #[test]
+    fn test_continuation_vicuna_special_tokens() {
+        let template =
+            ChatTemplate::from_json(include_str!("../../src-tauri/chat_templates/vicuna.json"))
+                .unwrap();
+        let mut messages = history();
+        messages.insert(2, message(MessageRole::Assistant, "Hey."));
+        messages.insert(3, message(MessageRole::User, "What are you doing?"));
+        let prompt = template.render_continuation(&messages).unwrap();
+
+        assert!(prompt.contains(" ASSISTANT: Hey.</s>"));
+        assert!(prompt.ends_with(" ASSISTANT: Hello, I was just about to"));
+    }
+
+    #[test]
+    fn test_template_exception() {
+        let template =
+            ChatTemplate::from_json(include_str!("../../src-tauri/chat_templates/llama2.json"))
+                .unwrap();
+        let messages = vec![
+            message(MessageRole::User, "Hi!"),
+            message(MessageRole::User, "Anyone there?"),
+        ];
+
+        let error = template.render(&messages, true).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Conversation roles must alternate"));
+    }
+
+    #[test]
+    fn test_continuation_requires_assistant_message() {
+        let template =
+            ChatTemplate::from_json(include_str!("../../src-tauri/chat_templates/chatml.json"))
+                .unwrap();
+
+        assert!(template.render_continuation(&history()[..2]).is_err());
+        assert!(template.render_continuation(&[]).is_err());
+    }
+}
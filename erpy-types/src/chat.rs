@@ -0,0 +1,980 @@
+use std::fmt;
+
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+
+use crate::migration::{upgrade, Migration, Versioned};
+
+/// A conversation as a tree of messages.
+///
+/// Alternative replies ("swipes") are siblings that share a parent, so every reply keeps
+/// its own continuation. `head` is the leaf of the branch that is shown and continued.
+///
+/// A chat with more than one participant is a group chat, where the characters take
+/// turns replying according to the speaker strategy.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(remote = "Self", rename_all = "camelCase")]
+pub struct Chat {
+    pub schema_version: u32,
+    pub id: String,
+    pub title: String,
+    /// The character the chat was started with.
+    pub character_id: String,
+    /// Every character in the chat, including `character_id`.
+    pub participants: Vec<Participant>,
+    #[serde(default)]
+    pub speaker_strategy: SpeakerStrategy,
+    #[serde(default)]
+    pub group_prompt: GroupPromptMode,
+    /// The messages of every branch, each one after its parent.
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub branches: Vec<ChatBranch>,
+    pub head: Option<String>,
+    pub archived: bool,
+    /// Summaries of older messages, which stand in for them in the prompt.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub summaries: Vec<ChatSummary>,
+}
+
+impl Default for Chat {
+    fn default() -> Self {
+        Chat {
+            schema_version: Chat::SCHEMA_VERSION,
+            id: String::new(),
+            title: String::new(),
+            character_id: String::new(),
+            participants: Vec::new(),
+            speaker_strategy: SpeakerStrategy::default(),
+            group_prompt: GroupPromptMode::default(),
+            messages: Vec::new(),
+            branches: Vec::new(),
+            head: None,
+            archived: false,
+            summaries: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatMessage {
+    pub id: String,
+    pub parent_id: Option<String>,
+    pub role: MessageRole,
+    /// The character that wrote an assistant message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub character_id: Option<String>,
+    #[serde(flatten)]
+    pub content: ChatContent,
+}
+
+impl ChatMessage {
+    pub fn text(&self) -> &str {
+        &self.content.content
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Participant {
+    pub character_id: String,
+    /// Muted characters are never picked to reply and are left out of merged prompts.
+    #[serde(default)]
+    pub muted: bool,
+}
+
+/// How the character that replies next in a group chat is picked.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SpeakerStrategy {
+    /// Every character in the order of the participants.
+    #[default]
+    RoundRobin,
+    /// The characters mentioned in the last message, in the order they are mentioned.
+    Natural,
+    Random,
+    /// The user picks every speaker.
+    Manual,
+}
+
+/// Which character definitions the prompt of a group chat is built from.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupPromptMode {
+    /// Only the definition of the character that replies.
+    #[default]
+    Swap,
+    /// The definitions of all characters that aren't muted.
+    Merge,
+}
+
+/// A named leaf of the tree that can be checked out again.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatBranch {
+    pub name: String,
+    pub leaf_id: String,
+}
+
+/// A summary of the messages from `start` to `end` on one branch.
+///
+/// Summaries of messages have level 0. Consecutive summaries are merged into a summary one
+/// level above theirs, which covers all of their messages.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatSummary {
+    pub id: String,
+    pub start: String,
+    pub end: String,
+    pub level: u32,
+    pub content: String,
+}
+
+/// The summaries that stand in for the start of the checked out branch.
+#[derive(Debug, Default, PartialEq)]
+pub struct Memory<'a> {
+    /// The summaries in the order of the messages they cover.
+    pub summaries: Vec<&'a ChatSummary>,
+    /// How many messages at the start of the history the summaries cover.
+    pub covered: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatContent {
+    pub content: String,
+    pub timestamp: DateTime<Utc>,
+    pub model_id: String,
+    /// How the message was generated, for messages written by the model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<GenerationMetadata>,
+}
+
+/// The settings and statistics of a generated message, so that it can be regenerated with
+/// the same settings and a bad reply can be traced back to what produced it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerationMetadata {
+    /// The kind of backend, e.g. `open-ai` or `mistral`.
+    pub backend: String,
+    pub sampler: SamplerSettings,
+    pub seed: Option<i64>,
+    /// Counted by the backend if it reports usage, else estimated from the length of the prompt.
+    pub prompt_tokens: usize,
+    /// Counted by the backend if it reports usage, else estimated from the length of the reply.
+    pub completion_tokens: usize,
+    pub finish_reason: Option<String>,
+    /// From sending the request to the end of the reply.
+    pub latency_ms: u64,
+    #[serde(default)]
+    pub lorebook_entries: Vec<InjectedEntry>,
+    /// The ids of the summaries of older messages in the prompt.
+    #[serde(default)]
+    pub summaries: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SamplerSettings {
+    pub max_tokens: Option<usize>,
+    pub temperature: Option<f64>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    pub top_p: Option<f64>,
+}
+
+/// A lorebook entry that was inserted into the prompt.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct InjectedEntry {
+    pub lorebook: String,
+    pub id: Option<i64>,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageRole {
+    User,
+    Assistant,
+    System,
+}
+
+impl fmt::Display for MessageRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MessageRole::User => write!(f, "user"),
+            MessageRole::Assistant => write!(f, "assistant"),
+            MessageRole::System => write!(f, "system"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChatError {
+    UnknownMessage(String),
+    UnknownBranch(String),
+    DuplicateBranch(String),
+    UnknownParticipant(String),
+    DuplicateParticipant(String),
+    /// The character the chat was started with can't leave it.
+    RemovingOwner(String),
+    /// The first message of a range doesn't come before the last one.
+    InvalidRange(String, String),
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChatError::UnknownMessage(id) => write!(f, "no message with id '{id}' in chat"),
+            ChatError::UnknownBranch(name) => write!(f, "no branch named '{name}' in chat"),
+            ChatError::DuplicateBranch(name) => write!(f, "a branch named '{name}' already exists"),
+            ChatError::UnknownParticipant(id) => {
+                write!(f, "character '{id}' is not part of the chat")
+            }
+            ChatError::DuplicateParticipant(id) => {
+                write!(f, "character '{id}' is already part of the chat")
+            }
+            ChatError::RemovingOwner(id) => {
+                write!(f, "character '{id}' started the chat and can't be removed")
+            }
+            ChatError::InvalidRange(start, end) => {
+                write!(f, "message '{start}' doesn't come before message '{end}'")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChatError {}
+
+impl Chat {
+    pub fn new(
+        id: impl Into<String>,
+        title: impl Into<String>,
+        character_id: impl Into<String>,
+    ) -> Self {
+        let character_id = character_id.into();
+        Chat {
+            id: id.into(),
+            title: title.into(),
+            participants: vec![Participant {
+                character_id: character_id.clone(),
+                muted: false,
+            }],
+            character_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_group(&self) -> bool {
+        self.participants.len() > 1
+    }
+
+    pub fn participant(&self, character_id: &str) -> Option<&Participant> {
+        self.participants
+            .iter()
+            .find(|participant| participant.character_id == character_id)
+    }
+
+    pub fn add_participant(&mut self, character_id: &str) -> Result<(), ChatError> {
+        if self.participant(character_id).is_some() {
+            return Err(ChatError::DuplicateParticipant(character_id.to_string()));
+        }
+        self.participants.push(Participant {
+            character_id: character_id.to_string(),
+            muted: false,
+        });
+        Ok(())
+    }
+
+    /// Removes a character from the chat. The messages it wrote stay.
+    pub fn remove_participant(&mut self, character_id: &str) -> Result<(), ChatError> {
+        if character_id == self.character_id {
+            return Err(ChatError::RemovingOwner(character_id.to_string()));
+        }
+        self.participant(character_id)
+            .ok_or_else(|| ChatError::UnknownParticipant(character_id.to_string()))?;
+        self.participants
+            .retain(|participant| participant.character_id != character_id);
+        Ok(())
+    }
+
+    pub fn set_muted(&mut self, character_id: &str, muted: bool) -> Result<(), ChatError> {
+        let participant = self
+            .participants
+            .iter_mut()
+            .find(|participant| participant.character_id == character_id)
+            .ok_or_else(|| ChatError::UnknownParticipant(character_id.to_string()))?;
+        participant.muted = muted;
+        Ok(())
+    }
+
+    /// The character that wrote the last assistant message of the checked out branch.
+    pub fn last_speaker(&self) -> Option<&str> {
+        self.history()
+            .into_iter()
+            .rev()
+            .find(|message| message.role == MessageRole::Assistant)
+            .and_then(|message| message.character_id.as_deref())
+    }
+
+    pub fn message(&self, id: &str) -> Option<&ChatMessage> {
+        self.messages.iter().find(|message| message.id == id)
+    }
+
+    /// The replies to a message, or the first messages of the chat for `None`, oldest first.
+    pub fn children<'a>(
+        &'a self,
+        parent_id: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a ChatMessage> + 'a {
+        self.messages
+            .iter()
+            .filter(move |message| message.parent_id.as_deref() == parent_id)
+    }
+
+    /// The message and its alternatives, i.e. all messages with the same parent and role.
+    pub fn alternatives(&self, id: &str) -> Vec<&ChatMessage> {
+        let Some(message) = self.message(id) else {
+            return Vec::new();
+        };
+        self.children(message.parent_id.as_deref())
+            .filter(|sibling| sibling.role == message.role)
+            .collect()
+    }
+
+    /// The messages from the start of the chat up to and including `id`.
+    pub fn path_to(&self, id: &str) -> Vec<&ChatMessage> {
+        let mut path = Vec::new();
+        let mut current = self.message(id);
+        while let Some(message) = current {
+            path.push(message);
+            current = message.parent_id.as_deref().and_then(|id| self.message(id));
+        }
+        path.reverse();
+        path
+    }
+
+    /// The checked out branch, from the start of the chat to `head`.
+    pub fn history(&self) -> Vec<&ChatMessage> {
+        self.head
+            .as_deref()
+            .map(|head| self.path_to(head))
+            .unwrap_or_default()
+    }
+
+    /// Messages without replies, i.e. the ends of all branches.
+    pub fn leaves(&self) -> Vec<&ChatMessage> {
+        self.messages
+            .iter()
+            .filter(|message| self.children(Some(&message.id)).next().is_none())
+            .collect()
+    }
+
+    /// Adds a message after `head` and checks it out. Branches that ended at `head` move
+    /// along with it. Assistant messages are written by the character the chat was started
+    /// with, see [`Chat::append_from`] for group chats.
+    pub fn append(&mut self, role: MessageRole, content: ChatContent) -> String {
+        let character_id = (role == MessageRole::Assistant).then(|| self.character_id.clone());
+        self.push_after_head(role, character_id, content)
+    }
+
+    /// Adds a reply by one of the characters in the chat and checks it out.
+    pub fn append_from(&mut self, character_id: &str, content: ChatContent) -> String {
+        self.push_after_head(
+            MessageRole::Assistant,
+            Some(character_id.to_string()),
+            content,
+        )
+    }
+
+    fn push_after_head(
+        &mut self,
+        role: MessageRole,
+        character_id: Option<String>,
+        content: ChatContent,
+    ) -> String {
+        let id = uuid::Uuid::new_v4().to_string();
+        for branch in &mut self.branches {
+            if self.head.as_ref() == Some(&branch.leaf_id) {
+                branch.leaf_id = id.clone();
+            }
+        }
+        self.messages.push(ChatMessage {
+            id: id.clone(),
+            parent_id: self.head.clone(),
+            role,
+            character_id,
+            content,
+        });
+        self.head = Some(id.clone());
+        id
+    }
+
+    /// Adds an alternative to a message, like a regenerated reply, and checks it out.
+    pub fn add_alternative(&mut self, id: &str, content: ChatContent) -> Result<String, ChatError> {
+        let message = self
+            .message(id)
+            .ok_or_else(|| ChatError::UnknownMessage(id.to_string()))?;
+        let alternative = ChatMessage {
+            id: uuid::Uuid::new_v4().to_string(),
+            parent_id: message.parent_id.clone(),
+            role: message.role,
+            character_id: message.character_id.clone(),
+            content,
+        };
+        let alternative_id = alternative.id.clone();
+        self.messages.push(alternative);
+        self.head = Some(alternative_id.clone());
+        Ok(alternative_id)
+    }
+
+    /// Checks out the branch through `id`, continuing to its most recent reply at every
+    /// step, so choosing an earlier reply brings back the conversation that followed it.
+    pub fn checkout(&mut self, id: &str) -> Result<(), ChatError> {
+        self.head = Some(self.latest_leaf(id)?.to_string());
+        Ok(())
+    }
+
+    /// Names the branch through `id`, so it can be checked out by name later.
+    pub fn create_branch(&mut self, name: &str, id: &str) -> Result<(), ChatError> {
+        if self.branches.iter().any(|branch| branch.name == name) {
+            return Err(ChatError::DuplicateBranch(name.to_string()));
+        }
+        let leaf_id = self.latest_leaf(id)?.to_string();
+        self.branches.push(ChatBranch {
+            name: name.to_string(),
+            leaf_id,
+        });
+        Ok(())
+    }
+
+    pub fn checkout_branch(&mut self, name: &str) -> Result<(), ChatError> {
+        let branch = self
+            .branches
+            .iter()
+            .find(|branch| branch.name == name)
+            .ok_or_else(|| ChatError::UnknownBranch(name.to_string()))?;
+        self.head = Some(branch.leaf_id.clone());
+        Ok(())
+    }
+
+    /// A new chat with the conversation up to and including `id`, without other branches.
+    pub fn fork(&self, id: &str) -> Result<Chat, ChatError> {
+        if self.message(id).is_none() {
+            return Err(ChatError::UnknownMessage(id.to_string()));
+        }
+        let path = self.path_to(id);
+        let summaries = self
+            .summaries
+            .iter()
+            .filter(|summary| path.iter().any(|message| message.id == summary.end))
+            .cloned()
+            .collect();
+        Ok(Chat {
+            schema_version: Chat::SCHEMA_VERSION,
+            id: uuid::Uuid::new_v4().to_string(),
+            title: self.title.clone(),
+            character_id: self.character_id.clone(),
+            participants: self.participants.clone(),
+            speaker_strategy: self.speaker_strategy,
+            group_prompt: self.group_prompt,
+            messages: path.into_iter().cloned().collect(),
+            branches: Vec::new(),
+            head: Some(id.to_string()),
+            archived: false,
+            summaries,
+        })
+    }
+
+    /// Adds a summary of the messages from `start` to `end`.
+    pub fn add_summary(
+        &mut self,
+        start: &str,
+        end: &str,
+        level: u32,
+        content: impl Into<String>,
+    ) -> Result<&ChatSummary, ChatError> {
+        if self.message(end).is_none() {
+            return Err(ChatError::UnknownMessage(end.to_string()));
+        }
+        if !self.path_to(end).iter().any(|message| message.id == start) {
+            return Err(ChatError::InvalidRange(start.to_string(), end.to_string()));
+        }
+        self.summaries.push(ChatSummary {
+            id: uuid::Uuid::new_v4().to_string(),
+            start: start.to_string(),
+            end: end.to_string(),
+            level,
+            content: content.into(),
+        });
+        Ok(self.summaries.last().unwrap())
+    }
+
+    /// The summaries covering the start of the checked out branch. From every message, the
+    /// summary reaching furthest is taken, so merged summaries replace their parts.
+    pub fn memory(&self) -> Memory<'_> {
+        let history = self.history();
+        let mut memory = Memory::default();
+        while let Some(message) = history.get(memory.covered) {
+            let next = self
+                .summaries
+                .iter()
+                .filter(|summary| summary.start == message.id)
+                .filter_map(|summary| {
+                    let end = history[memory.covered..]
+                        .iter()
+                        .position(|message| message.id == summary.end)?;
+                    Some((end, summary))
+                })
+                .max_by_key(|(end, summary)| (*end, summary.level));
+            let Some((end, summary)) = next else {
+                break;
+            };
+            memory.summaries.push(summary);
+            memory.covered += end + 1;
+        }
+        memory
+    }
+
+    fn latest_leaf(&self, id: &str) -> Result<&str, ChatError> {
+        let mut current = self
+            .message(id)
+            .ok_or_else(|| ChatError::UnknownMessage(id.to_string()))?;
+        while let Some(child) = self.children(Some(&current.id)).last() {
+            current = child;
+        }
+        Ok(&current.id)
+    }
+}
+
+/// Chat versions:
+///
+/// 1. A list of items with the alternative replies of each message, and a chosen reply.
+/// 2. A tree of messages, with timestamps in whatever format the frontend stored them.
+/// 3. The version field, and RFC 3339 timestamps in UTC.
+/// 4. Participants, and the character that wrote every assistant message.
+impl Versioned for Chat {
+    const SCHEMA_VERSION: u32 = 4;
+    const VERSION_FIELD: &'static str = "schemaVersion";
+    const MIGRATIONS: &'static [Migration] = &[
+        Migration {
+            from: 1,
+            description: "store messages as a tree",
+            migrate: linear_to_tree,
+        },
+        Migration {
+            from: 2,
+            description: "store timestamps as RFC 3339",
+            migrate: normalize_timestamps,
+        },
+        Migration {
+            from: 3,
+            description: "add participants",
+            migrate: add_participants,
+        },
+    ];
+
+    fn detect_version(document: &Value) -> u32 {
+        if document.get("history").is_some() {
+            1
+        } else {
+            2
+        }
+    }
+}
+
+impl Serialize for Chat {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Chat::serialize(self, serializer)
+    }
+}
+
+/// Reads chats of any version, upgrading older ones.
+impl<'de> Deserialize<'de> for Chat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let document =
+            upgrade::<Chat>(Value::deserialize(deserializer)?).map_err(de::Error::custom)?;
+        Chat::deserialize(document).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LinearItem {
+    role: MessageRole,
+    content: Vec<Map<String, Value>>,
+    chosen_answer: usize,
+}
+
+/// The chosen reply of every item continues the conversation, and its alternatives become
+/// branches of their own that end there.
+fn linear_to_tree(mut document: Value) -> Result<Value, String> {
+    let history = document["history"].take();
+    let items: Vec<LinearItem> = serde_json::from_value(history).map_err(|e| e.to_string())?;
+
+    let mut messages = Vec::new();
+    let mut parent_id: Option<String> = None;
+    for (index, item) in items.into_iter().enumerate() {
+        let mut chosen = None;
+        for (answer, mut message) in item.content.into_iter().enumerate() {
+            // stable ids, since the same linear chat may be converted more than once
+            let id = format!("{index}-{answer}");
+            if answer == item.chosen_answer {
+                chosen = Some(id.clone());
+            }
+            message.insert("id".into(), id.into());
+            message.insert("parentId".into(), parent_id.clone().into());
+            message.insert("role".into(), item.role.to_string().into());
+            messages.push(Value::Object(message));
+        }
+        if chosen.is_some() {
+            parent_id = chosen;
+        }
+    }
+
+    let object = document.as_object_mut().ok_or("not an object")?;
+    object.remove("history");
+    object.insert("messages".into(), messages.into());
+    object.insert("branches".into(), Value::Array(Vec::new()));
+    object.insert("head".into(), parent_id.into());
+    Ok(document)
+}
+
+fn normalize_timestamps(mut document: Value) -> Result<Value, String> {
+    if let Some(messages) = document["messages"].as_array_mut() {
+        for message in messages {
+            let timestamp = parse_timestamp(&message["timestamp"])?;
+            message["timestamp"] = timestamp
+                .to_rfc3339_opts(SecondsFormat::Millis, true)
+                .into();
+        }
+    }
+    Ok(document)
+}
+
+/// Makes the character of a one-on-one chat its only participant and the writer of its
+/// assistant messages.
+fn add_participants(mut document: Value) -> Result<Value, String> {
+    let character_id = document["characterId"]
+        .as_str()
+        .ok_or("chat has no character")?
+        .to_string();
+    if let Some(messages) = document["messages"].as_array_mut() {
+        for message in messages {
+            if message["role"] == "assistant" && message.get("characterId").is_none() {
+                message["characterId"] = character_id.clone().into();
+            }
+        }
+    }
+    document["participants"] = serde_json::json!([{ "characterId": character_id, "muted": false }]);
+    Ok(document)
+}
+
+/// Reads RFC 3339 timestamps, SQLite dates (in UTC) and milliseconds since the epoch.
+fn parse_timestamp(value: &Value) -> Result<DateTime<Utc>, String> {
+    let parsed = match value {
+        Value::String(text) => DateTime::parse_from_rfc3339(text)
+            .map(|timestamp| timestamp.to_utc())
+            .ok()
+            .or_else(|| {
+                ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"]
+                    .iter()
+                    .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+                    .map(|timestamp| timestamp.and_utc())
+            }),
+        Value::Number(millis) => millis.as_i64().and_then(DateTime::from_timestamp_millis),
+        _ => None,
+    };
+    parsed.ok_or_else(|| format!("invalid timestamp {value}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{Chat, ChatContent, ChatError, GenerationMetadata, MessageRole};
+    use crate::{
+        migration::Versioned,
+        test_support::{chat, content},
+    };
+
+    fn texts(chat: &Chat) -> Vec<&str> {
+        chat.history()
+            .iter()
+            .map(|message| message.text())
+            .collect()
+    }
+
+    #[test]
+    fn test_alternatives_keep_their_continuations() {
+        let mut chat = chat(&[]);
+        chat.append(MessageRole::Assistant, content("Hello!"));
+        chat.append(MessageRole::User, content("Hi."));
+        let first = chat.append(MessageRole::Assistant, content("How are you?"));
+        chat.append(MessageRole::User, content("Fine."));
+
+        let second = chat
+            .add_alternative(&first, content("Nice weather."))
+            .unwrap();
+        assert_eq!(texts(&chat), vec!["Hello!", "Hi.", "Nice weather."]);
+        chat.append(MessageRole::User, content("Indeed."));
+        assert_eq!(chat.alternatives(&first).len(), 2);
+
+        chat.checkout(&first).unwrap();
+        assert_eq!(texts(&chat), vec!["Hello!", "Hi.", "How are you?", "Fine."]);
+        chat.checkout(&second).unwrap();
+        assert_eq!(
+            texts(&chat),
+            vec!["Hello!", "Hi.", "Nice weather.", "Indeed."]
+        );
+        assert_eq!(chat.leaves().len(), 2);
+    }
+
+    #[test]
+    fn test_branches_and_forks() {
+        let mut chat = chat(&[]);
+        let greeting = chat.append(MessageRole::Assistant, content("Hello!"));
+        chat.append(MessageRole::User, content("Hi."));
+        chat.create_branch("main", &greeting).unwrap();
+        assert_eq!(
+            chat.create_branch("main", &greeting),
+            Err(ChatError::DuplicateBranch("main".into()))
+        );
+
+        chat.head = Some(greeting.clone());
+        chat.append(MessageRole::User, content("Go away."));
+        chat.checkout_branch("main").unwrap();
+        assert_eq!(texts(&chat), vec!["Hello!", "Hi."]);
+        chat.append(MessageRole::Assistant, content("Welcome."));
+        chat.head = Some(greeting.clone());
+        chat.checkout_branch("main").unwrap();
+        assert_eq!(texts(&chat), vec!["Hello!", "Hi.", "Welcome."]);
+        assert!(chat.checkout_branch("other").is_err());
+
+        let fork = chat.fork(&greeting).unwrap();
+        assert_ne!(fork.id, chat.id);
+        assert_eq!(texts(&fork), vec!["Hello!"]);
+        assert_eq!(fork.messages.len(), 1);
+        assert!(chat.fork("missing").is_err());
+    }
+
+    #[test]
+    fn test_version_1_linear_chat() {
+        let item = |role: &str, contents: &[&str], chosen: usize| {
+            json!({
+                "role": role,
+                "chosenAnswer": chosen,
+                "content": contents
+                    .iter()
+                    .map(|c| json!({ "content": c, "timestamp": "2024-01-01T00:00:00Z", "modelId": "model" }))
+                    .collect::<Vec<_>>(),
+            })
+        };
+        let linear = json!({
+            "id": "chat",
+            "title": "Test",
+            "characterId": "alice",
+            "archived": false,
+            "history": [
+                item("assistant", &["Hello!"], 0),
+                item("user", &["Hi."], 0),
+                item("assistant", &["How are you?", "Nice weather."], 1),
+                item("user", &["Indeed."], 0),
+            ],
+        });
+
+        let chat: Chat = serde_json::from_value(linear.clone()).unwrap();
+        assert_eq!(chat.schema_version, Chat::SCHEMA_VERSION);
+        assert_eq!(
+            texts(&chat),
+            vec!["Hello!", "Hi.", "Nice weather.", "Indeed."]
+        );
+        assert_eq!(chat.messages.len(), 5);
+        assert_eq!(chat.alternatives("2-0").len(), 2);
+        assert_eq!(chat.children(Some("2-0")).count(), 0);
+        assert_eq!(chat.messages[0].content, content("Hello!"));
+
+        let again: Chat = serde_json::from_value(linear).unwrap();
+        assert_eq!(again, chat);
+    }
+
+    #[test]
+    fn test_version_2_tree_timestamps() {
+        let message = |id: &str, parent: Option<&str>, timestamp: serde_json::Value| {
+            json!({
+                "id": id,
+                "parentId": parent,
+                "role": "assistant",
+                "content": "Hello!",
+                "timestamp": timestamp,
+                "modelId": "model",
+            })
+        };
+        let tree = json!({
+            "id": "chat",
+            "title": "Test",
+            "characterId": "alice",
+            "archived": false,
+            "head": "c",
+            "messages": [
+                message("a", None, json!("2024-01-01 00:00:00")),
+                message("b", Some("a"), json!(1704067200000i64)),
+                message("c", Some("b"), json!("2024-01-01T01:00:00+01:00")),
+            ],
+        });
+
+        let chat: Chat = serde_json::from_value(tree.clone()).unwrap();
+        assert!(chat.branches.is_empty());
+        for message in &chat.messages {
+            assert_eq!(message.content, content("Hello!"));
+        }
+
+        let mut invalid = tree;
+        invalid["messages"][0]["timestamp"] = json!("yesterday");
+        let error = serde_json::from_value::<Chat>(invalid).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "migration from schema version 2 failed: invalid timestamp \"yesterday\""
+        );
+    }
+
+    #[test]
+    fn test_version_4_round_trip() {
+        let mut chat = chat(&[]);
+        chat.append(MessageRole::Assistant, content("Hello!"));
+        chat.create_branch("main", chat.head.clone().unwrap().as_str())
+            .unwrap();
+        chat.append(
+            MessageRole::Assistant,
+            ChatContent {
+                generation: Some(GenerationMetadata {
+                    backend: "open-ai".into(),
+                    seed: Some(42),
+                    completion_tokens: 12,
+                    finish_reason: Some("stop".into()),
+                    ..Default::default()
+                }),
+                ..content("How are you?")
+            },
+        );
+
+        let json = serde_json::to_value(&chat).unwrap();
+        assert_eq!(json["schemaVersion"], 4);
+        assert_eq!(json["messages"][0]["timestamp"], "2024-01-01T00:00:00Z");
+        assert!(json["messages"][0].get("generation").is_none());
+        assert_eq!(json["messages"][1]["generation"]["finishReason"], "stop");
+        let round_trip: Chat = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(round_trip, chat);
+
+        let mut newer = json;
+        newer["schemaVersion"] = json!(5);
+        assert!(serde_json::from_value::<Chat>(newer).is_err());
+    }
+
+    #[test]
+    fn test_version_3_adds_participants() {
+        let message = |id: &str, parent: Option<&str>, role: &str| {
+            json!({
+                "id": id,
+                "parentId": parent,
+                "role": role,
+                "content": "Hello!",
+                "timestamp": "2024-01-01T00:00:00Z",
+                "modelId": "model",
+            })
+        };
+        let v3 = json!({
+            "schemaVersion": 3,
+            "id": "chat",
+            "title": "Test",
+            "characterId": "alice",
+            "archived": false,
+            "head": "b",
+            "branches": [],
+            "messages": [message("a", None, "assistant"), message("b", Some("a"), "user")],
+        });
+
+        let chat: Chat = serde_json::from_value(v3).unwrap();
+        assert_eq!(chat.participants, Chat::new("", "", "alice").participants);
+        assert_eq!(chat.messages[0].character_id.as_deref(), Some("alice"));
+        assert_eq!(chat.messages[1].character_id, None);
+        assert!(!chat.is_group());
+    }
+
+    #[test]
+    fn test_participants() {
+        let mut chat = chat(&[]);
+        chat.add_participant("bob").unwrap();
+        assert_eq!(
+            chat.add_participant("bob"),
+            Err(ChatError::DuplicateParticipant("bob".into()))
+        );
+        assert!(chat.is_group());
+
+        chat.append(MessageRole::Assistant, content("Hello!"));
+        chat.append(MessageRole::User, content("Hi both."));
+        assert_eq!(chat.last_speaker(), Some("alice"));
+        let reply = chat.append_from("bob", content("Hey."));
+        let alternative = chat.add_alternative(&reply, content("Yo.")).unwrap();
+        assert_eq!(
+            chat.message(&alternative).unwrap().character_id.as_deref(),
+            Some("bob")
+        );
+        assert_eq!(chat.last_speaker(), Some("bob"));
+
+        chat.set_muted("bob", true).unwrap();
+        assert!(chat.participant("bob").unwrap().muted);
+        assert!(chat.set_muted("carol", true).is_err());
+        assert_eq!(
+            chat.remove_participant("alice"),
+            Err(ChatError::RemovingOwner("alice".into()))
+        );
+        chat.remove_participant("bob").unwrap();
+        assert!(!chat.is_group());
+        assert_eq!(chat.messages.len(), 4);
+    }
+
+    #[test]
+    fn test_memory_follows_the_branch() {
+        let mut chat = chat(&[]);
+        let ids: Vec<String> = (0..6)
+            .map(|i| chat.append(MessageRole::User, content(&i.to_string())))
+            .collect();
+        chat.add_summary(&ids[0], &ids[1], 0, "first").unwrap();
+        chat.add_summary(&ids[2], &ids[3], 0, "second").unwrap();
+        assert_eq!(
+            chat.add_summary(&ids[3], &ids[2], 0, "backwards")
+                .unwrap_err(),
+            ChatError::InvalidRange(ids[3].clone(), ids[2].clone())
+        );
+
+        let memory = chat.memory();
+        let contents: Vec<&str> = memory
+            .summaries
+            .iter()
+            .map(|s| s.content.as_str())
+            .collect();
+        assert_eq!(contents, vec!["first", "second"]);
+        assert_eq!(memory.covered, 4);
+
+        chat.add_summary(&ids[0], &ids[3], 1, "merged").unwrap();
+        let memory = chat.memory();
+        assert_eq!(memory.summaries.len(), 1);
+        assert_eq!(memory.summaries[0].content, "merged");
+
+        // another reply to the third message leaves the summaries after it behind
+        chat.add_alternative(&ids[3], content("3b")).unwrap();
+        let memory = chat.memory();
+        let contents: Vec<&str> = memory
+            .summaries
+            .iter()
+            .map(|s| s.content.as_str())
+            .collect();
+        assert_eq!(contents, vec!["first"]);
+        assert_eq!(memory.covered, 2);
+        assert_eq!(chat.fork(&ids[2]).unwrap().summaries.len(), 1);
+    }
+}
@@ -1,80 +1,422 @@
-use std::fmt;
+use std::{collections::BTreeMap, fmt};
 
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-#[serde(rename_all = "camelCase")]
-pub struct Chat {
-    pub id: String,
-    pub title: String,
-    pub character_id: String,
-    pub history: Vec<ChatHistoryItem>,
-    pub archived: bool,
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-#[serde(rename_all = "camelCase")]
-pub struct ChatHistoryItem {
-    pub role: MessageRole,
-    pub content: Vec<ChatContent>,
-    pub chosen_answer: usize,
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-#[serde(rename_all = "camelCase")]
-pub struct ChatContent {
-    pub content: String,
-    pub timestamp: String,
-    pub model_id: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
-#[serde(rename_all = "lowercase")]
-pub enum MessageRole {
-    User,
-    Assistant,
-    System,
-}
-
-impl fmt::Display for MessageRole {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            MessageRole::User => write!(f, "user"),
-            MessageRole::Assistant => write!(f, "assistant"),
-            MessageRole::System => write!(f, "system"),
-        }
+pub mod chat;
+pub mod hash;
+pub mod lorebook;
+pub mod migration;
+pub mod persona;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+
+pub use chat::{
+    Chat, ChatBranch, ChatContent, ChatError, ChatMessage, ChatSummary, GenerationMetadata,
+    GroupPromptMode, InjectedEntry, Memory, MessageRole, Participant, SamplerSettings,
+    SpeakerStrategy,
+};
+pub use lorebook::{Lorebook, LorebookEntry, LorebookPosition, SelectiveLogic};
+pub use migration::{Migration, MigrationError, Versioned};
+pub use persona::{resolve_persona, Persona, PersonaLock};
+
+use lorebook::CharacterBook;
+use migration::upgrade;
+
+/// Deserializes `null` like a missing field, for documents from other apps.
+pub fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// An asset embedded in or referenced by a V3 character card.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct CharacterAsset {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub uri: String,
+    pub name: String,
+    pub ext: String,
+}
+
+/// The point of an image that is kept when it is cropped, relative to its size.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct FocalPoint {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Default for FocalPoint {
+    fn default() -> Self {
+        FocalPoint { x: 0.5, y: 0.5 }
     }
 }
-#[derive(Serialize, Deserialize)]
+
+/// A scaled down copy of a [`CharacterImage`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Thumbnail {
+    /// The asset id of the thumbnail.
+    pub id: String,
+    /// The size the thumbnail was requested at, i.e. its longest side.
+    pub size: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Whether the image was cropped around its focal point before scaling.
+    pub cropped: bool,
+}
+
+/// A character's image, kept in the asset store in its original form along with thumbnails.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CharacterImage {
+    /// The asset id of the original image.
+    pub original: String,
+    pub width: u32,
+    pub height: u32,
+    #[serde(default)]
+    pub focal_point: FocalPoint,
+    #[serde(default)]
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+/// A character, with every field of the V2 and V3 character card specs.
+///
+/// Fields missing from older stored characters fall back to their defaults.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(remote = "Self")]
 pub struct CharacterInformation {
+    pub schema_version: u32,
     pub name: String,
     pub description: String,
     pub personality: String,
+    /// The card's `first_mes`, followed by its `alternate_greetings`.
     pub first_messages: Vec<String>,
     pub tags: Vec<String>,
     pub system_prompt: String,
+    #[serde(default)]
+    pub scenario: String,
+    /// The card's `mes_example`.
+    #[serde(default)]
+    pub example_dialogues: String,
+    #[serde(default)]
+    pub post_history_instructions: String,
+    #[serde(default)]
+    pub creator: String,
+    #[serde(default)]
+    pub creator_notes: String,
+    /// Creator notes by ISO 639 language code (V3).
+    #[serde(default)]
+    pub creator_notes_multilingual: BTreeMap<String, String>,
+    #[serde(default)]
+    pub character_version: String,
+    /// The name used in place of `name` for `{{char}}` (V3).
+    #[serde(default)]
+    pub nickname: Option<String>,
+    /// Greetings only used in group chats (V3).
+    #[serde(default)]
+    pub group_only_greetings: Vec<String>,
+    /// Where the card was obtained from (V3).
+    #[serde(default)]
+    pub source: Vec<String>,
+    /// Unix timestamp in seconds (V3).
+    #[serde(default)]
+    pub creation_date: Option<i64>,
+    /// Unix timestamp in seconds (V3).
+    #[serde(default)]
+    pub modification_date: Option<i64>,
+    #[serde(default)]
+    pub assets: Vec<CharacterAsset>,
+    /// The card's embedded lorebook.
+    #[serde(default)]
+    pub character_book: Option<Lorebook>,
+    /// Application specific data, kept as-is.
+    #[serde(default)]
+    pub extensions: Map<String, Value>,
     pub avatar: Option<String>,
+    #[serde(default)]
+    pub image: Option<CharacterImage>,
+    /// The inline image of characters imported before images were kept in the asset store.
     pub image_base64: Option<String>,
 }
 
 impl fmt::Debug for CharacterInformation {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("CharacterInformation")
+            .field("schema_version", &self.schema_version)
             .field("name", &self.name)
             .field("description", &self.description)
             .field("personality", &self.personality)
             .field("first_messages", &self.first_messages)
             .field("tags", &self.tags)
             .field("system_prompt", &self.system_prompt)
+            .field("scenario", &self.scenario)
+            .field("example_dialogues", &self.example_dialogues)
+            .field("post_history_instructions", &self.post_history_instructions)
+            .field("creator", &self.creator)
+            .field("creator_notes", &self.creator_notes)
+            .field(
+                "creator_notes_multilingual",
+                &self.creator_notes_multilingual,
+            )
+            .field("character_version", &self.character_version)
+            .field("nickname", &self.nickname)
+            .field("group_only_greetings", &self.group_only_greetings)
+            .field("source", &self.source)
+            .field("creation_date", &self.creation_date)
+            .field("modification_date", &self.modification_date)
+            .field("assets", &self.assets)
+            .field("character_book", &self.character_book.is_some())
+            .field("extensions", &self.extensions)
             .field("avatar", &self.avatar)
+            .field("image", &self.image)
             .field("image_base64", &self.image_base64.is_some())
             .finish()
     }
 }
 
+impl Default for CharacterInformation {
+    fn default() -> Self {
+        CharacterInformation {
+            schema_version: CharacterInformation::SCHEMA_VERSION,
+            name: String::new(),
+            description: String::new(),
+            personality: String::new(),
+            first_messages: Vec::new(),
+            tags: Vec::new(),
+            system_prompt: String::new(),
+            scenario: String::new(),
+            example_dialogues: String::new(),
+            post_history_instructions: String::new(),
+            creator: String::new(),
+            creator_notes: String::new(),
+            creator_notes_multilingual: BTreeMap::new(),
+            character_version: String::new(),
+            nickname: None,
+            group_only_greetings: Vec::new(),
+            source: Vec::new(),
+            creation_date: None,
+            modification_date: None,
+            assets: Vec::new(),
+            character_book: None,
+            extensions: Map::new(),
+            avatar: None,
+            image: None,
+            image_base64: None,
+        }
+    }
+}
+
+/// Character versions:
+///
+/// 1. Written before the version field, with an inline image that is either a data URL or
+///    bare base64, and a lorebook that is either a [`Lorebook`] or the raw `character_book`
+///    of the card it was imported from.
+/// 2. The version field, inline images as data URLs and lorebooks as [`Lorebook`].
+impl Versioned for CharacterInformation {
+    const SCHEMA_VERSION: u32 = 2;
+    const VERSION_FIELD: &'static str = "schema_version";
+    const MIGRATIONS: &'static [Migration] = &[Migration {
+        from: 1,
+        description: "store inline images as data URLs and lorebooks as Lorebook",
+        migrate: |document| character_book_lorebook(inline_image_data_url(document)?),
+    }];
+
+    fn detect_version(_: &Value) -> u32 {
+        1
+    }
+}
+
+impl Serialize for CharacterInformation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        CharacterInformation::serialize(self, serializer)
+    }
+}
+
+/// Reads characters of any version, upgrading older ones.
+impl<'de> Deserialize<'de> for CharacterInformation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let document = upgrade::<CharacterInformation>(Value::deserialize(deserializer)?)
+            .map_err(de::Error::custom)?;
+        CharacterInformation::deserialize(document).map_err(de::Error::custom)
+    }
+}
+
+/// Characters fetched from chub.ai used to keep their image as bare base64.
+fn inline_image_data_url(mut document: Value) -> Result<Value, String> {
+    if let Some(Value::String(image)) = document.get_mut("image_base64") {
+        if !image.is_empty() && !image.starts_with("data:") {
+            let mime = match image.get(..5) {
+                Some("iVBOR") => "image/png",
+                Some("/9j/4") => "image/jpeg",
+                Some("R0lGO") => "image/gif",
+                Some("UklGR") => "image/webp",
+                _ => return Ok(document),
+            };
+            *image = format!("data:{mime};base64,{image}");
+        }
+    }
+    Ok(document)
+}
+
+/// Characters imported before lorebooks were read kept the `character_book` of their card
+/// as-is, with SillyTavern's fields in the entries' extensions.
+fn character_book_lorebook(mut document: Value) -> Result<Value, String> {
+    let Some(book) = document
+        .get_mut("character_book")
+        .filter(|book| !book.is_null())
+    else {
+        return Ok(document);
+    };
+    // every field of a lorebook entry is written, while cards keep the logic in extensions
+    let is_lorebook = book["entries"].as_array().is_some_and(|entries| {
+        entries
+            .iter()
+            .all(|entry| entry.get("selective_logic").is_some())
+    });
+    if !is_lorebook {
+        let card_book: CharacterBook =
+            serde_json::from_value(book.take()).map_err(|e| e.to_string())?;
+        *book = serde_json::to_value(card_book.into_lorebook()).map_err(|e| e.to_string())?;
+    }
+    Ok(document)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Character {
     pub id: String,
     pub url: Option<String>,
     pub payload: CharacterInformation,
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{CharacterInformation, Lorebook, LorebookEntry, LorebookPosition, Versioned};
+
+    #[test]
+    fn test_deserialize_stored_character_without_card_fields() {
+        let json = r#"{
+            "name": "Alice",
+            "description": "A librarian.",
+            "personality": "curious",
+            "first_messages": ["Hello!"],
+            "tags": ["books"],
+            "system_prompt": "Write Alice's next reply.",
+            "avatar": null,
+            "image_base64": null
+        }"#;
+
+        let character: CharacterInformation = serde_json::from_str(json).unwrap();
+        assert_eq!(character.name, "Alice");
+        assert!(character.scenario.is_empty());
+        assert!(character.character_book.is_none());
+        assert!(character.extensions.is_empty());
+        assert!(character.nickname.is_none());
+        assert_eq!(
+            character.schema_version,
+            CharacterInformation::SCHEMA_VERSION
+        );
+    }
+
+    #[test]
+    fn test_version_1_bare_base64_image() {
+        let baseline = json!({
+            "name": "Alice",
+            "description": "",
+            "personality": "",
+            "first_messages": [],
+            "tags": [],
+            "system_prompt": "",
+            "avatar": "https://avatars.charhub.io/alice.webp",
+            "image_base64": "UklGRiQAAABXRUJQ"
+        });
+
+        let character: CharacterInformation = serde_json::from_value(baseline).unwrap();
+        assert_eq!(
+            character.image_base64.as_deref(),
+            Some("data:image/webp;base64,UklGRiQAAABXRUJQ")
+        );
+    }
+
+    #[test]
+    fn test_version_1_card_character_book() {
+        let baseline = json!({
+            "name": "Alice",
+            "description": "",
+            "personality": "",
+            "first_messages": [],
+            "tags": [],
+            "system_prompt": "",
+            "avatar": null,
+            "image_base64": null,
+            "character_book": {
+                "name": "Gull Rock",
+                "entries": [{
+                    "keys": ["storm"],
+                    "content": "Storms last for days.",
+                    "id": "7",
+                    "comment": "Storms",
+                    "extensions": { "position": 4, "depth": 2, "group": "weather" }
+                }]
+            }
+        });
+
+        let character: CharacterInformation = serde_json::from_value(baseline).unwrap();
+        let lorebook = character.character_book.unwrap();
+        assert_eq!(lorebook.name, "Gull Rock");
+        let entry = &lorebook.entries[0];
+        assert_eq!(entry.id, Some(7));
+        assert_eq!(entry.name, "Storms");
+        assert_eq!(entry.position, LorebookPosition::AtDepth);
+        assert_eq!(entry.depth, Some(2));
+        assert_eq!(entry.extensions["group"], "weather");
+    }
+
+    #[test]
+    fn test_version_1_lorebook_is_kept() {
+        let lorebook = Lorebook {
+            name: "Gull Rock".into(),
+            entries: vec![LorebookEntry {
+                id: Some(7),
+                keys: vec!["storm".into()],
+                position: LorebookPosition::AtDepth,
+                extensions: [("group".to_string(), json!("weather"))]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut json = serde_json::to_value(CharacterInformation {
+            name: "Alice".into(),
+            character_book: Some(lorebook.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+        json.as_object_mut().unwrap().remove("schema_version");
+
+        let character: CharacterInformation = serde_json::from_value(json).unwrap();
+        assert_eq!(character.character_book, Some(lorebook));
+    }
+
+    #[test]
+    fn test_version_2_round_trip() {
+        let character = CharacterInformation {
+            name: "Alice".into(),
+            image_base64: Some("data:image/png;base64,iVBORw0KGgo".into()),
+            ..Default::default()
+        };
+
+        let json = serde_json::to_value(&character).unwrap();
+        assert_eq!(json["schema_version"], 2);
+        let round_trip: CharacterInformation = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(round_trip.image_base64, character.image_base64);
+
+        let mut newer = json;
+        newer["schema_version"] = json!(3);
+        assert!(serde_json::from_value::<CharacterInformation>(newer).is_err());
+    }
+}
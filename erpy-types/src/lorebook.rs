@@ -0,0 +1,340 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::null_as_default;
+
+/// A set of keyword-triggered world info entries, either embedded in a character card
+/// (`character_book`) or imported on its own.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Lorebook {
+    pub name: String,
+    pub description: String,
+    /// How many of the most recent messages are scanned for keys.
+    pub scan_depth: Option<usize>,
+    /// The most tokens that activated entries may add to the prompt.
+    pub token_budget: Option<usize>,
+    /// Whether activated entries are scanned for keys of other entries.
+    pub recursive_scanning: bool,
+    pub entries: Vec<LorebookEntry>,
+    pub extensions: Map<String, Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct LorebookEntry {
+    pub id: Option<i64>,
+    pub name: String,
+    pub keys: Vec<String>,
+    pub secondary_keys: Vec<String>,
+    /// Whether the secondary keys are checked, combined with `selective_logic`.
+    pub selective: bool,
+    pub selective_logic: SelectiveLogic,
+    pub content: String,
+    pub enabled: bool,
+    /// Constant entries are always inserted, regardless of their keys.
+    pub constant: bool,
+    /// Entries with a lower order are inserted first.
+    pub insertion_order: i64,
+    /// Entries with a higher priority are kept first when the token budget runs out.
+    pub priority: Option<i64>,
+    pub position: LorebookPosition,
+    /// The message depth for entries at [`LorebookPosition::AtDepth`].
+    pub depth: Option<usize>,
+    /// The chance in percent that the entry is inserted when it matches.
+    pub probability: Option<u8>,
+    pub case_sensitive: Option<bool>,
+    pub match_whole_words: Option<bool>,
+    /// Whether the keys are regular expressions.
+    pub use_regex: bool,
+    pub scan_depth: Option<usize>,
+    /// Number of messages the entry stays active after it matched.
+    pub sticky: Option<u32>,
+    /// Number of messages the entry can't be activated again after it was active.
+    pub cooldown: Option<u32>,
+    /// Number of messages the chat needs before the entry can be activated.
+    pub delay: Option<u32>,
+    /// The entry can't be activated by other entries' content.
+    pub exclude_recursion: bool,
+    /// The entry's content doesn't activate other entries.
+    pub prevent_recursion: bool,
+    pub extensions: Map<String, Value>,
+}
+
+impl Default for LorebookEntry {
+    fn default() -> Self {
+        LorebookEntry {
+            id: None,
+            name: String::new(),
+            keys: Vec::new(),
+            secondary_keys: Vec::new(),
+            selective: false,
+            selective_logic: SelectiveLogic::default(),
+            content: String::new(),
+            enabled: true,
+            constant: false,
+            insertion_order: 100,
+            priority: None,
+            position: LorebookPosition::default(),
+            depth: None,
+            probability: None,
+            case_sensitive: None,
+            match_whole_words: None,
+            use_regex: false,
+            scan_depth: None,
+            sticky: None,
+            cooldown: None,
+            delay: None,
+            exclude_recursion: false,
+            prevent_recursion: false,
+            extensions: Map::new(),
+        }
+    }
+}
+
+/// How the secondary keys of a selective entry combine with its primary keys.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectiveLogic {
+    /// Any secondary key must match.
+    #[default]
+    AndAny,
+    /// Not all secondary keys may match.
+    NotAll,
+    /// None of the secondary keys may match.
+    NotAny,
+    /// All secondary keys must match.
+    AndAll,
+}
+
+impl SelectiveLogic {
+    /// Reads the number SillyTavern uses for the logic.
+    pub fn from_sillytavern(logic: i64) -> Self {
+        match logic {
+            1 => SelectiveLogic::NotAll,
+            2 => SelectiveLogic::NotAny,
+            3 => SelectiveLogic::AndAll,
+            _ => SelectiveLogic::AndAny,
+        }
+    }
+
+    pub fn to_sillytavern(self) -> i64 {
+        match self {
+            SelectiveLogic::AndAny => 0,
+            SelectiveLogic::NotAll => 1,
+            SelectiveLogic::NotAny => 2,
+            SelectiveLogic::AndAll => 3,
+        }
+    }
+}
+
+/// Where an activated entry is inserted into the prompt.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LorebookPosition {
+    #[default]
+    #[serde(alias = "before_char")]
+    BeforeCharacter,
+    #[serde(alias = "after_char")]
+    AfterCharacter,
+    BeforeAuthorNote,
+    AfterAuthorNote,
+    AtDepth,
+    BeforeExamples,
+    AfterExamples,
+}
+
+impl LorebookPosition {
+    /// Reads the number SillyTavern uses for the position.
+    pub fn from_sillytavern(position: i64) -> Self {
+        match position {
+            1 => LorebookPosition::AfterCharacter,
+            2 => LorebookPosition::BeforeAuthorNote,
+            3 => LorebookPosition::AfterAuthorNote,
+            4 => LorebookPosition::AtDepth,
+            5 => LorebookPosition::BeforeExamples,
+            6 => LorebookPosition::AfterExamples,
+            _ => LorebookPosition::BeforeCharacter,
+        }
+    }
+
+    pub fn to_sillytavern(self) -> i64 {
+        match self {
+            LorebookPosition::BeforeCharacter => 0,
+            LorebookPosition::AfterCharacter => 1,
+            LorebookPosition::BeforeAuthorNote => 2,
+            LorebookPosition::AfterAuthorNote => 3,
+            LorebookPosition::AtDepth => 4,
+            LorebookPosition::BeforeExamples => 5,
+            LorebookPosition::AfterExamples => 6,
+        }
+    }
+}
+
+/// The `character_book` of V2 and V3 character cards.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CharacterBook {
+    #[serde(deserialize_with = "null_as_default")]
+    name: String,
+    #[serde(deserialize_with = "null_as_default")]
+    description: String,
+    scan_depth: Option<usize>,
+    token_budget: Option<usize>,
+    #[serde(deserialize_with = "null_as_default")]
+    recursive_scanning: bool,
+    #[serde(deserialize_with = "null_as_default")]
+    extensions: Map<String, Value>,
+    #[serde(deserialize_with = "null_as_default")]
+    entries: Vec<CharacterBookEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct CharacterBookEntry {
+    #[serde(deserialize_with = "null_as_default")]
+    keys: Vec<String>,
+    #[serde(deserialize_with = "null_as_default")]
+    content: String,
+    #[serde(deserialize_with = "null_as_default")]
+    extensions: Map<String, Value>,
+    enabled: Option<bool>,
+    insertion_order: Option<i64>,
+    case_sensitive: Option<bool>,
+    #[serde(deserialize_with = "null_as_default")]
+    use_regex: bool,
+    #[serde(deserialize_with = "null_as_default")]
+    name: String,
+    priority: Option<i64>,
+    id: Option<Value>,
+    #[serde(deserialize_with = "null_as_default")]
+    comment: String,
+    #[serde(deserialize_with = "null_as_default")]
+    selective: bool,
+    #[serde(deserialize_with = "null_as_default")]
+    secondary_keys: Vec<String>,
+    #[serde(deserialize_with = "null_as_default")]
+    constant: bool,
+    position: Option<String>,
+}
+
+impl Default for CharacterBookEntry {
+    fn default() -> Self {
+        CharacterBookEntry {
+            keys: Vec::new(),
+            content: String::new(),
+            extensions: Map::new(),
+            enabled: None,
+            insertion_order: None,
+            case_sensitive: None,
+            use_regex: false,
+            name: String::new(),
+            priority: None,
+            id: None,
+            comment: String::new(),
+            selective: false,
+            secondary_keys: Vec::new(),
+            constant: false,
+            position: None,
+        }
+    }
+}
+
+impl CharacterBookEntry {
+    fn into_entry(mut self) -> LorebookEntry {
+        // SillyTavern keeps the fields the spec lacks in the entry's extensions
+        let extensions = &mut self.extensions;
+        let position = take_i64(extensions, "position")
+            .map(LorebookPosition::from_sillytavern)
+            .unwrap_or(match self.position.as_deref() {
+                Some("after_char") => LorebookPosition::AfterCharacter,
+                _ => LorebookPosition::BeforeCharacter,
+            });
+        let use_probability = take_bool(extensions, "useProbability").unwrap_or(true);
+        let probability = take_i64(extensions, "probability")
+            .filter(|_| use_probability)
+            .map(|p| p.clamp(0, 100) as u8);
+        let extension_case_sensitive = take_bool(extensions, "case_sensitive");
+
+        LorebookEntry {
+            id: self.id.as_ref().and_then(value_as_i64),
+            name: if self.name.is_empty() {
+                self.comment
+            } else {
+                self.name
+            },
+            keys: self.keys,
+            secondary_keys: self.secondary_keys,
+            selective: self.selective,
+            selective_logic: take_i64(extensions, "selectiveLogic")
+                .map(SelectiveLogic::from_sillytavern)
+                .unwrap_or_default(),
+            content: self.content,
+            enabled: self.enabled.unwrap_or(true),
+            constant: self.constant,
+            insertion_order: self.insertion_order.unwrap_or(100),
+            priority: self.priority,
+            position,
+            depth: take_i64(extensions, "depth").map(|d| d.max(0) as usize),
+            probability,
+            case_sensitive: self.case_sensitive.or(extension_case_sensitive),
+            match_whole_words: take_bool(extensions, "match_whole_words"),
+            use_regex: self.use_regex,
+            scan_depth: take_i64(extensions, "scan_depth").map(|d| d.max(0) as usize),
+            sticky: take_turns(extensions, "sticky"),
+            cooldown: take_turns(extensions, "cooldown"),
+            delay: take_turns(extensions, "delay"),
+            exclude_recursion: take_bool(extensions, "exclude_recursion").unwrap_or(false),
+            prevent_recursion: take_bool(extensions, "prevent_recursion").unwrap_or(false),
+            extensions: self.extensions,
+        }
+    }
+}
+
+impl CharacterBook {
+    pub fn into_lorebook(self) -> Lorebook {
+        Lorebook {
+            name: self.name,
+            description: self.description,
+            scan_depth: self.scan_depth,
+            token_budget: self.token_budget,
+            recursive_scanning: self.recursive_scanning,
+            entries: self
+                .entries
+                .into_iter()
+                .map(CharacterBookEntry::into_entry)
+                .collect(),
+            extensions: self.extensions,
+        }
+    }
+}
+
+fn value_as_i64(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn take_i64(extensions: &mut Map<String, Value>, key: &str) -> Option<i64> {
+    extensions
+        .remove(key)
+        .as_ref()
+        .and_then(|value| match value {
+            Value::Number(n) => n.as_i64().or_else(|| n.as_f64().map(|f| f as i64)),
+            Value::String(s) => s.parse().ok(),
+            _ => None,
+        })
+}
+
+fn take_bool(extensions: &mut Map<String, Value>, key: &str) -> Option<bool> {
+    extensions.remove(key).and_then(|value| value.as_bool())
+}
+
+/// SillyTavern uses `0` or `null` for sticky, cooldown and delay when they're off.
+fn take_turns(extensions: &mut Map<String, Value>, key: &str) -> Option<u32> {
+    take_i64(extensions, key)
+        .filter(|&n| n > 0)
+        .map(|n| n.min(u32::MAX as i64) as u32)
+}
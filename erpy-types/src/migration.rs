@@ -0,0 +1,179 @@
+use std::fmt;
+
+use serde_json::Value;
+
+/// A type that is persisted as JSON and carries the version of its shape.
+///
+/// Documents written by older versions of the app are upgraded one version at a time by
+/// the type's migrations before they are deserialized.
+pub trait Versioned {
+    /// The version documents are written with.
+    const SCHEMA_VERSION: u32;
+    /// The field that holds the version.
+    const VERSION_FIELD: &'static str;
+    /// One migration for every older version.
+    const MIGRATIONS: &'static [Migration];
+
+    /// The version of a document written before the version field existed.
+    fn detect_version(document: &Value) -> u32;
+}
+
+/// Upgrades a document from version `from` to the next one.
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    pub migrate: fn(Value) -> Result<Value, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MigrationError {
+    NotAnObject,
+    InvalidVersion(Value),
+    /// The document was written by a newer version of the app.
+    NewerVersion {
+        version: u32,
+        supported: u32,
+    },
+    MissingMigration(u32),
+    Failed {
+        from: u32,
+        message: String,
+    },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::NotAnObject => write!(f, "document is not a JSON object"),
+            MigrationError::InvalidVersion(version) => {
+                write!(f, "invalid schema version {version}")
+            }
+            MigrationError::NewerVersion { version, supported } => write!(
+                f,
+                "schema version {version} is newer than the supported version {supported}"
+            ),
+            MigrationError::MissingMigration(from) => {
+                write!(f, "no migration from schema version {from}")
+            }
+            MigrationError::Failed { from, message } => {
+                write!(f, "migration from schema version {from} failed: {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+/// The version of a document, from its version field or its shape.
+pub fn schema_version<T: Versioned>(document: &Value) -> Result<u32, MigrationError> {
+    let object = document.as_object().ok_or(MigrationError::NotAnObject)?;
+    match object.get(T::VERSION_FIELD) {
+        None | Some(Value::Null) => Ok(T::detect_version(document)),
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .filter(|version| *version > 0)
+            .ok_or_else(|| MigrationError::InvalidVersion(version.clone())),
+    }
+}
+
+/// Applies the migrations from the document's version up to the current one.
+pub fn upgrade<T: Versioned>(mut document: Value) -> Result<Value, MigrationError> {
+    let mut version = schema_version::<T>(&document)?;
+    if version > T::SCHEMA_VERSION {
+        return Err(MigrationError::NewerVersion {
+            version,
+            supported: T::SCHEMA_VERSION,
+        });
+    }
+
+    while version < T::SCHEMA_VERSION {
+        let migration = T::MIGRATIONS
+            .iter()
+            .find(|migration| migration.from == version)
+            .ok_or(MigrationError::MissingMigration(version))?;
+        document = (migration.migrate)(document).map_err(|message| MigrationError::Failed {
+            from: version,
+            message,
+        })?;
+        version += 1;
+    }
+
+    match document.as_object_mut() {
+        Some(object) => {
+            object.insert(T::VERSION_FIELD.into(), T::SCHEMA_VERSION.into());
+            Ok(document)
+        }
+        None => Err(MigrationError::NotAnObject),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{upgrade, Migration, MigrationError, Versioned};
+
+    struct Note;
+
+    impl Versioned for Note {
+        const SCHEMA_VERSION: u32 = 3;
+        const VERSION_FIELD: &'static str = "version";
+        const MIGRATIONS: &'static [Migration] = &[
+            Migration {
+                from: 1,
+                description: "rename text to body",
+                migrate: |mut document| {
+                    let text = document["text"].take();
+                    document["body"] = text;
+                    Ok(document)
+                },
+            },
+            Migration {
+                from: 2,
+                description: "require a body",
+                migrate: |document| match document["body"] {
+                    Value::String(_) => Ok(document),
+                    _ => Err("body is not a string".into()),
+                },
+            },
+        ];
+
+        fn detect_version(_: &Value) -> u32 {
+            1
+        }
+    }
+
+    #[test]
+    fn test_upgrade_runs_migrations_in_order() {
+        let upgraded = upgrade::<Note>(json!({ "text": "hello" })).unwrap();
+        assert_eq!(upgraded["body"], "hello");
+        assert_eq!(upgraded["version"], 3);
+
+        let current = json!({ "version": 3, "body": "hello" });
+        assert_eq!(upgrade::<Note>(current.clone()).unwrap(), current);
+    }
+
+    #[test]
+    fn test_upgrade_errors() {
+        assert_eq!(
+            upgrade::<Note>(json!({ "version": 4 })),
+            Err(MigrationError::NewerVersion {
+                version: 4,
+                supported: 3
+            })
+        );
+        assert_eq!(
+            upgrade::<Note>(json!({ "version": "2" })),
+            Err(MigrationError::InvalidVersion(json!("2")))
+        );
+        assert_eq!(
+            upgrade::<Note>(json!({ "version": 2, "body": 1 })),
+            Err(MigrationError::Failed {
+                from: 2,
+                message: "body is not a string".into()
+            })
+        );
+        assert_eq!(upgrade::<Note>(json!([])), Err(MigrationError::NotAnObject));
+    }
+}
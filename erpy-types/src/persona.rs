@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+
+/// Who the user is in a chat: the name `{{user}}` expands to and a description of them
+/// that is added to the prompt.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Persona {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    /// The asset id of the avatar.
+    pub avatar: Option<String>,
+    /// Used in chats the persona isn't locked to, unless another persona is locked to them.
+    pub is_default: bool,
+    /// Characters whose chats use this persona.
+    pub locked_characters: Vec<String>,
+    /// Chats that use this persona, regardless of their character.
+    pub locked_chats: Vec<String>,
+}
+
+/// What a persona is locked to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "id", rename_all = "camelCase")]
+pub enum PersonaLock {
+    Character(String),
+    Chat(String),
+}
+
+impl Persona {
+    pub fn new(name: impl Into<String>) -> Self {
+        Persona {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_locked_to(&self, lock: &PersonaLock) -> bool {
+        match lock {
+            PersonaLock::Character(id) => self.locked_characters.contains(id),
+            PersonaLock::Chat(id) => self.locked_chats.contains(id),
+        }
+    }
+
+    /// Locks the persona to a character or chat, or unlocks it.
+    pub fn set_locked(&mut self, lock: &PersonaLock, locked: bool) {
+        let (ids, id) = match lock {
+            PersonaLock::Character(id) => (&mut self.locked_characters, id),
+            PersonaLock::Chat(id) => (&mut self.locked_chats, id),
+        };
+        ids.retain(|other| other != id);
+        if locked {
+            ids.push(id.clone());
+        }
+    }
+}
+
+/// The persona used in a chat: the one locked to the chat, then the one locked to its
+/// character, then the default persona.
+pub fn resolve_persona<'a>(
+    personas: &'a [Persona],
+    chat_id: &str,
+    character_id: &str,
+) -> Option<&'a Persona> {
+    let chat = PersonaLock::Chat(chat_id.to_string());
+    let character = PersonaLock::Character(character_id.to_string());
+    personas
+        .iter()
+        .find(|persona| persona.is_locked_to(&chat))
+        .or_else(|| {
+            personas
+                .iter()
+                .find(|persona| persona.is_locked_to(&character))
+        })
+        .or_else(|| personas.iter().find(|persona| persona.is_default))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve_persona, Persona, PersonaLock};
+
+    #[test]
+    fn test_resolve_persona() {
+        let mut knight = Persona::new("Sir Dave");
+        knight.set_locked(&PersonaLock::Character("arthur".into()), true);
+        let mut detective = Persona::new("Detective Dave");
+        detective.set_locked(&PersonaLock::Chat("mystery".into()), true);
+        let mut dave = Persona::new("Dave");
+        dave.is_default = true;
+        let personas = vec![knight, detective, dave];
+
+        let name = |chat: &str, character: &str| {
+            resolve_persona(&personas, chat, character).map(|persona| persona.name.as_str())
+        };
+        assert_eq!(name("quest", "arthur"), Some("Sir Dave"));
+        assert_eq!(name("mystery", "arthur"), Some("Detective Dave"));
+        assert_eq!(name("tavern", "merlin"), Some("Dave"));
+        assert_eq!(resolve_persona(&personas[..2], "tavern", "merlin"), None);
+    }
+}
@@ -0,0 +1,41 @@
+//! Fixtures for the tests of this crate and the crates that use it, enabled with the
+//! `test-support` feature.
+
+use crate::{Chat, ChatContent, MessageRole};
+
+/// The content of a message written by "model" at the start of 2024.
+pub fn content(text: &str) -> ChatContent {
+    content_at(text, "2024-01-01T00:00:00Z")
+}
+
+/// The content of a message written by "model" at an RFC 3339 `timestamp`.
+pub fn content_at(text: &str, timestamp: &str) -> ChatContent {
+    ChatContent {
+        content: text.into(),
+        timestamp: timestamp.parse().unwrap(),
+        model_id: "model".into(),
+        generation: None,
+    }
+}
+
+/// A chat "chat" with the character "alice", with the messages one after the other.
+pub fn chat(messages: &[(MessageRole, &str)]) -> Chat {
+    let mut chat = Chat::new("chat", "Test", "alice");
+    for (role, text) in messages {
+        chat.append(*role, content(text));
+    }
+    chat
+}
+
+/// A chat like [`chat`] where the character and the user take turns, the character first.
+pub fn dialogue(texts: &[&str]) -> Chat {
+    let messages: Vec<(MessageRole, &str)> = texts
+        .iter()
+        .enumerate()
+        .map(|(i, text)| match i % 2 {
+            0 => (MessageRole::Assistant, *text),
+            _ => (MessageRole::User, *text),
+        })
+        .collect();
+    chat(&messages)
+}
@@ -0,0 +1,682 @@
+use std::collections::{HashMap, HashSet};
+
+use erpy_ai::{estimate_tokens, MessageHistoryItem};
+use erpy_types::{hash::stable_hash, Lorebook, LorebookEntry, MessageRole, SelectiveLogic};
+use log::warn;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+
+/// Messages scanned for keys when neither the lorebook nor the entry sets a scan depth.
+pub const DEFAULT_SCAN_DEPTH: usize = 2;
+
+/// Stops recursion between entries that keep activating each other.
+const MAX_RECURSION_STEPS: usize = 10;
+
+/// Why an entry was activated, or why it wasn't even though it matched.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum ActivationReason {
+    Constant,
+    Key {
+        key: String,
+    },
+    /// A key was found in the content of another activated entry.
+    Recursion {
+        key: String,
+        source: String,
+    },
+    /// The entry is still active from an earlier message.
+    Sticky,
+    /// A primary key matched, but the secondary keys didn't satisfy the selective logic.
+    SecondaryKeys {
+        key: String,
+    },
+    Cooldown,
+    Delay,
+    Probability,
+    TokenBudget,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntryReport {
+    pub lorebook: String,
+    pub entry: String,
+    pub activated: bool,
+    pub reason: ActivationReason,
+}
+
+/// Lists the entries that were activated for the last message and the ones that matched
+/// but were left out.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ActivationReport {
+    pub entries: Vec<EntryReport>,
+}
+
+pub struct Activation<'a> {
+    /// The activated entries, sorted by insertion order.
+    pub entries: Vec<&'a LorebookEntry>,
+    pub report: ActivationReport,
+}
+
+type EntryId = (usize, usize);
+
+struct Key {
+    text: String,
+    regex: Regex,
+}
+
+struct CompiledEntry<'a> {
+    id: EntryId,
+    entry: &'a LorebookEntry,
+    scan_depth: usize,
+    recursive: bool,
+    keys: Vec<Key>,
+    secondary_keys: Vec<Key>,
+}
+
+/// Timers of an entry, in number of messages.
+#[derive(Clone, Copy)]
+struct Timer {
+    activated_at: usize,
+    sticky_until: usize,
+    cooldown_until: usize,
+}
+
+/// Finds the lorebook entries whose keys appear in the chat.
+///
+/// Sticky, cooldown and delay timers count messages, so instead of storing them the
+/// scanner replays the activation for every message of the chat. The random rolls for
+/// entries with a probability are seeded the same way, so replaying gives the same result.
+pub struct LorebookScanner<'a> {
+    lorebooks: Vec<&'a Lorebook>,
+    entries: Vec<CompiledEntry<'a>>,
+    seed: u64,
+}
+
+impl<'a> LorebookScanner<'a> {
+    pub fn new(lorebooks: impl IntoIterator<Item = &'a Lorebook>) -> Self {
+        let lorebooks: Vec<_> = lorebooks.into_iter().collect();
+        let entries = lorebooks
+            .iter()
+            .enumerate()
+            .flat_map(|(book_index, lorebook)| {
+                lorebook
+                    .entries
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, entry)| entry.enabled)
+                    .map(move |(index, entry)| CompiledEntry {
+                        id: (book_index, index),
+                        entry,
+                        scan_depth: entry
+                            .scan_depth
+                            .or(lorebook.scan_depth)
+                            .unwrap_or(DEFAULT_SCAN_DEPTH),
+                        recursive: lorebook.recursive_scanning,
+                        keys: compile_keys(&entry.keys, entry),
+                        secondary_keys: compile_keys(&entry.secondary_keys, entry),
+                    })
+            })
+            .collect();
+
+        LorebookScanner {
+            lorebooks,
+            entries,
+            seed: 0,
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Activates entries for a chat, given the text of its messages from oldest to newest.
+    pub fn activate(&self, messages: &[&str]) -> Activation<'a> {
+        let mut timers = HashMap::new();
+        for turn in 1..messages.len() {
+            self.step(&messages[..turn], &mut timers);
+        }
+        let (activated, report) = self.step(messages, &mut timers);
+
+        let mut entries: Vec<_> = activated
+            .into_iter()
+            .map(|index| &self.entries[index])
+            .collect();
+        entries.sort_by_key(|compiled| (compiled.entry.insertion_order, compiled.id));
+
+        Activation {
+            entries: entries.into_iter().map(|compiled| compiled.entry).collect(),
+            report,
+        }
+    }
+
+    /// Activates entries for the last of `messages` and updates the timers.
+    fn step(
+        &self,
+        messages: &[&str],
+        timers: &mut HashMap<EntryId, Timer>,
+    ) -> (Vec<usize>, ActivationReport) {
+        let turn = messages.len();
+        let mut scan_texts: HashMap<usize, String> = HashMap::new();
+        let mut decided = HashSet::new();
+        let mut outcomes = Vec::new();
+
+        for (index, compiled) in self.entries.iter().enumerate() {
+            let timer = timers.get(&compiled.id);
+            if timer.is_some_and(|t| turn > t.activated_at && turn <= t.sticky_until) {
+                decided.insert(index);
+                outcomes.push((index, true, ActivationReason::Sticky));
+                continue;
+            }
+
+            let reason = if compiled.entry.constant {
+                ActivationReason::Constant
+            } else {
+                let text = scan_texts
+                    .entry(compiled.scan_depth)
+                    .or_insert_with(|| scan_text(messages, compiled.scan_depth));
+                match compiled.evaluate(text) {
+                    Some(reason) => reason,
+                    None => continue,
+                }
+            };
+
+            decided.insert(index);
+            outcomes.push(self.gate(index, reason, turn, timers));
+        }
+
+        for _ in 0..MAX_RECURSION_STEPS {
+            let sources: Vec<_> = outcomes
+                .iter()
+                .filter(|(index, activated, _)| {
+                    *activated && !self.entries[*index].entry.prevent_recursion
+                })
+                .map(|(index, _, _)| *index)
+                .collect();
+
+            let mut found = Vec::new();
+            for (index, compiled) in self.entries.iter().enumerate() {
+                if decided.contains(&index)
+                    || !compiled.recursive
+                    || compiled.entry.exclude_recursion
+                    || compiled.entry.constant
+                {
+                    continue;
+                }
+
+                let matched = sources.iter().find_map(|source| {
+                    let source = &self.entries[*source];
+                    match compiled.evaluate(&source.entry.content)? {
+                        ActivationReason::Key { key } => Some(ActivationReason::Recursion {
+                            key,
+                            source: entry_label(source.entry),
+                        }),
+                        reason => Some(reason),
+                    }
+                });
+                if let Some(reason) = matched {
+                    decided.insert(index);
+                    found.push(self.gate(index, reason, turn, timers));
+                }
+            }
+
+            if found.is_empty() {
+                break;
+            }
+            outcomes.extend(found);
+        }
+
+        self.apply_token_budgets(&mut outcomes);
+
+        let mut activated = Vec::new();
+        let mut report = ActivationReport::default();
+        for (index, is_active, reason) in outcomes {
+            let compiled = &self.entries[index];
+            if is_active {
+                activated.push(index);
+                if reason != ActivationReason::Sticky {
+                    let entry = compiled.entry;
+                    let sticky_until = turn + entry.sticky.unwrap_or(0) as usize;
+                    timers.insert(
+                        compiled.id,
+                        Timer {
+                            activated_at: turn,
+                            sticky_until,
+                            cooldown_until: sticky_until + entry.cooldown.unwrap_or(0) as usize,
+                        },
+                    );
+                }
+            }
+
+            report.entries.push(EntryReport {
+                lorebook: self.lorebooks[compiled.id.0].name.clone(),
+                entry: entry_label(compiled.entry),
+                activated: is_active,
+                reason,
+            });
+        }
+
+        (activated, report)
+    }
+
+    /// Checks the delay, cooldown and probability of an entry that matched.
+    fn gate(
+        &self,
+        index: usize,
+        reason: ActivationReason,
+        turn: usize,
+        timers: &HashMap<EntryId, Timer>,
+    ) -> (usize, bool, ActivationReason) {
+        let compiled = &self.entries[index];
+        let entry = compiled.entry;
+        if matches!(reason, ActivationReason::SecondaryKeys { .. }) {
+            return (index, false, reason);
+        }
+        if turn < entry.delay.unwrap_or(0) as usize {
+            return (index, false, ActivationReason::Delay);
+        }
+        if timers
+            .get(&compiled.id)
+            .is_some_and(|t| turn > t.sticky_until && turn <= t.cooldown_until)
+        {
+            return (index, false, ActivationReason::Cooldown);
+        }
+        if let Some(probability) = entry.probability.filter(|p| *p < 100) {
+            let mut rng = StdRng::seed_from_u64(stable_hash(&(self.seed, turn, compiled.id)));
+            if rng.random_range(0..100) >= probability {
+                return (index, false, ActivationReason::Probability);
+            }
+        }
+
+        (index, true, reason)
+    }
+
+    /// Drops the activated entries that don't fit into their lorebook's token budget.
+    /// Constant entries are kept first, then entries by descending priority.
+    fn apply_token_budgets(&self, outcomes: &mut [(usize, bool, ActivationReason)]) {
+        let mut order: Vec<_> = (0..outcomes.len())
+            .filter(|position| outcomes[*position].1)
+            .collect();
+        order.sort_by_key(|position| {
+            let entry = self.entries[outcomes[*position].0].entry;
+            (
+                !entry.constant,
+                -entry.priority.unwrap_or(0),
+                entry.insertion_order,
+            )
+        });
+
+        let mut used = vec![0; self.lorebooks.len()];
+        for position in order {
+            let compiled = &self.entries[outcomes[position].0];
+            let book = compiled.id.0;
+            let Some(budget) = self.lorebooks[book].token_budget else {
+                continue;
+            };
+
+            let tokens = estimate_tokens(&[MessageHistoryItem {
+                role: MessageRole::System,
+                content: compiled.entry.content.clone(),
+            }]);
+            if used[book] + tokens > budget {
+                outcomes[position].1 = false;
+                outcomes[position].2 = ActivationReason::TokenBudget;
+            } else {
+                used[book] += tokens;
+            }
+        }
+    }
+}
+
+impl CompiledEntry<'_> {
+    /// Returns `None` if no primary key is found in the text.
+    fn evaluate(&self, text: &str) -> Option<ActivationReason> {
+        let key = self.keys.iter().find(|key| key.regex.is_match(text))?;
+        let key = key.text.clone();
+        if !self.entry.selective || self.secondary_keys.is_empty() {
+            return Some(ActivationReason::Key { key });
+        }
+
+        let matching = self
+            .secondary_keys
+            .iter()
+            .filter(|key| key.regex.is_match(text))
+            .count();
+        let total = self.secondary_keys.len();
+        let satisfied = match self.entry.selective_logic {
+            SelectiveLogic::AndAny => matching > 0,
+            SelectiveLogic::AndAll => matching == total,
+            SelectiveLogic::NotAny => matching == 0,
+            SelectiveLogic::NotAll => matching < total,
+        };
+
+        if satisfied {
+            Some(ActivationReason::Key { key })
+        } else {
+            Some(ActivationReason::SecondaryKeys { key })
+        }
+    }
+}
+
+fn scan_text(messages: &[&str], depth: usize) -> String {
+    messages[messages.len().saturating_sub(depth)..].join("\n")
+}
+
+fn entry_label(entry: &LorebookEntry) -> String {
+    if !entry.name.trim().is_empty() {
+        entry.name.clone()
+    } else {
+        entry.keys.first().cloned().unwrap_or_default()
+    }
+}
+
+/// Compiles every key into a regex. Keys written as `/pattern/flags` are always regular
+/// expressions, other keys only if the entry uses regex keys.
+fn compile_keys(keys: &[String], entry: &LorebookEntry) -> Vec<Key> {
+    let case_sensitive = entry.case_sensitive.unwrap_or(false);
+    let whole_words = entry.match_whole_words.unwrap_or(false);
+
+    keys.iter()
+        .map(|key| key.trim())
+        .filter(|key| !key.is_empty())
+        .filter_map(|key| {
+            let (pattern, case_insensitive) = match slash_regex(key) {
+                Some((pattern, flags)) => (pattern.to_string(), flags.contains('i')),
+                None if entry.use_regex => (key.to_string(), !case_sensitive),
+                None if whole_words => (
+                    format!(r"(?:^|\W){}(?:\W|$)", regex::escape(key)),
+                    !case_sensitive,
+                ),
+                None => (regex::escape(key), !case_sensitive),
+            };
+
+            match RegexBuilder::new(&pattern)
+                .case_insensitive(case_insensitive)
+                .build()
+            {
+                Ok(regex) => Some(Key {
+                    text: key.to_string(),
+                    regex,
+                }),
+                Err(e) => {
+                    warn!("ignoring invalid lorebook key '{key}': {e}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn slash_regex(key: &str) -> Option<(&str, &str)> {
+    let rest = key.strip_prefix('/')?;
+    let end = rest.rfind('/')?;
+    let (pattern, flags) = (&rest[..end], &rest[end + 1..]);
+    if pattern.is_empty() || !flags.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    Some((pattern, flags))
+}
+
+#[cfg(test)]
+mod tests {
+    use erpy_types::{Lorebook, LorebookEntry, SelectiveLogic};
+
+    use super::{ActivationReason, LorebookScanner};
+
+    fn entry(name: &str, keys: &[&str], content: &str) -> LorebookEntry {
+        LorebookEntry {
+            name: name.into(),
+            keys: keys.iter().map(|k| k.to_string()).collect(),
+            content: content.into(),
+            ..Default::default()
+        }
+    }
+
+    fn lorebook(entries: Vec<LorebookEntry>) -> Lorebook {
+        Lorebook {
+            name: "World".into(),
+            entries,
+            ..Default::default()
+        }
+    }
+
+    fn activated(lorebook: &Lorebook, messages: &[&str]) -> Vec<String> {
+        LorebookScanner::new([lorebook])
+            .activate(messages)
+            .entries
+            .iter()
+            .map(|entry| entry.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_keys_are_matched_case_insensitively_by_default() {
+        let mut sensitive = entry("sensitive", &["Dragon"], "");
+        sensitive.case_sensitive = Some(true);
+        let book = lorebook(vec![
+            entry("dragon", &["DRAGON"], "Dragons breathe fire."),
+            sensitive,
+            entry("castle", &["castle"], ""),
+        ]);
+
+        assert_eq!(activated(&book, &["A dragon appears!"]), vec!["dragon"]);
+    }
+
+    #[test]
+    fn test_whole_word_matching() {
+        let mut whole = entry("whole", &["cat"], "");
+        whole.match_whole_words = Some(true);
+        let book = lorebook(vec![whole, entry("partial", &["cat"], "")]);
+
+        assert_eq!(activated(&book, &["Let's concatenate."]), vec!["partial"]);
+        assert_eq!(
+            activated(&book, &["The cat sleeps."]),
+            vec!["whole", "partial"]
+        );
+    }
+
+    #[test]
+    fn test_regex_keys() {
+        let mut regex = entry("regex", &[r"\bsw(or|ea)d\b"], "");
+        regex.use_regex = true;
+        let book = lorebook(vec![regex, entry("slashes", &["/^hello/i"], "")]);
+
+        assert_eq!(
+            activated(&book, &["HELLO! A sword."]),
+            vec!["regex", "slashes"]
+        );
+        assert!(activated(&book, &["Well, hello. Swordsman!"]).is_empty());
+    }
+
+    #[test]
+    fn test_secondary_key_logic() {
+        let selective = |logic| LorebookEntry {
+            selective: true,
+            selective_logic: logic,
+            secondary_keys: vec!["night".into(), "moon".into()],
+            ..entry("wolf", &["wolf"], "")
+        };
+        let check = |logic, text| !activated(&lorebook(vec![selective(logic)]), &[text]).is_empty();
+
+        assert!(check(SelectiveLogic::AndAny, "A wolf at night."));
+        assert!(!check(SelectiveLogic::AndAny, "A wolf by day."));
+        assert!(check(
+            SelectiveLogic::AndAll,
+            "A wolf at night under the moon."
+        ));
+        assert!(!check(SelectiveLogic::AndAll, "A wolf at night."));
+        assert!(check(SelectiveLogic::NotAny, "A wolf by day."));
+        assert!(!check(SelectiveLogic::NotAny, "A wolf under the moon."));
+        assert!(check(SelectiveLogic::NotAll, "A wolf at night."));
+        assert!(!check(
+            SelectiveLogic::NotAll,
+            "A wolf at night under the moon."
+        ));
+    }
+
+    #[test]
+    fn test_scan_depth() {
+        let mut book = lorebook(vec![entry("sword", &["sword"], "")]);
+        book.scan_depth = Some(1);
+        assert!(activated(&book, &["A sword.", "Nothing here."]).is_empty());
+
+        book.scan_depth = Some(2);
+        assert_eq!(
+            activated(&book, &["A sword.", "Nothing here."]),
+            vec!["sword"]
+        );
+    }
+
+    #[test]
+    fn test_recursion() {
+        let mut excluded = entry("excluded", &["kingdom"], "");
+        excluded.exclude_recursion = true;
+        let mut book = lorebook(vec![
+            entry("king", &["king"], "The king rules the kingdom of Eldoria."),
+            entry("eldoria", &["eldoria"], "Eldoria lies by the sea."),
+            entry("sea", &["sea"], "The sea is cold."),
+            excluded,
+        ]);
+
+        assert_eq!(activated(&book, &["The king arrives."]), vec!["king"]);
+
+        book.recursive_scanning = true;
+        let activation = LorebookScanner::new([&book]).activate(&["The king arrives."]);
+        let names: Vec<_> = activation.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["king", "eldoria", "sea"]);
+        assert_eq!(
+            activation.report.entries[1].reason,
+            ActivationReason::Recursion {
+                key: "eldoria".into(),
+                source: "king".into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_prevent_recursion() {
+        let mut king = entry("king", &["king"], "The king of Eldoria.");
+        king.prevent_recursion = true;
+        let mut book = lorebook(vec![king, entry("eldoria", &["eldoria"], "")]);
+        book.recursive_scanning = true;
+
+        assert_eq!(activated(&book, &["The king arrives."]), vec!["king"]);
+    }
+
+    #[test]
+    fn test_sticky_and_cooldown() {
+        let mut ring = entry("ring", &["ring"], "");
+        ring.sticky = Some(2);
+        ring.cooldown = Some(1);
+        let book = lorebook(vec![ring]);
+        let messages = ["The ring glows.", "a", "b", "c", "The ring again.", "d"];
+
+        let reasons: Vec<_> = (1..=messages.len())
+            .map(|turn| {
+                let activation = LorebookScanner::new([&book]).activate(&messages[..turn]);
+                activation
+                    .report
+                    .entries
+                    .first()
+                    .map(|report| (report.activated, report.reason.clone()))
+            })
+            .collect();
+
+        let key = ActivationReason::Key { key: "ring".into() };
+        assert_eq!(
+            reasons,
+            vec![
+                Some((true, key.clone())),
+                Some((true, ActivationReason::Sticky)),
+                Some((true, ActivationReason::Sticky)),
+                None,
+                Some((true, key)),
+                Some((true, ActivationReason::Sticky)),
+            ]
+        );
+
+        let messages = ["The ring glows.", "a", "b", "ring"];
+        let activation = LorebookScanner::new([&book]).activate(&messages);
+        assert!(activation.entries.is_empty());
+        assert_eq!(
+            activation.report.entries[0].reason,
+            ActivationReason::Cooldown
+        );
+    }
+
+    #[test]
+    fn test_delay() {
+        let mut ghost = entry("ghost", &["ghost"], "");
+        ghost.delay = Some(3);
+        let book = lorebook(vec![ghost]);
+
+        assert!(activated(&book, &["a", "ghost"]).is_empty());
+        assert_eq!(activated(&book, &["a", "b", "ghost"]), vec!["ghost"]);
+    }
+
+    #[test]
+    fn test_token_budget_keeps_constant_and_high_priority_entries() {
+        let constant = LorebookEntry {
+            constant: true,
+            ..entry("constant", &[], "12345678")
+        };
+        let low = LorebookEntry {
+            priority: Some(1),
+            ..entry("low", &["tree"], "12345678")
+        };
+        let high = LorebookEntry {
+            priority: Some(10),
+            ..entry("high", &["tree"], "12345678")
+        };
+        let mut book = lorebook(vec![low, constant, high]);
+        book.token_budget = Some(4);
+
+        let activation = LorebookScanner::new([&book]).activate(&["A tree."]);
+        let names: Vec<_> = activation.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["constant", "high"]);
+        assert!(activation
+            .report
+            .entries
+            .iter()
+            .any(|r| r.entry == "low" && r.reason == ActivationReason::TokenBudget));
+    }
+
+    #[test]
+    fn test_probability_is_deterministic_under_seed() {
+        let entries = (0..20)
+            .map(|i| LorebookEntry {
+                probability: Some(50),
+                ..entry(&i.to_string(), &["key"], "")
+            })
+            .collect();
+        let book = lorebook(entries);
+
+        let run = |seed| {
+            LorebookScanner::new([&book])
+                .with_seed(seed)
+                .activate(&["key"])
+                .entries
+                .len()
+        };
+        assert_eq!(run(3), run(3));
+        assert!(run(3) > 0 && run(3) < 20);
+    }
+
+    #[test]
+    fn test_disabled_entries_and_insertion_order() {
+        let disabled = LorebookEntry {
+            enabled: false,
+            ..entry("disabled", &["a"], "")
+        };
+        let first = LorebookEntry {
+            insertion_order: 1,
+            ..entry("first", &["a"], "")
+        };
+        let book = lorebook(vec![disabled, entry("second", &["a"], ""), first]);
+
+        assert_eq!(activated(&book, &["a"]), vec!["first", "second"]);
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use sha2::{Digest, Sha256};
+
+/// URI scheme for assets kept in the [`AssetStore`], e.g. `erpy-asset://<hash>.png`.
+pub const ASSET_URI_PREFIX: &str = "erpy-asset://";
+
+/// The content type of an asset, by the extension of its id.
+pub fn content_type(id: &str) -> &'static str {
+    let ext = id.rsplit_once('.').map_or("", |(_, ext)| ext);
+    match ext {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "gif" => "image/gif",
+        "mp3" => "audio/mpeg",
+        "json" => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Content-addressed storage for character assets (avatars, sprites, backgrounds).
+///
+/// Files are named after the SHA-256 of their content, so importing the same asset twice
+/// only stores it once.
+#[derive(Debug, Clone)]
+pub struct AssetStore {
+    root: Utf8PathBuf,
+}
+
+impl AssetStore {
+    pub fn new(root: impl Into<Utf8PathBuf>) -> Self {
+        AssetStore { root: root.into() }
+    }
+
+    /// Stores the bytes and returns the asset id.
+    pub fn put(&self, bytes: &[u8], ext: &str) -> Result<String> {
+        let hash = content_hash(bytes);
+        let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+        let id = if ext.is_empty() {
+            hash
+        } else {
+            format!("{hash}.{ext}")
+        };
+
+        let path = self.path(&id)?;
+        if !path.exists() {
+            std::fs::create_dir_all(&self.root)
+                .with_context(|| format!("failed to create asset directory {}", self.root))?;
+            std::fs::write(&path, bytes)
+                .with_context(|| format!("failed to write asset {path}"))?;
+        }
+
+        Ok(id)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Vec<u8>> {
+        let path = self.path(id)?;
+        std::fs::read(&path).with_context(|| format!("failed to read asset {path}"))
+    }
+
+    pub fn path(&self, id: &str) -> Result<Utf8PathBuf> {
+        let valid = !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+            && !id.starts_with('.');
+        if !valid {
+            bail!("invalid asset id '{id}'");
+        }
+
+        Ok(self.root.join(id))
+    }
+
+    pub fn root(&self) -> &Utf8Path {
+        &self.root
+    }
+}
+
+/// The hex encoded SHA-256 of the bytes.
+pub fn content_hash(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+pub fn asset_uri(id: &str) -> String {
+    format!("{ASSET_URI_PREFIX}{id}")
+}
+
+pub fn asset_id(uri: &str) -> Option<&str> {
+    uri.strip_prefix(ASSET_URI_PREFIX)
+}
@@ -0,0 +1,257 @@
+use std::io::Cursor;
+
+use anyhow::{Context, Result};
+use erpy_types::{CharacterImage, CharacterInformation, FocalPoint, Thumbnail};
+use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageFormat};
+use log::debug;
+
+use crate::{assets::AssetStore, config::AvatarSettings};
+
+/// Keeps character images in the asset store, next to thumbnails in the configured sizes.
+///
+/// Thumbnails keep the aspect ratio of the image. With a crop aspect ratio set, every size
+/// also gets a thumbnail cropped to that ratio around the image's focal point.
+#[derive(Debug, Clone)]
+pub struct AvatarPipeline {
+    store: AssetStore,
+    settings: AvatarSettings,
+}
+
+impl AvatarPipeline {
+    pub fn new(store: AssetStore, settings: AvatarSettings) -> Self {
+        AvatarPipeline { store, settings }
+    }
+
+    pub fn store(&self) -> &AssetStore {
+        &self.store
+    }
+
+    /// Stores the image as-is and generates its thumbnails.
+    pub fn process(&self, bytes: &[u8], focal_point: FocalPoint) -> Result<CharacterImage> {
+        let format = image::guess_format(bytes).context("unsupported image format")?;
+        let image = image::load_from_memory_with_format(bytes, format)?;
+        let ext = format.extensions_str().first().copied().unwrap_or_default();
+        let original = self.store.put(bytes, ext)?;
+        debug!(
+            "stored {}x{} image as {original}",
+            image.width(),
+            image.height()
+        );
+
+        self.with_thumbnails(original, &image, focal_point)
+    }
+
+    /// Regenerates the thumbnails of a stored image around another focal point.
+    pub fn set_focal_point(
+        &self,
+        image: &CharacterImage,
+        focal_point: FocalPoint,
+    ) -> Result<CharacterImage> {
+        let bytes = self.store.get(&image.original)?;
+        let decoded = image::load_from_memory(&bytes)?;
+        self.with_thumbnails(image.original.clone(), &decoded, focal_point)
+    }
+
+    /// Makes the image the character's image, in place of any inline image.
+    pub fn attach(&self, character: &mut CharacterInformation, bytes: &[u8]) -> Result<()> {
+        character.image = Some(self.process(bytes, FocalPoint::default())?);
+        character.image_base64 = None;
+        Ok(())
+    }
+
+    fn with_thumbnails(
+        &self,
+        original: String,
+        image: &DynamicImage,
+        focal_point: FocalPoint,
+    ) -> Result<CharacterImage> {
+        let focal_point = FocalPoint {
+            x: focal_point.x.clamp(0.0, 1.0),
+            y: focal_point.y.clamp(0.0, 1.0),
+        };
+        let cropped = self
+            .settings
+            .crop_aspect_ratio
+            .filter(|ratio| ratio.is_finite() && *ratio > 0.0)
+            .map(|ratio| crop_around(image, ratio, focal_point));
+
+        let mut thumbnails = Vec::new();
+        for &size in &self.settings.thumbnail_sizes {
+            thumbnails.push(self.thumbnail(image, size, false)?);
+            if let Some(cropped) = &cropped {
+                thumbnails.push(self.thumbnail(cropped, size, true)?);
+            }
+        }
+
+        Ok(CharacterImage {
+            original,
+            width: image.width(),
+            height: image.height(),
+            focal_point,
+            thumbnails,
+        })
+    }
+
+    fn thumbnail(&self, image: &DynamicImage, size: u32, cropped: bool) -> Result<Thumbnail> {
+        let scaled = if image.width() > size || image.height() > size {
+            image.resize(size, size, FilterType::Lanczos3)
+        } else {
+            image.clone()
+        };
+
+        let mut bytes = Cursor::new(Vec::new());
+        scaled.to_rgba8().write_to(&mut bytes, ImageFormat::WebP)?;
+        let id = self.store.put(bytes.get_ref(), "webp")?;
+
+        Ok(Thumbnail {
+            id,
+            size,
+            width: scaled.width(),
+            height: scaled.height(),
+            cropped,
+        })
+    }
+}
+
+/// Crops the largest region with the aspect ratio, centered on the focal point as far as
+/// the edges of the image allow.
+fn crop_around(image: &DynamicImage, ratio: f32, focal_point: FocalPoint) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    let (crop_width, crop_height) = if width as f32 / height as f32 > ratio {
+        let crop_width = (height as f32 * ratio).round() as u32;
+        (crop_width.clamp(1, width), height)
+    } else {
+        let crop_height = (width as f32 / ratio).round() as u32;
+        (width, crop_height.clamp(1, height))
+    };
+
+    let offset = |focus: f32, size: u32, crop: u32| {
+        (focus * size as f32 - crop as f32 / 2.0)
+            .round()
+            .clamp(0.0, (size - crop) as f32) as u32
+    };
+    let x = offset(focal_point.x, width, crop_width);
+    let y = offset(focal_point.y, height, crop_height);
+
+    image.crop_imm(x, y, crop_width, crop_height)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use camino::Utf8Path;
+    use erpy_types::FocalPoint;
+    use image::{DynamicImage, GenericImageView, ImageFormat, Rgb, RgbImage};
+
+    use super::{crop_around, AvatarPipeline};
+    use crate::{assets::AssetStore, config::AvatarSettings};
+
+    /// A wide image that is red on its left half and blue on its right half.
+    fn wide_png() -> Vec<u8> {
+        let image = RgbImage::from_fn(400, 200, |x, _| {
+            if x < 200 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        });
+        let mut png = Cursor::new(Vec::new());
+        image.write_to(&mut png, ImageFormat::Png).unwrap();
+        png.into_inner()
+    }
+
+    fn pipeline(dir: &tempfile::TempDir) -> AvatarPipeline {
+        let store = AssetStore::new(Utf8Path::from_path(dir.path()).unwrap());
+        let settings = AvatarSettings {
+            thumbnail_sizes: vec![50, 100, 1000],
+            crop_aspect_ratio: Some(1.0),
+        };
+        AvatarPipeline::new(store, settings)
+    }
+
+    #[test]
+    fn test_keeps_original_and_preserves_aspect_ratio() {
+        let dir = tempfile::tempdir().unwrap();
+        let pipeline = pipeline(&dir);
+        let png = wide_png();
+
+        let image = pipeline.process(&png, FocalPoint::default()).unwrap();
+        assert!(image.original.ends_with(".png"));
+        assert_eq!(pipeline.store().get(&image.original).unwrap(), png);
+        assert_eq!((image.width, image.height), (400, 200));
+
+        let sizes: Vec<_> = image
+            .thumbnails
+            .iter()
+            .map(|t| (t.size, t.cropped, t.width, t.height))
+            .collect();
+        assert_eq!(
+            sizes,
+            vec![
+                (50, false, 50, 25),
+                (50, true, 50, 50),
+                (100, false, 100, 50),
+                (100, true, 100, 100),
+                // never scaled up
+                (1000, false, 400, 200),
+                (1000, true, 200, 200),
+            ]
+        );
+
+        let thumbnail = pipeline.store().get(&image.thumbnails[2].id).unwrap();
+        let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!(thumbnail.dimensions(), (100, 50));
+    }
+
+    #[test]
+    fn test_same_image_is_stored_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let pipeline = pipeline(&dir);
+
+        let first = pipeline
+            .process(&wide_png(), FocalPoint::default())
+            .unwrap();
+        let second = pipeline
+            .process(&wide_png(), FocalPoint::default())
+            .unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_focal_point_crop() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(400, 200, |x, _| {
+            Rgb([if x < 200 { 255 } else { 0 }, 0, 0])
+        }));
+        let left = crop_around(&image, 1.0, FocalPoint { x: 0.1, y: 0.5 });
+        assert_eq!(left.dimensions(), (200, 200));
+        assert_eq!(left.to_rgb8().get_pixel(199, 0), &Rgb([255, 0, 0]));
+
+        let right = crop_around(&image, 1.0, FocalPoint { x: 0.9, y: 0.5 });
+        assert_eq!(right.to_rgb8().get_pixel(0, 0), &Rgb([0, 0, 0]));
+
+        let tall = crop_around(&image, 0.5, FocalPoint::default());
+        assert_eq!(tall.dimensions(), (100, 200));
+    }
+
+    #[test]
+    fn test_set_focal_point_regenerates_cropped_thumbnails() {
+        let dir = tempfile::tempdir().unwrap();
+        let pipeline = pipeline(&dir);
+        let image = pipeline
+            .process(&wide_png(), FocalPoint::default())
+            .unwrap();
+
+        let moved = pipeline
+            .set_focal_point(&image, FocalPoint { x: 0.0, y: 2.0 })
+            .unwrap();
+        assert_eq!(moved.original, image.original);
+        assert_eq!(moved.focal_point, FocalPoint { x: 0.0, y: 1.0 });
+        assert_eq!(moved.thumbnails[0], image.thumbnails[0]);
+        assert_ne!(moved.thumbnails[1].id, image.thumbnails[1].id);
+
+        let cropped = pipeline.store().get(&moved.thumbnails[1].id).unwrap();
+        let cropped = image::load_from_memory(&cropped).unwrap().to_rgb8();
+        assert!(cropped.pixels().all(|pixel| pixel == &Rgb([255, 0, 0])));
+    }
+}
@@ -1,13 +1,23 @@
 #![allow(dead_code)]
 
-use std::io::Cursor;
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::{Cursor, Read, Write},
+};
 
-use anyhow::{anyhow, bail, Result};
-use erpy_types::CharacterInformation;
-use image::imageops::FilterType;
-use log::{debug, info};
-use serde::Deserialize;
-use serde_json::Value;
+use anyhow::{anyhow, bail, Context, Result};
+use erpy_types::{null_as_default, CharacterAsset, CharacterInformation, Lorebook, Versioned};
+use image::{DynamicImage, Rgb, RgbImage};
+use log::{debug, info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use zip::{write::SimpleFileOptions, ZipArchive, ZipWriter};
+
+use crate::{
+    assets::{asset_id, asset_uri, AssetStore},
+    avatar::AvatarPipeline,
+    lorebook::{lorebook_from_value, lorebook_to_character_book},
+};
 
 const DEFAULT_SYSTEM_PROMPT: &str =
     "Write {{char}}'s next reply in a fictional chat between {{char}} and {{user}}.";
@@ -45,137 +55,1365 @@ pub struct ChubAiDefinition {
     pub post_history_instructions: String,
     pub tavern_personality: String,
     pub alternate_greetings: Vec<String>,
+    #[serde(default)]
+    pub embedded_lorebook: Option<Value>,
+}
+
+pub const CARD_V1_SPEC: &str = "chara_card_v1";
+pub const CARD_V2_SPEC: &str = "chara_card_v2";
+pub const CARD_V3_SPEC: &str = "chara_card_v3";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardVersion {
+    V1,
+    V2,
+    V3,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct CharacterCard {
     pub spec: String,
     pub spec_version: String,
     pub data: CharacterCardData,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl CharacterCard {
+    pub fn version(&self) -> CardVersion {
+        match self.spec.as_str() {
+            CARD_V3_SPEC => CardVersion::V3,
+            CARD_V2_SPEC => CardVersion::V2,
+            _ => CardVersion::V1,
+        }
+    }
+}
+
+/// The union of the V1, V2 and V3 card fields. Every field is optional and `null` is read
+/// as the field's default, since cards in the wild are rarely complete.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct CharacterCardData {
+    #[serde(deserialize_with = "null_as_default")]
     pub alternate_greetings: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub avatar: Option<String>,
+    #[serde(deserialize_with = "null_as_default")]
     pub character_version: String,
+    #[serde(deserialize_with = "null_as_default")]
     pub creator: String,
+    #[serde(deserialize_with = "null_as_default")]
     pub creator_notes: String,
+    #[serde(deserialize_with = "null_as_default")]
     pub description: String,
-    #[serde(rename = "first_mes")]
+    #[serde(rename = "first_mes", deserialize_with = "null_as_default")]
     pub first_message: String,
-    #[serde(rename = "mes_example")]
+    #[serde(rename = "mes_example", deserialize_with = "null_as_default")]
     pub message_example: String,
+    #[serde(deserialize_with = "null_as_default")]
     pub name: String,
+    #[serde(deserialize_with = "null_as_default")]
     pub personality: String,
-    pub post_history_instructions: Option<String>,
+    #[serde(deserialize_with = "null_as_default")]
+    pub post_history_instructions: String,
+    #[serde(deserialize_with = "null_as_default")]
     pub scenario: String,
+    #[serde(deserialize_with = "null_as_default")]
     pub system_prompt: String,
+    #[serde(deserialize_with = "null_as_default")]
     pub tags: Vec<String>,
+    pub character_book: Option<Value>,
+    #[serde(deserialize_with = "null_as_default")]
+    pub extensions: Map<String, Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nickname: Option<String>,
+    #[serde(
+        deserialize_with = "null_as_default",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub creator_notes_multilingual: BTreeMap<String, String>,
+    #[serde(
+        deserialize_with = "null_as_default",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub group_only_greetings: Vec<String>,
+    #[serde(
+        deserialize_with = "null_as_default",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub source: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creation_date: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modification_date: Option<i64>,
+    #[serde(
+        deserialize_with = "null_as_default",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub assets: Vec<CharacterAsset>,
 }
 
-pub async fn character_from_chub_ai(url: &str) -> Result<CharacterInformation> {
-    use base64::prelude::*;
-
-    let parsed_url = reqwest::Url::parse(url)?;
-    let api_url = format!("https://api.chub.ai/api{}?full=true", parsed_url.path());
-    let response: ChubAiCharacter = reqwest::get(&api_url).await?.json().await?;
-    debug!("received character: {response:#?} for URL {url}");
-
-    let mut first_messages = vec![response.node.definition.first_message];
-    first_messages.extend(response.node.definition.alternate_greetings);
-
-    let image_bytes = reqwest::get(&response.node.definition.avatar)
-        .await?
-        .bytes()
-        .await?;
-
-    let base64 = BASE64_STANDARD.encode(image_bytes);
-
-    Ok(CharacterInformation {
-        name: response.node.definition.name,
-        description: response.node.definition.description,
-        personality: response.node.definition.personality,
-        first_messages,
-        tags: response.node.topics,
-        system_prompt: if response.node.definition.system_prompt.trim().is_empty() {
-            DEFAULT_SYSTEM_PROMPT.to_string()
+fn deserialize_card_fields<T: DeserializeOwned>(value: Value, prefix: &str) -> Result<T> {
+    serde_path_to_error::deserialize(value).map_err(|error| {
+        let path = error.path().to_string();
+        let inner = error.into_inner();
+        if path == "." {
+            anyhow!("malformed character card: {inner}")
         } else {
-            response.node.definition.system_prompt
-        },
-        avatar: Some(response.node.definition.avatar),
-        image_base64: Some(base64),
+            anyhow!("malformed character card field `{prefix}{path}`: {inner}")
+        }
     })
 }
 
-pub fn character_from_png_bytes(bytes: &[u8]) -> Result<CharacterInformation> {
-    use base64::prelude::*;
+/// Parses a V1, V2 or V3 character card from its JSON representation.
+///
+/// V2 and V3 cards are recognized by their `spec`, V1 cards by having their fields at the
+/// top level instead of in a `data` object. Errors name the field that could not be read.
+pub fn parse_character_card(json: &[u8]) -> Result<CharacterCard> {
+    let value: Value = serde_json::from_slice(json).context("character card is not valid JSON")?;
+    let Value::Object(mut object) = value else {
+        bail!("character card must be a JSON object");
+    };
+
+    let spec = match object.get("spec") {
+        None | Some(Value::Null) => None,
+        Some(Value::String(spec)) => Some(spec.clone()),
+        Some(other) => bail!("character card field `spec` must be a string, found {other}"),
+    };
+    let spec_version = match object.get("spec_version") {
+        Some(Value::String(version)) => Some(version.clone()),
+        Some(Value::Number(version)) => Some(version.to_string()),
+        _ => None,
+    };
+
+    let (version, data, prefix) = match (spec.as_deref(), object.remove("data")) {
+        (Some(CARD_V3_SPEC), Some(data)) => (CardVersion::V3, data, "data."),
+        (Some(CARD_V2_SPEC), Some(data)) => (CardVersion::V2, data, "data."),
+        (Some(spec @ (CARD_V2_SPEC | CARD_V3_SPEC)), None) => {
+            bail!("character card with spec `{spec}` has no `data` field")
+        }
+        (Some(CARD_V1_SPEC) | None, None) => (CardVersion::V1, Value::Object(object), ""),
+        // some exporters wrap the V2 fields without declaring a spec
+        (None, Some(data)) => (CardVersion::V2, data, "data."),
+        (Some(spec), _) => bail!("unsupported character card spec `{spec}`"),
+    };
+
+    let data: CharacterCardData = deserialize_card_fields(data, prefix)?;
+
+    if data.name.trim().is_empty() {
+        bail!("character card field `{prefix}name` is missing or empty");
+    }
+
+    let (spec, default_version) = match version {
+        CardVersion::V1 => (CARD_V1_SPEC, "1.0"),
+        CardVersion::V2 => (CARD_V2_SPEC, "2.0"),
+        CardVersion::V3 => (CARD_V3_SPEC, "3.0"),
+    };
+
+    Ok(CharacterCard {
+        spec: spec.to_string(),
+        spec_version: spec_version.unwrap_or_else(|| default_version.to_string()),
+        data,
+    })
+}
+
+impl CharacterCardData {
+    pub fn into_character(self, image_base64: Option<String>) -> CharacterInformation {
+        let first_messages = std::iter::once(self.first_message)
+            .chain(self.alternate_greetings)
+            .filter(|message| !message.trim().is_empty())
+            .collect();
+
+        CharacterInformation {
+            schema_version: CharacterInformation::SCHEMA_VERSION,
+            name: self.name,
+            description: self.description,
+            personality: self.personality,
+            first_messages,
+            tags: self.tags,
+            system_prompt: system_prompt_or_default(self.system_prompt),
+            scenario: self.scenario,
+            example_dialogues: self.message_example,
+            post_history_instructions: self.post_history_instructions,
+            creator: self.creator,
+            creator_notes: self.creator_notes,
+            creator_notes_multilingual: self.creator_notes_multilingual,
+            character_version: self.character_version,
+            nickname: self.nickname,
+            group_only_greetings: self.group_only_greetings,
+            source: self.source,
+            creation_date: self.creation_date,
+            modification_date: self.modification_date,
+            assets: self.assets,
+            character_book: self.character_book.and_then(embedded_lorebook),
+            extensions: self.extensions,
+            avatar: self.avatar,
+            image: None,
+            image_base64,
+        }
+    }
+}
+
+impl From<&CharacterInformation> for CharacterCardData {
+    fn from(character: &CharacterInformation) -> Self {
+        let (first_message, alternate_greetings) = match character.first_messages.split_first() {
+            Some((first, rest)) => (first.clone(), rest.to_vec()),
+            None => (String::new(), Vec::new()),
+        };
+
+        CharacterCardData {
+            name: character.name.clone(),
+            description: character.description.clone(),
+            personality: character.personality.clone(),
+            scenario: character.scenario.clone(),
+            first_message,
+            alternate_greetings,
+            message_example: character.example_dialogues.clone(),
+            system_prompt: character.system_prompt.clone(),
+            post_history_instructions: character.post_history_instructions.clone(),
+            tags: character.tags.clone(),
+            creator: character.creator.clone(),
+            creator_notes: character.creator_notes.clone(),
+            creator_notes_multilingual: character.creator_notes_multilingual.clone(),
+            character_version: character.character_version.clone(),
+            nickname: character.nickname.clone(),
+            group_only_greetings: character.group_only_greetings.clone(),
+            source: character.source.clone(),
+            creation_date: character.creation_date,
+            modification_date: character.modification_date,
+            assets: character.assets.clone(),
+            character_book: character
+                .character_book
+                .as_ref()
+                .map(lorebook_to_character_book),
+            extensions: character.extensions.clone(),
+            avatar: character.avatar.clone(),
+        }
+    }
+}
+
+impl CharacterCardData {
+    /// Drops the fields that only exist in V3 cards.
+    fn into_v2(self) -> Self {
+        CharacterCardData {
+            nickname: None,
+            creator_notes_multilingual: BTreeMap::new(),
+            group_only_greetings: Vec::new(),
+            source: Vec::new(),
+            creation_date: None,
+            modification_date: None,
+            assets: Vec::new(),
+            ..self
+        }
+    }
+}
+
+/// Reads the lorebook embedded in a card. A broken lorebook shouldn't prevent importing
+/// the character, so it's dropped with a warning instead.
+fn embedded_lorebook(value: Value) -> Option<Lorebook> {
+    if value.is_null() {
+        return None;
+    }
+
+    match lorebook_from_value(value) {
+        Ok(lorebook) => Some(lorebook),
+        Err(error) => {
+            warn!("ignoring embedded lorebook: {error:#}");
+            None
+        }
+    }
+}
+
+fn system_prompt_or_default(system_prompt: String) -> String {
+    if system_prompt.trim().is_empty() {
+        DEFAULT_SYSTEM_PROMPT.to_string()
+    } else {
+        system_prompt
+    }
+}
+
+impl ChubAiCharacter {
+    pub fn into_character(self, url: &str) -> CharacterInformation {
+        let definition = self.node.definition;
+        let mut first_messages = vec![definition.first_message];
+        first_messages.extend(definition.alternate_greetings);
+
+        CharacterInformation {
+            name: definition.name,
+            description: definition.description,
+            personality: definition.personality,
+            first_messages,
+            tags: self.node.topics,
+            system_prompt: system_prompt_or_default(definition.system_prompt),
+            scenario: definition.scenario,
+            example_dialogues: definition.example_dialogs,
+            post_history_instructions: definition.post_history_instructions,
+            character_book: definition.embedded_lorebook.and_then(embedded_lorebook),
+            creator_notes: self.node.description,
+            source: vec![url.to_string()],
+            avatar: Some(definition.avatar),
+            ..Default::default()
+        }
+    }
+}
+
+/// The character formats that are distributed as bare JSON files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CharacterJsonFormat {
+    TavernV1,
+    TavernV2,
+    TavernV3,
+    Pygmalion,
+    Oobabooga,
+    Agnai,
+}
+
+impl CharacterJsonFormat {
+    pub fn detect(value: &Value) -> Option<Self> {
+        let object = value.as_object()?;
+        let has = |key: &str| object.contains_key(key);
+
+        match object.get("spec").and_then(Value::as_str) {
+            Some(CARD_V3_SPEC) => return Some(Self::TavernV3),
+            Some(CARD_V2_SPEC) => return Some(Self::TavernV2),
+            Some(CARD_V1_SPEC) => return Some(Self::TavernV1),
+            _ => {}
+        }
+
+        if object.get("data").is_some_and(Value::is_object) {
+            Some(Self::TavernV2)
+        } else if has("char_name") {
+            Some(Self::Pygmalion)
+        } else if object.get("kind").and_then(Value::as_str) == Some("character") || has("persona")
+        {
+            Some(Self::Agnai)
+        } else if has("first_mes") || has("mes_example") {
+            Some(Self::TavernV1)
+        } else if has("name") && (has("context") || has("greeting")) {
+            Some(Self::Oobabooga)
+        } else {
+            None
+        }
+    }
+}
+
+/// Pygmalion's format, also exported by older versions of TavernAI and the Oobabooga UI.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PygmalionCharacter {
+    #[serde(deserialize_with = "null_as_default")]
+    char_name: String,
+    #[serde(deserialize_with = "null_as_default")]
+    char_persona: String,
+    #[serde(deserialize_with = "null_as_default")]
+    char_greeting: String,
+    #[serde(deserialize_with = "null_as_default")]
+    world_scenario: String,
+    #[serde(deserialize_with = "null_as_default")]
+    example_dialogue: String,
+}
+
+/// The Oobabooga UI's own character format, usually written as YAML.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct OobaboogaCharacter {
+    #[serde(deserialize_with = "null_as_default")]
+    name: String,
+    #[serde(deserialize_with = "null_as_default")]
+    greeting: String,
+    #[serde(deserialize_with = "null_as_default")]
+    context: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct AgnaiCharacter {
+    #[serde(deserialize_with = "null_as_default")]
+    name: String,
+    #[serde(deserialize_with = "null_as_default")]
+    description: String,
+    #[serde(deserialize_with = "null_as_default")]
+    appearance: String,
+    #[serde(deserialize_with = "null_as_default")]
+    scenario: String,
+    #[serde(deserialize_with = "null_as_default")]
+    greeting: String,
+    #[serde(deserialize_with = "null_as_default")]
+    sample_chat: String,
+    #[serde(deserialize_with = "null_as_default")]
+    system_prompt: String,
+    #[serde(deserialize_with = "null_as_default")]
+    post_history_instructions: String,
+    #[serde(deserialize_with = "null_as_default")]
+    alternate_greetings: Vec<String>,
+    #[serde(deserialize_with = "null_as_default")]
+    tags: Vec<String>,
+    #[serde(deserialize_with = "null_as_default")]
+    creator: String,
+    #[serde(deserialize_with = "null_as_default")]
+    character_version: String,
+    #[serde(deserialize_with = "null_as_default")]
+    persona: AgnaiPersona,
+    character_book: Option<Value>,
+    #[serde(deserialize_with = "null_as_default")]
+    extensions: Map<String, Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct AgnaiPersona {
+    #[serde(deserialize_with = "null_as_default")]
+    kind: String,
+    #[serde(deserialize_with = "null_as_default")]
+    attributes: BTreeMap<String, Vec<String>>,
+}
+
+impl AgnaiPersona {
+    /// Plain text personas are used as-is, the structured kinds (W++, SBF, Boostyle)
+    /// become one `attribute: values` line per attribute.
+    fn into_text(self) -> String {
+        if self.kind == "text" {
+            return self
+                .attributes
+                .into_values()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join("\n");
+        }
+
+        self.attributes
+            .into_iter()
+            .map(|(key, values)| format!("{key}: {}", values.join(", ")))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Imports a character from a bare JSON file, detecting its format from its fields. JSON
+/// files have no image, so the character gets the placeholder.
+pub fn character_from_json(json: &[u8], avatars: &AvatarPipeline) -> Result<CharacterInformation> {
+    let value: Value = serde_json::from_slice(json).context("character file is not valid JSON")?;
+    let format = CharacterJsonFormat::detect(&value)
+        .ok_or_else(|| anyhow!("unrecognized character format"))?;
+    info!("importing character from JSON in {format:?} format");
+
+    let data = match format {
+        CharacterJsonFormat::TavernV1
+        | CharacterJsonFormat::TavernV2
+        | CharacterJsonFormat::TavernV3 => parse_character_card(json)?.data,
+        CharacterJsonFormat::Pygmalion => {
+            let character: PygmalionCharacter = deserialize_card_fields(value, "")?;
+            CharacterCardData {
+                name: character.char_name,
+                description: character.char_persona,
+                first_message: character.char_greeting,
+                scenario: character.world_scenario,
+                message_example: character.example_dialogue,
+                ..Default::default()
+            }
+        }
+        CharacterJsonFormat::Oobabooga => {
+            let character: OobaboogaCharacter = deserialize_card_fields(value, "")?;
+            CharacterCardData {
+                name: character.name,
+                description: character.context,
+                first_message: character.greeting,
+                ..Default::default()
+            }
+        }
+        CharacterJsonFormat::Agnai => {
+            let character: AgnaiCharacter = deserialize_card_fields(value, "")?;
+            let description = [character.description, character.appearance]
+                .into_iter()
+                .filter(|s| !s.trim().is_empty())
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            CharacterCardData {
+                name: character.name,
+                description,
+                personality: character.persona.into_text(),
+                scenario: character.scenario,
+                first_message: character.greeting,
+                alternate_greetings: character.alternate_greetings,
+                message_example: character.sample_chat,
+                system_prompt: character.system_prompt,
+                post_history_instructions: character.post_history_instructions,
+                tags: character.tags,
+                creator: character.creator,
+                character_version: character.character_version,
+                character_book: character.character_book,
+                extensions: character.extensions,
+                ..Default::default()
+            }
+        }
+    };
+
+    if data.name.trim().is_empty() {
+        bail!("character has no name");
+    }
+
+    let mut png = Cursor::new(Vec::new());
+    placeholder_image().write_to(&mut png, image::ImageFormat::Png)?;
+    let mut character = data.into_character(None);
+    avatars.attach(&mut character, png.get_ref())?;
+    Ok(character)
+}
+
+/// Reads a PNG character card. The PNG itself becomes the character's image.
+pub fn character_from_png_bytes(
+    bytes: &[u8],
+    avatars: &AvatarPipeline,
+) -> Result<CharacterInformation> {
     use zune_png::PngDecoder;
 
     info!("parsing character data from PNG file");
 
-    let mut decoder = PngDecoder::new(&*bytes);
+    let mut decoder = PngDecoder::new(bytes);
     decoder.decode()?;
 
     let info = decoder
         .get_info()
         .ok_or_else(|| anyhow!("no metadata in png file"))?;
 
-    let base64 = info
-        .text_chunk
-        .iter()
-        .find(|c| c.keyword == b"chara")
-        .map(|c| c.text.as_slice())
-        .ok_or_else(|| anyhow!("no character metadata in png file"))?;
-
-    let json = BASE64_STANDARD.decode(base64)?;
-    let json: CharacterCard = serde_json::from_slice(&json)?;
-
-    info!("parsed data for character '{}'", json.data.name);
-
-    let image = image::load_from_memory(bytes)?;
-    let image = image.resize(500, 500, FilterType::Lanczos3);
-    let image = image.into_rgba8();
-
-    let mut writer = Cursor::new(Vec::new());
-    image.write_to(&mut writer, image::ImageFormat::WebP)?;
-    let base64 = BASE64_STANDARD.encode(writer.get_ref());
-    let image_base64 = format!("data:image/webp;base64,{}", base64);
-
-    let mut first_messages = vec![json.data.first_message];
-    first_messages.extend(json.data.alternate_greetings);
-
-    Ok(CharacterInformation {
-        name: json.data.name,
-        description: json.data.description,
-        personality: json.data.personality,
-        tags: json.data.tags,
-        system_prompt: if json.data.system_prompt.trim().is_empty() {
-            DEFAULT_SYSTEM_PROMPT.to_string()
-        } else {
-            json.data.system_prompt
+    let chunk = |keyword: &[u8]| {
+        info.text_chunk
+            .iter()
+            .find(|c| c.keyword == keyword)
+            .map(|c| c.text.as_slice())
+    };
+    let card = match (chunk(b"ccv3"), chunk(b"chara")) {
+        (Some(v3), fallback) => match card_from_text_chunk(v3) {
+            Ok(card) => card,
+            Err(error) => {
+                let Some(v2) = fallback else {
+                    return Err(error);
+                };
+                warn!("ignoring malformed ccv3 chunk: {error:#}");
+                card_from_text_chunk(v2)?
+            }
         },
-        first_messages,
-        avatar: json.data.avatar,
-        image_base64: Some(image_base64),
-    })
+        (None, Some(v2)) => card_from_text_chunk(v2)?,
+        (None, None) => bail!("no character metadata in png file"),
+    };
+
+    info!(
+        "parsed {:?} data for character '{}'",
+        card.version(),
+        card.data.name
+    );
+
+    let mut character = card.data.into_character(None);
+    avatars.attach(&mut character, bytes)?;
+    Ok(character)
 }
 
-pub async fn character_fron_png_url(url: &str) -> Result<CharacterInformation> {
-    let bytes = reqwest::get(url).await?.bytes().await?;
+/// Decodes an image stored as a data URL or as plain base64.
+fn image_from_base64(data_url: &str) -> Result<DynamicImage> {
+    use base64::prelude::*;
 
-    character_from_png_bytes(&*bytes)
+    let base64 = data_url.split_once(',').map_or(data_url, |(_, data)| data);
+    let bytes = BASE64_STANDARD.decode(base64)?;
+    Ok(image::load_from_memory(&bytes)?)
 }
 
-pub async fn character_from_string(string: &str) -> Result<CharacterInformation> {
-    if string.starts_with("https://") {
-        let url = reqwest::Url::parse(string)?;
-        if url.host_str() == Some("chub.ai") {
-            character_from_chub_ai(string).await
-        } else {
-            character_fron_png_url(string).await
+fn card_from_text_chunk(text: &[u8]) -> Result<CharacterCard> {
+    use base64::prelude::*;
+
+    let json = BASE64_STANDARD
+        .decode(text.trim_ascii())
+        .context("character metadata is not valid base64")?;
+    parse_character_card(&json)
+}
+
+/// Encodes the character's image as a PNG with the character embedded as a V2 `chara` and
+/// a V3 `ccv3` text chunk, the way SillyTavern and other frontends write them.
+pub fn character_to_png_bytes(
+    character: &CharacterInformation,
+    store: &AssetStore,
+) -> Result<Vec<u8>> {
+    use base64::prelude::*;
+
+    let image = match character_image_bytes(character, store)? {
+        Some((bytes, _)) => image::load_from_memory(&bytes)?,
+        None => placeholder_image(),
+    };
+
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, image::ImageFormat::Png)?;
+
+    let data = CharacterCardData::from(character);
+    let v2 = CharacterCard {
+        spec: CARD_V2_SPEC.to_string(),
+        spec_version: "2.0".to_string(),
+        data: data.clone().into_v2(),
+    };
+    let v3 = CharacterCard {
+        spec: CARD_V3_SPEC.to_string(),
+        spec_version: "3.0".to_string(),
+        data,
+    };
+
+    let chunks = [
+        ("chara", BASE64_STANDARD.encode(serde_json::to_vec(&v2)?)),
+        ("ccv3", BASE64_STANDARD.encode(serde_json::to_vec(&v3)?)),
+    ];
+    insert_text_chunks(&png.into_inner(), &chunks)
+}
+
+/// The character's original image from the asset store with its extension, or the inline
+/// image of characters imported before images were kept there as a PNG.
+fn character_image_bytes(
+    character: &CharacterInformation,
+    store: &AssetStore,
+) -> Result<Option<(Vec<u8>, String)>> {
+    if let Some(image) = &character.image {
+        let ext = image
+            .original
+            .rsplit_once('.')
+            .map_or("png", |(_, ext)| ext);
+        return Ok(Some((store.get(&image.original)?, ext.to_string())));
+    }
+
+    match character.image_base64.as_deref().filter(|s| !s.is_empty()) {
+        Some(data_url) => {
+            let mut png = Cursor::new(Vec::new());
+            image_from_base64(data_url)?.write_to(&mut png, image::ImageFormat::Png)?;
+            Ok(Some((png.into_inner(), "png".to_string())))
         }
+        None => Ok(None),
+    }
+}
+
+/// A vertical gradient, for characters that were created without an image.
+fn placeholder_image() -> DynamicImage {
+    let (width, height) = (400, 600);
+    let image = RgbImage::from_fn(width, height, |_, y| {
+        let t = y as f32 / height as f32;
+        let mix = |from: f32, to: f32| (from + (to - from) * t) as u8;
+        Rgb([mix(88.0, 30.0), mix(70.0, 27.0), mix(160.0, 60.0)])
+    });
+
+    DynamicImage::ImageRgb8(image)
+}
+
+/// Inserts `tEXt` chunks right after the `IHDR` chunk of an encoded PNG.
+fn insert_text_chunks(png: &[u8], chunks: &[(&str, String)]) -> Result<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    // the IHDR chunk is always the first one and has a fixed size
+    const IHDR_END: usize = SIGNATURE.len() + 4 + 4 + 13 + 4;
+
+    if !png.starts_with(SIGNATURE) || png.get(12..16) != Some(b"IHDR") || png.len() < IHDR_END {
+        bail!("not a valid png file");
+    }
+
+    let mut output = Vec::with_capacity(png.len());
+    output.extend_from_slice(&png[..IHDR_END]);
+    for (keyword, text) in chunks {
+        let mut data = Vec::with_capacity(4 + keyword.len() + 1 + text.len());
+        data.extend_from_slice(b"tEXt");
+        data.extend_from_slice(keyword.as_bytes());
+        data.push(0);
+        data.extend_from_slice(text.as_bytes());
+
+        output.extend_from_slice(&(data.len() as u32 - 4).to_be_bytes());
+        output.extend_from_slice(&data);
+        output.extend_from_slice(&crc32fast::hash(&data).to_be_bytes());
+    }
+    output.extend_from_slice(&png[IHDR_END..]);
+
+    Ok(output)
+}
+
+/// Reads a CharX archive: a V3 `card.json` and the assets it embeds. Embedded assets are
+/// copied into the asset store and their URIs rewritten to point there.
+pub fn character_from_charx_bytes(
+    bytes: &[u8],
+    avatars: &AvatarPipeline,
+) -> Result<CharacterInformation> {
+    info!("parsing character data from CharX archive");
+
+    let store = avatars.store();
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).context("not a valid charx archive")?;
+    let json = read_zip_entry(&mut archive, "card.json")?;
+    let mut card = parse_character_card(&json)?;
+
+    let mut avatar = None;
+    for asset in &mut card.data.assets {
+        let Some(path) = embedded_asset_path(&asset.uri) else {
+            continue;
+        };
+        let bytes = read_zip_entry(&mut archive, path)?;
+        let id = store.put(&bytes, &asset.ext)?;
+        debug!("stored asset '{}' from {path} as {id}", asset.name);
+
+        if asset.kind == "icon" && (asset.name == "main" || avatar.is_none()) {
+            avatar = Some(bytes);
+        }
+        asset.uri = asset_uri(&id);
+    }
+
+    info!(
+        "parsed data for character '{}' with {} assets",
+        card.data.name,
+        card.data.assets.len()
+    );
+
+    let mut character = card.data.into_character(None);
+    if let Some(bytes) = avatar {
+        avatars.attach(&mut character, &bytes)?;
+    }
+    Ok(character)
+}
+
+/// Writes the character as a CharX archive, embedding every asset from the asset store.
+/// The character's image becomes the main icon if it has none.
+pub fn character_to_charx_bytes(
+    character: &CharacterInformation,
+    store: &AssetStore,
+) -> Result<Vec<u8>> {
+    let mut data = CharacterCardData::from(character);
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default();
+    let mut paths = HashSet::new();
+
+    for asset in &mut data.assets {
+        let Some(id) = asset_id(&asset.uri) else {
+            continue;
+        };
+        let bytes = store.get(id)?;
+        let path = charx_asset_path(asset, &mut paths);
+        zip.start_file(path.as_str(), options)?;
+        zip.write_all(&bytes)?;
+        asset.uri = format!("{EMBEDDED_ASSET_PREFIX}{path}");
+    }
+
+    let has_icon = data.assets.iter().any(|asset| asset.kind == "icon");
+    let image = if has_icon {
+        None
     } else {
-        bail!("unsupported character source: {string}");
+        character_image_bytes(character, store)?
+    };
+    if let Some((bytes, ext)) = image {
+        let mut icon = CharacterAsset {
+            kind: "icon".into(),
+            uri: String::new(),
+            name: "main".into(),
+            ext,
+        };
+        let path = charx_asset_path(&icon, &mut paths);
+        zip.start_file(path.as_str(), options)?;
+        zip.write_all(&bytes)?;
+        icon.uri = format!("{EMBEDDED_ASSET_PREFIX}{path}");
+        data.assets.push(icon);
+    }
+
+    let card = CharacterCard {
+        spec: CARD_V3_SPEC.to_string(),
+        spec_version: "3.0".to_string(),
+        data,
+    };
+    zip.start_file("card.json", options)?;
+    serde_json::to_writer_pretty(&mut zip, &card)?;
+
+    Ok(zip.finish()?.into_inner())
+}
+
+/// The V3 spec spells the scheme `embeded://`; some exporters use the correct spelling.
+const EMBEDDED_ASSET_PREFIX: &str = "embeded://";
+
+fn embedded_asset_path(uri: &str) -> Option<&str> {
+    uri.strip_prefix(EMBEDDED_ASSET_PREFIX)
+        .or_else(|| uri.strip_prefix("embedded://"))
+}
+
+fn read_zip_entry(archive: &mut ZipArchive<Cursor<&[u8]>>, path: &str) -> Result<Vec<u8>> {
+    let mut file = archive
+        .by_name(path)
+        .with_context(|| format!("charx archive has no file '{path}'"))?;
+    let mut bytes = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Picks an unused path following the `assets/{type}/{category}/{name}.{ext}` layout.
+fn charx_asset_path(asset: &CharacterAsset, taken: &mut HashSet<String>) -> String {
+    let clean = |s: &str| -> String {
+        s.chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    };
+    let kind = clean(&asset.kind);
+    let name = clean(&asset.name);
+    let ext = clean(&asset.ext);
+    let category = match ext.to_ascii_lowercase().as_str() {
+        "png" | "jpg" | "jpeg" | "webp" | "gif" | "avif" => "images",
+        "mp3" | "wav" | "ogg" | "flac" => "audio",
+        _ => "other",
+    };
+
+    let mut path = format!("assets/{kind}/{category}/{name}.{ext}");
+    let mut n = 1;
+    while !taken.insert(path.clone()) {
+        n += 1;
+        path = format!("assets/{kind}/{category}/{name}-{n}.{ext}");
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::prelude::*;
+    use camino::Utf8Path;
+    use erpy_types::{
+        CharacterAsset, CharacterInformation, Lorebook, LorebookEntry, LorebookPosition,
+        SelectiveLogic, Versioned,
+    };
+    use serde_json::json;
+
+    use super::{
+        character_from_charx_bytes, character_from_json, character_from_png_bytes,
+        character_to_charx_bytes, character_to_png_bytes, insert_text_chunks, parse_character_card,
+        CardVersion, CharacterJsonFormat,
+    };
+    use crate::{
+        assets::{asset_id, asset_uri, AssetStore, ASSET_URI_PREFIX},
+        avatar::AvatarPipeline,
+        config::AvatarSettings,
+    };
+
+    fn avatars(dir: &tempfile::TempDir) -> AvatarPipeline {
+        let store = AssetStore::new(Utf8Path::from_path(dir.path()).unwrap());
+        AvatarPipeline::new(store, AvatarSettings::default())
+    }
+
+    fn import_json(json: &[u8]) -> anyhow::Result<CharacterInformation> {
+        let dir = tempfile::tempdir().unwrap();
+        character_from_json(json, &avatars(&dir))
+    }
+
+    fn parse(card: serde_json::Value) -> anyhow::Result<super::CharacterCard> {
+        parse_character_card(card.to_string().as_bytes())
+    }
+
+    #[test]
+    fn test_card_keeps_v3_fields() {
+        let card = json!({
+            "spec": "chara_card_v3",
+            "spec_version": "3.0",
+            "data": {
+                "name": "Alice",
+                "description": "{{char}} is a librarian.",
+                "personality": "curious",
+                "scenario": "A quiet library.",
+                "first_mes": "Hello!",
+                "mes_example": "<START>",
+                "creator_notes": "Be nice to her.",
+                "system_prompt": "",
+                "post_history_instructions": "Stay in character.",
+                "alternate_greetings": ["Hi there."],
+                "tags": ["fantasy"],
+                "creator": "someone",
+                "character_version": "1.2",
+                "extensions": { "depth_prompt": { "depth": 4, "prompt": "Whisper." } },
+                "character_book": { "entries": [{ "keys": ["library"], "content": "Old." }] },
+                "nickname": "Ali",
+                "creator_notes_multilingual": { "de": "Sei nett zu ihr." },
+                "group_only_greetings": ["Hello everyone."],
+                "source": ["https://example.com/alice"],
+                "creation_date": 1700000000,
+                "modification_date": 1710000000,
+                "assets": [{ "type": "icon", "uri": "ccdefault:", "name": "main", "ext": "png" }]
+            }
+        });
+
+        let card = parse(card).unwrap();
+        assert_eq!(card.version(), CardVersion::V3);
+        let character = card.data.into_character(None);
+
+        assert_eq!(character.first_messages, vec!["Hello!", "Hi there."]);
+        assert_eq!(character.creator, "someone");
+        assert_eq!(character.creator_notes, "Be nice to her.");
+        assert_eq!(character.character_version, "1.2");
+        assert_eq!(character.nickname.as_deref(), Some("Ali"));
+        assert_eq!(
+            character.creator_notes_multilingual["de"],
+            "Sei nett zu ihr."
+        );
+        assert_eq!(character.group_only_greetings, vec!["Hello everyone."]);
+        assert_eq!(character.source, vec!["https://example.com/alice"]);
+        assert_eq!(character.creation_date, Some(1700000000));
+        assert_eq!(character.modification_date, Some(1710000000));
+        assert_eq!(character.assets[0].kind, "icon");
+        assert_eq!(character.extensions["depth_prompt"]["depth"], 4);
+        assert!(character.character_book.is_some());
+        assert!(!character.system_prompt.is_empty());
+    }
+
+    #[test]
+    fn test_card_without_v3_fields() {
+        let card = json!({
+            "spec": "chara_card_v2",
+            "spec_version": "2.0",
+            "data": {
+                "name": "Bob",
+                "description": "",
+                "personality": "",
+                "scenario": "",
+                "first_mes": "Hey.",
+                "mes_example": "",
+                "creator_notes": "",
+                "system_prompt": "Be Bob.",
+                "post_history_instructions": null,
+                "alternate_greetings": [],
+                "tags": [],
+                "creator": "",
+                "character_version": ""
+            }
+        });
+
+        let card = parse(card).unwrap();
+        assert_eq!(card.version(), CardVersion::V2);
+        let character = card.data.into_character(None);
+
+        assert_eq!(character.system_prompt, "Be Bob.");
+        assert!(character.extensions.is_empty());
+        assert!(character.assets.is_empty());
+        assert_eq!(character.nickname, None);
+    }
+
+    #[test]
+    fn test_parse_v1_card() {
+        let card = json!({
+            "name": "Carol",
+            "description": "{{char}} runs a bakery.",
+            "personality": "cheerful",
+            "scenario": null,
+            "first_mes": "Fresh bread today!",
+            "mes_example": ""
+        });
+
+        let card = parse(card).unwrap();
+        assert_eq!(card.version(), CardVersion::V1);
+        assert_eq!(card.spec_version, "1.0");
+        let character = card.data.into_character(None);
+
+        assert_eq!(character.name, "Carol");
+        assert_eq!(character.scenario, "");
+        assert_eq!(character.first_messages, vec!["Fresh bread today!"]);
+    }
+
+    #[test]
+    fn test_parse_minimal_v2_card() {
+        let card = parse(json!({
+            "spec": "chara_card_v2",
+            "spec_version": "2.0",
+            "data": { "name": "Dave", "first_mes": "", "alternate_greetings": ["Yo."] }
+        }))
+        .unwrap();
+        let character = card.data.into_character(None);
+
+        assert_eq!(character.first_messages, vec!["Yo."]);
+        assert_eq!(character.description, "");
+    }
+
+    #[test]
+    fn test_parse_errors_name_the_field() {
+        let error = parse(json!({
+            "spec": "chara_card_v3",
+            "spec_version": "3.0",
+            "data": { "name": "Eve", "alternate_greetings": ["Hi", 5] }
+        }))
+        .unwrap_err();
+        assert!(
+            error.to_string().contains("`data.alternate_greetings[1]`"),
+            "{error}"
+        );
+
+        let error = parse(json!({ "name": "Eve", "first_mes": ["Hi"] })).unwrap_err();
+        assert!(error.to_string().contains("`first_mes`"), "{error}");
+
+        let error =
+            parse(json!({ "spec": "chara_card_v2", "data": { "description": "?" } })).unwrap_err();
+        assert!(error.to_string().contains("`data.name`"), "{error}");
+
+        let error = parse(json!({ "spec": "chara_card_v9", "data": {} })).unwrap_err();
+        assert!(error.to_string().contains("chara_card_v9"), "{error}");
+
+        assert!(parse_character_card(b"[]").is_err());
+    }
+
+    fn plain_png() -> Vec<u8> {
+        let image = image::RgbImage::from_pixel(2, 3, image::Rgb([255, 0, 0]));
+        let mut png = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut png, image::ImageFormat::Png).unwrap();
+        png.into_inner()
+    }
+
+    fn png_with_chunks(chunks: &[(&str, serde_json::Value)]) -> Vec<u8> {
+        use base64::prelude::*;
+
+        let chunks: Vec<_> = chunks
+            .iter()
+            .map(|(keyword, card)| (*keyword, BASE64_STANDARD.encode(card.to_string())))
+            .collect();
+        insert_text_chunks(&plain_png(), &chunks).unwrap()
+    }
+
+    #[test]
+    fn test_png_prefers_ccv3_chunk() {
+        let v2 = json!({ "spec": "chara_card_v2", "data": { "name": "Old" } });
+        let v3 = json!({ "spec": "chara_card_v3", "data": { "name": "New" } });
+
+        let dir = tempfile::tempdir().unwrap();
+        let avatars = avatars(&dir);
+        let name = |png: &[u8]| character_from_png_bytes(png, &avatars).unwrap().name;
+
+        let png = png_with_chunks(&[("chara", v2.clone()), ("ccv3", v3)]);
+        assert_eq!(name(&png), "New");
+
+        let png = png_with_chunks(&[("chara", v2.clone()), ("ccv3", json!({ "spec": 3 }))]);
+        assert_eq!(name(&png), "Old");
+
+        let png = png_with_chunks(&[("chara", v2)]);
+        assert_eq!(name(&png), "Old");
+    }
+
+    #[test]
+    fn test_png_export_round_trip() {
+        let character = CharacterInformation {
+            schema_version: CharacterInformation::SCHEMA_VERSION,
+            name: "Alice".into(),
+            description: "{{char}} is a librarian.".into(),
+            personality: "curious".into(),
+            first_messages: vec!["Hello!".into(), "Hi there.".into()],
+            tags: vec!["fantasy".into()],
+            system_prompt: "Write {{char}}'s reply.".into(),
+            scenario: "A quiet library.".into(),
+            example_dialogues: "<START>".into(),
+            post_history_instructions: "Stay in character.".into(),
+            creator: "someone".into(),
+            creator_notes: "Be nice to her.".into(),
+            creator_notes_multilingual: [("de".to_string(), "Sei nett.".to_string())].into(),
+            character_version: "1.2".into(),
+            nickname: Some("Ali".into()),
+            group_only_greetings: vec!["Hello everyone.".into()],
+            source: vec!["https://example.com/alice".into()],
+            creation_date: Some(1700000000),
+            modification_date: Some(1710000000),
+            assets: vec![CharacterAsset {
+                kind: "icon".into(),
+                uri: "ccdefault:".into(),
+                name: "main".into(),
+                ext: "png".into(),
+            }],
+            character_book: Some(Lorebook {
+                name: "Alice's library".into(),
+                token_budget: Some(512),
+                recursive_scanning: true,
+                entries: vec![
+                    LorebookEntry {
+                        id: Some(1),
+                        name: "Library".into(),
+                        keys: vec!["library".into(), "books".into()],
+                        content: "The library is old.".into(),
+                        ..Default::default()
+                    },
+                    LorebookEntry {
+                        id: Some(2),
+                        keys: vec!["/ghost(s)?/i".into()],
+                        secondary_keys: vec!["night".into()],
+                        selective: true,
+                        selective_logic: SelectiveLogic::NotAny,
+                        content: "Nobody has seen a ghost here.".into(),
+                        position: LorebookPosition::AtDepth,
+                        depth: Some(2),
+                        probability: Some(50),
+                        case_sensitive: Some(true),
+                        match_whole_words: Some(false),
+                        sticky: Some(3),
+                        cooldown: Some(1),
+                        prevent_recursion: true,
+                        extensions: json!({ "group": "spooky" }).as_object().unwrap().clone(),
+                        ..Default::default()
+                    },
+                ],
+                ..Default::default()
+            }),
+            extensions: json!({ "talkativeness": "0.5" })
+                .as_object()
+                .unwrap()
+                .clone(),
+            avatar: None,
+            image: None,
+            image_base64: None,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let avatars = avatars(&dir);
+        let png = character_to_png_bytes(&character, avatars.store()).unwrap();
+        let imported = character_from_png_bytes(&png, &avatars).unwrap();
+        let image = imported.image.clone().unwrap();
+        assert_eq!(avatars.store().get(&image.original).unwrap(), png);
+        assert_eq!((image.width, image.height), (400, 600));
+
+        let without_image = |character: &CharacterInformation| {
+            let mut value = serde_json::to_value(character).unwrap();
+            value["image"] = serde_json::Value::Null;
+            value
+        };
+        assert_eq!(without_image(&imported), without_image(&character));
+
+        // exporting again keeps the stored original rather than the placeholder
+        let exported = character_to_png_bytes(&imported, avatars.store()).unwrap();
+        assert_eq!(image::load_from_memory(&exported).unwrap().width(), 400);
+    }
+
+    #[test]
+    fn test_png_export_writes_v2_chunk() {
+        use base64::prelude::*;
+        use zune_png::PngDecoder;
+
+        let character = CharacterInformation {
+            name: "Bob".into(),
+            first_messages: vec!["Hey.".into()],
+            nickname: Some("Bobby".into()),
+            image_base64: Some(format!(
+                "data:image/png;base64,{}",
+                BASE64_STANDARD.encode(plain_png())
+            )),
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let png = character_to_png_bytes(&character, avatars(&dir).store()).unwrap();
+
+        let mut decoder = PngDecoder::new(&png);
+        decoder.decode().unwrap();
+        let info = decoder.get_info().unwrap();
+        let chara = info
+            .text_chunk
+            .iter()
+            .find(|c| c.keyword == b"chara")
+            .unwrap();
+        let card: serde_json::Value =
+            serde_json::from_slice(&BASE64_STANDARD.decode(&chara.text).unwrap()).unwrap();
+
+        assert_eq!(card["spec"], "chara_card_v2");
+        assert_eq!(card["data"]["first_mes"], "Hey.");
+        assert!(card["data"].get("nickname").is_none());
+        assert_eq!(image::load_from_memory(&png).unwrap().width(), 2);
+    }
+
+    #[test]
+    fn test_charx_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = AssetStore::new(Utf8Path::from_path(dir.path()).unwrap().join("assets"));
+        let sprite = store.put(b"not really audio", "mp3").unwrap();
+
+        let character = CharacterInformation {
+            name: "Alice".into(),
+            first_messages: vec!["Hello!".into()],
+            system_prompt: "Write {{char}}'s reply.".into(),
+            assets: vec![CharacterAsset {
+                kind: "other".into(),
+                uri: asset_uri(&sprite),
+                name: "theme".into(),
+                ext: "mp3".into(),
+            }],
+            image_base64: Some(format!(
+                "data:image/png;base64,{}",
+                BASE64_STANDARD.encode(plain_png())
+            )),
+            ..Default::default()
+        };
+        let charx = character_to_charx_bytes(&character, &store).unwrap();
+
+        let other = tempfile::tempdir().unwrap();
+        let other = avatars(&other);
+        let imported = character_from_charx_bytes(&charx, &other).unwrap();
+
+        assert_eq!(imported.name, "Alice");
+        assert_eq!(imported.first_messages, vec!["Hello!"]);
+        assert_eq!(imported.assets.len(), 2);
+        assert_eq!(imported.assets[0].uri, asset_uri(&sprite));
+        assert_eq!(other.store().get(&sprite).unwrap(), b"not really audio");
+        assert_eq!(imported.assets[1].kind, "icon");
+        assert_eq!(imported.assets[1].name, "main");
+        assert_eq!(imported.image.unwrap().width, 2);
+        assert!(imported.image_base64.is_none());
+    }
+
+    #[test]
+    fn test_charx_import_embedded_assets() {
+        use std::io::Write;
+
+        let card = json!({
+            "spec": "chara_card_v3",
+            "spec_version": "3.0",
+            "data": {
+                "name": "Bob",
+                "first_mes": "Hey.",
+                "assets": [
+                    { "type": "icon", "uri": "embeded://assets/icon/images/main.png", "name": "main", "ext": "png" },
+                    { "type": "background", "uri": "https://example.com/bg.png", "name": "bg", "ext": "png" },
+                    { "type": "emotion", "uri": "embedded://sprites/happy.png", "name": "happy", "ext": "png" }
+                ]
+            }
+        });
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        zip.start_file("card.json", options).unwrap();
+        zip.write_all(card.to_string().as_bytes()).unwrap();
+        zip.start_file("assets/icon/images/main.png", options)
+            .unwrap();
+        zip.write_all(&plain_png()).unwrap();
+        zip.start_file("sprites/happy.png", options).unwrap();
+        zip.write_all(b"happy").unwrap();
+        let charx = zip.finish().unwrap().into_inner();
+
+        let dir = tempfile::tempdir().unwrap();
+        let avatars = avatars(&dir);
+        let store = avatars.store();
+        let character = character_from_charx_bytes(&charx, &avatars).unwrap();
+
+        assert!(character.image.is_some());
+        assert!(character.assets[0].uri.starts_with(ASSET_URI_PREFIX));
+        assert_eq!(character.assets[1].uri, "https://example.com/bg.png");
+        let happy = asset_id(&character.assets[2].uri).unwrap();
+        assert_eq!(store.get(happy).unwrap(), b"happy");
+
+        let missing = json!({
+            "spec": "chara_card_v3",
+            "data": { "name": "Bob", "assets": [{ "type": "icon", "uri": "embeded://nope.png", "name": "main", "ext": "png" }] }
+        });
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file("card.json", options).unwrap();
+        zip.write_all(missing.to_string().as_bytes()).unwrap();
+        let charx = zip.finish().unwrap().into_inner();
+        let error = character_from_charx_bytes(&charx, &avatars).unwrap_err();
+        assert!(error.to_string().contains("nope.png"), "{error}");
+    }
+
+    #[test]
+    fn test_detect_json_formats() {
+        let cases = [
+            (
+                include_str!("../fixtures/cards/tavern-v1.json"),
+                CharacterJsonFormat::TavernV1,
+            ),
+            (
+                include_str!("../fixtures/cards/tavern-v2.json"),
+                CharacterJsonFormat::TavernV2,
+            ),
+            (
+                include_str!("../fixtures/cards/tavern-v3.json"),
+                CharacterJsonFormat::TavernV3,
+            ),
+            (
+                include_str!("../fixtures/cards/pygmalion.json"),
+                CharacterJsonFormat::Pygmalion,
+            ),
+            (
+                include_str!("../fixtures/cards/oobabooga.json"),
+                CharacterJsonFormat::Oobabooga,
+            ),
+            (
+                include_str!("../fixtures/cards/agnai.json"),
+                CharacterJsonFormat::Agnai,
+            ),
+        ];
+
+        for (json, format) in cases {
+            let value: serde_json::Value = serde_json::from_str(json).unwrap();
+            assert_eq!(CharacterJsonFormat::detect(&value), Some(format));
+        }
+        assert_eq!(CharacterJsonFormat::detect(&json!({ "foo": 1 })), None);
+        assert!(import_json(b"{\"foo\": 1}").is_err());
+    }
+
+    #[test]
+    fn test_json_tavern_v1() {
+        let dir = tempfile::tempdir().unwrap();
+        let avatars = avatars(&dir);
+        let character =
+            character_from_json(include_bytes!("../fixtures/cards/tavern-v1.json"), &avatars)
+                .unwrap();
+
+        assert_eq!(character.name, "Mira");
+        assert_eq!(character.scenario, "{{user}} washes ashore after a storm.");
+        assert_eq!(character.first_messages.len(), 1);
+        assert!(character.example_dialogues.starts_with("<START>"));
+        // the placeholder is kept in the asset store, not inline
+        assert!(character.image_base64.is_none());
+        let image = character.image.unwrap();
+        assert_eq!((image.width, image.height), (400, 600));
+        assert!(avatars.store().get(&image.original).is_ok());
+    }
+
+    #[test]
+    fn test_json_tavern_v2() {
+        let character = import_json(include_bytes!("../fixtures/cards/tavern-v2.json")).unwrap();
+
+        assert_eq!(character.first_messages.len(), 2);
+        assert_eq!(character.creator, "tidewriter");
+        assert_eq!(
+            character.post_history_instructions,
+            "Keep replies under three paragraphs."
+        );
+        assert_eq!(character.extensions["talkativeness"], "0.4");
+    }
+
+    #[test]
+    fn test_json_tavern_v3() {
+        let character = import_json(include_bytes!("../fixtures/cards/tavern-v3.json")).unwrap();
+
+        assert_eq!(character.nickname.as_deref(), Some("the Keeper"));
+        assert_eq!(character.group_only_greetings.len(), 1);
+        assert_eq!(character.creation_date, Some(1714000000));
+        assert_eq!(character.assets[0].uri, "ccdefault:");
+    }
+
+    #[test]
+    fn test_json_pygmalion() {
+        let character = import_json(include_bytes!("../fixtures/cards/pygmalion.json")).unwrap();
+
+        assert_eq!(character.name, "Mira");
+        assert!(character.description.contains("lighthouse keeper"));
+        assert_eq!(character.scenario, "{{user}} washes ashore after a storm.");
+        assert!(character.first_messages[0].starts_with("*Mira kneels"));
+        assert!(character.example_dialogues.contains("Gull Rock"));
+    }
+
+    #[test]
+    fn test_json_oobabooga() {
+        let character = import_json(include_bytes!("../fixtures/cards/oobabooga.json")).unwrap();
+
+        assert_eq!(character.name, "Mira");
+        assert!(character
+            .description
+            .ends_with("washes ashore after a storm."));
+        assert!(character.first_messages[0].starts_with("*Mira kneels"));
+    }
+
+    #[test]
+    fn test_json_agnai() {
+        let character = import_json(include_bytes!("../fixtures/cards/agnai.json")).unwrap();
+
+        assert_eq!(
+            character.description,
+            "A lighthouse keeper on a remote island.\n\nWeathered oilskin coat, grey braid."
+        );
+        assert_eq!(
+            character.personality,
+            "occupation: lighthouse keeper\npersonality: patient, observant, dry humor"
+        );
+        assert!(character.example_dialogues.contains("Gull Rock"));
+        assert_eq!(character.first_messages.len(), 2);
+        assert_eq!(character.character_version, "1.0");
+        assert!(character.character_book.is_none());
     }
 }
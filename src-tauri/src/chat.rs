@@ -1,16 +1,36 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use erpy_ai::{CompletionApis, CompletionRequest, MessageHistoryItem};
 use erpy_types::{Chat, MessageRole};
 
-pub async fn summarize(chat: &Chat, client: &CompletionApis, prompt: &str) -> Result<String> {
-    let mut history: Vec<_> = chat
-        .history
-        .iter()
-        .map(|entry| MessageHistoryItem {
-            role: entry.role,
-            content: entry.content[entry.chosen_answer].content.clone(),
+use crate::{prompt::PromptBuilder, text::strip_reasoning};
+
+pub const DEFAULT_IMPERSONATION_PROMPT: &str = "[Write your next reply from the point of view of {{user}}, using the chat history so far as a guideline for the writing style of {{user}}. Don't write as {{char}} or system. Don't describe actions of {{char}}.]";
+
+const TITLE_PROMPT: &str = "[Pause your roleplay. Write a title for this chat so far, in 8 words or less. Reply with the title only.]";
+
+/// Titles are generated from the first messages only, which is enough to tell chats apart.
+const TITLE_MESSAGES: usize = 6;
+
+const MAX_TITLE_LENGTH: usize = 60;
+
+/// Enough for a title, but not for a reasoning model to think first.
+const TITLE_TOKENS: usize = 30;
+
+/// Room for a reasoning model to think before writing the title.
+const THINKING_TITLE_TOKENS: usize = 1000;
+
+fn history_messages(chat: &Chat) -> Vec<MessageHistoryItem> {
+    chat.history()
+        .into_iter()
+        .map(|message| MessageHistoryItem {
+            role: message.role,
+            content: message.text().to_string(),
         })
-        .collect();
+        .collect()
+}
+
+pub async fn summarize(chat: &Chat, client: &CompletionApis, prompt: &str) -> Result<String> {
+    let mut history = history_messages(chat);
 
     history.push(MessageHistoryItem {
         role: MessageRole::User,
@@ -29,3 +49,193 @@ pub async fn summarize(chat: &Chat, client: &CompletionApis, prompt: &str) -> Re
     let response = client.get_completions(request).await?;
     Ok(response.into_message())
 }
+
+/// Turns the reply of a model into a title: without thinking, quotes, a "Title:" prefix or
+/// extra lines, and at most [`MAX_TITLE_LENGTH`] characters, cut at a word boundary.
+pub fn sanitize_title(reply: &str) -> Option<String> {
+    let reply = strip_reasoning(reply);
+    // a reply cut off while thinking has no title yet
+    let reply = ["<think>", "<thinking>", "<reasoning>"]
+        .iter()
+        .filter_map(|tag| reply.find(tag))
+        .min()
+        .map_or(reply.as_str(), |start| &reply[..start]);
+    let line = reply.lines().map(str::trim).find(|line| !line.is_empty())?;
+    let decoration =
+        |c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '*' | '#' | '`' | '“' | '”' | '.');
+    let line = line.trim_matches(decoration);
+    let line = match line.get(..6) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("title:") => line[6..].trim_matches(decoration),
+        _ => line,
+    };
+
+    let mut title = String::new();
+    for word in line.split_whitespace() {
+        let length = title.chars().count() + word.chars().count() + 1;
+        if !title.is_empty() && length > MAX_TITLE_LENGTH {
+            break;
+        }
+        if !title.is_empty() {
+            title.push(' ');
+        }
+        title.push_str(word);
+    }
+    let title: String = title.chars().take(MAX_TITLE_LENGTH).collect();
+    if title.is_empty() {
+        None
+    } else {
+        Some(title)
+    }
+}
+
+pub async fn generate_title(chat: &Chat, client: &CompletionApis) -> Result<String> {
+    let mut history: Vec<MessageHistoryItem> = history_messages(chat)
+        .into_iter()
+        .filter(|message| message.role != MessageRole::System && !message.content.is_empty())
+        .take(TITLE_MESSAGES)
+        .collect();
+    history.push(MessageHistoryItem {
+        role: MessageRole::User,
+        content: TITLE_PROMPT.into(),
+    });
+
+    let mut max_tokens = TITLE_TOKENS;
+    loop {
+        let request = CompletionRequest {
+            messages: history.clone(),
+            temperature: Some(0.2),
+            model: "unused".into(),
+            stream: false,
+            max_tokens: Some(max_tokens),
+            ..Default::default()
+        }
+        .strip_thinking_tags();
+
+        let response = client.get_completions(request).await?;
+        let cut_off = response
+            .choices
+            .first()
+            .is_some_and(|choice| choice.finish_reason.as_deref() == Some("length"));
+        let reply = response.into_message();
+        match sanitize_title(&reply) {
+            Some(title) => return Ok(title),
+            // the reply was cut off while the model was still thinking
+            None if cut_off && max_tokens < THINKING_TITLE_TOKENS => {
+                max_tokens = THINKING_TITLE_TOKENS;
+            }
+            None => bail!("the model replied without a title: '{reply}'"),
+        }
+    }
+}
+
+/// Builds the messages for drafting the user's next message: the prompt of the chat, with
+/// the character card and the user's persona, followed by an instruction to reply as the
+/// user instead of the character.
+pub fn impersonation_messages(
+    builder: &PromptBuilder,
+    prompt: Option<&str>,
+) -> Vec<MessageHistoryItem> {
+    let mut messages = builder.build();
+    let prompt = prompt
+        .filter(|p| !p.trim().is_empty())
+        .unwrap_or(DEFAULT_IMPERSONATION_PROMPT);
+
+    messages.push(MessageHistoryItem {
+        role: MessageRole::User,
+        content: builder.expand_macros(prompt),
+    });
+
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use erpy_types::{test_support, CharacterInformation, Chat, MessageRole, Persona};
+
+    use super::{impersonation_messages, sanitize_title};
+    use crate::{config::PromptSettings, prompt::PromptBuilder};
+
+    fn chat() -> Chat {
+        test_support::dialogue(&["Welcome to the library.", "Hi Alice!"])
+    }
+
+    fn character() -> CharacterInformation {
+        CharacterInformation {
+            name: "Alice".into(),
+            description: "{{char}} is a librarian.".into(),
+            scenario: "A quiet afternoon in the library.".into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_impersonation_messages() {
+        let chat = chat();
+        let character = character();
+        let settings = PromptSettings::default();
+        let builder = PromptBuilder::new(&chat, &character, "Bob", &settings);
+
+        let messages = impersonation_messages(&builder, None);
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0].role, MessageRole::System);
+        assert!(messages[0].content.contains("Alice is a librarian."));
+        assert!(messages[0]
+            .content
+            .contains("A quiet afternoon in the library."));
+        assert_eq!(messages[1].content, "Welcome to the library.");
+        assert_eq!(messages[2].content, "Hi Alice!");
+        assert_eq!(messages[3].role, MessageRole::User);
+        assert!(messages[3]
+            .content
+            .starts_with("[Write your next reply from the point of view of Bob,"));
+
+        let messages = impersonation_messages(&builder, Some("Reply as {{user}} to {{char}}."));
+        assert_eq!(messages[3].content, "Reply as Bob to Alice.");
+        let messages = impersonation_messages(&builder, Some("  "));
+        assert!(messages[3].content.contains("Don't write as Alice"));
+    }
+
+    #[test]
+    fn test_impersonation_messages_with_persona() {
+        let chat = chat();
+        let character = character();
+        let settings = PromptSettings::default();
+        let persona = Persona {
+            description: "{{user}} is a student.".into(),
+            ..Persona::new("Dave")
+        };
+        let builder =
+            PromptBuilder::new(&chat, &character, "Bob", &settings).with_persona(Some(&persona));
+
+        let messages = impersonation_messages(&builder, Some("Reply as {{user}}."));
+        assert!(messages[0]
+            .content
+            .contains("Dave's persona: Dave is a student."));
+        assert_eq!(messages[3].content, "Reply as Dave.");
+    }
+
+    #[test]
+    fn test_sanitize_title() {
+        assert_eq!(
+            sanitize_title("<think>A title about tea?</think>\n\n\"Tea at Midnight.\"\nEnjoy!"),
+            Some("Tea at Midnight".into())
+        );
+        assert_eq!(
+            sanitize_title("**Title:** The Dragon's Bargain"),
+            Some("The Dragon's Bargain".into())
+        );
+        let long = sanitize_title(&"dragon ".repeat(20)).unwrap();
+        assert_eq!(long.len(), 55);
+        assert!(long.ends_with("dragon"));
+        assert_eq!(sanitize_title(&"x".repeat(80)).unwrap().len(), 60);
+        assert_eq!(sanitize_title(" \"\" "), None);
+        assert_eq!(
+            sanitize_title("<think>The chat is about tea, so a title could be"),
+            None
+        );
+        assert_eq!(
+            sanitize_title("Okay.</think>The Midnight Tea Party"),
+            Some("The Midnight Tea Party".into())
+        );
+    }
+}
@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::prompt::PromptSection;
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct SyncSettings {
@@ -19,6 +21,8 @@ pub struct LlmSettings {
     pub top_p: Option<f64>,
     pub seed: Option<i64>,
     pub strip_thinking_tags: Option<bool>,
+    /// How many answers are generated for a message, each a swipe of the reply.
+    pub choices: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -34,6 +38,122 @@ pub struct ExperimentalSettings {
     pub text_to_speech: bool,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PromptSettings {
+    /// The order in which the parts of the prompt are assembled.
+    pub sections: Vec<PromptSection>,
+    pub author_note: Option<String>,
+    /// How many messages from the end of the history the author's note is inserted at.
+    pub author_note_depth: usize,
+    /// Older messages are left out of the prompt once this budget is exceeded.
+    pub max_context_tokens: Option<usize>,
+}
+
+impl Default for PromptSettings {
+    fn default() -> Self {
+        PromptSettings {
+            sections: PromptSection::DEFAULT_ORDER.to_vec(),
+            author_note: None,
+            author_note_depth: 4,
+            max_context_tokens: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AvatarSettings {
+    /// The longest side of each generated thumbnail, in pixels.
+    pub thumbnail_sizes: Vec<u32>,
+    /// Width divided by height of the extra thumbnails cropped around the focal point.
+    /// No cropped thumbnails are made without it.
+    pub crop_aspect_ratio: Option<f32>,
+}
+
+impl Default for AvatarSettings {
+    fn default() -> Self {
+        AvatarSettings {
+            thumbnail_sizes: vec![128, 256, 512],
+            crop_aspect_ratio: Some(1.0),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MemorySettings {
+    /// Whether messages that no longer fit into the prompt are summarized.
+    pub enabled: bool,
+    /// How many messages are summarized together.
+    pub chunk_size: usize,
+    /// How many consecutive summaries of one level are merged into one of the next level.
+    pub merge_count: usize,
+    /// The most tokens a single summary may take.
+    pub max_tokens: usize,
+}
+
+impl Default for MemorySettings {
+    fn default() -> Self {
+        MemorySettings {
+            enabled: true,
+            chunk_size: 8,
+            merge_count: 4,
+            max_tokens: 200,
+        }
+    }
+}
+
+/// Where message embeddings come from. There's no local mistral.rs model yet, since the
+/// mistral.rs revision the app builds with can't compute embeddings.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum EmbeddingBackend {
+    OpenAi,
+    Ollama,
+    // TODO add a local mistral.rs backend once mistral.rs is updated to a version with
+    // embedding models
+    /// Hashed words, which need no model.
+    #[default]
+    Hashing,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct EmbeddingSettings {
+    /// Whether older messages relevant to the latest ones are recalled into the prompt.
+    pub enabled: bool,
+    pub backend: EmbeddingBackend,
+    pub api_url: Option<String>,
+    pub api_key: Option<String>,
+    pub model: Option<String>,
+    /// The most messages that are recalled.
+    pub top_k: usize,
+    /// The most tokens the recalled messages may take.
+    pub token_budget: usize,
+    /// Messages this close to the end of the chat aren't recalled, since they are in the
+    /// prompt anyway.
+    pub min_distance: usize,
+    /// The text the recalled messages are inserted with, at `{{memories}}`.
+    pub template: String,
+}
+
+impl Default for EmbeddingSettings {
+    fn default() -> Self {
+        EmbeddingSettings {
+            enabled: false,
+            backend: EmbeddingBackend::default(),
+            api_url: None,
+            api_key: None,
+            model: None,
+            top_k: 4,
+            token_budget: 400,
+            min_distance: 10,
+            template: "Earlier in the story:\n{{memories}}".into(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
@@ -42,4 +162,12 @@ pub struct Config {
     pub sync: SyncSettings,
     pub llm: LlmSettings,
     pub experimental: ExperimentalSettings,
+    #[serde(default)]
+    pub prompt: PromptSettings,
+    #[serde(default)]
+    pub avatars: AvatarSettings,
+    #[serde(default)]
+    pub memory: MemorySettings,
+    #[serde(default)]
+    pub embeddings: EmbeddingSettings,
 }
@@ -0,0 +1,511 @@
+use std::{collections::HashMap, fmt::Write};
+
+use anyhow::Result;
+use base64::prelude::*;
+use chrono::{DateTime, Utc};
+use erpy_types::{CharacterInformation, Chat, MessageRole};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assets::{content_type, AssetStore},
+    text::strip_reasoning,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExportFormat {
+    #[default]
+    Markdown,
+    /// A single HTML file with the avatars and styles inlined.
+    Html,
+    Text,
+    /// The `.jsonl` chat files of SillyTavern.
+    SillyTavern,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Html => "html",
+            ExportFormat::Text => "txt",
+            ExportFormat::SillyTavern => "jsonl",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    /// Adds the replies that weren't chosen, after the chosen one.
+    pub include_swipes: bool,
+    /// Leaves out the `<think>` blocks of reasoning models.
+    pub strip_reasoning: bool,
+}
+
+/// Someone who wrote messages in the chat, with their avatar as a data URL.
+#[derive(Debug, Clone, Default)]
+pub struct Speaker {
+    pub name: String,
+    pub avatar: Option<String>,
+}
+
+/// The user and the characters of a chat, by id.
+#[derive(Debug, Default)]
+pub struct Speakers {
+    pub user: Speaker,
+    pub characters: HashMap<String, Speaker>,
+}
+
+/// A message of the checked out branch, with its alternatives if they are exported.
+struct Entry<'a> {
+    name: &'a str,
+    avatar: Option<&'a str>,
+    role: MessageRole,
+    timestamp: DateTime<Utc>,
+    swipes: Vec<String>,
+    chosen: usize,
+}
+
+impl Entry<'_> {
+    fn text(&self) -> &str {
+        &self.swipes[self.chosen]
+    }
+
+    /// The replies that weren't chosen.
+    fn others(&self) -> impl Iterator<Item = &str> {
+        self.swipes
+            .iter()
+            .enumerate()
+            .filter(move |(index, _)| *index != self.chosen)
+            .map(|(_, swipe)| swipe.as_str())
+    }
+}
+
+fn entries<'a>(chat: &Chat, speakers: &'a Speakers, options: &ExportOptions) -> Vec<Entry<'a>> {
+    let main_character = speakers.characters.get(&chat.character_id);
+    chat.history()
+        .into_iter()
+        .filter(|message| message.role != MessageRole::System)
+        .map(|message| {
+            let swipes = if options.include_swipes {
+                chat.alternatives(&message.id)
+            } else {
+                vec![message]
+            };
+            let speaker = match message.role {
+                MessageRole::User => Some(&speakers.user),
+                _ => message
+                    .character_id
+                    .as_ref()
+                    .and_then(|id| speakers.characters.get(id))
+                    .or(main_character),
+            };
+            Entry {
+                name: speaker.map_or("Unknown", |speaker| &speaker.name),
+                avatar: speaker.and_then(|speaker| speaker.avatar.as_deref()),
+                role: message.role,
+                timestamp: message.content.timestamp,
+                chosen: swipes
+                    .iter()
+                    .position(|swipe| swipe.id == message.id)
+                    .unwrap_or_default(),
+                swipes: swipes
+                    .iter()
+                    .map(|swipe| match options.strip_reasoning {
+                        true => strip_reasoning(swipe.text()),
+                        false => swipe.text().trim().to_string(),
+                    })
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+fn title(chat: &Chat, speakers: &Speakers) -> String {
+    if !chat.title.trim().is_empty() {
+        return chat.title.trim().to_string();
+    }
+    match speakers.characters.get(&chat.character_id) {
+        Some(character) => format!("Chat with {}", character.name),
+        None => "Chat".to_string(),
+    }
+}
+
+fn format_time(timestamp: DateTime<Utc>) -> String {
+    timestamp.format("%Y-%m-%d %H:%M UTC").to_string()
+}
+
+/// Renders the checked out branch of the chat in the format of the options.
+pub fn export_chat(chat: &Chat, speakers: &Speakers, options: &ExportOptions) -> Result<String> {
+    let entries = entries(chat, speakers, options);
+    let title = title(chat, speakers);
+    Ok(match options.format {
+        ExportFormat::Markdown => markdown(&title, &entries),
+        ExportFormat::Html => html(&title, &entries),
+        ExportFormat::Text => text(&title, &entries),
+        ExportFormat::SillyTavern => silly_tavern(chat, speakers, &entries)?,
+    })
+}
+
+/// Escapes what Markdown renderers would take for HTML, and keeps the rest of the
+/// formatting of the messages.
+fn escape_markdown(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;")
+}
+
+fn markdown(title: &str, entries: &[Entry]) -> String {
+    let mut out = format!("# {title}\n");
+    for entry in entries {
+        let _ = write!(
+            out,
+            "\n### {}\n\n*{}*\n\n{}\n",
+            entry.name,
+            format_time(entry.timestamp),
+            escape_markdown(entry.text())
+        );
+        for (index, other) in entry.others().enumerate() {
+            let _ = write!(
+                out,
+                "\n#### Other reply {}\n\n{}\n",
+                index + 1,
+                escape_markdown(other)
+            );
+        }
+    }
+    out
+}
+
+fn text(title: &str, entries: &[Entry]) -> String {
+    let mut out = format!("{title}\n{}\n", "=".repeat(title.chars().count()));
+    for entry in entries {
+        let _ = write!(
+            out,
+            "\n{} ({}):\n{}\n",
+            entry.name,
+            format_time(entry.timestamp),
+            entry.text()
+        );
+        for (index, other) in entry.others().enumerate() {
+            let _ = write!(
+                out,
+                "\n{}, other reply {}:\n{other}\n",
+                entry.name,
+                index + 1
+            );
+        }
+    }
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Paragraphs for blank lines and line breaks for single newlines.
+fn html_paragraphs(text: &str) -> String {
+    text.split("\n\n")
+        .map(str::trim)
+        .filter(|paragraph| !paragraph.is_empty())
+        .map(|paragraph| format!("<p>{}</p>", escape_html(paragraph).replace('\n', "<br>")))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+const HTML_STYLE: &str = "body { font-family: system-ui, sans-serif; line-height: 1.5; max-width: 48rem; margin: 2rem auto; padding: 0 1rem; background: #1d232a; color: #d5dae3; }
+article { display: flex; gap: 1rem; margin: 1.5rem 0; }
+.avatar { flex: none; width: 3rem; height: 3rem; border-radius: 50%; object-fit: cover; background: #6a5bd2; color: white; display: flex; align-items: center; justify-content: center; font-weight: bold; }
+.user .avatar { background: #3a7ca5; }
+header { margin-bottom: 0.25rem; }
+time { opacity: 0.6; font-size: 0.85rem; margin-left: 0.5rem; }
+p { margin: 0 0 0.75rem; }
+details { opacity: 0.8; }
+summary { cursor: pointer; }
+.swipe { border-left: 2px solid #444c56; padding-left: 0.75rem; margin-top: 0.75rem; }";
+
+fn html(title: &str, entries: &[Entry]) -> String {
+    let title = escape_html(title);
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>\n{HTML_STYLE}\n</style>\n</head>\n<body>\n<h1>{title}</h1>\n"
+    );
+    for entry in entries {
+        let name = escape_html(entry.name);
+        let class = match entry.role {
+            MessageRole::User => "user",
+            _ => "character",
+        };
+        let avatar = match entry.avatar {
+            Some(avatar) => format!(
+                "<img class=\"avatar\" src=\"{}\" alt=\"\">",
+                escape_html(avatar)
+            ),
+            None => format!(
+                "<div class=\"avatar\">{}</div>",
+                name.chars().next().unwrap_or('?')
+            ),
+        };
+        let _ = write!(
+            out,
+            "<article class=\"{class}\">\n{avatar}\n<div>\n<header><strong>{name}</strong><time datetime=\"{}\">{}</time></header>\n{}\n",
+            entry.timestamp.to_rfc3339(),
+            format_time(entry.timestamp),
+            html_paragraphs(entry.text()),
+        );
+        let others: Vec<&str> = entry.others().collect();
+        if !others.is_empty() {
+            let _ = writeln!(
+                out,
+                "<details>\n<summary>{} other {}</summary>",
+                others.len(),
+                if others.len() == 1 {
+                    "reply"
+                } else {
+                    "replies"
+                }
+            );
+            for other in others {
+                let _ = writeln!(
+                    out,
+                    "<section class=\"swipe\">\n{}\n</section>",
+                    html_paragraphs(other)
+                );
+            }
+            out.push_str("</details>\n");
+        }
+        out.push_str("</div>\n</article>\n");
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// The first line of a SillyTavern chat file.
+#[derive(Serialize)]
+struct SillyTavernHeader<'a> {
+    user_name: &'a str,
+    character_name: &'a str,
+    create_date: String,
+    chat_metadata: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct SillyTavernMessage<'a> {
+    name: &'a str,
+    is_user: bool,
+    is_system: bool,
+    send_date: String,
+    mes: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    swipes: Option<&'a [String]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    swipe_id: Option<usize>,
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+fn silly_tavern(chat: &Chat, speakers: &Speakers, entries: &[Entry]) -> Result<String> {
+    let created = entries
+        .first()
+        .map_or(DateTime::UNIX_EPOCH, |entry| entry.timestamp);
+    let header = SillyTavernHeader {
+        user_name: &speakers.user.name,
+        character_name: speakers
+            .characters
+            .get(&chat.character_id)
+            .map_or("Unknown", |character| &character.name),
+        create_date: created.format("%Y-%m-%d@%Hh%Mm%Ss").to_string(),
+        chat_metadata: Default::default(),
+    };
+
+    let mut out = serde_json::to_string(&header)?;
+    out.push('\n');
+    for entry in entries {
+        // SillyTavern only has swipes on the replies of characters
+        let swipes = entry.role == MessageRole::Assistant && entry.swipes.len() > 1;
+        let message = SillyTavernMessage {
+            name: entry.name,
+            is_user: entry.role == MessageRole::User,
+            is_system: false,
+            send_date: entry.timestamp.format("%B %-d, %Y %-I:%M%P").to_string(),
+            mes: entry.text(),
+            swipes: swipes.then_some(entry.swipes.as_slice()),
+            swipe_id: swipes.then_some(entry.chosen),
+            extra: Default::default(),
+        };
+        out.push_str(&serde_json::to_string(&message)?);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// An asset as a data URL, e.g. the avatar of a persona.
+pub fn asset_data_url(assets: &AssetStore, id: &str) -> Option<String> {
+    let bytes = assets
+        .get(id)
+        .inspect_err(|e| warn!("failed to read avatar {id}: {e:?}"))
+        .ok()?;
+    Some(format!(
+        "data:{};base64,{}",
+        content_type(id),
+        BASE64_STANDARD.encode(bytes)
+    ))
+}
+
+/// The avatar of a character as a data URL: its smallest thumbnail, or the inline image of
+/// characters that were imported before images were kept in the asset store.
+pub fn character_avatar(character: &CharacterInformation, assets: &AssetStore) -> Option<String> {
+    if let Some(image) = &character.image {
+        let id = image
+            .thumbnails
+            .iter()
+            .min_by_key(|thumbnail| thumbnail.size)
+            .map_or(&image.original, |thumbnail| &thumbnail.id);
+        return asset_data_url(assets, id);
+    }
+    let image = character
+        .image_base64
+        .as_deref()
+        .filter(|image| !image.is_empty())?;
+    if image.starts_with("data:") {
+        Some(image.to_string())
+    } else {
+        Some(format!("data:image/png;base64,{image}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use erpy_types::{
+        test_support::{self, content_at},
+        Chat, ChatContent, MessageRole,
+    };
+
+    use super::{export_chat, ExportFormat, ExportOptions, Speaker, Speakers};
+
+    fn content(text: &str, minute: u32) -> ChatContent {
+        content_at(text, &format!("2024-01-01T20:{minute:02}:00Z"))
+    }
+
+    fn chat() -> Chat {
+        let mut chat = test_support::chat(&[]);
+        chat.title = String::new();
+        chat.append(
+            MessageRole::Assistant,
+            content(
+                "*Alice looks up from her book.* Oh, hello!\nCan I help you?",
+                0,
+            ),
+        );
+        chat.append(
+            MessageRole::User,
+            content("I'm looking for the <rare> books & maps.", 1),
+        );
+        let reply = chat.append(
+            MessageRole::Assistant,
+            content(
+                "<think>They want maps.</think>\n\n\"Follow me,\" she says.",
+                2,
+            ),
+        );
+        let other = chat
+            .add_alternative(&reply, content("She points at the stairs.", 3))
+            .unwrap();
+        chat.checkout(&reply).unwrap();
+        assert_ne!(reply, other);
+        chat
+    }
+
+    fn speakers() -> Speakers {
+        Speakers {
+            user: Speaker {
+                name: "Bob".into(),
+                avatar: None,
+            },
+            characters: HashMap::from([(
+                "alice".to_string(),
+                Speaker {
+                    name: "Alice".into(),
+                    avatar: Some("data:image/png;base64,iVBORw0KGgo=".into()),
+                },
+            )]),
+        }
+    }
+
+    /// Compares the export with the file in `fixtures/exports`. Set `ERPY_UPDATE_GOLDEN` to
+    /// write the export to the file instead.
+    fn assert_golden(format: ExportFormat, expected: &str) {
+        let options = ExportOptions {
+            format,
+            include_swipes: true,
+            strip_reasoning: true,
+        };
+        let exported = export_chat(&chat(), &speakers(), &options).unwrap();
+        if std::env::var_os("ERPY_UPDATE_GOLDEN").is_some() {
+            let path = format!(
+                "{}/fixtures/exports/chat.{}",
+                env!("CARGO_MANIFEST_DIR"),
+                format.extension()
+            );
+            std::fs::write(path, &exported).unwrap();
+        } else {
+            assert_eq!(exported, expected);
+        }
+    }
+
+    #[test]
+    fn test_golden_markdown() {
+        assert_golden(
+            ExportFormat::Markdown,
+            include_str!("../fixtures/exports/chat.md"),
+        );
+    }
+
+    #[test]
+    fn test_golden_html() {
+        assert_golden(
+            ExportFormat::Html,
+            include_str!("../fixtures/exports/chat.html"),
+        );
+    }
+
+    #[test]
+    fn test_golden_text() {
+        assert_golden(
+            ExportFormat::Text,
+            include_str!("../fixtures/exports/chat.txt"),
+        );
+    }
+
+    #[test]
+    fn test_golden_silly_tavern() {
+        assert_golden(
+            ExportFormat::SillyTavern,
+            include_str!("../fixtures/exports/chat.jsonl"),
+        );
+    }
+
+    #[test]
+    fn test_chosen_answers_only() {
+        let options = ExportOptions {
+            format: ExportFormat::Text,
+            ..Default::default()
+        };
+        let exported = export_chat(&chat(), &speakers(), &options).unwrap();
+        assert!(exported.starts_with("Chat with Alice\n"));
+        assert!(exported.contains("<think>They want maps.</think>"));
+        assert!(!exported.contains("stairs"));
+    }
+}
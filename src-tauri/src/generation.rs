@@ -0,0 +1,196 @@
+use std::time::{Duration, Instant};
+
+use erpy_ai::{
+    estimate_tokens, CompletionRequest, MessageHistoryItem, StreamingCompletionChoice, Usage,
+};
+use erpy_types::{GenerationMetadata, InjectedEntry, MessageRole};
+
+#[derive(Default)]
+struct ChoiceStats {
+    content: String,
+    finish_reason: Option<String>,
+    finished_after: Option<Duration>,
+}
+
+/// Collects the metadata of every choice of a streamed completion.
+pub struct GenerationTracker {
+    started: Instant,
+    metadata: GenerationMetadata,
+    choices: Vec<ChoiceStats>,
+    usage: Option<Usage>,
+}
+
+impl GenerationTracker {
+    /// Starts timing a request that is about to be sent to the backend.
+    pub fn new(backend: &str, request: &CompletionRequest) -> Self {
+        GenerationTracker {
+            started: Instant::now(),
+            metadata: GenerationMetadata {
+                backend: backend.to_string(),
+                sampler: request.sampler(),
+                seed: request.seed,
+                prompt_tokens: request.estimated_tokens(),
+                ..Default::default()
+            },
+            choices: (0..request.choice_count())
+                .map(|_| ChoiceStats::default())
+                .collect(),
+            usage: None,
+        }
+    }
+
+    pub fn with_lorebook_entries(mut self, entries: Vec<InjectedEntry>) -> Self {
+        self.metadata.lorebook_entries = entries;
+        self
+    }
+
+    /// The ids of the chat summaries that were in the prompt.
+    pub fn with_summaries(mut self, summaries: Vec<String>) -> Self {
+        self.metadata.summaries = summaries;
+        self
+    }
+
+    pub fn record(&mut self, choice: &StreamingCompletionChoice) {
+        if self.choices.len() <= choice.index {
+            self.choices
+                .resize_with(choice.index + 1, ChoiceStats::default);
+        }
+
+        let stats = &mut self.choices[choice.index];
+        stats.content.push_str(&choice.delta.content);
+        if let Some(reason) = &choice.finish_reason {
+            stats.finish_reason = Some(reason.clone());
+            stats.finished_after.get_or_insert(self.started.elapsed());
+        }
+    }
+
+    /// The token counts the backend reported for the whole request.
+    pub fn record_usage(&mut self, usage: &Usage) {
+        self.usage = Some(usage.clone());
+    }
+
+    /// The metadata of every choice, by index. Choices that didn't finish, e.g. because
+    /// the stream was cancelled, end now.
+    pub fn finish(mut self) -> Vec<GenerationMetadata> {
+        let elapsed = self.started.elapsed();
+        // the usage is summed over all choices, so it can only be used for a single one
+        let reported_completion_tokens = match &self.usage {
+            Some(usage) if self.choices.len() == 1 => Some(usage.completion_tokens),
+            _ => None,
+        };
+        if let Some(usage) = &self.usage {
+            self.metadata.prompt_tokens = usage.prompt_tokens;
+        }
+
+        self.choices
+            .into_iter()
+            .map(|stats| GenerationMetadata {
+                completion_tokens: reported_completion_tokens.unwrap_or_else(|| {
+                    estimate_tokens(&[MessageHistoryItem {
+                        role: MessageRole::Assistant,
+                        content: stats.content,
+                    }])
+                }),
+                finish_reason: stats.finish_reason,
+                latency_ms: stats.finished_after.unwrap_or(elapsed).as_millis() as u64,
+                ..self.metadata.clone()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use erpy_ai::{
+        CompletionRequest, DeltaContent, MessageHistoryItem, StreamingCompletionChoice,
+        StreamingCompletionResponse,
+    };
+    use erpy_types::{InjectedEntry, MessageRole};
+
+    use super::GenerationTracker;
+
+    fn choice(
+        index: usize,
+        content: &str,
+        finish_reason: Option<&str>,
+    ) -> StreamingCompletionChoice {
+        StreamingCompletionChoice {
+            index,
+            delta: DeltaContent {
+                content: content.into(),
+            },
+            finish_reason: finish_reason.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_tracks_every_choice() {
+        let request = CompletionRequest {
+            messages: vec![MessageHistoryItem {
+                role: MessageRole::User,
+                content: "Tell me a story, please.".into(),
+            }],
+            temperature: Some(0.7),
+            seed: Some(42),
+            n: Some(2),
+            ..Default::default()
+        };
+        let entry = InjectedEntry {
+            lorebook: "Eldoria".into(),
+            id: Some(3),
+            name: "King".into(),
+        };
+        let mut tracker =
+            GenerationTracker::new("open-ai", &request).with_lorebook_entries(vec![entry.clone()]);
+
+        tracker.record(&choice(0, "Once", None));
+        tracker.record(&choice(1, "There", None));
+        tracker.record(&choice(0, " upon", None));
+        tracker.record(&choice(0, "", Some("stop")));
+
+        let metadata = tracker.finish();
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata[0].backend, "open-ai");
+        assert_eq!(metadata[0].sampler.temperature, Some(0.7));
+        assert_eq!(metadata[0].seed, Some(42));
+        assert_eq!(metadata[0].prompt_tokens, 6);
+        assert_eq!(metadata[0].completion_tokens, 2);
+        assert_eq!(metadata[0].finish_reason.as_deref(), Some("stop"));
+        assert_eq!(metadata[0].lorebook_entries, vec![entry]);
+        assert_eq!(metadata[1].completion_tokens, 1);
+        assert_eq!(metadata[1].finish_reason, None);
+    }
+
+    #[test]
+    fn test_tracks_open_ai_stream() {
+        let chunks = [
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1694268190,"model":"gpt-4o-mini","choices":[{"index":0,"delta":{"role":"assistant","content":"Hello there, traveller"},"logprobs":null,"finish_reason":null}],"usage":null}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1694268190,"model":"gpt-4o-mini","choices":[{"index":0,"delta":{},"logprobs":null,"finish_reason":"stop"}],"usage":null}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1694268190,"model":"gpt-4o-mini","choices":[],"usage":{"prompt_tokens":9,"completion_tokens":4,"total_tokens":13}}"#,
+        ];
+        let request = CompletionRequest {
+            messages: vec![MessageHistoryItem {
+                role: MessageRole::User,
+                content: "Greet me.".into(),
+            }],
+            ..Default::default()
+        };
+        let mut tracker = GenerationTracker::new("open-ai", &request);
+
+        for chunk in chunks {
+            let response: StreamingCompletionResponse = serde_json::from_str(chunk).unwrap();
+            if let Some(usage) = &response.usage {
+                tracker.record_usage(usage);
+            }
+            for choice in response.split_choices() {
+                tracker.record(&choice.choices[0]);
+            }
+        }
+
+        let metadata = tracker.finish();
+        assert_eq!(metadata.len(), 1);
+        assert_eq!(metadata[0].finish_reason.as_deref(), Some("stop"));
+        assert_eq!(metadata[0].prompt_tokens, 9);
+        assert_eq!(metadata[0].completion_tokens, 4);
+    }
+}
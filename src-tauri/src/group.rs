@@ -0,0 +1,324 @@
+use erpy_types::{
+    hash::stable_hash, Character, CharacterInformation, Chat, GroupPromptMode, SpeakerStrategy,
+};
+use rand::{rngs::StdRng, seq::IndexedRandom, SeedableRng};
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+
+/// The characters of a group chat, and the one whose reply the prompt is built for.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupPrompt {
+    pub speaker: String,
+    pub characters: Vec<Character>,
+}
+
+fn character<'a>(characters: &'a [Character], id: &str) -> Option<&'a CharacterInformation> {
+    characters
+        .iter()
+        .find(|character| character.id == id)
+        .map(|character| &character.payload)
+}
+
+/// Picks the character that replies next in a group chat. Returns `None` for the manual
+/// strategy, or when every character is muted.
+pub fn next_speaker(chat: &Chat, characters: &[Character], seed: Option<u64>) -> Option<String> {
+    let candidates: Vec<&str> = chat
+        .participants
+        .iter()
+        .filter(|participant| !participant.muted)
+        .map(|participant| participant.character_id.as_str())
+        .collect();
+    if candidates.is_empty() {
+        return None;
+    }
+
+    let last_speaker = chat.last_speaker();
+    let speaker = match chat.speaker_strategy {
+        SpeakerStrategy::Manual => return None,
+        SpeakerStrategy::RoundRobin => round_robin(chat, last_speaker),
+        SpeakerStrategy::Natural => first_mentioned(chat, characters, &candidates)
+            .or_else(|| round_robin(chat, last_speaker)),
+        SpeakerStrategy::Random => {
+            let others: Vec<&str> = candidates
+                .iter()
+                .copied()
+                .filter(|id| Some(*id) != last_speaker)
+                .collect();
+            let pool = if others.is_empty() {
+                &candidates
+            } else {
+                &others
+            };
+            let turn = chat.history().len();
+            let mut rng = StdRng::seed_from_u64(stable_hash(&(&chat.id, seed, turn)));
+            pool.choose(&mut rng).copied()
+        }
+    };
+
+    speaker.map(String::from)
+}
+
+/// The next character that isn't muted, in the order of the participants.
+fn round_robin<'a>(chat: &'a Chat, last_speaker: Option<&str>) -> Option<&'a str> {
+    let participants = &chat.participants;
+    let start = last_speaker
+        .and_then(|id| {
+            participants
+                .iter()
+                .position(|participant| participant.character_id == id)
+        })
+        .map_or(0, |index| index + 1);
+
+    (0..participants.len())
+        .map(|offset| &participants[(start + offset) % participants.len()])
+        .find(|participant| !participant.muted)
+        .map(|participant| participant.character_id.as_str())
+}
+
+/// The character mentioned first in the last message, other than the one who wrote it.
+fn first_mentioned<'a>(
+    chat: &Chat,
+    characters: &[Character],
+    candidates: &[&'a str],
+) -> Option<&'a str> {
+    let history = chat.history();
+    let last = history.last()?;
+    candidates
+        .iter()
+        .filter(|id| last.character_id.as_deref() != Some(**id))
+        .filter_map(|id| {
+            let character = character(characters, id)?;
+            let position = std::iter::once(&character.name)
+                .chain(character.nickname.as_ref())
+                .filter(|name| !name.trim().is_empty())
+                .filter_map(|name| name_pattern(name).find(last.text()))
+                .map(|found| found.start())
+                .min()?;
+            Some((position, *id))
+        })
+        .min_by_key(|(position, _)| *position)
+        .map(|(_, id)| id)
+}
+
+fn name_pattern(name: &str) -> Regex {
+    RegexBuilder::new(&format!(r"\b{}\b", regex::escape(name.trim())))
+        .case_insensitive(true)
+        .build()
+        .expect("escaped name is a valid pattern")
+}
+
+/// The card the prompt for `speaker`'s reply is built from: the speaker's own card, or
+/// the definitions of every character that isn't muted merged into it. Either way the
+/// system prompt tells the model who else is in the chat.
+pub fn group_card(
+    chat: &Chat,
+    characters: &[Character],
+    speaker: &str,
+) -> Option<CharacterInformation> {
+    let mut card = character(characters, speaker)?.clone();
+    let members: Vec<&CharacterInformation> = chat
+        .participants
+        .iter()
+        .filter(|participant| !participant.muted || participant.character_id == speaker)
+        .filter_map(|participant| character(characters, &participant.character_id))
+        .collect();
+
+    if chat.group_prompt == GroupPromptMode::Merge {
+        let merged = |text: fn(&CharacterInformation) -> String| {
+            members
+                .iter()
+                .map(|member| (member, text(member)))
+                .filter(|(_, text)| !text.trim().is_empty())
+                .map(|(member, text)| replace_char(text.trim(), &member.name))
+                .collect::<Vec<_>>()
+                .join("\n\n")
+        };
+        card.description = merged(|member| {
+            if member.personality.trim().is_empty() {
+                member.description.clone()
+            } else {
+                format!(
+                    "{}\n{{{{char}}}}'s personality: {}",
+                    member.description.trim(),
+                    member.personality.trim()
+                )
+            }
+        });
+        card.personality = String::new();
+        card.example_dialogues = merged(|member| member.example_dialogues.clone());
+        let mut scenarios: Vec<&str> = Vec::new();
+        for scenario in members.iter().map(|member| member.scenario.trim()) {
+            if !scenario.is_empty() && !scenarios.contains(&scenario) {
+                scenarios.push(scenario);
+            }
+        }
+        card.scenario = scenarios.join("\n\n");
+    }
+
+    let names: Vec<&str> = members.iter().map(|member| member.name.as_str()).collect();
+    let group = format!(
+        "This is a group chat between {{{{user}}}} and {}. Write only {{{{char}}}}'s next reply.",
+        names.join(", ")
+    );
+    card.system_prompt = if card.system_prompt.trim().is_empty() {
+        group
+    } else {
+        format!("{}\n\n{group}", card.system_prompt.trim())
+    };
+
+    Some(card)
+}
+
+/// Resolves `{{char}}` to a character other than the speaker, for merged definitions.
+fn replace_char(text: &str, name: &str) -> String {
+    let pattern = Regex::new(r"(?i)\{\{char\}\}|<BOT>").expect("valid pattern");
+    pattern
+        .replace_all(text, regex::NoExpand(name))
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use erpy_types::{
+        test_support::{self, content},
+        Character, CharacterInformation, Chat, GroupPromptMode, MessageRole, SpeakerStrategy,
+    };
+
+    use super::{group_card, next_speaker, GroupPrompt};
+    use crate::{config::PromptSettings, prompt::PromptBuilder};
+
+    fn character(id: &str, name: &str, description: &str) -> Character {
+        Character {
+            id: id.into(),
+            url: None,
+            payload: CharacterInformation {
+                name: name.into(),
+                description: description.into(),
+                system_prompt: "Write {{char}}'s reply.".into(),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn characters() -> Vec<Character> {
+        vec![
+            character("alice", "Alice", "{{char}} is a librarian."),
+            character("bob", "Bob", "{{char}} is a knight."),
+            character("carol", "Carol", "<BOT> is a bard."),
+        ]
+    }
+
+    fn group(strategy: SpeakerStrategy) -> Chat {
+        let mut chat = test_support::chat(&[]);
+        chat.add_participant("bob").unwrap();
+        chat.add_participant("carol").unwrap();
+        chat.speaker_strategy = strategy;
+        chat.append(MessageRole::Assistant, content("Welcome."));
+        chat
+    }
+
+    #[test]
+    fn test_round_robin_skips_muted_characters() {
+        let mut chat = group(SpeakerStrategy::RoundRobin);
+        let characters = characters();
+        assert_eq!(
+            next_speaker(&chat, &characters, None).as_deref(),
+            Some("bob")
+        );
+
+        chat.set_muted("bob", true).unwrap();
+        assert_eq!(
+            next_speaker(&chat, &characters, None).as_deref(),
+            Some("carol")
+        );
+        chat.append_from("carol", content("Hello."));
+        assert_eq!(
+            next_speaker(&chat, &characters, None).as_deref(),
+            Some("alice")
+        );
+
+        for id in ["alice", "carol"] {
+            chat.set_muted(id, true).unwrap();
+        }
+        assert_eq!(next_speaker(&chat, &characters, None), None);
+    }
+
+    #[test]
+    fn test_natural_order_follows_mentions() {
+        let mut chat = group(SpeakerStrategy::Natural);
+        let characters = characters();
+        chat.append(
+            MessageRole::User,
+            content("What do you think, carol? And Bob?"),
+        );
+        assert_eq!(
+            next_speaker(&chat, &characters, None).as_deref(),
+            Some("carol")
+        );
+
+        // the writer of the last message isn't picked, nor are partial names
+        chat.append_from("carol", content("Carol thinks Bobby is right."));
+        assert_eq!(
+            next_speaker(&chat, &characters, None).as_deref(),
+            Some("alice")
+        );
+    }
+
+    #[test]
+    fn test_random_and_manual() {
+        let chat = group(SpeakerStrategy::Random);
+        let characters = characters();
+        let speaker = next_speaker(&chat, &characters, Some(7));
+        assert!(matches!(speaker.as_deref(), Some("bob" | "carol")));
+        assert_eq!(next_speaker(&chat, &characters, Some(7)), speaker);
+
+        let manual = group(SpeakerStrategy::Manual);
+        assert_eq!(next_speaker(&manual, &characters, None), None);
+    }
+
+    #[test]
+    fn test_group_cards() {
+        let mut chat = group(SpeakerStrategy::RoundRobin);
+        chat.set_muted("carol", true).unwrap();
+        let characters = characters();
+
+        let swapped = group_card(&chat, &characters, "bob").unwrap();
+        assert_eq!(swapped.name, "Bob");
+        assert_eq!(swapped.description, "{{char}} is a knight.");
+        assert_eq!(
+            swapped.system_prompt,
+            "Write {{char}}'s reply.\n\nThis is a group chat between {{user}} and Alice, Bob. Write only {{char}}'s next reply."
+        );
+
+        chat.group_prompt = GroupPromptMode::Merge;
+        let merged = group_card(&chat, &characters, "carol").unwrap();
+        assert_eq!(
+            merged.description,
+            "Alice is a librarian.\n\nBob is a knight.\n\nCarol is a bard."
+        );
+        assert!(group_card(&chat, &characters, "dave").is_none());
+    }
+
+    #[test]
+    fn test_group_prompt_names_other_speakers() {
+        let mut chat = group(SpeakerStrategy::RoundRobin);
+        chat.append(MessageRole::User, content("Hi."));
+        chat.append_from("bob", content("Greetings."));
+        let group = GroupPrompt {
+            speaker: "bob".into(),
+            characters: characters(),
+        };
+        let card = group_card(&chat, &group.characters, &group.speaker).unwrap();
+        let settings = PromptSettings::default();
+
+        let messages = PromptBuilder::new(&chat, &card, "Dave", &settings)
+            .with_group(&group)
+            .build();
+        let history: Vec<_> = messages[1..]
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect();
+        assert_eq!(history, vec!["Alice: Welcome.", "Hi.", "Greetings."]);
+    }
+}
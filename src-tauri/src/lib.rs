@@ -1,27 +1,166 @@
+use std::collections::HashMap;
+
+use activation::ActivationReport;
 use anyhow::anyhow;
 use anyhow_tauri::{bail, IntoTAResult, TAResult};
+use assets::AssetStore;
+use avatar::AvatarPipeline;
+use camino::Utf8PathBuf;
 use character::character_from_png_bytes;
-use character::character_from_string;
+use character::character_to_png_bytes;
+use character::{character_from_charx_bytes, character_from_json, character_to_charx_bytes};
 use config::Config;
 use erpy_ai::estimate_tokens;
 use erpy_ai::{open_ai::OpenAiCompletions, CompletionApis, CompletionRequest, MessageHistoryItem};
-use erpy_ai::{CompletionApi, ModelInfo};
-use erpy_types::CharacterInformation;
-use erpy_types::Chat;
+use erpy_ai::{CompletionApi, ModelInfo, StreamingCompletionResponse};
+use erpy_types::Lorebook;
+use erpy_types::{Character, Chat, ChatSummary, GenerationMetadata, InjectedEntry};
+use erpy_types::{CharacterImage, CharacterInformation, FocalPoint};
+use erpy_types::{Persona, PersonaLock};
+use export::{ExportFormat, ExportOptions};
+use generation::GenerationTracker;
+use group::GroupPrompt;
+use library::{import_directory, DirectoryImport};
 use log::debug;
 use log::error;
 use log::{info, LevelFilter};
+use lorebook::lorebook_from_json;
+use memory::Speakers;
+use persona::PersonaStore;
+use prompt::{Prompt, PromptBuilder};
+use recall::VectorIndex;
+use search::{SearchHit, SearchIndex, SearchQuery};
 use serde::{Deserialize, Serialize};
+use source::SourceRegistry;
+use tauri::http::{header, Request, Response, StatusCode};
 use tauri::{AppHandle, Emitter, Listener, Manager};
 use tokio::sync::{oneshot, Mutex};
-use tokio_stream::StreamExt;
+use tokio_stream::{Stream, StreamExt};
 
+pub mod activation;
+pub mod assets;
+pub mod avatar;
 pub mod character;
 pub mod chat;
 pub mod config;
+pub mod export;
+pub mod generation;
+pub mod group;
+pub mod library;
+pub mod lorebook;
+pub mod macros;
+pub mod memory;
+pub mod persona;
+pub mod prompt;
+pub mod recall;
+pub mod search;
+pub mod source;
+#[cfg(test)]
+mod test_util;
+pub mod text;
 
 struct State {
     completions: Mutex<Option<CompletionApis>>,
+    search: Mutex<Option<SearchIndex>>,
+}
+
+fn asset_store(app: &AppHandle) -> anyhow::Result<AssetStore> {
+    let dir = app.path().app_data_dir()?.join("assets");
+    let dir = Utf8PathBuf::from_path_buf(dir)
+        .map_err(|dir| anyhow!("app data directory is not UTF-8: {}", dir.display()))?;
+    Ok(AssetStore::new(dir))
+}
+
+/// The search index, opened on first use.
+async fn search_index(app: &AppHandle) -> anyhow::Result<SearchIndex> {
+    let state = app.state::<State>();
+    let mut search = state.search.lock().await;
+    if let Some(index) = search.as_ref() {
+        return Ok(index.clone());
+    }
+
+    let dir = app.path().app_data_dir()?.join("search");
+    let dir = Utf8PathBuf::from_path_buf(dir)
+        .map_err(|dir| anyhow!("app data directory is not UTF-8: {}", dir.display()))?;
+    let index = SearchIndex::open(&dir)?;
+    *search = Some(index.clone());
+    Ok(index)
+}
+
+fn avatar_pipeline(app: &AppHandle, config: Config) -> anyhow::Result<AvatarPipeline> {
+    Ok(AvatarPipeline::new(asset_store(app)?, config.avatars))
+}
+
+fn persona_store(app: &AppHandle) -> anyhow::Result<PersonaStore> {
+    let path = app.path().app_data_dir()?.join("personas.json");
+    let path = Utf8PathBuf::from_path_buf(path)
+        .map_err(|path| anyhow!("app data directory is not UTF-8: {}", path.display()))?;
+    Ok(PersonaStore::new(path))
+}
+
+fn vector_index_path(app: &AppHandle, chat: &Chat) -> anyhow::Result<Utf8PathBuf> {
+    let root = app.path().app_data_dir()?.join("embeddings");
+    let root = Utf8PathBuf::from_path_buf(root)
+        .map_err(|path| anyhow!("app data directory is not UTF-8: {}", path.display()))?;
+    recall::index_path(&root, &chat.id)
+}
+
+async fn update_recollections(
+    app: &AppHandle,
+    config: &Config,
+    chat: &Chat,
+    last_omitted_message: Option<&str>,
+) -> anyhow::Result<Vec<String>> {
+    let settings = &config.embeddings;
+    let path = vector_index_path(app, chat)?;
+    let api = recall::embedding_api(settings)?;
+    let mut index = VectorIndex::load(&path)?;
+    let recalled = recall::recall(&mut index, chat, &api, settings, last_omitted_message).await;
+    index.save(&path)?;
+    recalled
+}
+
+/// The messages the prompt left out that are relevant to the latest ones. When embeddings
+/// fail, the prompt goes without.
+async fn recollections(
+    app: &AppHandle,
+    config: &Config,
+    chat: &Chat,
+    last_omitted_message: Option<&str>,
+) -> Vec<String> {
+    update_recollections(app, config, chat, last_omitted_message)
+        .await
+        .inspect_err(|e| error!("failed to recall messages: {e:?}"))
+        .unwrap_or_default()
+}
+
+/// The persona the user speaks as in the chat, if they have any.
+fn chat_persona(app: &AppHandle, chat: &Chat) -> Option<Persona> {
+    let personas = persona_store(app)
+        .and_then(|store| store.list())
+        .inspect_err(|e| error!("failed to load personas: {e:?}"))
+        .ok()?;
+    erpy_types::resolve_persona(&personas, &chat.id, &chat.character_id).cloned()
+}
+
+/// Serves the asset store at `erpy-asset://localhost/<id>`, so images can be shown without
+/// sending them over IPC.
+fn serve_asset(app: &AppHandle, request: Request<Vec<u8>>) -> Response<Vec<u8>> {
+    let id = request.uri().path().trim_start_matches('/');
+    let bytes = asset_store(app).and_then(|store| store.get(id));
+    match bytes {
+        Ok(bytes) => Response::builder()
+            .header(header::CONTENT_TYPE, assets::content_type(id))
+            .body(bytes)
+            .expect("valid asset response"),
+        Err(e) => {
+            error!("failed to serve asset {id}: {e:#}");
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Vec::new())
+                .expect("valid asset response")
+        }
+    }
 }
 
 #[tauri::command]
@@ -46,25 +185,9 @@ fn list_models_on_disk() -> TAResult<Vec<ModelInfo>> {
     Ok(models)
 }
 
-#[tauri::command]
-async fn chat_completion(
-    app: AppHandle,
-    config: Config,
-    message_history: Vec<MessageHistoryItem>,
-) -> TAResult<()> {
-    info!(
-        "received request to chat with {} tokens",
-        estimate_tokens(&message_history)
-    );
-    debug!("chat history: {message_history:#?}");
-    let state = app.state::<State>();
-    let lock = state.completions.lock().await;
-    let Some(api) = lock.as_ref() else {
-        bail!("no model loaded")
-    };
-
-    let mut request = CompletionRequest {
-        messages: message_history,
+fn completion_request(config: &Config, messages: Vec<MessageHistoryItem>) -> CompletionRequest {
+    let request = CompletionRequest {
+        messages,
         temperature: config.llm.temperature,
         model: Default::default(),
         stream: true,
@@ -74,13 +197,27 @@ async fn chat_completion(
         repeat_penalty: config.llm.repeat_penalty,
         top_p: config.llm.top_p,
         seed: config.llm.seed,
+        ..Default::default()
     };
 
     if config.llm.strip_thinking_tags.unwrap_or(false) {
-        request = request.strip_thinking_tags();
+        request.strip_thinking_tags()
+    } else {
+        request
     }
+}
 
-    let mut stream = api.get_completions_stream(request).await?;
+/// Forwards every choice of a completion stream as `event` until the stream ends or the
+/// frontend sends `cancel`, followed by `{event}_done` with the generation metadata of
+/// every choice.
+async fn emit_completion_stream<S>(
+    app: &AppHandle,
+    mut stream: S,
+    mut tracker: GenerationTracker,
+    event: &str,
+) where
+    S: Stream<Item = StreamingCompletionResponse> + Unpin,
+{
     let (rx, mut tx) = oneshot::channel();
 
     app.once("cancel", move |_| {
@@ -96,36 +233,325 @@ async fn chat_completion(
             break;
         }
 
-        app.emit("completion", response)
-            .expect("failed to emit completion");
+        if let Some(usage) = &response.usage {
+            tracker.record_usage(usage);
+        }
+        for choice in response.split_choices() {
+            tracker.record(&choice.choices[0]);
+            app.emit(event, choice).expect("failed to emit completion");
+        }
     }
 
-    info!("completion stream finished");
-    app.emit("completion_done", ())
+    info!("{event} stream finished");
+    app.emit(&format!("{event}_done"), tracker.finish())
         .expect("failed to emit completion-done");
+}
+
+#[tauri::command]
+async fn chat_completion(
+    app: AppHandle,
+    config: Config,
+    message_history: Vec<MessageHistoryItem>,
+    choices: Option<usize>,
+    lorebook_entries: Option<Vec<InjectedEntry>>,
+    summaries: Option<Vec<String>>,
+    same_settings_as: Option<GenerationMetadata>,
+) -> TAResult<()> {
+    info!(
+        "received request to chat with {} tokens and {} choice(s)",
+        estimate_tokens(&message_history),
+        choices.or(config.llm.choices).unwrap_or(1)
+    );
+    debug!("chat history: {message_history:#?}");
+    let state = app.state::<State>();
+    let lock = state.completions.lock().await;
+    let Some(api) = lock.as_ref() else {
+        bail!("no model loaded")
+    };
+
+    let mut request = CompletionRequest {
+        n: choices.or(config.llm.choices),
+        ..completion_request(&config, message_history)
+    };
+    // regenerating a reply with the settings it was generated with
+    if let Some(generation) = same_settings_as {
+        request = request.with_sampler(&generation.sampler, generation.seed);
+    }
+
+    let tracker = GenerationTracker::new(api.backend_name(), &request)
+        .with_lorebook_entries(lorebook_entries.unwrap_or_default())
+        .with_summaries(summaries.unwrap_or_default());
+    let stream = api.get_completions_stream(request).await?;
+    emit_completion_stream(&app, stream, tracker, "completion").await;
 
     Ok(())
 }
 
 #[tauri::command]
-async fn fetch_character(character_url: String) -> TAResult<CharacterInformation> {
-    info!("creating character from URL {character_url}");
-    let character = character_from_string(&character_url).await?;
-    Ok(character)
+async fn continue_completion(
+    app: AppHandle,
+    config: Config,
+    message_history: Vec<MessageHistoryItem>,
+) -> TAResult<()> {
+    info!(
+        "received request to continue the last message with {} tokens",
+        estimate_tokens(&message_history)
+    );
+    let state = app.state::<State>();
+    let lock = state.completions.lock().await;
+    let Some(api) = lock.as_ref() else {
+        bail!("no model loaded")
+    };
+
+    let request = completion_request(&config, message_history);
+    let tracker = GenerationTracker::new(api.backend_name(), &request);
+    let stream = api.continue_completion(request).await?;
+    emit_completion_stream(&app, stream, tracker, "completion").await;
+
+    Ok(())
+}
+
+/// Builds the prompt for the next reply of the chat. With `continuation`, the prompt is for
+/// continuing its last message instead, see [`PromptBuilder::build_continuation`].
+#[tauri::command]
+async fn build_prompt(
+    app: AppHandle,
+    config: Config,
+    chat: Chat,
+    character: CharacterInformation,
+    lorebooks: Option<Vec<Lorebook>>,
+    group: Option<GroupPrompt>,
+    continuation: Option<bool>,
+) -> Prompt {
+    let build = |builder: &PromptBuilder<'_>| match continuation {
+        Some(true) => builder.build_continuation(),
+        _ => builder.build_prompt(),
+    };
+    let lorebooks = lorebooks.unwrap_or_default();
+    let persona = chat_persona(&app, &chat);
+    let character = group
+        .as_ref()
+        .and_then(|group| group::group_card(&chat, &group.characters, &group.speaker))
+        .unwrap_or(character);
+    let mut builder = PromptBuilder::new(&chat, &character, &config.user_name, &config.prompt)
+        .with_lorebooks(&lorebooks)
+        .with_seed(config.llm.seed.map(|seed| seed as u64))
+        .with_persona(persona.as_ref());
+    if let Some(group) = &group {
+        builder = builder.with_group(group);
+    }
+    let prompt = build(&builder);
+    if !config.embeddings.enabled || prompt.last_omitted_message.is_none() {
+        return prompt;
+    }
+
+    // recalling what fits in the history would only repeat it
+    let recalled =
+        recollections(&app, &config, &chat, prompt.last_omitted_message.as_deref()).await;
+    build(&builder.with_recollections(&recalled, &config.embeddings.template))
+}
+
+/// Picks the character that replies next in a group chat, or none if the user picks.
+#[tauri::command]
+fn next_group_speaker(config: Config, chat: Chat, characters: Vec<Character>) -> Option<String> {
+    group::next_speaker(&chat, &characters, config.llm.seed.map(|seed| seed as u64))
+}
+
+#[tauri::command]
+fn debug_lorebooks(
+    app: AppHandle,
+    config: Config,
+    chat: Chat,
+    character: CharacterInformation,
+    lorebooks: Option<Vec<Lorebook>>,
+) -> ActivationReport {
+    let lorebooks = lorebooks.unwrap_or_default();
+    let persona = chat_persona(&app, &chat);
+    PromptBuilder::new(&chat, &character, &config.user_name, &config.prompt)
+        .with_lorebooks(&lorebooks)
+        .with_seed(config.llm.seed.map(|seed| seed as u64))
+        .with_persona(persona.as_ref())
+        .activate_lorebooks()
+        .report
+}
+
+#[tauri::command]
+async fn fetch_character(
+    app: AppHandle,
+    config: Config,
+    character_url: String,
+) -> TAResult<Vec<CharacterInformation>> {
+    info!("creating characters from URL {character_url}");
+    let registry = SourceRegistry::with_defaults(avatar_pipeline(&app, config)?);
+    let characters = registry.fetch(&character_url).await?;
+    Ok(characters)
 }
 
 #[tauri::command]
-async fn upload_character_pngs(pngs: Vec<String>) -> TAResult<Vec<CharacterInformation>> {
+async fn upload_character_pngs(
+    app: AppHandle,
+    config: Config,
+    pngs: Vec<String>,
+) -> TAResult<Vec<CharacterInformation>> {
     use base64::prelude::*;
 
     info!("uploading {} character PNG files", pngs.len());
+    let avatars = avatar_pipeline(&app, config)?;
 
     let mut characters = Vec::new();
     for base64 in pngs {
         let bytes = BASE64_STANDARD
             .decode(base64)
             .map_err(|e| anyhow!("failed to decode base64: {e}"))?;
-        let character = character_from_png_bytes(&bytes)?;
+        let character = character_from_png_bytes(&bytes, &avatars)?;
+        debug!("received character: {:#?}", character);
+
+        characters.push(character);
+    }
+
+    Ok(characters)
+}
+
+#[tauri::command]
+async fn export_character_png(
+    app: AppHandle,
+    character: CharacterInformation,
+    path: String,
+) -> TAResult<()> {
+    info!("exporting character '{}' to {path}", character.name);
+    let bytes = character_to_png_bytes(&character, &asset_store(&app)?)?;
+    tokio::fs::write(&path, bytes)
+        .await
+        .map_err(|e| anyhow!("failed to write {path}: {e}"))?;
+    Ok(())
+}
+
+/// Writes the checked out branch of the chat to `path`, in the format of the options.
+#[tauri::command]
+async fn export_chat(
+    app: AppHandle,
+    config: Config,
+    chat: Chat,
+    characters: Vec<Character>,
+    options: ExportOptions,
+    path: String,
+) -> TAResult<()> {
+    info!("exporting chat {} to {path}", chat.id);
+    let assets = asset_store(&app)?;
+    // only HTML embeds the avatars
+    let avatars = options.format == ExportFormat::Html;
+    let persona = chat_persona(&app, &chat);
+    let user = match &persona {
+        Some(persona) => export::Speaker {
+            name: persona.name.clone(),
+            avatar: persona
+                .avatar
+                .as_deref()
+                .filter(|_| avatars)
+                .and_then(|avatar| export::asset_data_url(&assets, avatar)),
+        },
+        None => export::Speaker {
+            name: config.user_name.clone(),
+            avatar: None,
+        },
+    };
+    let characters = characters
+        .into_iter()
+        .map(|character| {
+            let speaker = export::Speaker {
+                avatar: avatars
+                    .then(|| export::character_avatar(&character.payload, &assets))
+                    .flatten(),
+                name: character.payload.name,
+            };
+            (character.id, speaker)
+        })
+        .collect();
+
+    let exported = export::export_chat(&chat, &export::Speakers { user, characters }, &options)?;
+    tokio::fs::write(&path, exported)
+        .await
+        .map_err(|e| anyhow!("failed to write {path}: {e}"))?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn import_character_json(
+    app: AppHandle,
+    config: Config,
+    json: String,
+) -> TAResult<CharacterInformation> {
+    let avatars = avatar_pipeline(&app, config)?;
+    let character = character_from_json(json.as_bytes(), &avatars)?;
+    debug!("received character: {:#?}", character);
+    Ok(character)
+}
+
+/// Imports all characters and lorebooks in a directory, emitting `import_progress` after
+/// every file.
+#[tauri::command]
+async fn import_character_directory(
+    app: AppHandle,
+    config: Config,
+    path: String,
+    known_hashes: Option<Vec<String>>,
+) -> TAResult<DirectoryImport> {
+    let known_hashes = known_hashes.unwrap_or_default().into_iter().collect();
+    let avatars = avatar_pipeline(&app, config)?;
+    // walking, reading and decoding the files of a large install takes a while
+    let import = tokio::task::spawn_blocking(move || {
+        import_directory(
+            Utf8PathBuf::from(path).as_path(),
+            &avatars,
+            &known_hashes,
+            |progress| {
+                if let Err(e) = app.emit("import_progress", progress.clone()) {
+                    error!("failed to emit import progress: {e}");
+                }
+            },
+        )
+    })
+    .await??;
+    info!(
+        "imported {} characters and {} lorebooks from {} files",
+        import.characters.len(),
+        import.lorebooks.len(),
+        import.files.len()
+    );
+    Ok(import)
+}
+
+#[tauri::command]
+async fn import_lorebook(json: String, name: Option<String>) -> TAResult<Lorebook> {
+    let mut lorebook = lorebook_from_json(json.as_bytes())?;
+    if lorebook.name.trim().is_empty() {
+        lorebook.name = name.unwrap_or_default();
+    }
+    info!(
+        "imported lorebook '{}' with {} entries",
+        lorebook.name,
+        lorebook.entries.len()
+    );
+    Ok(lorebook)
+}
+
+#[tauri::command]
+async fn upload_character_charx(
+    app: AppHandle,
+    config: Config,
+    archives: Vec<String>,
+) -> TAResult<Vec<CharacterInformation>> {
+    use base64::prelude::*;
+
+    info!("uploading {} CharX archives", archives.len());
+    let avatars = avatar_pipeline(&app, config)?;
+
+    let mut characters = Vec::new();
+    for base64 in archives {
+        let bytes = BASE64_STANDARD
+            .decode(base64)
+            .map_err(|e| anyhow!("failed to decode base64: {e}"))?;
+        let character = character_from_charx_bytes(&bytes, &avatars)?;
         debug!("received character: {:#?}", character);
 
         characters.push(character);
@@ -134,6 +560,33 @@ async fn upload_character_pngs(pngs: Vec<String>) -> TAResult<Vec<CharacterInfor
     Ok(characters)
 }
 
+#[tauri::command]
+async fn export_character_charx(
+    app: AppHandle,
+    character: CharacterInformation,
+    path: String,
+) -> TAResult<()> {
+    info!("exporting character '{}' to {path}", character.name);
+    let store = asset_store(&app)?;
+    let bytes = character_to_charx_bytes(&character, &store)?;
+    tokio::fs::write(&path, bytes)
+        .await
+        .map_err(|e| anyhow!("failed to write {path}: {e}"))?;
+    Ok(())
+}
+
+/// Moves the focal point of a character image and regenerates its cropped thumbnails.
+#[tauri::command]
+async fn set_avatar_focal_point(
+    app: AppHandle,
+    config: Config,
+    image: CharacterImage,
+    focal_point: FocalPoint,
+) -> TAResult<CharacterImage> {
+    let avatars = avatar_pipeline(&app, config)?;
+    Ok(avatars.set_focal_point(&image, focal_point)?)
+}
+
 #[tauri::command]
 async fn active_model(app: AppHandle) -> Option<String> {
     let state = app.state::<State>();
@@ -160,6 +613,202 @@ async fn summarize(app: AppHandle, chat: Chat, prompt: String) -> TAResult<Strin
     }
 }
 
+/// Generates a short title for the chat from its first messages.
+#[tauri::command]
+async fn generate_chat_title(app: AppHandle, chat: Chat) -> TAResult<String> {
+    let state = app.state::<State>();
+    let lock = state.completions.lock().await;
+    let Some(api) = lock.as_ref() else {
+        bail!("no model loaded")
+    };
+
+    let title = chat::generate_title(&chat, api)
+        .await
+        .inspect_err(|e| error!("failed to generate a title: {e:?}"))?;
+    info!("generated title '{title}' for chat {}", chat.id);
+    Ok(title)
+}
+
+/// Adds new and changed messages of the chats to the search index. With `all`, the chats
+/// are every chat there is, and chats that aren't among them are removed from the index.
+#[tauri::command]
+async fn index_chats(
+    app: AppHandle,
+    chats: Vec<Chat>,
+    character_names: HashMap<String, String>,
+    all: Option<bool>,
+) -> TAResult<()> {
+    let index = search_index(&app).await?;
+    index
+        .update_chats(&chats, &character_names)
+        .inspect_err(|e| error!("failed to index chats: {e:?}"))?;
+    if all.unwrap_or(false) {
+        index.retain_chats(&chats.iter().map(|chat| chat.id.as_str()).collect())?;
+    }
+    Ok(())
+}
+
+/// Embeds the messages that were added to the chat, so that recalling them later only has
+/// to embed the latest ones. Returns how many were embedded.
+#[tauri::command]
+async fn embed_chat(app: AppHandle, config: Config, chat: Chat) -> TAResult<usize> {
+    if !config.embeddings.enabled {
+        return Ok(0);
+    }
+    let path = vector_index_path(&app, &chat)?;
+    let api = recall::embedding_api(&config.embeddings)?;
+    let mut index = VectorIndex::load(&path)?;
+    let embedded = index.update(&chat, &api).await;
+    index.save(&path)?;
+    Ok(embedded?)
+}
+
+#[tauri::command]
+async fn search_chats(app: AppHandle, query: SearchQuery) -> TAResult<Vec<SearchHit>> {
+    let index = search_index(&app).await?;
+    Ok(index.search(&query)?)
+}
+
+/// Summarizes the messages that were left out of the last prompt and merges older summaries.
+/// Returns the new summaries, for the frontend to store on the chat.
+#[tauri::command]
+async fn update_memory(
+    app: AppHandle,
+    config: Config,
+    mut chat: Chat,
+    character: CharacterInformation,
+    characters: Option<Vec<Character>>,
+    last_omitted_message: Option<String>,
+) -> TAResult<Vec<ChatSummary>> {
+    let persona = chat_persona(&app, &chat);
+    let characters = characters.unwrap_or_default();
+    let speakers = Speakers {
+        user: persona
+            .as_ref()
+            .map_or(&config.user_name, |persona| &persona.name),
+        character: &character.name,
+        group: &characters,
+    };
+    let state = app.state::<State>();
+    let lock = state.completions.lock().await;
+    let Some(api) = lock.as_ref() else {
+        bail!("no model loaded")
+    };
+
+    let summaries = memory::update_memory(
+        &mut chat,
+        &config.memory,
+        last_omitted_message.as_deref(),
+        &speakers,
+        |messages| {
+            let request = memory::summary_request(messages, &config.memory);
+            async move { Ok(api.get_completions(request).await?.into_message()) }
+        },
+    )
+    .await
+    .inspect_err(|e| error!("failed to update memory: {e:?}"))?;
+    info!("added {} summaries to chat {}", summaries.len(), chat.id);
+    Ok(summaries)
+}
+
+/// Starts a new chat with the conversation up to and including the message.
+#[tauri::command]
+async fn fork_chat(chat: Chat, message_id: String) -> TAResult<Chat> {
+    Ok(chat.fork(&message_id)?)
+}
+
+/// Checks out the branch through the message, e.g. after choosing another reply.
+#[tauri::command]
+async fn checkout_message(mut chat: Chat, message_id: String) -> TAResult<Chat> {
+    chat.checkout(&message_id)?;
+    Ok(chat)
+}
+
+#[tauri::command]
+async fn create_chat_branch(mut chat: Chat, name: String, message_id: String) -> TAResult<Chat> {
+    chat.create_branch(&name, &message_id)?;
+    Ok(chat)
+}
+
+#[tauri::command]
+async fn checkout_chat_branch(mut chat: Chat, name: String) -> TAResult<Chat> {
+    chat.checkout_branch(&name)?;
+    Ok(chat)
+}
+
+#[tauri::command]
+async fn impersonate(
+    app: AppHandle,
+    config: Config,
+    chat: Chat,
+    character: CharacterInformation,
+    prompt: Option<String>,
+) -> TAResult<()> {
+    let persona = chat_persona(&app, &chat);
+    info!(
+        "received request to impersonate {}",
+        persona
+            .as_ref()
+            .map_or(&config.user_name, |persona| &persona.name)
+    );
+    let state = app.state::<State>();
+    let lock = state.completions.lock().await;
+    let Some(api) = lock.as_ref() else {
+        bail!("no model loaded")
+    };
+
+    let builder = PromptBuilder::new(&chat, &character, &config.user_name, &config.prompt)
+        .with_seed(config.llm.seed.map(|seed| seed as u64))
+        .with_persona(persona.as_ref());
+    let messages = chat::impersonation_messages(&builder, prompt.as_deref());
+    let request = completion_request(&config, messages);
+    let tracker = GenerationTracker::new(api.backend_name(), &request);
+    let stream = api.get_completions_stream(request).await?;
+    emit_completion_stream(&app, stream, tracker, "impersonation").await;
+
+    Ok(())
+}
+
+#[tauri::command]
+async fn list_personas(app: AppHandle) -> TAResult<Vec<Persona>> {
+    Ok(persona_store(&app)?.list()?)
+}
+
+/// Adds or updates a persona. A new avatar is sent as a data URL and kept in the asset store.
+#[tauri::command]
+async fn save_persona(
+    app: AppHandle,
+    mut persona: Persona,
+    avatar: Option<String>,
+) -> TAResult<Persona> {
+    if let Some(avatar) = avatar {
+        persona.avatar = Some(persona::store_avatar(&asset_store(&app)?, &avatar)?);
+    }
+    Ok(persona_store(&app)?.save(persona)?)
+}
+
+#[tauri::command]
+async fn delete_persona(app: AppHandle, id: String) -> TAResult<()> {
+    Ok(persona_store(&app)?.delete(&id)?)
+}
+
+/// Locks a persona to a character or chat, or unlocks it.
+#[tauri::command]
+async fn set_persona_lock(
+    app: AppHandle,
+    id: String,
+    lock: PersonaLock,
+    locked: bool,
+) -> TAResult<Persona> {
+    Ok(persona_store(&app)?.set_locked(&id, &lock, locked)?)
+}
+
+/// The persona the user speaks as in the chat, if they have any.
+#[tauri::command]
+async fn resolve_persona(app: AppHandle, chat: Chat) -> Option<Persona> {
+    chat_persona(&app, &chat)
+}
+
 #[derive(Serialize, Debug, Clone, Copy)]
 #[serde(rename_all = "kebab-case")]
 pub enum BackendType {
@@ -274,6 +923,7 @@ pub fn run() {
         .setup(|app| {
             app.manage(State {
                 completions: Mutex::new(None),
+                search: Mutex::new(None),
             });
             Ok(())
         })
@@ -287,13 +937,43 @@ pub fn run() {
                 .level(LevelFilter::Info)
                 .build(),
         )
+        .register_uri_scheme_protocol("erpy-asset", |ctx, request| {
+            serve_asset(ctx.app_handle(), request)
+        })
         .invoke_handler(tauri::generate_handler![
             chat_completion,
+            continue_completion,
+            build_prompt,
+            next_group_speaker,
+            debug_lorebooks,
             list_models,
             fetch_character,
             active_model,
             summarize,
+            generate_chat_title,
+            index_chats,
+            embed_chat,
+            search_chats,
+            update_memory,
+            fork_chat,
+            checkout_message,
+            create_chat_branch,
+            checkout_chat_branch,
+            impersonate,
             upload_character_pngs,
+            export_character_png,
+            upload_character_charx,
+            import_character_json,
+            import_lorebook,
+            import_character_directory,
+            export_character_charx,
+            export_chat,
+            set_avatar_focal_point,
+            list_personas,
+            save_persona,
+            delete_persona,
+            set_persona_lock,
+            resolve_persona,
             load_model,
             unload_model,
             test_connection,
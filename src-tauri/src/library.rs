@@ -0,0 +1,353 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use erpy_types::{CharacterInformation, Lorebook};
+use log::{info, warn};
+use serde::Serialize;
+use serde_json::Value;
+use walkdir::WalkDir;
+
+use crate::{
+    assets::content_hash,
+    avatar::AvatarPipeline,
+    character::{character_from_json, CharacterJsonFormat},
+    lorebook::{lorebook_from_value, LorebookFormat},
+    source::{character_from_file_bytes, has_character_extension},
+};
+
+/// Directories that never contain characters, like the dependencies of a SillyTavern
+/// install, and are skipped along with hidden directories.
+const SKIPPED_DIRECTORIES: [&str; 2] = ["node_modules", "backups"];
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportedCharacter {
+    pub path: String,
+    pub content_hash: String,
+    pub character: CharacterInformation,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportedLorebook {
+    pub path: String,
+    pub content_hash: String,
+    pub lorebook: Lorebook,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum FileImportStatus {
+    Character {
+        name: String,
+    },
+    Lorebook {
+        name: String,
+    },
+    /// The file has the same content as an earlier file or an already imported one.
+    #[serde(rename_all = "camelCase")]
+    Duplicate {
+        duplicate_of: Option<String>,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileImportResult {
+    /// The path relative to the imported directory.
+    pub path: String,
+    #[serde(flatten)]
+    pub status: FileImportStatus,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DirectoryImport {
+    pub characters: Vec<ImportedCharacter>,
+    pub lorebooks: Vec<ImportedLorebook>,
+    pub files: Vec<FileImportResult>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportProgress {
+    pub processed: usize,
+    pub total: usize,
+    pub path: String,
+}
+
+enum ImportedFile {
+    Character(Box<CharacterInformation>),
+    Lorebook(Lorebook),
+}
+
+/// Imports every character card and lorebook below `dir`, e.g. the `characters` and
+/// `worlds` folders of a SillyTavern install.
+///
+/// Files whose content hash is in `known_hashes` or was seen earlier in the walk are
+/// reported as duplicates. A file that can't be read doesn't stop the import.
+pub fn import_directory(
+    dir: &Utf8Path,
+    avatars: &AvatarPipeline,
+    known_hashes: &HashSet<String>,
+    mut on_progress: impl FnMut(&ImportProgress),
+) -> Result<DirectoryImport> {
+    if !dir.is_dir() {
+        bail!("{dir} is not a directory");
+    }
+
+    let paths = character_files(dir);
+    info!("importing {} files from {dir}", paths.len());
+
+    let mut import = DirectoryImport::default();
+    let mut seen: HashMap<String, String> = HashMap::new();
+    for (index, path) in paths.iter().enumerate() {
+        let relative = path.strip_prefix(dir).unwrap_or(path).to_string();
+        let status = match std::fs::read(path) {
+            Err(e) => FileImportStatus::Failed {
+                error: format!("failed to read file: {e}"),
+            },
+            Ok(bytes) => {
+                let hash = content_hash(&bytes);
+                if let Some(original) = seen.get(&hash) {
+                    FileImportStatus::Duplicate {
+                        duplicate_of: Some(original.clone()),
+                    }
+                } else if known_hashes.contains(&hash) {
+                    FileImportStatus::Duplicate { duplicate_of: None }
+                } else {
+                    seen.insert(hash.clone(), relative.clone());
+                    match import_file(path, &bytes, avatars) {
+                        Ok(ImportedFile::Character(character)) => {
+                            let name = character.name.clone();
+                            import.characters.push(ImportedCharacter {
+                                path: relative.clone(),
+                                content_hash: hash,
+                                character: *character,
+                            });
+                            FileImportStatus::Character { name }
+                        }
+                        Ok(ImportedFile::Lorebook(lorebook)) => {
+                            let name = lorebook.name.clone();
+                            import.lorebooks.push(ImportedLorebook {
+                                path: relative.clone(),
+                                content_hash: hash,
+                                lorebook,
+                            });
+                            FileImportStatus::Lorebook { name }
+                        }
+                        Err(e) => {
+                            warn!("failed to import {path}: {e:#}");
+                            FileImportStatus::Failed {
+                                error: format!("{e:#}"),
+                            }
+                        }
+                    }
+                }
+            }
+        };
+
+        import.files.push(FileImportResult {
+            path: relative.clone(),
+            status,
+        });
+        on_progress(&ImportProgress {
+            processed: index + 1,
+            total: paths.len(),
+            path: relative,
+        });
+    }
+
+    Ok(import)
+}
+
+fn character_files(dir: &Utf8Path) -> Vec<Utf8PathBuf> {
+    let mut paths: Vec<_> = WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|entry| {
+            let name = entry.file_name().to_string_lossy();
+            entry.depth() == 0
+                || !entry.file_type().is_dir()
+                || !(name.starts_with('.') || SKIPPED_DIRECTORIES.contains(&name.as_ref()))
+        })
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                warn!("skipping unreadable entry in {dir}: {e}");
+                None
+            }
+        })
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| Utf8PathBuf::from_path_buf(entry.into_path()).ok())
+        .filter(|path| has_character_extension(path.as_str()))
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Reads a character card, or a lorebook if a JSON file isn't a character.
+fn import_file(path: &Utf8Path, bytes: &[u8], avatars: &AvatarPipeline) -> Result<ImportedFile> {
+    let is_json = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"));
+    if !is_json {
+        let character = character_from_file_bytes(bytes, avatars)?;
+        return Ok(ImportedFile::Character(Box::new(character)));
+    }
+
+    let value: Value = serde_json::from_slice(bytes)?;
+    if CharacterJsonFormat::detect(&value).is_some() {
+        let character = character_from_json(bytes, avatars)?;
+        return Ok(ImportedFile::Character(Box::new(character)));
+    }
+    if LorebookFormat::detect(&value).is_some() {
+        let mut lorebook = lorebook_from_value(value)?;
+        if lorebook.name.trim().is_empty() {
+            lorebook.name = path.file_stem().unwrap_or_default().to_string();
+        }
+        Ok(ImportedFile::Lorebook(lorebook))
+    } else {
+        bail!("not a character card or lorebook")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use camino::Utf8Path;
+    use erpy_types::CharacterInformation;
+    use serde_json::json;
+
+    use super::{import_directory, FileImportStatus};
+    use crate::{
+        assets::{content_hash, AssetStore},
+        avatar::AvatarPipeline,
+        character::character_to_png_bytes,
+        config::AvatarSettings,
+        test_util::card_json,
+    };
+
+    #[test]
+    fn test_import_sillytavern_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(dir.path()).unwrap();
+        let avatars = AvatarPipeline::new(
+            AssetStore::new(root.join("assets")),
+            AvatarSettings::default(),
+        );
+        let public = root.join("public");
+        std::fs::create_dir_all(public.join("characters")).unwrap();
+        std::fs::create_dir_all(public.join("worlds")).unwrap();
+        std::fs::create_dir_all(root.join("node_modules/pkg")).unwrap();
+
+        let png = character_to_png_bytes(
+            &CharacterInformation {
+                name: "Alice".into(),
+                first_messages: vec!["Hello!".into()],
+                ..Default::default()
+            },
+            avatars.store(),
+        )
+        .unwrap();
+        std::fs::write(public.join("characters/Alice.png"), &png).unwrap();
+        std::fs::write(public.join("characters/Alice copy.png"), &png).unwrap();
+        std::fs::write(public.join("characters/Bob.json"), card_json("Bob")).unwrap();
+        std::fs::write(public.join("characters/Carol.json"), card_json("Carol")).unwrap();
+        std::fs::write(public.join("characters/broken.png"), b"not a png").unwrap();
+        std::fs::write(public.join("characters/notes.txt"), "ignored").unwrap();
+        std::fs::write(
+            public.join("worlds/Eldoria.json"),
+            json!({ "entries": { "0": { "uid": 0, "key": ["king"], "content": "The king." } } })
+                .to_string(),
+        )
+        .unwrap();
+        std::fs::write(
+            public.join("worlds/settings.json"),
+            json!({ "theme": "dark" }).to_string(),
+        )
+        .unwrap();
+        std::fs::write(root.join("node_modules/pkg/package.json"), "{}").unwrap();
+
+        let known = HashSet::from([content_hash(card_json("Carol").as_bytes())]);
+        let mut progress = Vec::new();
+        let import = import_directory(root, &avatars, &known, |p| {
+            progress.push((p.processed, p.total))
+        })
+        .unwrap();
+
+        let names: Vec<_> = import
+            .characters
+            .iter()
+            .map(|c| c.character.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["Alice", "Bob"]);
+        assert!(import.characters[0].character.image.is_some());
+        assert_eq!(import.lorebooks.len(), 1);
+        assert_eq!(import.lorebooks[0].lorebook.name, "Eldoria");
+        assert_eq!(import.lorebooks[0].lorebook.entries.len(), 1);
+
+        let statuses: Vec<_> = import
+            .files
+            .iter()
+            .map(|f| (f.path.as_str(), f.status.clone()))
+            .collect();
+        assert_eq!(statuses.len(), 7);
+        assert_eq!(
+            statuses[0],
+            (
+                "public/characters/Alice copy.png",
+                FileImportStatus::Character {
+                    name: "Alice".into()
+                }
+            )
+        );
+        assert_eq!(
+            statuses[1].1,
+            FileImportStatus::Duplicate {
+                duplicate_of: Some("public/characters/Alice copy.png".into())
+            }
+        );
+        assert_eq!(
+            statuses[3],
+            (
+                "public/characters/Carol.json",
+                FileImportStatus::Duplicate { duplicate_of: None }
+            )
+        );
+        assert!(matches!(
+            statuses[4],
+            (
+                "public/characters/broken.png",
+                FileImportStatus::Failed { .. }
+            )
+        ));
+        assert_eq!(
+            statuses[6],
+            (
+                "public/worlds/settings.json",
+                FileImportStatus::Failed {
+                    error: "not a character card or lorebook".into()
+                }
+            )
+        );
+
+        assert_eq!(progress.len(), 7);
+        assert_eq!(progress.last(), Some(&(7, 7)));
+    }
+
+    #[test]
+    fn test_import_requires_a_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(dir.path()).unwrap();
+        let avatars = AvatarPipeline::new(
+            AssetStore::new(root.join("assets")),
+            AvatarSettings::default(),
+        );
+
+        let error =
+            import_directory(&root.join("missing"), &avatars, &HashSet::new(), |_| {}).unwrap_err();
+        assert!(error.to_string().ends_with("is not a directory"));
+    }
+}
@@ -0,0 +1,552 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context, Result};
+use erpy_types::{
+    lorebook::CharacterBook, null_as_default, Lorebook, LorebookEntry, LorebookPosition,
+    SelectiveLogic,
+};
+use log::info;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Map, Value};
+
+/// A SillyTavern world info file, with its entries keyed by their uid.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct WorldInfo {
+    #[serde(deserialize_with = "null_as_default")]
+    name: String,
+    #[serde(deserialize_with = "null_as_default")]
+    description: String,
+    #[serde(deserialize_with = "null_as_default")]
+    entries: BTreeMap<String, WorldInfoEntry>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct WorldInfoEntry {
+    uid: Option<i64>,
+    #[serde(deserialize_with = "null_as_default")]
+    key: Vec<String>,
+    #[serde(deserialize_with = "null_as_default")]
+    keysecondary: Vec<String>,
+    #[serde(deserialize_with = "null_as_default")]
+    comment: String,
+    #[serde(deserialize_with = "null_as_default")]
+    content: String,
+    #[serde(deserialize_with = "null_as_default")]
+    constant: bool,
+    #[serde(deserialize_with = "null_as_default")]
+    selective: bool,
+    selective_logic: Option<i64>,
+    order: Option<i64>,
+    position: Option<i64>,
+    #[serde(deserialize_with = "null_as_default")]
+    disable: bool,
+    probability: Option<i64>,
+    use_probability: Option<bool>,
+    depth: Option<usize>,
+    case_sensitive: Option<bool>,
+    match_whole_words: Option<bool>,
+    scan_depth: Option<usize>,
+    sticky: Option<u32>,
+    cooldown: Option<u32>,
+    delay: Option<u32>,
+    #[serde(deserialize_with = "null_as_default")]
+    exclude_recursion: bool,
+    #[serde(deserialize_with = "null_as_default")]
+    prevent_recursion: bool,
+    display_index: Option<i64>,
+    /// Everything else (groups, roles, automation ids) is kept in the entry's extensions.
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+impl WorldInfoEntry {
+    fn into_entry(self) -> LorebookEntry {
+        let use_probability = self.use_probability.unwrap_or(true);
+
+        LorebookEntry {
+            id: self.uid,
+            name: self.comment,
+            keys: self.key,
+            secondary_keys: self.keysecondary,
+            selective: self.selective,
+            selective_logic: self
+                .selective_logic
+                .map(SelectiveLogic::from_sillytavern)
+                .unwrap_or_default(),
+            content: self.content,
+            enabled: !self.disable,
+            constant: self.constant,
+            insertion_order: self.order.unwrap_or(100),
+            priority: None,
+            position: self
+                .position
+                .map(LorebookPosition::from_sillytavern)
+                .unwrap_or_default(),
+            depth: self.depth,
+            probability: self
+                .probability
+                .filter(|_| use_probability)
+                .map(|p| p.clamp(0, 100) as u8),
+            case_sensitive: self.case_sensitive,
+            match_whole_words: self.match_whole_words,
+            use_regex: false,
+            scan_depth: self.scan_depth,
+            sticky: self.sticky.filter(|&n| n > 0),
+            cooldown: self.cooldown.filter(|&n| n > 0),
+            delay: self.delay.filter(|&n| n > 0),
+            exclude_recursion: self.exclude_recursion,
+            prevent_recursion: self.prevent_recursion,
+            extensions: self.other,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct NovelAiLorebook {
+    #[serde(deserialize_with = "null_as_default")]
+    entries: Vec<NovelAiEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct NovelAiEntry {
+    #[serde(deserialize_with = "null_as_default")]
+    text: String,
+    #[serde(deserialize_with = "null_as_default")]
+    display_name: String,
+    #[serde(deserialize_with = "null_as_default")]
+    keys: Vec<String>,
+    enabled: bool,
+    #[serde(deserialize_with = "null_as_default")]
+    force_activation: bool,
+    #[serde(deserialize_with = "null_as_default")]
+    context_config: NovelAiContextConfig,
+}
+
+impl Default for NovelAiEntry {
+    fn default() -> Self {
+        NovelAiEntry {
+            text: String::new(),
+            display_name: String::new(),
+            keys: Vec::new(),
+            enabled: true,
+            force_activation: false,
+            context_config: NovelAiContextConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct NovelAiContextConfig {
+    budget_priority: Option<i64>,
+}
+
+/// Agnai's memory books, also used for the `characterBook` of Agnai character exports.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct AgnaiMemoryBook {
+    #[serde(deserialize_with = "null_as_default")]
+    name: String,
+    #[serde(deserialize_with = "null_as_default")]
+    description: String,
+    #[serde(deserialize_with = "null_as_default")]
+    entries: Vec<AgnaiMemoryEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct AgnaiMemoryEntry {
+    #[serde(deserialize_with = "null_as_default")]
+    name: String,
+    #[serde(deserialize_with = "null_as_default")]
+    entry: String,
+    #[serde(deserialize_with = "null_as_default")]
+    keywords: Vec<String>,
+    priority: Option<i64>,
+    weight: Option<i64>,
+    enabled: bool,
+}
+
+impl Default for AgnaiMemoryEntry {
+    fn default() -> Self {
+        AgnaiMemoryEntry {
+            name: String::new(),
+            entry: String::new(),
+            keywords: Vec::new(),
+            priority: None,
+            weight: None,
+            enabled: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LorebookFormat {
+    CharacterBook,
+    SillyTavern,
+    NovelAi,
+    Agnai,
+}
+
+impl LorebookFormat {
+    pub fn detect(value: &Value) -> Option<Self> {
+        let object = value.as_object()?;
+
+        if object.contains_key("lorebookVersion") {
+            return Some(Self::NovelAi);
+        }
+        if object.get("kind").and_then(Value::as_str) == Some("memory") {
+            return Some(Self::Agnai);
+        }
+
+        match object.get("entries")? {
+            Value::Object(_) => Some(Self::SillyTavern),
+            Value::Array(entries) => {
+                let agnai = entries
+                    .first()
+                    .and_then(Value::as_object)
+                    .is_some_and(|entry| entry.contains_key("keywords"));
+                if agnai {
+                    Some(Self::Agnai)
+                } else {
+                    Some(Self::CharacterBook)
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Reads a lorebook in any of the supported formats. Character cards are accepted too,
+/// in which case their `character_book` is used.
+pub fn lorebook_from_value(mut value: Value) -> Result<Lorebook> {
+    if value.get("spec").is_some() {
+        value = value
+            .get_mut("data")
+            .and_then(|data| data.get_mut("character_book"))
+            .map(Value::take)
+            .filter(|book| !book.is_null())
+            .context("character card has no lorebook")?;
+    }
+
+    let format =
+        LorebookFormat::detect(&value).ok_or_else(|| anyhow!("unrecognized lorebook format"))?;
+    info!("reading lorebook in {format:?} format");
+
+    let lorebook = match format {
+        LorebookFormat::CharacterBook => {
+            let book: CharacterBook = deserialize_lorebook_fields(value)?;
+            book.into_lorebook()
+        }
+        LorebookFormat::SillyTavern => {
+            let book: WorldInfo = deserialize_lorebook_fields(value)?;
+            let mut entries: Vec<_> = book.entries.into_values().collect();
+            entries.sort_by_key(|entry| (entry.display_index, entry.uid));
+            Lorebook {
+                name: book.name,
+                description: book.description,
+                entries: entries
+                    .into_iter()
+                    .map(WorldInfoEntry::into_entry)
+                    .collect(),
+                ..Default::default()
+            }
+        }
+        LorebookFormat::NovelAi => {
+            let book: NovelAiLorebook = deserialize_lorebook_fields(value)?;
+            Lorebook {
+                entries: book
+                    .entries
+                    .into_iter()
+                    .map(|entry| LorebookEntry {
+                        name: entry.display_name,
+                        keys: entry.keys,
+                        content: entry.text,
+                        enabled: entry.enabled,
+                        constant: entry.force_activation,
+                        insertion_order: entry.context_config.budget_priority.unwrap_or(400),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }
+        }
+        LorebookFormat::Agnai => {
+            let book: AgnaiMemoryBook = deserialize_lorebook_fields(value)?;
+            Lorebook {
+                name: book.name,
+                description: book.description,
+                entries: book
+                    .entries
+                    .into_iter()
+                    .map(|entry| LorebookEntry {
+                        name: entry.name,
+                        keys: entry.keywords,
+                        content: entry.entry,
+                        enabled: entry.enabled,
+                        insertion_order: entry.weight.unwrap_or(100),
+                        priority: entry.priority,
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }
+        }
+    };
+
+    Ok(lorebook)
+}
+
+/// Reads a standalone lorebook file.
+pub fn lorebook_from_json(json: &[u8]) -> Result<Lorebook> {
+    let value: Value = serde_json::from_slice(json).context("lorebook is not valid JSON")?;
+    lorebook_from_value(value)
+}
+
+/// Writes the lorebook as a V2/V3 `character_book`, with the fields the spec lacks stored
+/// in each entry's extensions the way SillyTavern does.
+pub fn lorebook_to_character_book(lorebook: &Lorebook) -> Value {
+    let entries: Vec<_> = lorebook
+        .entries
+        .iter()
+        .map(|entry| {
+            let mut extensions = entry.extensions.clone();
+            let mut set = |key: &str, value: Value| {
+                extensions.insert(key.to_string(), value);
+            };
+            set("position", json!(entry.position.to_sillytavern()));
+            set(
+                "selectiveLogic",
+                json!(entry.selective_logic.to_sillytavern()),
+            );
+            set("probability", json!(entry.probability.unwrap_or(100)));
+            set("useProbability", json!(entry.probability.is_some()));
+            set("exclude_recursion", json!(entry.exclude_recursion));
+            set("prevent_recursion", json!(entry.prevent_recursion));
+            let optional = [
+                ("depth", entry.depth.map(|n| json!(n))),
+                ("case_sensitive", entry.case_sensitive.map(|b| json!(b))),
+                (
+                    "match_whole_words",
+                    entry.match_whole_words.map(|b| json!(b)),
+                ),
+                ("scan_depth", entry.scan_depth.map(|n| json!(n))),
+                ("sticky", entry.sticky.map(|n| json!(n))),
+                ("cooldown", entry.cooldown.map(|n| json!(n))),
+                ("delay", entry.delay.map(|n| json!(n))),
+            ];
+            for (key, value) in optional {
+                if let Some(value) = value {
+                    set(key, value);
+                }
+            }
+
+            let position = match entry.position {
+                LorebookPosition::BeforeCharacter => "before_char",
+                _ => "after_char",
+            };
+
+            json!({
+                "keys": entry.keys,
+                "content": entry.content,
+                "extensions": extensions,
+                "enabled": entry.enabled,
+                "insertion_order": entry.insertion_order,
+                "case_sensitive": entry.case_sensitive,
+                "use_regex": entry.use_regex,
+                "name": entry.name,
+                "priority": entry.priority,
+                "id": entry.id,
+                "comment": entry.name,
+                "selective": entry.selective,
+                "secondary_keys": entry.secondary_keys,
+                "constant": entry.constant,
+                "position": position,
+            })
+        })
+        .collect();
+
+    json!({
+        "name": lorebook.name,
+        "description": lorebook.description,
+        "scan_depth": lorebook.scan_depth,
+        "token_budget": lorebook.token_budget,
+        "recursive_scanning": lorebook.recursive_scanning,
+        "extensions": lorebook.extensions,
+        "entries": entries,
+    })
+}
+
+fn deserialize_lorebook_fields<T: DeserializeOwned>(value: Value) -> Result<T> {
+    serde_path_to_error::deserialize(value).map_err(|error| {
+        let path = error.path().to_string();
+        let inner = error.into_inner();
+        if path == "." {
+            anyhow!("malformed lorebook: {inner}")
+        } else {
+            anyhow!("malformed lorebook field `{path}`: {inner}")
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use erpy_types::{LorebookPosition, SelectiveLogic};
+    use serde_json::json;
+
+    use super::{
+        lorebook_from_json, lorebook_from_value, lorebook_to_character_book, LorebookFormat,
+    };
+
+    #[test]
+    fn test_character_book_with_sillytavern_extensions() {
+        let book = json!({
+            "name": "Gull Rock",
+            "scan_depth": 3,
+            "token_budget": 400,
+            "recursive_scanning": true,
+            "extensions": {},
+            "entries": [{
+                "keys": ["storm"],
+                "secondary_keys": ["night"],
+                "content": "Storms last for days.",
+                "enabled": true,
+                "insertion_order": 50,
+                "case_sensitive": null,
+                "name": "",
+                "comment": "Storms",
+                "id": "7",
+                "selective": true,
+                "constant": false,
+                "position": "after_char",
+                "extensions": {
+                    "position": 4,
+                    "depth": 2,
+                    "probability": 25,
+                    "useProbability": true,
+                    "selectiveLogic": 2,
+                    "case_sensitive": true,
+                    "match_whole_words": false,
+                    "sticky": 2,
+                    "cooldown": 0,
+                    "group": "weather"
+                }
+            }]
+        });
+
+        let lorebook = lorebook_from_value(book).unwrap();
+        assert_eq!(lorebook.scan_depth, Some(3));
+        assert_eq!(lorebook.token_budget, Some(400));
+        assert!(lorebook.recursive_scanning);
+
+        let entry = &lorebook.entries[0];
+        assert_eq!(entry.id, Some(7));
+        assert_eq!(entry.name, "Storms");
+        assert_eq!(entry.insertion_order, 50);
+        assert_eq!(entry.position, LorebookPosition::AtDepth);
+        assert_eq!(entry.depth, Some(2));
+        assert_eq!(entry.probability, Some(25));
+        assert_eq!(entry.selective_logic, SelectiveLogic::NotAny);
+        assert_eq!(entry.case_sensitive, Some(true));
+        assert_eq!(entry.match_whole_words, Some(false));
+        assert_eq!(entry.sticky, Some(2));
+        assert_eq!(entry.cooldown, None);
+        assert_eq!(entry.extensions.len(), 1);
+        assert_eq!(entry.extensions["group"], "weather");
+    }
+
+    #[test]
+    fn test_character_book_round_trip() {
+        let lorebook =
+            lorebook_from_json(include_bytes!("../fixtures/lorebooks/sillytavern.json")).unwrap();
+
+        let exported = lorebook_to_character_book(&lorebook);
+        assert_eq!(
+            LorebookFormat::detect(&exported),
+            Some(LorebookFormat::CharacterBook)
+        );
+        assert_eq!(lorebook_from_value(exported).unwrap(), lorebook);
+    }
+
+    #[test]
+    fn test_sillytavern_world_info() {
+        let lorebook =
+            lorebook_from_json(include_bytes!("../fixtures/lorebooks/sillytavern.json")).unwrap();
+
+        let names: Vec<_> = lorebook.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["Storms", "Gull Rock", "Setting"]);
+
+        let storms = &lorebook.entries[0];
+        assert_eq!(storms.keys, vec!["/storm(s)?/i"]);
+        assert_eq!(storms.secondary_keys, vec!["night", "lamp"]);
+        assert_eq!(storms.selective_logic, SelectiveLogic::AndAll);
+        assert_eq!(storms.position, LorebookPosition::AtDepth);
+        assert_eq!(storms.probability, Some(25));
+        assert_eq!(storms.scan_depth, Some(6));
+        assert_eq!(storms.sticky, Some(2));
+        assert_eq!(storms.cooldown, Some(3));
+        assert_eq!(storms.delay, None);
+        assert!(storms.exclude_recursion);
+        assert_eq!(storms.extensions["group"], "weather");
+
+        let gull_rock = &lorebook.entries[1];
+        assert_eq!(gull_rock.sticky, None);
+        assert_eq!(gull_rock.case_sensitive, None);
+
+        let setting = &lorebook.entries[2];
+        assert!(setting.constant);
+        assert!(!setting.enabled);
+        assert_eq!(setting.position, LorebookPosition::AfterCharacter);
+        assert_eq!(setting.probability, None);
+    }
+
+    #[test]
+    fn test_novelai_lorebook() {
+        let lorebook =
+            lorebook_from_json(include_bytes!("../fixtures/lorebooks/novelai.json")).unwrap();
+
+        assert_eq!(lorebook.entries.len(), 2);
+        assert_eq!(lorebook.entries[0].name, "Gull Rock");
+        assert_eq!(lorebook.entries[0].keys, vec!["Gull Rock", "island"]);
+        assert_eq!(lorebook.entries[0].insertion_order, 400);
+        assert!(lorebook.entries[0].enabled);
+        assert!(lorebook.entries[1].constant);
+        assert!(!lorebook.entries[1].enabled);
+    }
+
+    #[test]
+    fn test_agnai_memory_book() {
+        let lorebook = lorebook_from_value(json!({
+            "kind": "memory",
+            "name": "Gull Rock",
+            "entries": [
+                { "name": "Lamp", "entry": "The lamp is never allowed to go out.", "keywords": ["lamp"], "priority": 10, "weight": 5, "enabled": true }
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(lorebook.name, "Gull Rock");
+        assert_eq!(lorebook.entries[0].keys, vec!["lamp"]);
+        assert_eq!(lorebook.entries[0].priority, Some(10));
+        assert_eq!(lorebook.entries[0].insertion_order, 5);
+    }
+
+    #[test]
+    fn test_lorebook_from_card() {
+        let card = json!({
+            "spec": "chara_card_v2",
+            "data": { "name": "Mira", "character_book": { "entries": [{ "keys": ["lamp"], "content": "Bright." }] } }
+        });
+        let lorebook = lorebook_from_value(card).unwrap();
+        assert_eq!(lorebook.entries[0].content, "Bright.");
+
+        let error =
+            lorebook_from_value(json!({ "entries": { "0": { "key": "lamp" } } })).unwrap_err();
+        assert!(error.to_string().contains("`entries.0.key`"), "{error}");
+        assert!(lorebook_from_value(json!({ "foo": [] })).is_err());
+    }
+}
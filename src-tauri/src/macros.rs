@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, FixedOffset, Local, TimeDelta};
+use erpy_types::{hash::stable_hash, Chat, MessageRole};
+use rand::{rngs::StdRng, seq::IndexedRandom, Rng, SeedableRng};
+
+/// Expands SillyTavern style `{{macros}}` in prompts and character text.
+///
+/// Variables set with `{{setvar}}` live as long as the context, so a context should be
+/// reused for all texts of one prompt, in the order they appear in.
+pub struct MacroContext {
+    character_name: String,
+    user_name: String,
+    now: DateTime<FixedOffset>,
+    last_message: String,
+    last_user_message_at: Option<DateTime<FixedOffset>>,
+    variables: HashMap<String, String>,
+    /// Keeps `{{pick}}` stable for the same chat, unlike `{{random}}`.
+    pick_seed: u64,
+    picks: HashMap<String, usize>,
+    rng: StdRng,
+}
+
+impl MacroContext {
+    pub fn new(character_name: impl Into<String>, user_name: impl Into<String>) -> Self {
+        MacroContext {
+            character_name: character_name.into(),
+            user_name: user_name.into(),
+            now: Local::now().fixed_offset(),
+            last_message: String::new(),
+            last_user_message_at: None,
+            variables: HashMap::new(),
+            pick_seed: 0,
+            picks: HashMap::new(),
+            rng: StdRng::from_os_rng(),
+        }
+    }
+
+    pub fn for_chat(
+        chat: &Chat,
+        character_name: impl Into<String>,
+        user_name: impl Into<String>,
+    ) -> Self {
+        let history = chat.history();
+        let last_message = history
+            .iter()
+            .rev()
+            .map(|message| message.text())
+            .find(|text| !text.trim().is_empty())
+            .unwrap_or_default()
+            .to_string();
+        let last_user_message_at = history
+            .iter()
+            .rev()
+            .filter(|message| message.role == MessageRole::User)
+            .map(|message| message.content.timestamp.fixed_offset())
+            .next();
+
+        MacroContext {
+            last_message,
+            last_user_message_at,
+            pick_seed: stable_hash(&chat.id),
+            ..Self::new(character_name, user_name)
+        }
+    }
+
+    /// Makes `{{random}}`, `{{pick}}` and `{{roll}}` deterministic.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self.pick_seed = self.pick_seed.wrapping_add(seed);
+        self
+    }
+
+    pub fn with_now(mut self, now: DateTime<FixedOffset>) -> Self {
+        self.now = now;
+        self
+    }
+
+    pub fn expand(&mut self, text: &str) -> String {
+        let text = text
+            .replace("<BOT>", &self.character_name)
+            .replace("<USER>", &self.user_name);
+
+        self.expand_nested(&text)
+    }
+
+    fn expand_nested(&mut self, text: &str) -> String {
+        let mut output = String::with_capacity(text.len());
+        let mut rest = text;
+
+        while let Some(start) = rest.find("{{") {
+            output.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let Some(end) = find_closing_braces(after) else {
+                output.push_str(&rest[start..]);
+                return output;
+            };
+
+            let body = &after[..end];
+            output.push_str(&self.evaluate(body));
+            rest = &after[end + 2..];
+        }
+
+        output.push_str(rest);
+        output
+    }
+
+    fn evaluate(&mut self, body: &str) -> String {
+        if body.trim_start().starts_with("//") {
+            return String::new();
+        }
+
+        let body = self.expand_nested(body);
+        let (name, rest) = match body.find(':') {
+            Some(index) => (&body[..index], &body[index..]),
+            None => (body.as_str(), ""),
+        };
+
+        let value = match name.trim().to_lowercase().as_str() {
+            "char" => Some(self.character_name.clone()),
+            "user" => Some(self.user_name.clone()),
+            "time" => Some(self.now.format("%-I:%M %p").to_string()),
+            "date" => Some(self.now.format("%B %-d, %Y").to_string()),
+            "idle_duration" => Some(self.idle_duration()),
+            "lastmessage" => Some(self.last_message.clone()),
+            "random" => {
+                let options = arguments(rest);
+                options.choose(&mut self.rng).map(|o| o.to_string())
+            }
+            "pick" => self.pick(&body, &arguments(rest)),
+            "roll" => self.roll(rest.trim_start_matches(':')),
+            "setvar" => match arguments(rest).as_slice() {
+                [name, value] => {
+                    self.variables
+                        .insert(name.trim().to_string(), value.to_string());
+                    Some(String::new())
+                }
+                _ => None,
+            },
+            "getvar" => match arguments(rest).as_slice() {
+                [name] => Some(self.variables.get(name.trim()).cloned().unwrap_or_default()),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        value.unwrap_or_else(|| format!("{{{{{body}}}}}"))
+    }
+
+    fn idle_duration(&self) -> String {
+        match self.last_user_message_at {
+            Some(timestamp) => humanize(self.now - timestamp),
+            None => "just now".into(),
+        }
+    }
+
+    fn pick(&mut self, body: &str, options: &[&str]) -> Option<String> {
+        if options.is_empty() {
+            return None;
+        }
+
+        let occurrence = self.picks.entry(body.to_string()).or_default();
+        let hash = stable_hash(&(self.pick_seed, body, *occurrence));
+        *occurrence += 1;
+
+        Some(options[hash as usize % options.len()].to_string())
+    }
+
+    /// Rolls dice in the `2d6+3` notation. A single number rolls one die with that many sides.
+    fn roll(&mut self, formula: &str) -> Option<String> {
+        let formula: String = formula.chars().filter(|c| !c.is_whitespace()).collect();
+        let formula = formula.to_lowercase();
+        let (dice, modifier) = match formula.find(['+', '-']) {
+            Some(index) => (&formula[..index], formula[index..].parse::<i64>().ok()?),
+            None => (formula.as_str(), 0),
+        };
+        let (count, sides) = match dice.split_once('d') {
+            Some(("", sides)) => (1, sides.parse::<u32>().ok()?),
+            Some((count, sides)) => (count.parse::<u32>().ok()?, sides.parse::<u32>().ok()?),
+            None => (1, dice.parse::<u32>().ok()?),
+        };
+        if count == 0 || count > 1000 || sides == 0 {
+            return None;
+        }
+
+        let total: i64 = (0..count)
+            .map(|_| self.rng.random_range(1..=sides) as i64)
+            .sum();
+        Some((total + modifier).to_string())
+    }
+}
+
+/// Splits macro arguments, either `:a,b,c` or `::a::b::c`.
+fn arguments(rest: &str) -> Vec<&str> {
+    if let Some(rest) = rest.strip_prefix("::") {
+        rest.split("::").collect()
+    } else if let Some(rest) = rest.strip_prefix(':') {
+        rest.split(',').map(str::trim).collect()
+    } else {
+        vec![]
+    }
+}
+
+fn find_closing_braces(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut depth = 0;
+    let mut index = 0;
+
+    while index + 1 < bytes.len() {
+        if bytes[index..].starts_with(b"{{") {
+            depth += 1;
+            index += 2;
+        } else if bytes[index..].starts_with(b"}}") {
+            if depth == 0 {
+                return Some(index);
+            }
+            depth -= 1;
+            index += 2;
+        } else {
+            index += 1;
+        }
+    }
+
+    None
+}
+
+fn humanize(duration: TimeDelta) -> String {
+    let seconds = duration.num_seconds().max(0);
+    let minutes = (seconds as f64 / 60.0).round() as i64;
+    let hours = (seconds as f64 / 3600.0).round() as i64;
+    let days = (seconds as f64 / 86400.0).round() as i64;
+
+    match seconds {
+        0..45 => "a few seconds".into(),
+        45..90 => "a minute".into(),
+        90..2700 => format!("{minutes} minutes"),
+        2700..5400 => "an hour".into(),
+        5400..79200 => format!("{hours} hours"),
+        79200..129600 => "a day".into(),
+        _ => format!("{days} days"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+    use erpy_types::{
+        test_support::{self, content_at},
+        Chat, MessageRole,
+    };
+
+    use super::MacroContext;
+
+    fn chat() -> Chat {
+        let mut chat = test_support::chat(&[]);
+        for (role, text, timestamp) in [
+            (MessageRole::Assistant, "Hello!", "2024-03-05T14:00:00Z"),
+            (MessageRole::User, "Hi Alice.", "2024-03-05T14:02:00Z"),
+            (
+                MessageRole::Assistant,
+                "How are you?",
+                "2024-03-05T14:03:00Z",
+            ),
+        ] {
+            chat.append(role, content_at(text, timestamp));
+        }
+        chat
+    }
+
+    fn context() -> MacroContext {
+        MacroContext::for_chat(&chat(), "Alice", "Bob")
+            .with_seed(42)
+            .with_now(DateTime::parse_from_rfc3339("2024-03-05T15:04:00Z").unwrap())
+    }
+
+    #[test]
+    fn test_names() {
+        let mut macros = context();
+        assert_eq!(
+            macros.expand("{{char}} greets {{user}}. <BOT> and <USER>, {{Char}}."),
+            "Alice greets Bob. Alice and Bob, Alice."
+        );
+    }
+
+    #[test]
+    fn test_time_and_date() {
+        let mut macros = context();
+        assert_eq!(
+            macros.expand("It is {{time}} on {{date}}."),
+            "It is 3:04 PM on March 5, 2024."
+        );
+    }
+
+    #[test]
+    fn test_idle_duration() {
+        let mut macros = context();
+        assert_eq!(macros.expand("{{idle_duration}}"), "an hour");
+
+        let mut macros = MacroContext::new("Alice", "Bob");
+        assert_eq!(macros.expand("{{idle_duration}}"), "just now");
+    }
+
+    #[test]
+    fn test_last_message() {
+        let mut macros = context();
+        assert_eq!(macros.expand("> {{lastMessage}}"), "> How are you?");
+    }
+
+    #[test]
+    fn test_comments() {
+        let mut macros = context();
+        assert_eq!(macros.expand("a{{// a comment with {{user}} }}b"), "ab");
+    }
+
+    #[test]
+    fn test_random_is_deterministic_under_seed() {
+        let text = "{{random:red,green,blue}} {{random::one::two::three}}";
+        let first = context().expand(text);
+        let second = context().expand(text);
+        assert_eq!(first, second);
+
+        let (color, number) = first.split_once(' ').unwrap();
+        assert!(["red", "green", "blue"].contains(&color));
+        assert!(["one", "two", "three"].contains(&number));
+    }
+
+    #[test]
+    fn test_pick_is_stable_for_chat() {
+        let text = "{{pick:a,b,c,d,e,f,g,h}}";
+        let first = MacroContext::for_chat(&chat(), "Alice", "Bob").expand(text);
+        let second = MacroContext::for_chat(&chat(), "Alice", "Bob").expand(text);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_roll() {
+        let mut macros = context();
+        for _ in 0..50 {
+            let roll: i64 = macros.expand("{{roll:1d20}}").parse().unwrap();
+            assert!((1..=20).contains(&roll));
+            let roll: i64 = macros.expand("{{roll:2d6+3}}").parse().unwrap();
+            assert!((5..=15).contains(&roll));
+            let roll: i64 = macros.expand("{{roll:d4}}").parse().unwrap();
+            assert!((1..=4).contains(&roll));
+        }
+        assert_eq!(macros.expand("{{roll:0d6}}"), "{{roll:0d6}}");
+    }
+
+    #[test]
+    fn test_variables() {
+        let mut macros = context();
+        assert_eq!(macros.expand("{{setvar::mood::happy}}"), "");
+        assert_eq!(
+            macros.expand("{{char}} is {{getvar::mood}}."),
+            "Alice is happy."
+        );
+        assert_eq!(macros.expand("[{{getvar::unknown}}]"), "[]");
+    }
+
+    #[test]
+    fn test_nested_macros() {
+        let mut macros = context();
+        assert_eq!(macros.expand("{{random:{{user}}}}"), "Bob");
+        assert_eq!(
+            macros.expand("{{setvar::name::{{char}}}}{{getvar::name}}"),
+            "Alice"
+        );
+    }
+
+    #[test]
+    fn test_unknown_and_unclosed_macros_are_kept() {
+        let mut macros = context();
+        assert_eq!(
+            macros.expand("{{unknown}} {{user}} {{char"),
+            "{{unknown}} Bob {{char"
+        );
+    }
+}
@@ -0,0 +1,253 @@
+use std::future::Future;
+
+use anyhow::Result;
+use erpy_ai::{CompletionRequest, MessageHistoryItem};
+use erpy_types::{Character, Chat, ChatMessage, ChatSummary, MessageRole};
+
+use crate::config::MemorySettings;
+
+const SUMMARY_PROMPT: &str = "Summarize this part of a roleplay in a few sentences. Keep names, places, relationships, decisions and unresolved plot threads, and write in the past tense. Reply with the summary only.";
+
+const MERGE_PROMPT: &str = "Combine these consecutive summaries of a roleplay into a single summary of one paragraph. Keep the most important events, facts and unresolved plot threads. Reply with the summary only.";
+
+/// The names the messages are attributed to in the text that is summarized.
+pub struct Speakers<'a> {
+    pub user: &'a str,
+    pub character: &'a str,
+    /// The characters of a group chat.
+    pub group: &'a [Character],
+}
+
+impl Speakers<'_> {
+    fn name(&self, message: &ChatMessage) -> &str {
+        match message.role {
+            MessageRole::User => self.user,
+            _ => message
+                .character_id
+                .as_deref()
+                .and_then(|id| self.group.iter().find(|character| character.id == id))
+                .map_or(self.character, |character| &character.payload.name),
+        }
+    }
+}
+
+/// A summary that is due: of a chunk of messages, or of summaries one level below it.
+#[derive(Debug)]
+pub struct SummaryTask {
+    pub start: String,
+    pub end: String,
+    pub level: u32,
+    pub messages: Vec<MessageHistoryItem>,
+}
+
+/// The next summary to write. Runs of summaries are merged before more messages are
+/// summarized, and only messages up to `last_omitted`, the newest message that was left
+/// out of the prompt, are summarized in full chunks.
+pub fn next_task(
+    chat: &Chat,
+    settings: &MemorySettings,
+    last_omitted: Option<&str>,
+    speakers: &Speakers,
+) -> Option<SummaryTask> {
+    let memory = chat.memory();
+    let merge_count = settings.merge_count.max(2);
+    let run = memory
+        .summaries
+        .windows(merge_count)
+        .find(|run| run.iter().all(|summary| summary.level == run[0].level));
+    if let Some(run) = run {
+        let summaries: Vec<&str> = run.iter().map(|summary| summary.content.trim()).collect();
+        return Some(SummaryTask {
+            start: run[0].start.clone(),
+            end: run[run.len() - 1].end.clone(),
+            level: run[0].level + 1,
+            messages: instruction(MERGE_PROMPT, &summaries.join("\n\n")),
+        });
+    }
+
+    let history = chat.history();
+    let omitted = history
+        .iter()
+        .position(|message| Some(message.id.as_str()) == last_omitted)?;
+    let chunk = history.get(memory.covered..memory.covered + settings.chunk_size.max(1))?;
+    if memory.covered + chunk.len() > omitted + 1 {
+        return None;
+    }
+
+    let transcript: Vec<String> = chunk
+        .iter()
+        .filter(|message| message.role != MessageRole::System && !message.text().trim().is_empty())
+        .map(|message| format!("{}: {}", speakers.name(message), message.text().trim()))
+        .collect();
+    Some(SummaryTask {
+        start: chunk[0].id.clone(),
+        end: chunk[chunk.len() - 1].id.clone(),
+        level: 0,
+        messages: instruction(SUMMARY_PROMPT, &transcript.join("\n\n")),
+    })
+}
+
+fn instruction(prompt: &str, text: &str) -> Vec<MessageHistoryItem> {
+    vec![
+        MessageHistoryItem {
+            role: MessageRole::System,
+            content: prompt.to_string(),
+        },
+        MessageHistoryItem {
+            role: MessageRole::User,
+            content: text.to_string(),
+        },
+    ]
+}
+
+pub fn summary_request(
+    messages: Vec<MessageHistoryItem>,
+    settings: &MemorySettings,
+) -> CompletionRequest {
+    CompletionRequest {
+        messages,
+        temperature: Some(0.2),
+        model: "unused".into(),
+        stream: false,
+        max_tokens: Some(settings.max_tokens),
+        ..Default::default()
+    }
+}
+
+/// Writes every summary that is due with `summarize`, adds them to the chat and returns
+/// them.
+pub async fn update_memory<F, Fut>(
+    chat: &mut Chat,
+    settings: &MemorySettings,
+    last_omitted: Option<&str>,
+    speakers: &Speakers<'_>,
+    mut summarize: F,
+) -> Result<Vec<ChatSummary>>
+where
+    F: FnMut(Vec<MessageHistoryItem>) -> Fut,
+    Fut: Future<Output = Result<String>>,
+{
+    let mut added = Vec::new();
+    if !settings.enabled {
+        return Ok(added);
+    }
+
+    while let Some(task) = next_task(chat, settings, last_omitted, speakers) {
+        let content = summarize(task.messages).await?;
+        let summary = chat.add_summary(&task.start, &task.end, task.level, content.trim())?;
+        added.push(summary.clone());
+    }
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use erpy_types::{test_support::dialogue, Chat};
+
+    use super::{update_memory, Speakers};
+    use crate::{
+        config::{MemorySettings, PromptSettings},
+        prompt::{PromptBuilder, PromptSection},
+    };
+
+    fn chat_with(messages: usize) -> Chat {
+        let texts: Vec<String> = (0..messages).map(|i| format!("message {i}")).collect();
+        dialogue(&texts.iter().map(String::as_str).collect::<Vec<_>>())
+    }
+
+    const SPEAKERS: Speakers = Speakers {
+        user: "Bob",
+        character: "Alice",
+        group: &[],
+    };
+
+    fn settings() -> MemorySettings {
+        MemorySettings {
+            chunk_size: 2,
+            merge_count: 2,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_summarizes_omitted_chunks_and_merges_them() {
+        let mut chat = chat_with(7);
+        let last_omitted = chat.history()[4].id.clone();
+        let mut requests = Vec::new();
+        let added = update_memory(
+            &mut chat,
+            &settings(),
+            Some(&last_omitted),
+            &SPEAKERS,
+            |messages| {
+                let summary = format!("summary {}", requests.len());
+                requests.push(messages[1].content.clone());
+                async move { Ok(summary) }
+            },
+        )
+        .await
+        .unwrap();
+
+        // two chunks of two messages, merged, while the fifth message waits for the sixth
+        assert_eq!(
+            requests,
+            vec![
+                "Alice: message 0\n\nBob: message 1",
+                "Alice: message 2\n\nBob: message 3",
+                "summary 0\n\nsummary 1",
+            ]
+        );
+        let levels: Vec<u32> = added.iter().map(|summary| summary.level).collect();
+        assert_eq!(levels, vec![0, 0, 1]);
+        let memory = chat.memory();
+        assert_eq!(memory.summaries.len(), 1);
+        assert_eq!(memory.summaries[0].content, "summary 2");
+        assert_eq!(memory.covered, 4);
+
+        let disabled = MemorySettings {
+            enabled: false,
+            ..settings()
+        };
+        let mut chat = chat_with(7);
+        let added = update_memory(
+            &mut chat,
+            &disabled,
+            Some(&last_omitted),
+            &SPEAKERS,
+            |_| async { Ok(String::new()) },
+        )
+        .await
+        .unwrap();
+        assert!(added.is_empty());
+    }
+
+    #[test]
+    fn test_summaries_replace_messages_in_the_prompt() {
+        let mut chat = chat_with(6);
+        let history: Vec<String> = chat.history().iter().map(|m| m.id.clone()).collect();
+        let summary = chat
+            .add_summary(&history[0], &history[3], 0, "Alice met {{user}}.")
+            .unwrap()
+            .id
+            .clone();
+        let character = Default::default();
+        let settings = PromptSettings {
+            sections: vec![PromptSection::Memory, PromptSection::History],
+            max_context_tokens: Some(12),
+            ..Default::default()
+        };
+
+        let prompt = PromptBuilder::new(&chat, &character, "Bob", &settings).build_prompt();
+        let contents: Vec<&str> = prompt
+            .messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect();
+        assert_eq!(
+            contents,
+            vec!["Summary of the story so far:\nAlice met Bob.", "message 5"]
+        );
+        assert_eq!(prompt.summaries, vec![summary]);
+        assert_eq!(prompt.last_omitted_message.as_ref(), Some(&history[4]));
+    }
+}
@@ -0,0 +1,155 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::prelude::*;
+use camino::Utf8PathBuf;
+use erpy_types::{Persona, PersonaLock};
+
+use crate::assets::AssetStore;
+
+/// The user's personas, kept in a single JSON file.
+///
+/// At most one persona is the default, and every character or chat is locked to at most
+/// one persona, so saving a persona takes these away from the others.
+#[derive(Debug, Clone)]
+pub struct PersonaStore {
+    path: Utf8PathBuf,
+}
+
+impl PersonaStore {
+    pub fn new(path: impl Into<Utf8PathBuf>) -> Self {
+        PersonaStore { path: path.into() }
+    }
+
+    pub fn list(&self) -> Result<Vec<Persona>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let json = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("failed to read personas from {}", self.path))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("failed to parse personas in {}", self.path))
+    }
+
+    /// Adds the persona or replaces the one with the same id. Personas without an id get
+    /// a new one.
+    pub fn save(&self, mut persona: Persona) -> Result<Persona> {
+        if persona.name.trim().is_empty() {
+            bail!("a persona needs a name");
+        }
+        if persona.id.is_empty() {
+            persona.id = uuid::Uuid::new_v4().to_string();
+        }
+
+        let mut personas = self.list()?;
+        for other in personas.iter_mut().filter(|other| other.id != persona.id) {
+            other.is_default &= !persona.is_default;
+            other
+                .locked_characters
+                .retain(|id| !persona.locked_characters.contains(id));
+            other
+                .locked_chats
+                .retain(|id| !persona.locked_chats.contains(id));
+        }
+        match personas.iter_mut().find(|other| other.id == persona.id) {
+            Some(existing) => *existing = persona.clone(),
+            None => personas.push(persona.clone()),
+        }
+
+        self.write(&personas)?;
+        Ok(persona)
+    }
+
+    pub fn delete(&self, id: &str) -> Result<()> {
+        let mut personas = self.list()?;
+        personas.retain(|persona| persona.id != id);
+        self.write(&personas)
+    }
+
+    /// Locks a persona to a character or chat, or unlocks it.
+    pub fn set_locked(&self, id: &str, lock: &PersonaLock, locked: bool) -> Result<Persona> {
+        let mut persona = self
+            .list()?
+            .into_iter()
+            .find(|persona| persona.id == id)
+            .ok_or_else(|| anyhow!("no persona with id '{id}'"))?;
+        persona.set_locked(lock, locked);
+        self.save(persona)
+    }
+
+    fn write(&self, personas: &[Persona]) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create directory {parent}"))?;
+        }
+        let json = serde_json::to_string_pretty(personas)?;
+        std::fs::write(&self.path, json)
+            .with_context(|| format!("failed to write personas to {}", self.path))
+    }
+}
+
+/// Stores an avatar sent as a data URL and returns its asset id.
+pub fn store_avatar(assets: &AssetStore, data_url: &str) -> Result<String> {
+    let (header, data) = data_url
+        .strip_prefix("data:")
+        .and_then(|data_url| data_url.split_once(";base64,"))
+        .ok_or_else(|| anyhow!("avatar is not a base64 data URL"))?;
+    let ext = match header {
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/webp" => "webp",
+        "image/gif" => "gif",
+        _ => bail!("unsupported avatar type '{header}'"),
+    };
+    let bytes = BASE64_STANDARD
+        .decode(data)
+        .context("avatar is not valid base64")?;
+    assets.put(&bytes, ext)
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use erpy_types::{Persona, PersonaLock};
+
+    use super::{store_avatar, PersonaStore};
+    use crate::assets::AssetStore;
+
+    #[test]
+    fn test_persona_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+        let store = PersonaStore::new(root.join("personas.json"));
+        assert!(store.list().unwrap().is_empty());
+
+        let dave = store
+            .save(Persona {
+                name: "Dave".into(),
+                is_default: true,
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(!dave.id.is_empty());
+        let knight = store
+            .save(Persona {
+                is_default: true,
+                ..Persona::new("Sir Dave")
+            })
+            .unwrap();
+        let arthur = PersonaLock::Character("arthur".into());
+        store.set_locked(&dave.id, &arthur, true).unwrap();
+        store.set_locked(&knight.id, &arthur, true).unwrap();
+
+        let personas = store.list().unwrap();
+        assert_eq!(personas.len(), 2);
+        assert!(!personas[0].is_default && !personas[0].is_locked_to(&arthur));
+        assert!(personas[1].is_default && personas[1].is_locked_to(&arthur));
+
+        store.delete(&dave.id).unwrap();
+        assert_eq!(store.list().unwrap(), vec![personas[1].clone()]);
+        assert!(store.save(Persona::new(" ")).is_err());
+
+        let assets = AssetStore::new(root.join("assets"));
+        let id = store_avatar(&assets, "data:image/png;base64,iVBORw0KGgo=").unwrap();
+        assert!(id.ends_with(".png"));
+        assert!(store_avatar(&assets, "data:text/plain;base64,aGk=").is_err());
+    }
+}
@@ -0,0 +1,1039 @@
+use std::cmp::Reverse;
+
+use erpy_ai::{estimate_tokens, MessageHistoryItem};
+use erpy_types::{
+    hash::stable_hash, CharacterInformation, Chat, ChatMessage, InjectedEntry, Lorebook,
+    LorebookEntry, LorebookPosition, Memory, MessageRole, Persona,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    activation::{Activation, LorebookScanner},
+    config::PromptSettings,
+    group::GroupPrompt,
+    macros::MacroContext,
+};
+
+/// The depth of lorebook entries at [`LorebookPosition::AtDepth`] that don't set one.
+const DEFAULT_LOREBOOK_DEPTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PromptSection {
+    SystemPrompt,
+    Description,
+    Personality,
+    Scenario,
+    /// The description of the user's persona.
+    Persona,
+    ExampleDialogues,
+    /// The summaries of the older messages, which are left out of the history.
+    Memory,
+    /// Older messages that are relevant to the latest ones, found with embeddings.
+    Recollections,
+    History,
+    PostHistoryInstructions,
+}
+
+impl PromptSection {
+    pub const DEFAULT_ORDER: [PromptSection; 10] = [
+        PromptSection::SystemPrompt,
+        PromptSection::Description,
+        PromptSection::Personality,
+        PromptSection::Scenario,
+        PromptSection::Persona,
+        PromptSection::ExampleDialogues,
+        PromptSection::Memory,
+        PromptSection::Recollections,
+        PromptSection::History,
+        PromptSection::PostHistoryInstructions,
+    ];
+}
+
+/// Assembles the messages sent to the model from a chat and the character it is with.
+///
+/// Consecutive sections other than the history are merged into a single system message.
+/// System messages stored in the chat history are skipped, since the builder recreates
+/// them from the character. Macros are expanded in the order the sections appear in.
+///
+/// Activated lorebook entries are inserted around the character definitions (description,
+/// personality and scenario), the example dialogues, the author's note or into the history.
+pub struct PromptBuilder<'a> {
+    chat: &'a Chat,
+    character: &'a CharacterInformation,
+    user_name: &'a str,
+    settings: &'a PromptSettings,
+    lorebooks: Vec<&'a Lorebook>,
+    seed: Option<u64>,
+    group: Option<&'a GroupPrompt>,
+    persona: Option<&'a Persona>,
+    recollections: &'a [String],
+    recollection_template: &'a str,
+}
+
+enum PromptPart {
+    Section(PromptSection, String),
+    Lore(String),
+    History,
+}
+
+impl PromptPart {
+    fn system_text(&self) -> Option<&str> {
+        match self {
+            PromptPart::Section(_, text) | PromptPart::Lore(text) => Some(text),
+            PromptPart::History => None,
+        }
+    }
+
+    fn is_character_definition(&self) -> bool {
+        matches!(
+            self,
+            PromptPart::Section(
+                PromptSection::Description | PromptSection::Personality | PromptSection::Scenario,
+                _
+            )
+        )
+    }
+}
+
+/// The messages sent to the model, and the lorebook entries and summaries inserted into them.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Prompt {
+    pub messages: Vec<MessageHistoryItem>,
+    pub lorebook_entries: Vec<InjectedEntry>,
+    /// The ids of the summaries in the prompt.
+    pub summaries: Vec<String>,
+    /// The newest message that was left out to stay within the token budget.
+    pub last_omitted_message: Option<String>,
+}
+
+/// The text of the activated lorebook entries, grouped by where they are inserted.
+#[derive(Default)]
+struct LoreInsertions {
+    entries: Vec<InjectedEntry>,
+    before_character: Vec<String>,
+    after_character: Vec<String>,
+    before_examples: Vec<String>,
+    after_examples: Vec<String>,
+    before_author_note: Vec<String>,
+    after_author_note: Vec<String>,
+    at_depth: Vec<(usize, String)>,
+}
+
+impl<'a> PromptBuilder<'a> {
+    pub fn new(
+        chat: &'a Chat,
+        character: &'a CharacterInformation,
+        user_name: &'a str,
+        settings: &'a PromptSettings,
+    ) -> Self {
+        PromptBuilder {
+            chat,
+            character,
+            user_name,
+            settings,
+            lorebooks: character.character_book.iter().collect(),
+            seed: None,
+            group: None,
+            persona: None,
+            recollections: &[],
+            recollection_template: "",
+        }
+    }
+
+    /// Scans these lorebooks in addition to the character's own.
+    pub fn with_lorebooks(mut self, lorebooks: impl IntoIterator<Item = &'a Lorebook>) -> Self {
+        self.lorebooks.extend(lorebooks);
+        self
+    }
+
+    /// Seeds the macro engine, so that random macros expand the same way every time.
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Builds the prompt for the speaker's reply in a group chat, with the messages of the
+    /// other characters prefixed with their names.
+    pub fn with_group(mut self, group: &'a GroupPrompt) -> Self {
+        self.group = Some(group);
+        self
+    }
+
+    /// Speaks as the persona: `{{user}}` is its name and its description is added to the
+    /// prompt. Without one, `{{user}}` is the user name the builder was created with.
+    pub fn with_persona(mut self, persona: Option<&'a Persona>) -> Self {
+        if let Some(persona) = persona {
+            self.user_name = &persona.name;
+        }
+        self.persona = persona;
+        self
+    }
+
+    /// Inserts these messages, by id, into the template at `{{memories}}`.
+    pub fn with_recollections(mut self, message_ids: &'a [String], template: &'a str) -> Self {
+        self.recollections = message_ids;
+        self.recollection_template = template;
+        self
+    }
+
+    /// Finds the lorebook entries activated by the chat history.
+    pub fn activate_lorebooks(&self) -> Activation<'a> {
+        let messages: Vec<&str> = self
+            .chat
+            .history()
+            .into_iter()
+            .filter(|message| message.role != MessageRole::System)
+            .map(|message| message.text())
+            .collect();
+
+        let seed = stable_hash(&self.chat.id).wrapping_add(self.seed.unwrap_or_default());
+        LorebookScanner::new(self.lorebooks.iter().copied())
+            .with_seed(seed)
+            .activate(&messages)
+    }
+
+    pub fn build(&self) -> Vec<MessageHistoryItem> {
+        self.build_prompt().messages
+    }
+
+    fn macros(&self) -> MacroContext {
+        let macros = MacroContext::for_chat(self.chat, &self.character.name, self.user_name);
+        match self.seed {
+            Some(seed) => macros.with_seed(seed),
+            None => macros,
+        }
+    }
+
+    /// Expands the macros in text that is added to the prompt, like an instruction after it.
+    pub fn expand_macros(&self, text: &str) -> String {
+        self.macros().expand(text)
+    }
+
+    pub fn build_prompt(&self) -> Prompt {
+        let mut macros = self.macros();
+
+        let lore = self.lore_insertions(&mut macros);
+        let mut parts = Vec::new();
+        let mut history = None;
+        for section in &self.settings.sections {
+            if *section == PromptSection::History {
+                if history.is_none() {
+                    history = Some(self.history(&mut macros));
+                }
+                parts.push(PromptPart::History);
+            } else if let Some(text) = self.section_text(*section, &mut macros) {
+                parts.push(PromptPart::Section(*section, text));
+            }
+        }
+        insert_lore_parts(
+            &mut parts,
+            lore.before_character,
+            lore.after_character,
+            |part| part.is_character_definition(),
+        );
+        insert_lore_parts(
+            &mut parts,
+            lore.before_examples,
+            lore.after_examples,
+            |part| {
+                matches!(
+                    part,
+                    PromptPart::Section(PromptSection::ExampleDialogues, _)
+                )
+            },
+        );
+
+        let author_note = self
+            .author_note(&mut macros)
+            .into_iter()
+            .chain(lore.after_author_note);
+        let author_note: Vec<_> = lore
+            .before_author_note
+            .into_iter()
+            .chain(author_note)
+            .collect();
+        let mut insertions = lore.at_depth;
+        if !author_note.is_empty() {
+            insertions.push((self.settings.author_note_depth, author_note.join("\n")));
+        }
+
+        let (history_ids, mut history): (Vec<_>, Vec<_>) =
+            history.unwrap_or_default().into_iter().unzip();
+        let omitted = self.fit_history(&mut history, &parts, &insertions);
+        let last_omitted_message = omitted
+            .checked_sub(1)
+            .map(|index| history_ids[index].to_string());
+
+        // indices refer to the history before any insertion, so the latest ones go first
+        let len = history.len();
+        let mut insertions: Vec<_> = insertions
+            .into_iter()
+            .enumerate()
+            .map(|(order, (depth, text))| (len.saturating_sub(depth), order, text))
+            .collect();
+        insertions.sort_by_key(|(index, order, _)| Reverse((*index, *order)));
+        for (index, _, content) in insertions {
+            history.insert(
+                index,
+                MessageHistoryItem {
+                    role: MessageRole::System,
+                    content,
+                },
+            );
+        }
+
+        let mut messages = Vec::new();
+        let mut system_parts = Vec::new();
+        for part in parts {
+            match part {
+                PromptPart::Section(_, text) | PromptPart::Lore(text) => system_parts.push(text),
+                PromptPart::History => {
+                    flush_system_parts(&mut system_parts, &mut messages);
+                    messages.extend(history.iter().cloned());
+                }
+            }
+        }
+        flush_system_parts(&mut system_parts, &mut messages);
+
+        Prompt {
+            messages,
+            lorebook_entries: lore.entries,
+            summaries: self
+                .memory()
+                .summaries
+                .iter()
+                .map(|summary| summary.id.clone())
+                .collect(),
+            last_omitted_message,
+        }
+    }
+
+    /// Builds the prompt for continuing the last message of the chat. What follows the history,
+    /// like the post-history instructions, goes before that message so that it comes last.
+    pub fn build_continuation(&self) -> Prompt {
+        let mut prompt = self.build_prompt();
+        let last = prompt
+            .messages
+            .iter()
+            .rposition(|message| message.role != MessageRole::System);
+        if let Some(last) = last {
+            let message = prompt.messages.remove(last);
+            prompt.messages.push(message);
+        }
+        prompt
+    }
+
+    fn lore_insertions(&self, macros: &mut MacroContext) -> LoreInsertions {
+        let mut lore = LoreInsertions::default();
+        let mut at_depth: Vec<(usize, Vec<String>)> = Vec::new();
+        for entry in self.activate_lorebooks().entries {
+            let content = macros.expand(entry.content.trim());
+            let content = content.trim().to_string();
+            if content.is_empty() {
+                continue;
+            }
+            lore.entries.push(self.injected_entry(entry));
+
+            let group = match entry.position {
+                LorebookPosition::BeforeCharacter => &mut lore.before_character,
+                LorebookPosition::AfterCharacter => &mut lore.after_character,
+                LorebookPosition::BeforeExamples => &mut lore.before_examples,
+                LorebookPosition::AfterExamples => &mut lore.after_examples,
+                LorebookPosition::BeforeAuthorNote => &mut lore.before_author_note,
+                LorebookPosition::AfterAuthorNote => &mut lore.after_author_note,
+                LorebookPosition::AtDepth => {
+                    let depth = entry.depth.unwrap_or(DEFAULT_LOREBOOK_DEPTH);
+                    match at_depth.iter_mut().find(|(d, _)| *d == depth) {
+                        Some((_, group)) => group,
+                        None => {
+                            at_depth.push((depth, Vec::new()));
+                            &mut at_depth.last_mut().unwrap().1
+                        }
+                    }
+                }
+            };
+            group.push(content);
+        }
+
+        lore.at_depth = at_depth
+            .into_iter()
+            .map(|(depth, texts)| (depth, texts.join("\n")))
+            .collect();
+        lore
+    }
+
+    fn injected_entry(&self, entry: &LorebookEntry) -> InjectedEntry {
+        let lorebook = self
+            .lorebooks
+            .iter()
+            .find(|lorebook| lorebook.entries.iter().any(|e| std::ptr::eq(e, entry)));
+        InjectedEntry {
+            lorebook: lorebook.map(|l| l.name.clone()).unwrap_or_default(),
+            id: entry.id,
+            name: entry.name.clone(),
+        }
+    }
+
+    fn section_text(&self, section: PromptSection, macros: &mut MacroContext) -> Option<String> {
+        let character = self.character;
+        let text = match section {
+            PromptSection::SystemPrompt => character.system_prompt.clone(),
+            PromptSection::Description => character.description.clone(),
+            PromptSection::Personality if !character.personality.trim().is_empty() => {
+                format!(
+                    "{{{{char}}}}'s personality: {}",
+                    character.personality.trim()
+                )
+            }
+            PromptSection::Scenario if !character.scenario.trim().is_empty() => {
+                format!("Scenario: {}", character.scenario.trim())
+            }
+            PromptSection::Persona => match self.persona {
+                Some(persona) if !persona.description.trim().is_empty() => {
+                    format!("{{{{user}}}}'s persona: {}", persona.description.trim())
+                }
+                _ => return None,
+            },
+            PromptSection::ExampleDialogues => character.example_dialogues.clone(),
+            PromptSection::Memory => {
+                let memory = self.memory();
+                if memory.summaries.is_empty() {
+                    return None;
+                }
+                let summaries: Vec<&str> = memory
+                    .summaries
+                    .iter()
+                    .map(|summary| summary.content.trim())
+                    .collect();
+                format!("Summary of the story so far:\n{}", summaries.join("\n\n"))
+            }
+            PromptSection::Recollections => {
+                let history = self.chat.history();
+                let recollections: Vec<String> = history
+                    .iter()
+                    .filter(|message| self.recollections.contains(&message.id))
+                    .map(|message| format!("{}: {}", self.speaker(message), message.text().trim()))
+                    .collect();
+                if recollections.is_empty() {
+                    return None;
+                }
+                self.recollection_template
+                    .replace("{{memories}}", &recollections.join("\n\n"))
+            }
+            PromptSection::PostHistoryInstructions => character.post_history_instructions.clone(),
+            PromptSection::Personality | PromptSection::Scenario | PromptSection::History => {
+                return None
+            }
+        };
+
+        let text = macros.expand(text.trim());
+        let text = text.trim();
+        if text.is_empty() {
+            None
+        } else {
+            Some(text.to_string())
+        }
+    }
+
+    fn author_note(&self, macros: &mut MacroContext) -> Option<String> {
+        let note = macros.expand(self.settings.author_note.as_deref()?);
+        let note = note.trim();
+        if note.is_empty() {
+            None
+        } else {
+            Some(note.to_string())
+        }
+    }
+
+    /// The summaries that replace the oldest messages, if the prompt has room for them.
+    fn memory(&self) -> Memory<'a> {
+        if self.settings.sections.contains(&PromptSection::Memory) {
+            self.chat.memory()
+        } else {
+            Memory::default()
+        }
+    }
+
+    /// The messages after the ones the summaries cover, by id.
+    fn history(&self, macros: &mut MacroContext) -> Vec<(&'a str, MessageHistoryItem)> {
+        self.chat
+            .history()
+            .into_iter()
+            .skip(self.memory().covered)
+            .filter(|message| message.role != MessageRole::System)
+            .filter_map(|message| {
+                let content = macros.expand(message.text());
+                if content.trim().is_empty() {
+                    None
+                } else {
+                    let content = match self.other_speaker(message.character_id.as_deref()) {
+                        Some(name) => format!("{name}: {content}"),
+                        None => content,
+                    };
+                    Some((
+                        message.id.as_str(),
+                        MessageHistoryItem {
+                            role: message.role,
+                            content,
+                        },
+                    ))
+                }
+            })
+            .collect()
+    }
+
+    /// The name of whoever wrote the message.
+    fn speaker(&self, message: &ChatMessage) -> &'a str {
+        if message.role == MessageRole::User {
+            return self.user_name;
+        }
+        let group_member = self.group.and_then(|group| {
+            group
+                .characters
+                .iter()
+                .find(|character| Some(character.id.as_str()) == message.character_id.as_deref())
+        });
+        group_member.map_or(&self.character.name, |character| &character.payload.name)
+    }
+
+    /// The name of a character in the group other than the one replying.
+    fn other_speaker(&self, character_id: Option<&str>) -> Option<&'a str> {
+        let group = self.group?;
+        let character_id = character_id.filter(|id| *id != group.speaker)?;
+        group
+            .characters
+            .iter()
+            .find(|character| character.id == character_id)
+            .map(|character| character.payload.name.as_str())
+    }
+
+    /// Leaves out the oldest messages once the history exceeds the token budget left
+    /// after all other sections and the messages inserted into the history. Returns how
+    /// many were left out.
+    fn fit_history(
+        &self,
+        history: &mut Vec<MessageHistoryItem>,
+        parts: &[PromptPart],
+        insertions: &[(usize, String)],
+    ) -> usize {
+        let Some(max_tokens) = self.settings.max_context_tokens else {
+            return 0;
+        };
+
+        let fixed_text: usize = parts
+            .iter()
+            .filter_map(PromptPart::system_text)
+            .chain(insertions.iter().map(|(_, text)| text.as_str()))
+            .map(str::len)
+            .sum();
+        let budget = max_tokens.saturating_sub(fixed_text / 4);
+
+        let mut used = 0;
+        let kept = history
+            .iter()
+            .rev()
+            .take_while(|message| {
+                used += estimate_tokens(std::slice::from_ref(*message));
+                used <= budget
+            })
+            .count();
+        let omitted = history.len() - kept;
+        history.drain(..omitted);
+        omitted
+    }
+}
+
+/// Inserts lorebook text before the first and after the last part matching `anchor`.
+/// Without such a part, the text goes after the system prompt.
+fn insert_lore_parts(
+    parts: &mut Vec<PromptPart>,
+    before: Vec<String>,
+    after: Vec<String>,
+    anchor: impl Fn(&PromptPart) -> bool,
+) {
+    let lore = |texts: Vec<String>| {
+        if texts.is_empty() {
+            None
+        } else {
+            Some(PromptPart::Lore(texts.join("\n")))
+        }
+    };
+
+    let first = parts.iter().position(&anchor);
+    let last = parts.iter().rposition(&anchor);
+    let (start, end) = match (first, last) {
+        (Some(first), Some(last)) => (first, last + 1),
+        _ => {
+            let index = parts
+                .iter()
+                .position(|part| {
+                    matches!(part, PromptPart::Section(PromptSection::SystemPrompt, _))
+                })
+                .map_or(0, |index| index + 1);
+            (index, index)
+        }
+    };
+
+    if let Some(part) = lore(after) {
+        parts.insert(end, part);
+    }
+    if let Some(part) = lore(before) {
+        parts.insert(start, part);
+    }
+}
+
+fn flush_system_parts(parts: &mut Vec<String>, messages: &mut Vec<MessageHistoryItem>) {
+    if !parts.is_empty() {
+        messages.push(MessageHistoryItem {
+            role: MessageRole::System,
+            content: parts.join("\n\n"),
+        });
+        parts.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use erpy_ai::MessageHistoryItem;
+    use erpy_types::{
+        test_support::{self, content},
+        CharacterInformation, Chat, Lorebook, LorebookEntry, LorebookPosition, MessageRole,
+        Persona,
+    };
+
+    use super::{PromptBuilder, PromptSection};
+    use crate::config::PromptSettings;
+
+    fn chat() -> Chat {
+        test_support::chat(&[
+            (MessageRole::System, "stored system prompt"),
+            (MessageRole::Assistant, "Hello {{user}}, I'm {{char}}."),
+            (MessageRole::User, "Hi Alice!"),
+            (MessageRole::Assistant, "How are you?"),
+            (MessageRole::User, "Great."),
+        ])
+    }
+
+    fn character() -> CharacterInformation {
+        CharacterInformation {
+            name: "Alice".into(),
+            description: "{{char}} is a librarian.".into(),
+            personality: "curious".into(),
+            first_messages: vec!["Hello {{user}}, I'm {{char}}.".into()],
+            tags: vec![],
+            system_prompt: "Write {{char}}'s next reply to {{user}}.".into(),
+            scenario: "A quiet afternoon in the library.".into(),
+            example_dialogues: "<START>\n{{user}}: Hi\n{{char}}: Shh!".into(),
+            post_history_instructions: "Stay in character.".into(),
+            ..Default::default()
+        }
+    }
+
+    fn only(section: PromptSection) -> PromptSettings {
+        PromptSettings {
+            sections: vec![section],
+            ..Default::default()
+        }
+    }
+
+    fn build(settings: &PromptSettings) -> Vec<MessageHistoryItem> {
+        let chat = chat();
+        let character = character();
+        PromptBuilder::new(&chat, &character, "Bob", settings).build()
+    }
+
+    fn contents(messages: &[MessageHistoryItem]) -> Vec<(MessageRole, &str)> {
+        messages
+            .iter()
+            .map(|m| (m.role, m.content.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_system_prompt() {
+        let messages = build(&only(PromptSection::SystemPrompt));
+        assert_eq!(
+            contents(&messages),
+            vec![(MessageRole::System, "Write Alice's next reply to Bob.")]
+        );
+    }
+
+    #[test]
+    fn test_description() {
+        let messages = build(&only(PromptSection::Description));
+        assert_eq!(
+            contents(&messages),
+            vec![(MessageRole::System, "Alice is a librarian.")]
+        );
+    }
+
+    #[test]
+    fn test_personality() {
+        let messages = build(&only(PromptSection::Personality));
+        assert_eq!(
+            contents(&messages),
+            vec![(MessageRole::System, "Alice's personality: curious")]
+        );
+    }
+
+    #[test]
+    fn test_scenario() {
+        let messages = build(&only(PromptSection::Scenario));
+        assert_eq!(
+            contents(&messages),
+            vec![(
+                MessageRole::System,
+                "Scenario: A quiet afternoon in the library."
+            )]
+        );
+    }
+
+    #[test]
+    fn test_example_dialogues() {
+        let messages = build(&only(PromptSection::ExampleDialogues));
+        assert_eq!(
+            contents(&messages),
+            vec![(MessageRole::System, "<START>\nBob: Hi\nAlice: Shh!")]
+        );
+    }
+
+    #[test]
+    fn test_persona() {
+        let chat = chat();
+        let character = character();
+        let persona = Persona {
+            description: "{{user}} is a student.".into(),
+            ..Persona::new("Dave")
+        };
+        let settings = PromptSettings {
+            sections: vec![PromptSection::SystemPrompt, PromptSection::Persona],
+            ..Default::default()
+        };
+
+        let messages = PromptBuilder::new(&chat, &character, "Bob", &settings)
+            .with_persona(Some(&persona))
+            .build();
+        assert_eq!(
+            contents(&messages),
+            vec![(
+                MessageRole::System,
+                "Write Alice's next reply to Dave.\n\nDave's persona: Dave is a student."
+            )]
+        );
+        assert!(build(&only(PromptSection::Persona)).is_empty());
+    }
+
+    #[test]
+    fn test_history() {
+        let messages = build(&only(PromptSection::History));
+        assert_eq!(
+            contents(&messages),
+            vec![
+                (MessageRole::Assistant, "Hello Bob, I'm Alice."),
+                (MessageRole::User, "Hi Alice!"),
+                (MessageRole::Assistant, "How are you?"),
+                (MessageRole::User, "Great."),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_history_follows_head_and_skips_empty_messages() {
+        let mut chat = chat();
+        let question = chat.history()[3].id.clone();
+        chat.add_alternative(&question, content("What brings you here?"))
+            .unwrap();
+        chat.append(MessageRole::User, content("A book."));
+        chat.append(MessageRole::Assistant, content(""));
+        let character = character();
+        let settings = only(PromptSection::History);
+
+        let messages = PromptBuilder::new(&chat, &character, "Bob", &settings).build();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[2].content, "What brings you here?");
+        assert_eq!(messages[3].content, "A book.");
+    }
+
+    #[test]
+    fn test_post_history_instructions() {
+        let settings = PromptSettings {
+            sections: vec![
+                PromptSection::History,
+                PromptSection::PostHistoryInstructions,
+            ],
+            ..Default::default()
+        };
+        let messages = build(&settings);
+        assert_eq!(messages.len(), 5);
+        assert_eq!(
+            contents(&messages[4..]),
+            vec![(MessageRole::System, "Stay in character.")]
+        );
+    }
+
+    #[test]
+    fn test_continuation() {
+        let mut chat = chat();
+        chat.append(MessageRole::Assistant, content("Let me show you"));
+        let character = character();
+        let settings = PromptSettings {
+            sections: vec![
+                PromptSection::History,
+                PromptSection::PostHistoryInstructions,
+            ],
+            author_note: Some("[{{char}} is tired.]".into()),
+            author_note_depth: 0,
+            ..Default::default()
+        };
+
+        let prompt = PromptBuilder::new(&chat, &character, "Bob", &settings).build_continuation();
+        assert_eq!(
+            contents(&prompt.messages[4..]),
+            vec![
+                (MessageRole::System, "[Alice is tired.]"),
+                (MessageRole::System, "Stay in character."),
+                (MessageRole::Assistant, "Let me show you"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_author_note_depth() {
+        let settings = PromptSettings {
+            sections: vec![PromptSection::History],
+            author_note: Some("[{{char}} is tired.]".into()),
+            author_note_depth: 1,
+            ..Default::default()
+        };
+        let messages = build(&settings);
+        assert_eq!(messages.len(), 5);
+        assert_eq!(
+            contents(&messages[3..]),
+            vec![
+                (MessageRole::System, "[Alice is tired.]"),
+                (MessageRole::User, "Great."),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_author_note_deeper_than_history() {
+        let settings = PromptSettings {
+            sections: vec![PromptSection::History],
+            author_note: Some("note".into()),
+            author_note_depth: 100,
+            ..Default::default()
+        };
+        let messages = build(&settings);
+        assert_eq!(
+            contents(&messages[..1]),
+            vec![(MessageRole::System, "note")]
+        );
+    }
+
+    #[test]
+    fn test_default_order_merges_system_sections() {
+        let messages = build(&PromptSettings::default());
+        assert_eq!(messages.len(), 6);
+        assert_eq!(messages[0].role, MessageRole::System);
+        assert_eq!(
+            messages[0].content,
+            "Write Alice's next reply to Bob.\n\nAlice is a librarian.\n\nAlice's personality: curious\n\nScenario: A quiet afternoon in the library.\n\n<START>\nBob: Hi\nAlice: Shh!"
+        );
+        assert_eq!(messages[5].content, "Stay in character.");
+    }
+
+    #[test]
+    fn test_custom_order() {
+        let settings = PromptSettings {
+            sections: vec![
+                PromptSection::Scenario,
+                PromptSection::History,
+                PromptSection::SystemPrompt,
+            ],
+            ..Default::default()
+        };
+        let messages = build(&settings);
+        assert_eq!(messages.len(), 6);
+        assert!(messages[0].content.starts_with("Scenario:"));
+        assert_eq!(messages[5].content, "Write Alice's next reply to Bob.");
+    }
+
+    #[test]
+    fn test_empty_sections_are_skipped() {
+        let chat = chat();
+        let mut character = character();
+        character.scenario = "  ".into();
+        character.personality = String::new();
+        let settings = PromptSettings {
+            sections: vec![PromptSection::Personality, PromptSection::Scenario],
+            ..Default::default()
+        };
+
+        let messages = PromptBuilder::new(&chat, &character, "Bob", &settings).build();
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn test_macros_share_variables_across_sections() {
+        let chat = chat();
+        let mut character = character();
+        character.system_prompt = "{{setvar::place::library}}You are {{char}}.".into();
+        character.scenario = "{{user}} visits the {{getvar::place}}.".into();
+        let settings = PromptSettings {
+            sections: vec![PromptSection::SystemPrompt, PromptSection::Scenario],
+            ..Default::default()
+        };
+
+        let messages = PromptBuilder::new(&chat, &character, "Bob", &settings).build();
+        assert_eq!(
+            messages[0].content,
+            "You are Alice.\n\nScenario: Bob visits the library."
+        );
+    }
+
+    #[test]
+    fn test_seeded_macros_are_deterministic() {
+        let mut chat = chat();
+        chat.append(MessageRole::User, content("{{roll:1d1000}}"));
+        let character = character();
+        let settings = only(PromptSection::History);
+
+        let build = || {
+            PromptBuilder::new(&chat, &character, "Bob", &settings)
+                .with_seed(Some(7))
+                .build()
+        };
+        assert_eq!(build()[4].content, build()[4].content);
+    }
+
+    #[test]
+    fn test_token_budget_drops_oldest_messages() {
+        let settings = PromptSettings {
+            sections: vec![PromptSection::SystemPrompt, PromptSection::History],
+            // the system prompt takes 8 tokens, leaving room for the last two messages
+            max_context_tokens: Some(12),
+            ..Default::default()
+        };
+        let messages = build(&settings);
+        assert_eq!(
+            contents(&messages),
+            vec![
+                (MessageRole::System, "Write Alice's next reply to Bob."),
+                (MessageRole::Assistant, "How are you?"),
+                (MessageRole::User, "Great."),
+            ]
+        );
+    }
+
+    fn lore(key: &str, content: &str, position: LorebookPosition) -> LorebookEntry {
+        LorebookEntry {
+            keys: vec![key.into()],
+            content: content.into(),
+            position,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_lorebook_entries_around_character_and_examples() {
+        let chat = chat();
+        let mut character = character();
+        character.character_book = Some(Lorebook {
+            entries: vec![
+                lore(
+                    "great",
+                    "Before {{char}}.",
+                    LorebookPosition::BeforeCharacter,
+                ),
+                lore(
+                    "great",
+                    "After character.",
+                    LorebookPosition::AfterCharacter,
+                ),
+                lore("great", "After examples.", LorebookPosition::AfterExamples),
+                lore(
+                    "dragon",
+                    "Not activated.",
+                    LorebookPosition::BeforeCharacter,
+                ),
+            ]
+            .into_iter()
+            .zip(0..)
+            .map(|(entry, id)| LorebookEntry {
+                id: Some(id),
+                ..entry
+            })
+            .collect(),
+            name: "Alice's book".into(),
+            ..Default::default()
+        });
+        let settings = PromptSettings {
+            sections: vec![
+                PromptSection::SystemPrompt,
+                PromptSection::Description,
+                PromptSection::Scenario,
+                PromptSection::ExampleDialogues,
+            ],
+            ..Default::default()
+        };
+
+        let prompt = PromptBuilder::new(&chat, &character, "Bob", &settings).build_prompt();
+        let injected: Vec<_> = prompt
+            .lorebook_entries
+            .iter()
+            .map(|entry| (entry.lorebook.as_str(), entry.id))
+            .collect();
+        assert_eq!(
+            injected,
+            vec![
+                ("Alice's book", Some(0)),
+                ("Alice's book", Some(1)),
+                ("Alice's book", Some(2))
+            ]
+        );
+        let messages = prompt.messages;
+        assert_eq!(
+            messages[0].content,
+            "Write Alice's next reply to Bob.\n\nBefore Alice.\n\nAlice is a librarian.\n\nScenario: A quiet afternoon in the library.\n\nAfter character.\n\n<START>\nBob: Hi\nAlice: Shh!\n\nAfter examples."
+        );
+    }
+
+    #[test]
+    fn test_lorebook_entries_at_depth_and_author_note() {
+        let chat = chat();
+        let character = character();
+        let mut at_depth = lore("great", "Deep lore.", LorebookPosition::AtDepth);
+        at_depth.depth = Some(2);
+        let extra = Lorebook {
+            entries: vec![
+                at_depth,
+                lore("how", "Before note.", LorebookPosition::BeforeAuthorNote),
+            ],
+            ..Default::default()
+        };
+        let settings = PromptSettings {
+            sections: vec![PromptSection::History],
+            author_note: Some("Note.".into()),
+            author_note_depth: 1,
+            ..Default::default()
+        };
+
+        let messages = PromptBuilder::new(&chat, &character, "Bob", &settings)
+            .with_lorebooks([&extra])
+            .build();
+        assert_eq!(
+            contents(&messages),
+            vec![
+                (MessageRole::Assistant, "Hello Bob, I'm Alice."),
+                (MessageRole::User, "Hi Alice!"),
+                (MessageRole::System, "Deep lore."),
+                (MessageRole::Assistant, "How are you?"),
+                (MessageRole::System, "Before note.\nNote."),
+                (MessageRole::User, "Great."),
+            ]
+        );
+    }
+}
@@ -0,0 +1,386 @@
+use anyhow::{anyhow, bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use erpy_ai::{
+    embeddings::{
+        cosine_similarity, EmbeddingApi, EmbeddingApis, HashingEmbeddings, OllamaEmbeddings,
+        OpenAiEmbeddings,
+    },
+    estimate_tokens, MessageHistoryItem,
+};
+use erpy_types::{Chat, ChatMessage, MessageRole};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{EmbeddingBackend, EmbeddingSettings};
+
+const DEFAULT_OLLAMA_URL: &str = "http://localhost:11434";
+/// How many messages are sent to the embedding API at once, to stay below the request
+/// limits of the servers.
+const EMBEDDING_BATCH_SIZE: usize = 32;
+
+pub fn embedding_api(settings: &EmbeddingSettings) -> Result<EmbeddingApis> {
+    let model = || {
+        settings
+            .model
+            .clone()
+            .filter(|model| !model.trim().is_empty())
+            .ok_or_else(|| anyhow!("no embedding model set"))
+    };
+    let api = match settings.backend {
+        EmbeddingBackend::OpenAi => EmbeddingApis::OpenAi(OpenAiEmbeddings::new(
+            settings
+                .api_url
+                .clone()
+                .ok_or_else(|| anyhow!("no embedding API URL set"))?,
+            settings.api_key.clone(),
+            model()?,
+        )),
+        EmbeddingBackend::Ollama => EmbeddingApis::Ollama(OllamaEmbeddings::new(
+            settings
+                .api_url
+                .clone()
+                .unwrap_or_else(|| DEFAULT_OLLAMA_URL.into()),
+            model()?,
+        )),
+        EmbeddingBackend::Hashing => EmbeddingApis::Hashing(HashingEmbeddings::default()),
+    };
+    Ok(api)
+}
+
+/// The vectors of the messages of one chat, across all of its branches.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct VectorIndex {
+    /// The model the vectors were made with.
+    pub model: String,
+    pub entries: Vec<IndexEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexEntry {
+    pub message_id: String,
+    pub vector: Vec<f32>,
+}
+
+/// Where the index of a chat is kept, below `root`.
+pub fn index_path(root: &Utf8Path, chat_id: &str) -> Result<Utf8PathBuf> {
+    let valid = !chat_id.is_empty()
+        && chat_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        bail!("invalid chat id '{chat_id}'");
+    }
+    Ok(root.join(format!("{chat_id}.json")))
+}
+
+fn is_recallable(message: &ChatMessage) -> bool {
+    message.role != MessageRole::System && !message.text().trim().is_empty()
+}
+
+impl VectorIndex {
+    pub fn load(path: &Utf8Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(VectorIndex::default());
+        }
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read vector index {path}"))?;
+        serde_json::from_str(&json).with_context(|| format!("failed to parse vector index {path}"))
+    }
+
+    pub fn save(&self, path: &Utf8Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create directory {parent}"))?;
+        }
+        std::fs::write(path, serde_json::to_string(self)?)
+            .with_context(|| format!("failed to write vector index {path}"))
+    }
+
+    fn contains(&self, message_id: &str) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| entry.message_id == message_id)
+    }
+
+    /// Embeds the messages of the checked out branch that aren't indexed yet, and returns
+    /// how many there were. The index starts over when the model changes. When a batch fails,
+    /// the batches before it stay in the index.
+    pub async fn update(&mut self, chat: &Chat, api: &impl EmbeddingApi) -> Result<usize> {
+        let model = api.model_id();
+        if self.model != model {
+            self.model = model;
+            self.entries.clear();
+        }
+
+        let missing: Vec<&ChatMessage> = chat
+            .history()
+            .into_iter()
+            .filter(|message| is_recallable(message) && !self.contains(&message.id))
+            .collect();
+        if missing.is_empty() {
+            return Ok(0);
+        }
+
+        for batch in missing.chunks(EMBEDDING_BATCH_SIZE) {
+            let texts: Vec<String> = batch
+                .iter()
+                .map(|message| message.text().to_string())
+                .collect();
+            let vectors = api.embed(&texts).await?;
+            self.entries.extend(
+                batch
+                    .iter()
+                    .zip(vectors)
+                    .map(|(message, vector)| IndexEntry {
+                        message_id: message.id.clone(),
+                        vector,
+                    }),
+            );
+        }
+        Ok(missing.len())
+    }
+
+    /// The messages accepted by `candidate`, most similar to `query` first.
+    pub fn search(&self, query: &[f32], candidate: impl Fn(&str) -> bool) -> Vec<(&str, f32)> {
+        let mut results: Vec<(&str, f32)> = self
+            .entries
+            .iter()
+            .filter(|entry| candidate(&entry.message_id))
+            .map(|entry| {
+                (
+                    entry.message_id.as_str(),
+                    cosine_similarity(query, &entry.vector),
+                )
+            })
+            .collect();
+        results.sort_by(|a, b| b.1.total_cmp(&a.1));
+        results
+    }
+}
+
+/// Finds the messages of the checked out branch that are most relevant to the latest ones,
+/// within the settings' limits. Only messages up to `last_omitted_message`, which the prompt
+/// left out of its history, are recalled. Returns their ids in the order of the chat.
+pub async fn recall(
+    index: &mut VectorIndex,
+    chat: &Chat,
+    api: &impl EmbeddingApi,
+    settings: &EmbeddingSettings,
+    last_omitted_message: Option<&str>,
+) -> Result<Vec<String>> {
+    index.update(chat, api).await?;
+
+    let branch = chat.history();
+    let Some(omitted) =
+        last_omitted_message.and_then(|id| branch.iter().position(|message| message.id == id))
+    else {
+        return Ok(Vec::new());
+    };
+    let omitted = branch[..=omitted]
+        .iter()
+        .filter(|message| is_recallable(message))
+        .count();
+    let history: Vec<&ChatMessage> = branch
+        .into_iter()
+        .filter(|message| is_recallable(message))
+        .collect();
+    let Some(older) = history.len().checked_sub(settings.min_distance.max(1)) else {
+        return Ok(Vec::new());
+    };
+    let older = older.min(omitted);
+    if older == 0 || settings.top_k == 0 {
+        return Ok(Vec::new());
+    }
+
+    let query: Vec<&str> = history
+        .iter()
+        .rev()
+        .take(2)
+        .rev()
+        .map(|message| message.text())
+        .collect();
+    let query = api
+        .embed(&[query.join("\n")])
+        .await?
+        .pop()
+        .ok_or_else(|| anyhow!("no embedding for the latest messages"))?;
+
+    let candidates = &history[..older];
+    let mut used = 0;
+    let mut recalled: Vec<usize> = Vec::new();
+    for (id, _) in index.search(&query, |id| candidates.iter().any(|m| m.id == id)) {
+        if recalled.len() == settings.top_k {
+            break;
+        }
+        let position = candidates.iter().position(|m| m.id == id).unwrap();
+        let tokens = estimate_tokens(&[MessageHistoryItem {
+            role: candidates[position].role,
+            content: candidates[position].text().to_string(),
+        }]);
+        if used + tokens <= settings.token_budget {
+            used += tokens;
+            recalled.push(position);
+        }
+    }
+
+    recalled.sort();
+    Ok(recalled
+        .into_iter()
+        .map(|position| candidates[position].id.clone())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use anyhow::Result;
+    use erpy_ai::embeddings::EmbeddingApi;
+    use erpy_types::{
+        test_support::{content, dialogue},
+        CharacterInformation, MessageRole,
+    };
+
+    use super::{recall, VectorIndex, EMBEDDING_BATCH_SIZE};
+    use crate::{
+        config::{EmbeddingSettings, PromptSettings},
+        prompt::{PromptBuilder, PromptSection},
+    };
+
+    /// Embeds texts by the topics they mention, and counts the texts and requests it embedded.
+    struct FakeEmbedder {
+        model: &'static str,
+        embedded: AtomicUsize,
+        requests: AtomicUsize,
+    }
+
+    impl FakeEmbedder {
+        fn new(model: &'static str) -> Self {
+            FakeEmbedder {
+                model,
+                embedded: AtomicUsize::new(0),
+                requests: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl EmbeddingApi for FakeEmbedder {
+        async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+            self.embedded.fetch_add(texts.len(), Ordering::SeqCst);
+            self.requests.fetch_add(1, Ordering::SeqCst);
+            Ok(texts
+                .iter()
+                .map(|text| {
+                    ["dragon", "tea", "sword"]
+                        .iter()
+                        .map(|topic| if text.contains(topic) { 1.0 } else { 0.1 })
+                        .collect()
+                })
+                .collect())
+        }
+
+        fn model_id(&self) -> String {
+            self.model.into()
+        }
+    }
+
+    fn settings() -> EmbeddingSettings {
+        EmbeddingSettings {
+            enabled: true,
+            top_k: 2,
+            min_distance: 2,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_index_is_updated_incrementally() {
+        let mut chat = dialogue(&["The dragon sleeps.", "Some tea?", ""]);
+        let embedder = FakeEmbedder::new("fake");
+        let mut index = VectorIndex::default();
+
+        assert_eq!(index.update(&chat, &embedder).await.unwrap(), 2);
+        chat.append(MessageRole::Assistant, content("A sword!"));
+        assert_eq!(index.update(&chat, &embedder).await.unwrap(), 1);
+        assert_eq!(index.update(&chat, &embedder).await.unwrap(), 0);
+        assert_eq!(embedder.embedded.load(Ordering::SeqCst), 3);
+
+        let other = FakeEmbedder::new("other");
+        assert_eq!(index.update(&chat, &other).await.unwrap(), 3);
+        assert_eq!(index.model, "other");
+        assert_eq!(index.entries.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_large_chats_are_embedded_in_batches() {
+        let texts: Vec<String> = (0..EMBEDDING_BATCH_SIZE * 2 + 1)
+            .map(|i| format!("Message {i}"))
+            .collect();
+        let chat = dialogue(&texts.iter().map(String::as_str).collect::<Vec<_>>());
+        let embedder = FakeEmbedder::new("fake");
+        let mut index = VectorIndex::default();
+
+        assert_eq!(index.update(&chat, &embedder).await.unwrap(), texts.len());
+        assert_eq!(embedder.requests.load(Ordering::SeqCst), 3);
+        assert_eq!(index.entries.len(), texts.len());
+    }
+
+    #[tokio::test]
+    async fn test_recalls_relevant_older_messages() {
+        let chat = dialogue(&[
+            "A dragon circles the tower.",
+            "I put the kettle on for tea.",
+            "The blacksmith shows you a sword.",
+            "We drink tea in silence.",
+            "Where did the dragon go?",
+            "Maybe the dragon went north.",
+        ]);
+        let history: Vec<String> = chat.history().iter().map(|m| m.id.clone()).collect();
+        let embedder = FakeEmbedder::new("fake");
+        let mut index = VectorIndex::default();
+
+        let recalled = recall(&mut index, &chat, &embedder, &settings(), Some(&history[3]))
+            .await
+            .unwrap();
+        // the dragon first, then the closest other message, in the order of the chat
+        assert_eq!(recalled.len(), 2);
+        assert_eq!(recalled[0], history[0]);
+        assert!(!recalled.contains(&history[4]) && !recalled.contains(&history[5]));
+
+        let tight = EmbeddingSettings {
+            token_budget: 7,
+            ..settings()
+        };
+        let recalled = recall(&mut index, &chat, &embedder, &tight, Some(&history[3]))
+            .await
+            .unwrap();
+        assert_eq!(recalled, vec![history[0].clone()]);
+
+        // messages that are still in the prompt aren't recalled again
+        let in_prompt = recall(&mut index, &chat, &embedder, &settings(), Some(&history[1]))
+            .await
+            .unwrap();
+        assert_eq!(in_prompt, vec![history[0].clone(), history[1].clone()]);
+        let in_prompt = recall(&mut index, &chat, &embedder, &settings(), None)
+            .await
+            .unwrap();
+        assert!(in_prompt.is_empty());
+
+        let character = CharacterInformation {
+            name: "Alice".into(),
+            ..Default::default()
+        };
+        let prompt_settings = PromptSettings {
+            sections: vec![PromptSection::Recollections],
+            ..Default::default()
+        };
+        let messages = PromptBuilder::new(&chat, &character, "Bob", &prompt_settings)
+            .with_recollections(&recalled, "Earlier:\n{{memories}}")
+            .build();
+        assert_eq!(
+            messages[0].content,
+            "Earlier:\nAlice: A dragon circles the tower."
+        );
+    }
+}
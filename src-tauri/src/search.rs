@@ -0,0 +1,636 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Bound,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context, Result};
+use camino::Utf8Path;
+use chrono::{DateTime, Utc};
+use erpy_types::{hash::stable_hash, Chat, ChatMessage, MessageRole};
+use log::info;
+use serde::{Deserialize, Serialize};
+use tantivy::{
+    collector::{DocSetCollector, TopDocs},
+    directory::MmapDirectory,
+    query::{AllQuery, BooleanQuery, Query, QueryParser, RangeQuery, TermQuery},
+    schema::{
+        Field, IndexRecordOption, OwnedValue, Schema, Value, FAST, INDEXED, STORED, STRING, TEXT,
+    },
+    snippet::SnippetGenerator,
+    DateTime as TantivyDateTime, Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument,
+    Term,
+};
+
+const WRITER_MEMORY: usize = 50_000_000;
+
+const DEFAULT_LIMIT: usize = 50;
+
+const SNIPPET_LENGTH: usize = 160;
+
+#[derive(Clone, Copy)]
+struct Fields {
+    chat_id: Field,
+    message_id: Field,
+    /// `{chat_id}/{message_id}`, since forks of a chat share the ids of their messages.
+    key: Field,
+    title: Field,
+    character_id: Field,
+    character_name: Field,
+    speaker_id: Field,
+    role: Field,
+    content: Field,
+    timestamp: Field,
+    archived: Field,
+    fingerprint: Field,
+}
+
+impl Fields {
+    fn schema() -> (Schema, Fields) {
+        let mut schema = Schema::builder();
+        let fields = Fields {
+            chat_id: schema.add_text_field("chat_id", STRING | STORED),
+            message_id: schema.add_text_field("message_id", STRING | STORED),
+            key: schema.add_text_field("key", STRING),
+            title: schema.add_text_field("title", TEXT | STORED),
+            character_id: schema.add_text_field("character_id", STRING),
+            character_name: schema.add_text_field("character_name", TEXT),
+            speaker_id: schema.add_text_field("speaker_id", STRING | STORED),
+            role: schema.add_text_field("role", STRING | STORED),
+            content: schema.add_text_field("content", TEXT | STORED),
+            timestamp: schema.add_date_field("timestamp", INDEXED | STORED | FAST),
+            archived: schema.add_bool_field("archived", INDEXED),
+            fingerprint: schema.add_u64_field("fingerprint", STORED),
+        };
+        (schema.build(), fields)
+    }
+}
+
+/// What to search for. Without a query, every message that passes the filters matches.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SearchQuery {
+    pub query: String,
+    /// Only chats with this character.
+    pub character_id: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub archived: Option<bool>,
+    pub role: Option<MessageRole>,
+    pub limit: Option<usize>,
+}
+
+/// A message that matches a search, best matches first.
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchHit {
+    pub chat_id: String,
+    pub message_id: String,
+    pub title: String,
+    /// The character that wrote the message, or the character of the chat.
+    pub character_id: String,
+    pub role: MessageRole,
+    pub timestamp: DateTime<Utc>,
+    pub score: f32,
+    /// A part of the message around the matched terms.
+    pub snippet: String,
+    /// The ranges of the matched terms in the snippet, in UTF-16 code units like
+    /// JavaScript strings.
+    pub highlights: Vec<[usize; 2]>,
+}
+
+/// A full-text index of the messages of every chat, kept in a directory.
+///
+/// Every message of every branch is a document, together with the title and characters of
+/// its chat. Chats are updated incrementally: only messages that are new or changed since
+/// they were indexed are written again.
+#[derive(Clone)]
+pub struct SearchIndex {
+    index: Index,
+    reader: IndexReader,
+    writer: Arc<Mutex<IndexWriter>>,
+    fields: Fields,
+}
+
+fn role_of(name: &str) -> MessageRole {
+    match name {
+        "user" => MessageRole::User,
+        "system" => MessageRole::System,
+        _ => MessageRole::Assistant,
+    }
+}
+
+fn text(document: &TantivyDocument, field: Field) -> &str {
+    document
+        .get_first(field)
+        .and_then(|value| value.as_str())
+        .unwrap_or_default()
+}
+
+/// Whether there's an index in `dir` that was made with another schema.
+fn has_other_schema(dir: &Utf8Path, schema: &Schema) -> bool {
+    MmapDirectory::open(dir)
+        .ok()
+        .and_then(|directory| Index::open(directory).ok())
+        .is_some_and(|index| index.schema() != *schema)
+}
+
+/// Converts byte offsets in `text` to UTF-16 offsets.
+fn utf16_offset(text: &str, offset: usize) -> usize {
+    text[..offset].encode_utf16().count()
+}
+
+impl SearchIndex {
+    /// Opens the index in `dir`. An index with an older schema is removed, and filled again
+    /// when the chats are indexed.
+    pub fn open(dir: &Utf8Path) -> Result<Self> {
+        let (schema, fields) = Fields::schema();
+        if has_other_schema(dir, &schema) {
+            info!("rebuilding the search index in {dir} for a new schema");
+            std::fs::remove_dir_all(dir)
+                .with_context(|| format!("failed to remove search index in {dir}"))?;
+        }
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create directory {dir}"))?;
+        let directory = MmapDirectory::open(dir)?;
+        let index = Index::open_or_create(directory, schema)
+            .with_context(|| format!("failed to open search index in {dir}"))?;
+        SearchIndex::new(index, fields)
+    }
+
+    pub fn in_memory() -> Result<Self> {
+        let (schema, fields) = Fields::schema();
+        SearchIndex::new(Index::create_in_ram(schema), fields)
+    }
+
+    fn new(index: Index, fields: Fields) -> Result<Self> {
+        let writer = index.writer(WRITER_MEMORY)?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        Ok(SearchIndex {
+            index,
+            reader,
+            writer: Arc::new(Mutex::new(writer)),
+            fields,
+        })
+    }
+
+    fn chat_term(&self, chat_id: &str) -> Term {
+        Term::from_field_text(self.fields.chat_id, chat_id)
+    }
+
+    fn message_term(&self, chat_id: &str, message_id: &str) -> Term {
+        Term::from_field_text(self.fields.key, &format!("{chat_id}/{message_id}"))
+    }
+
+    /// The fingerprints of the indexed messages of a chat, by message id.
+    fn indexed_messages(&self, chat_id: &str) -> Result<HashMap<String, u64>> {
+        let searcher = self.reader.searcher();
+        let query = TermQuery::new(self.chat_term(chat_id), IndexRecordOption::Basic);
+        let mut messages = HashMap::new();
+        for address in searcher.search(&query, &DocSetCollector)? {
+            let document: TantivyDocument = searcher.doc(address)?;
+            let fingerprint = document
+                .get_first(self.fields.fingerprint)
+                .and_then(|value| value.as_u64())
+                .unwrap_or_default();
+            messages.insert(
+                text(&document, self.fields.message_id).to_string(),
+                fingerprint,
+            );
+        }
+        Ok(messages)
+    }
+
+    fn document(
+        &self,
+        chat: &Chat,
+        message: &ChatMessage,
+        character_names: &HashMap<String, String>,
+    ) -> TantivyDocument {
+        let fields = self.fields;
+        let speaker = match message.role {
+            MessageRole::Assistant => message.character_id.as_deref(),
+            _ => None,
+        }
+        .unwrap_or(&chat.character_id);
+        let mut characters: Vec<&str> = std::iter::once(chat.character_id.as_str())
+            .chain(chat.participants.iter().map(|p| p.character_id.as_str()))
+            .collect();
+        characters.sort();
+        characters.dedup();
+        let names: Vec<Option<&String>> = characters
+            .iter()
+            .map(|id| character_names.get(*id))
+            .collect();
+        let fingerprint = stable_hash(&(
+            &chat.title,
+            chat.archived,
+            &names,
+            speaker,
+            message.role,
+            message.text(),
+            message.content.timestamp,
+        ));
+
+        let mut document = TantivyDocument::new();
+        document.add_text(fields.chat_id, &chat.id);
+        document.add_text(fields.message_id, &message.id);
+        document.add_text(fields.key, format!("{}/{}", chat.id, message.id));
+        document.add_text(fields.title, &chat.title);
+        for (character_id, name) in characters.iter().zip(names) {
+            document.add_text(fields.character_id, character_id);
+            if let Some(name) = name {
+                document.add_text(fields.character_name, name);
+            }
+        }
+        document.add_text(fields.speaker_id, speaker);
+        document.add_text(fields.role, message.role.to_string());
+        document.add_text(fields.content, message.text());
+        document.add_date(
+            fields.timestamp,
+            TantivyDateTime::from_timestamp_millis(message.content.timestamp.timestamp_millis()),
+        );
+        document.add_bool(fields.archived, chat.archived);
+        document.add_u64(fields.fingerprint, fingerprint);
+        document
+    }
+
+    /// Indexes the messages of the chats that are new or changed, and removes the ones
+    /// that were deleted. Names of the characters are looked up by id in
+    /// `character_names`. Returns how many messages were written.
+    pub fn update_chats(
+        &self,
+        chats: &[Chat],
+        character_names: &HashMap<String, String>,
+    ) -> Result<usize> {
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_| anyhow!("search index writer is poisoned"))?;
+        let mut changes = 0;
+        for chat in chats {
+            let mut indexed = self.indexed_messages(&chat.id)?;
+            for message in &chat.messages {
+                let document = self.document(chat, message, character_names);
+                let fingerprint = document
+                    .get_first(self.fields.fingerprint)
+                    .and_then(|value| value.as_u64());
+                match indexed.remove(&message.id) {
+                    Some(existing) if Some(existing) == fingerprint => continue,
+                    Some(_) => {
+                        writer.delete_term(self.message_term(&chat.id, &message.id));
+                    }
+                    None => {}
+                }
+                writer.add_document(document)?;
+                changes += 1;
+            }
+            for message_id in indexed.keys() {
+                writer.delete_term(self.message_term(&chat.id, message_id));
+                changes += 1;
+            }
+        }
+
+        if changes > 0 {
+            writer.commit()?;
+            self.reader.reload()?;
+            info!("updated {changes} messages in the search index");
+        }
+        Ok(changes)
+    }
+
+    /// Removes every chat that isn't one of `chat_ids` from the index.
+    pub fn retain_chats(&self, chat_ids: &HashSet<&str>) -> Result<()> {
+        let searcher = self.reader.searcher();
+        let mut removed = HashSet::new();
+        for address in searcher.search(&AllQuery, &DocSetCollector)? {
+            let document: TantivyDocument = searcher.doc(address)?;
+            let chat_id = text(&document, self.fields.chat_id);
+            if !chat_ids.contains(chat_id) {
+                removed.insert(chat_id.to_string());
+            }
+        }
+        if removed.is_empty() {
+            return Ok(());
+        }
+
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_| anyhow!("search index writer is poisoned"))?;
+        for chat_id in &removed {
+            writer.delete_term(self.chat_term(chat_id));
+        }
+        writer.commit()?;
+        self.reader.reload()?;
+        info!("removed {} chats from the search index", removed.len());
+        Ok(())
+    }
+
+    fn query(&self, search: &SearchQuery) -> Box<dyn Query> {
+        let fields = self.fields;
+        let mut queries: Vec<Box<dyn Query>> = Vec::new();
+        if !search.query.trim().is_empty() {
+            let mut parser = QueryParser::for_index(
+                &self.index,
+                vec![fields.content, fields.title, fields.character_name],
+            );
+            parser.set_conjunction_by_default();
+            // typos in the query syntax are searched for as text instead of failing
+            let (query, _) = parser.parse_query_lenient(&search.query);
+            queries.push(query);
+        }
+        if let Some(character_id) = &search.character_id {
+            queries.push(Box::new(TermQuery::new(
+                Term::from_field_text(fields.character_id, character_id),
+                IndexRecordOption::Basic,
+            )));
+        }
+        if let Some(role) = search.role {
+            queries.push(Box::new(TermQuery::new(
+                Term::from_field_text(fields.role, &role.to_string()),
+                IndexRecordOption::Basic,
+            )));
+        }
+        if let Some(archived) = search.archived {
+            queries.push(Box::new(TermQuery::new(
+                Term::from_field_bool(fields.archived, archived),
+                IndexRecordOption::Basic,
+            )));
+        }
+        if search.from.is_some() || search.to.is_some() {
+            let bound = |date: Option<DateTime<Utc>>| match date {
+                Some(date) => Bound::Included(TantivyDateTime::from_timestamp_millis(
+                    date.timestamp_millis(),
+                )),
+                None => Bound::Unbounded,
+            };
+            queries.push(Box::new(RangeQuery::new_date_bounds(
+                "timestamp".into(),
+                bound(search.from),
+                bound(search.to),
+            )));
+        }
+
+        match queries.len() {
+            0 => Box::new(AllQuery),
+            1 => queries.pop().unwrap(),
+            _ => Box::new(BooleanQuery::intersection(queries)),
+        }
+    }
+
+    pub fn search(&self, search: &SearchQuery) -> Result<Vec<SearchHit>> {
+        let fields = self.fields;
+        let searcher = self.reader.searcher();
+        let query = self.query(search);
+        let limit = search.limit.unwrap_or(DEFAULT_LIMIT).max(1);
+        let top_docs = searcher.search(&query, &TopDocs::with_limit(limit))?;
+        let mut snippets = SnippetGenerator::create(&searcher, &*query, fields.content)?;
+        snippets.set_max_num_chars(SNIPPET_LENGTH);
+
+        let mut hits = Vec::new();
+        for (score, address) in top_docs {
+            let document: TantivyDocument = searcher.doc(address)?;
+            let content = text(&document, fields.content);
+            let snippet = snippets.snippet(content);
+            let (snippet, highlights) = if snippet.is_empty() {
+                let end = content
+                    .char_indices()
+                    .nth(SNIPPET_LENGTH)
+                    .map_or(content.len(), |(end, _)| end);
+                (content[..end].to_string(), Vec::new())
+            } else {
+                let fragment = snippet.fragment();
+                let highlights = snippet
+                    .highlighted()
+                    .iter()
+                    .map(|range| {
+                        [
+                            utf16_offset(fragment, range.start),
+                            utf16_offset(fragment, range.end),
+                        ]
+                    })
+                    .collect();
+                (fragment.to_string(), highlights)
+            };
+            let timestamp = match document.get_first(fields.timestamp) {
+                Some(OwnedValue::Date(date)) => {
+                    DateTime::from_timestamp_millis(date.into_timestamp_millis())
+                }
+                _ => None,
+            };
+
+            hits.push(SearchHit {
+                chat_id: text(&document, fields.chat_id).to_string(),
+                message_id: text(&document, fields.message_id).to_string(),
+                title: text(&document, fields.title).to_string(),
+                character_id: text(&document, fields.speaker_id).to_string(),
+                role: role_of(text(&document, fields.role)),
+                timestamp: timestamp.unwrap_or_default(),
+                score,
+                snippet,
+                highlights,
+            });
+        }
+        Ok(hits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use camino::Utf8PathBuf;
+    use erpy_types::{test_support::content_at, Chat, MessageRole};
+    use tantivy::{
+        schema::{Schema, STRING},
+        Index,
+    };
+
+    use super::{SearchIndex, SearchQuery};
+
+    fn chats() -> Vec<Chat> {
+        let mut tavern = Chat::new("tavern", "A night at the tavern", "alice");
+        tavern.append(
+            MessageRole::Assistant,
+            content_at(
+                "The bard plays a song about the dragon.",
+                "2024-01-01T20:00:00Z",
+            ),
+        );
+        tavern.append(
+            MessageRole::User,
+            content_at(
+                "I ask the bard about the dragon's hoard.",
+                "2024-01-01T20:01:00Z",
+            ),
+        );
+
+        let mut tower = Chat::new("tower", "Climbing the tower", "bob");
+        tower.append(
+            MessageRole::Assistant,
+            content_at("A dragon circles the tower.", "2024-03-01T10:00:00Z"),
+        );
+        tower.archived = true;
+        vec![tavern, tower]
+    }
+
+    fn names() -> HashMap<String, String> {
+        HashMap::from([
+            ("alice".to_string(), "Alice".to_string()),
+            ("bob".to_string(), "Bob the Knight".to_string()),
+        ])
+    }
+
+    fn search(index: &SearchIndex, query: SearchQuery) -> Vec<(String, String)> {
+        index
+            .search(&query)
+            .unwrap()
+            .into_iter()
+            .map(|hit| (hit.chat_id, hit.snippet))
+            .collect()
+    }
+
+    #[test]
+    fn test_search_with_filters() {
+        let index = SearchIndex::in_memory().unwrap();
+        assert_eq!(index.update_chats(&chats(), &names()).unwrap(), 3);
+
+        let hits = index
+            .search(&SearchQuery {
+                query: "hoard".into(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].title, "A night at the tavern");
+        assert_eq!(hits[0].role, MessageRole::User);
+        let [start, end] = hits[0].highlights[0];
+        assert_eq!(&hits[0].snippet[start..end], "hoard");
+
+        let dragon = |query: SearchQuery| {
+            search(
+                &index,
+                SearchQuery {
+                    query: "dragon".into(),
+                    ..query
+                },
+            )
+        };
+        assert_eq!(dragon(Default::default()).len(), 3);
+        assert_eq!(
+            dragon(SearchQuery {
+                archived: Some(true),
+                ..Default::default()
+            }),
+            vec![("tower".into(), "A dragon circles the tower".into())]
+        );
+        assert_eq!(
+            dragon(SearchQuery {
+                character_id: Some("alice".into()),
+                role: Some(MessageRole::Assistant),
+                ..Default::default()
+            }),
+            vec![(
+                "tavern".into(),
+                "The bard plays a song about the dragon".into()
+            )]
+        );
+        assert_eq!(
+            dragon(SearchQuery {
+                from: Some("2024-02-01T00:00:00Z".parse().unwrap()),
+                ..Default::default()
+            })
+            .len(),
+            1
+        );
+
+        // titles and character names match too, with the start of the message as snippet
+        let knight = search(
+            &index,
+            SearchQuery {
+                query: "knight".into(),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            knight,
+            vec![("tower".into(), "A dragon circles the tower.".into())]
+        );
+    }
+
+    #[test]
+    fn test_incremental_updates() {
+        let index = SearchIndex::in_memory().unwrap();
+        let mut chats = chats();
+        index.update_chats(&chats, &names()).unwrap();
+        assert_eq!(index.update_chats(&chats, &names()).unwrap(), 0);
+
+        chats[0].append(
+            MessageRole::Assistant,
+            content_at("The innkeeper brings stew.", "2024-01-01T20:02:00Z"),
+        );
+        chats[0].messages[0].content.content = "The bard tunes his lute.".into();
+        assert_eq!(index.update_chats(&chats[..1], &names()).unwrap(), 2);
+        let stew = SearchQuery {
+            query: "stew OR lute".into(),
+            ..Default::default()
+        };
+        assert_eq!(search(&index, stew).len(), 2);
+
+        index.retain_chats(&HashSet::from(["tower"])).unwrap();
+        let everything = search(&index, SearchQuery::default());
+        assert_eq!(everything.len(), 1);
+        assert_eq!(everything[0].0, "tower");
+    }
+
+    #[test]
+    fn test_forks_share_message_ids() {
+        let index = SearchIndex::in_memory().unwrap();
+        let tavern = chats().remove(0);
+        let mut fork = tavern.fork(&tavern.messages[1].id).unwrap();
+        index
+            .update_chats(&[tavern.clone(), fork.clone()], &names())
+            .unwrap();
+        let hoard = SearchQuery {
+            query: "hoard".into(),
+            ..Default::default()
+        };
+        assert_eq!(search(&index, hoard).len(), 2);
+
+        // changing the fork leaves the messages of the original alone
+        fork.messages[1].content.content = "I ask the bard about the dragon's lair.".into();
+        assert_eq!(index.update_chats(&[fork.clone()], &names()).unwrap(), 1);
+        let hoard = SearchQuery {
+            query: "hoard".into(),
+            ..Default::default()
+        };
+        assert_eq!(search(&index, hoard)[0].0, tavern.id);
+        fork.messages.remove(0);
+        assert_eq!(index.update_chats(&[fork], &names()).unwrap(), 1);
+        let song = SearchQuery {
+            query: "song".into(),
+            ..Default::default()
+        };
+        assert_eq!(search(&index, song)[0].0, tavern.id);
+    }
+
+    #[test]
+    fn test_index_with_other_schema_is_rebuilt() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+        let mut schema = Schema::builder();
+        schema.add_text_field("message_id", STRING);
+        Index::create_in_dir(&root, schema.build()).unwrap();
+
+        let index = SearchIndex::open(&root).unwrap();
+        assert!(search(&index, SearchQuery::default()).is_empty());
+        index.update_chats(&chats(), &names()).unwrap();
+        drop(index);
+        let index = SearchIndex::open(&root).unwrap();
+        assert_eq!(search(&index, SearchQuery::default()).len(), 3);
+    }
+}
@@ -0,0 +1,604 @@
+use std::{
+    future::Future,
+    io::{Cursor, Read},
+    pin::Pin,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use erpy_types::CharacterInformation;
+use log::{debug, info, warn};
+use reqwest::{Client, Url};
+use zip::ZipArchive;
+
+use crate::{
+    avatar::AvatarPipeline,
+    character::{
+        character_from_charx_bytes, character_from_json, character_from_png_bytes, ChubAiCharacter,
+    },
+};
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const ZIP_SIGNATURE: &[u8] = b"PK\x03\x04";
+
+/// File extensions of the character formats that can be read from files.
+const CHARACTER_EXTENSIONS: [&str; 3] = ["png", "json", "charx"];
+
+pub type SourceFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Vec<CharacterInformation>>> + Send + 'a>>;
+
+/// A place characters can be imported from, identified by URL.
+///
+/// A single URL may resolve to several characters, e.g. a directory or a zip bundle.
+pub trait CharacterSource: Send + Sync {
+    /// A short name for logging.
+    fn name(&self) -> &'static str;
+
+    fn matches(&self, url: &Url) -> bool;
+
+    fn fetch<'a>(&'a self, url: &'a Url) -> SourceFuture<'a>;
+}
+
+/// Dispatches a character URL to the first registered source that matches it.
+#[derive(Default)]
+pub struct SourceRegistry {
+    sources: Vec<Box<dyn CharacterSource>>,
+}
+
+impl SourceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The built-in sources: chub.ai, local files and character files on the web.
+    pub fn with_defaults(avatars: AvatarPipeline) -> Self {
+        let client = Client::new();
+        let mut registry = Self::new();
+        registry.register(ChubAiSource::new(client.clone(), avatars.clone()));
+        registry.register(FileSource::new(avatars.clone()));
+        registry.register(HttpFileSource::new(client, avatars));
+        registry
+    }
+
+    /// Adds a source, which is tried after the ones registered before it.
+    pub fn register(&mut self, source: impl CharacterSource + 'static) {
+        self.sources.push(Box::new(source));
+    }
+
+    pub fn source_for(&self, url: &Url) -> Option<&dyn CharacterSource> {
+        self.sources
+            .iter()
+            .find(|source| source.matches(url))
+            .map(|source| source.as_ref())
+    }
+
+    pub async fn fetch(&self, input: &str) -> Result<Vec<CharacterInformation>> {
+        let url = parse_source_url(input)?;
+        let Some(source) = self.source_for(&url) else {
+            bail!("unsupported character source: {input}");
+        };
+
+        info!(
+            "fetching characters from {url} with the {} source",
+            source.name()
+        );
+        let characters = source.fetch(&url).await?;
+        if characters.is_empty() {
+            bail!("no characters found at {input}");
+        }
+        Ok(characters)
+    }
+}
+
+/// Parses a character URL. Absolute paths are accepted as `file://` URLs.
+pub fn parse_source_url(input: &str) -> Result<Url> {
+    let input = input.trim();
+    if Utf8Path::new(input).is_absolute() && !input.contains("://") {
+        return Url::from_file_path(input).map_err(|_| anyhow!("invalid file path: {input}"));
+    }
+
+    Url::parse(input).with_context(|| format!("invalid character URL: {input}"))
+}
+
+/// Characters on chub.ai, fetched from its API by the path of the character page.
+pub struct ChubAiSource {
+    client: Client,
+    avatars: AvatarPipeline,
+    api_url: Url,
+}
+
+impl ChubAiSource {
+    pub fn new(client: Client, avatars: AvatarPipeline) -> Self {
+        let api_url = Url::parse("https://api.chub.ai/api/").expect("valid chub.ai API URL");
+        ChubAiSource {
+            client,
+            avatars,
+            api_url,
+        }
+    }
+
+    pub fn with_api_url(mut self, api_url: Url) -> Self {
+        self.api_url = api_url;
+        self
+    }
+
+    async fn fetch_character(&self, url: &Url) -> Result<CharacterInformation> {
+        let mut api_url = self
+            .api_url
+            .join(url.path().trim_start_matches('/'))
+            .with_context(|| format!("invalid chub.ai character URL: {url}"))?;
+        api_url.set_query(Some("full=true"));
+
+        let response: ChubAiCharacter = self
+            .client
+            .get(api_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        debug!("received character: {response:#?} for URL {url}");
+
+        let image_bytes = self
+            .client
+            .get(&response.node.definition.avatar)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        let mut character = response.into_character(url.as_str());
+        self.avatars.attach(&mut character, &image_bytes)?;
+        Ok(character)
+    }
+}
+
+impl CharacterSource for ChubAiSource {
+    fn name(&self) -> &'static str {
+        "chub.ai"
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        let host = url.host_str().unwrap_or_default();
+        matches!(url.scheme(), "http" | "https")
+            && matches!(host, "chub.ai" | "www.chub.ai")
+            && url.path().starts_with("/characters/")
+    }
+
+    fn fetch<'a>(&'a self, url: &'a Url) -> SourceFuture<'a> {
+        Box::pin(async move { Ok(vec![self.fetch_character(url).await?]) })
+    }
+}
+
+/// A PNG, JSON or CharX character file or a zip bundle of them, downloaded over HTTP.
+pub struct HttpFileSource {
+    client: Client,
+    avatars: AvatarPipeline,
+}
+
+impl HttpFileSource {
+    pub fn new(client: Client, avatars: AvatarPipeline) -> Self {
+        HttpFileSource { client, avatars }
+    }
+}
+
+impl CharacterSource for HttpFileSource {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        matches!(url.scheme(), "http" | "https") && url.host_str().is_some()
+    }
+
+    fn fetch<'a>(&'a self, url: &'a Url) -> SourceFuture<'a> {
+        Box::pin(async move {
+            let bytes = self
+                .client
+                .get(url.clone())
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+            characters_from_bytes(&bytes, &self.avatars)
+        })
+    }
+}
+
+/// A local character file, zip bundle or directory of character files.
+pub struct FileSource {
+    avatars: AvatarPipeline,
+}
+
+impl FileSource {
+    pub fn new(avatars: AvatarPipeline) -> Self {
+        FileSource { avatars }
+    }
+
+    async fn read_directory(&self, dir: &Utf8Path) -> Result<Vec<CharacterInformation>> {
+        let mut paths = Vec::new();
+        let mut entries = tokio::fs::read_dir(dir)
+            .await
+            .with_context(|| format!("failed to read directory {dir}"))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(path) = Utf8PathBuf::from_path_buf(entry.path()) else {
+                continue;
+            };
+            if entry.file_type().await?.is_file() && has_character_extension(path.as_str()) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        let mut characters = Vec::new();
+        for path in paths {
+            let bytes = tokio::fs::read(&path)
+                .await
+                .with_context(|| format!("failed to read {path}"))?;
+            match character_from_file_bytes(&bytes, &self.avatars) {
+                Ok(character) => characters.push(character),
+                Err(e) => warn!("skipping {path}: {e:#}"),
+            }
+        }
+
+        Ok(characters)
+    }
+}
+
+impl CharacterSource for FileSource {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        url.scheme() == "file"
+    }
+
+    fn fetch<'a>(&'a self, url: &'a Url) -> SourceFuture<'a> {
+        Box::pin(async move {
+            let path = url
+                .to_file_path()
+                .ok()
+                .and_then(|path| Utf8PathBuf::from_path_buf(path).ok())
+                .ok_or_else(|| anyhow!("invalid file URL: {url}"))?;
+
+            if tokio::fs::metadata(&path)
+                .await
+                .with_context(|| format!("failed to read {path}"))?
+                .is_dir()
+            {
+                self.read_directory(&path).await
+            } else {
+                let bytes = tokio::fs::read(&path)
+                    .await
+                    .with_context(|| format!("failed to read {path}"))?;
+                characters_from_bytes(&bytes, &self.avatars)
+            }
+        })
+    }
+}
+
+pub(crate) fn has_character_extension(name: &str) -> bool {
+    Utf8Path::new(name)
+        .extension()
+        .is_some_and(|ext| CHARACTER_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Reads a character file, or every character file in a zip bundle.
+fn characters_from_bytes(
+    bytes: &[u8],
+    avatars: &AvatarPipeline,
+) -> Result<Vec<CharacterInformation>> {
+    if bytes.starts_with(ZIP_SIGNATURE) && !is_charx(bytes) {
+        characters_from_bundle(bytes, avatars)
+    } else {
+        Ok(vec![character_from_file_bytes(bytes, avatars)?])
+    }
+}
+
+/// Reads a PNG, CharX or JSON character, told apart by their content.
+pub(crate) fn character_from_file_bytes(
+    bytes: &[u8],
+    avatars: &AvatarPipeline,
+) -> Result<CharacterInformation> {
+    if bytes.starts_with(PNG_SIGNATURE) {
+        character_from_png_bytes(bytes, avatars)
+    } else if bytes.starts_with(ZIP_SIGNATURE) {
+        character_from_charx_bytes(bytes, avatars)
+    } else {
+        character_from_json(bytes, avatars)
+    }
+}
+
+fn is_charx(bytes: &[u8]) -> bool {
+    ZipArchive::new(Cursor::new(bytes))
+        .is_ok_and(|mut archive| archive.by_name("card.json").is_ok())
+}
+
+fn characters_from_bundle(
+    bytes: &[u8],
+    avatars: &AvatarPipeline,
+) -> Result<Vec<CharacterInformation>> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).context("invalid zip bundle")?;
+    let mut names: Vec<_> = archive
+        .file_names()
+        .filter(|name| !name.ends_with('/') && has_character_extension(name))
+        .map(String::from)
+        .collect();
+    names.sort();
+
+    let mut characters = Vec::new();
+    for name in names {
+        let mut file = archive.by_name(&name)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)
+            .with_context(|| format!("failed to read {name} from zip bundle"))?;
+        match character_from_file_bytes(&bytes, avatars) {
+            Ok(character) => characters.push(character),
+            Err(e) => warn!("skipping {name} in zip bundle: {e:#}"),
+        }
+    }
+
+    Ok(characters)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{Cursor, Write},
+        sync::Arc,
+    };
+
+    use camino::Utf8Path;
+    use erpy_types::CharacterInformation;
+    use image::{ImageFormat, RgbImage};
+    use reqwest::{Client, Url};
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::{
+        parse_source_url, CharacterSource, ChubAiSource, FileSource, HttpFileSource, SourceRegistry,
+    };
+    use crate::{
+        assets::AssetStore, avatar::AvatarPipeline, character::character_to_png_bytes,
+        config::AvatarSettings, test_util::card_json,
+    };
+
+    /// Serves fixed responses by path on a local port, standing in for remote hosts.
+    /// The routes are created from the server's base URL.
+    async fn serve(routes: impl FnOnce(&Url) -> Vec<(&'static str, Vec<u8>)>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let routes: Arc<HashMap<&str, Vec<u8>>> = Arc::new(routes(&base).into_iter().collect());
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let routes = routes.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buffer = [0; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let n = stream.read(&mut buffer).await.unwrap();
+                        if n == 0 {
+                            break;
+                        }
+                        request.extend_from_slice(&buffer[..n]);
+                    }
+
+                    let request = String::from_utf8_lossy(&request);
+                    let target = request.split(' ').nth(1).unwrap_or_default();
+                    let response = match routes.get(target) {
+                        Some(body) => [
+                            format!(
+                                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                                body.len()
+                            )
+                            .into_bytes(),
+                            body.clone(),
+                        ]
+                        .concat(),
+                        None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                            .to_vec(),
+                    };
+                    stream.write_all(&response).await.unwrap();
+                });
+            }
+        });
+
+        base
+    }
+
+    fn client() -> Client {
+        Client::builder().no_proxy().build().unwrap()
+    }
+
+    fn avatars(dir: &tempfile::TempDir) -> AvatarPipeline {
+        let store = AssetStore::new(Utf8Path::from_path(dir.path()).unwrap().join("assets"));
+        AvatarPipeline::new(store, AvatarSettings::default())
+    }
+
+    fn names(characters: &[CharacterInformation]) -> Vec<&str> {
+        characters.iter().map(|c| c.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_url_matching() {
+        let dir = tempfile::tempdir().unwrap();
+        let chub = ChubAiSource::new(client(), avatars(&dir));
+        let http = HttpFileSource::new(client(), avatars(&dir));
+        let file = FileSource::new(avatars(&dir));
+        let url = |s: &str| Url::parse(s).unwrap();
+
+        assert!(chub.matches(&url("https://chub.ai/characters/alice/librarian")));
+        assert!(chub.matches(&url("https://www.chub.ai/characters/alice/librarian")));
+        assert!(!chub.matches(&url("https://chub.ai.example.com/characters/a/b")));
+        assert!(!chub.matches(&url("https://example.com/chub.ai/characters/a/b")));
+        assert!(!chub.matches(&url("https://chub.ai/lorebooks/a/b")));
+
+        assert!(http.matches(&url("http://localhost:8080/card.png")));
+        assert!(http.matches(&url("https://example.com/card")));
+        assert!(!http.matches(&url("file:///tmp/card.png")));
+
+        assert!(file.matches(&url("file:///tmp/card.png")));
+        assert!(!file.matches(&url("https://example.com/card.png")));
+    }
+
+    #[test]
+    fn test_parse_source_url() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("card.png");
+        let url = parse_source_url(path.to_str().unwrap()).unwrap();
+        assert_eq!(url.scheme(), "file");
+        assert_eq!(url.to_file_path().unwrap(), path);
+
+        assert!(parse_source_url("not a url").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_registry_rejects_unknown_schemes() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = SourceRegistry::with_defaults(avatars(&dir));
+        let error = registry
+            .fetch("ftp://example.com/card.png")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unsupported character source: ftp://example.com/card.png"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chub_ai_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut avatar = Cursor::new(Vec::new());
+        RgbImage::new(4, 6)
+            .write_to(&mut avatar, ImageFormat::Png)
+            .unwrap();
+        let avatar = avatar.into_inner();
+
+        let base = serve(|base| {
+            let character = json!({
+                "errors": null,
+                "node": {
+                    "id": 1,
+                    "name": "Alice",
+                    "description": "Notes from the creator.",
+                    "topics": ["books"],
+                    "nTokens": 100,
+                    "tagline": "",
+                    "permissions": "",
+                    "definition": {
+                        "id": 1,
+                        "avatar": base.join("avatar.png").unwrap().as_str(),
+                        "name": "Alice",
+                        "description": "A librarian.",
+                        "example_dialogs": "",
+                        "first_message": "Hello!",
+                        "personality": "curious",
+                        "scenario": "",
+                        "system_prompt": "",
+                        "post_history_instructions": "",
+                        "tavern_personality": "",
+                        "alternate_greetings": ["Hi!"]
+                    }
+                }
+            });
+            vec![
+                (
+                    "/api/characters/alice/librarian?full=true",
+                    character.to_string().into_bytes(),
+                ),
+                ("/avatar.png", avatar.clone()),
+            ]
+        })
+        .await;
+
+        let source =
+            ChubAiSource::new(client(), avatars(&dir)).with_api_url(base.join("api/").unwrap());
+        let url = Url::parse("https://chub.ai/characters/alice/librarian").unwrap();
+        assert!(source.matches(&url));
+        let characters = source.fetch(&url).await.unwrap();
+
+        assert_eq!(characters.len(), 1);
+        let alice = &characters[0];
+        assert_eq!(alice.name, "Alice");
+        assert_eq!(alice.first_messages, vec!["Hello!", "Hi!"]);
+        assert_eq!(alice.tags, vec!["books"]);
+        assert_eq!(alice.source, vec![url.to_string()]);
+        assert!(alice.image_base64.is_none());
+        let image = alice.image.as_ref().unwrap();
+        assert_eq!((image.width, image.height), (4, 6));
+        let store = AssetStore::new(Utf8Path::from_path(dir.path()).unwrap().join("assets"));
+        assert_eq!(store.get(&image.original).unwrap(), avatar);
+    }
+
+    #[tokio::test]
+    async fn test_http_source_detects_the_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = HttpFileSource::new(client(), avatars(&dir));
+        let png = character_to_png_bytes(
+            &CharacterInformation {
+                name: "Alice".into(),
+                first_messages: vec!["Hello!".into()],
+                ..Default::default()
+            },
+            source.avatars.store(),
+        )
+        .unwrap();
+        let base = serve(|_| {
+            vec![
+                ("/download/1", png),
+                ("/bob.json", card_json("Bob").into_bytes()),
+            ]
+        })
+        .await;
+
+        let alice = source
+            .fetch(&base.join("download/1").unwrap())
+            .await
+            .unwrap();
+        assert_eq!(names(&alice), vec!["Alice"]);
+        assert!(alice[0].image.is_some());
+        let bob = source.fetch(&base.join("bob.json").unwrap()).await.unwrap();
+        assert_eq!(names(&bob), vec!["Bob"]);
+        assert!(source.fetch(&base.join("missing").unwrap()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_file_source_reads_directories_and_bundles() {
+        let dir = tempfile::tempdir().unwrap();
+        let cards = dir.path().join("cards");
+        std::fs::create_dir(&cards).unwrap();
+        std::fs::write(cards.join("b.json"), card_json("Bob")).unwrap();
+        std::fs::write(cards.join("a.json"), card_json("Alice")).unwrap();
+        std::fs::write(cards.join("notes.txt"), "not a character").unwrap();
+        std::fs::write(cards.join("broken.json"), "{").unwrap();
+
+        let registry = SourceRegistry::with_defaults(avatars(&dir));
+        let characters = registry.fetch(cards.to_str().unwrap()).await.unwrap();
+        assert_eq!(names(&characters), vec!["Alice", "Bob"]);
+
+        let bundle = dir.path().join("bundle.zip");
+        let mut zip = ZipWriter::new(std::fs::File::create(&bundle).unwrap());
+        for (path, name) in [("cards/carol.json", "Carol"), ("dave.json", "Dave")] {
+            zip.start_file(path, SimpleFileOptions::default()).unwrap();
+            zip.write_all(card_json(name).as_bytes()).unwrap();
+        }
+        zip.start_file("readme.md", SimpleFileOptions::default())
+            .unwrap();
+        zip.finish().unwrap();
+
+        let url = Url::from_file_path(&bundle).unwrap();
+        let characters = registry.fetch(url.as_str()).await.unwrap();
+        assert_eq!(names(&characters), vec!["Carol", "Dave"]);
+    }
+}
@@ -0,0 +1,13 @@
+//! Fixtures shared by the tests of several modules.
+
+use serde_json::json;
+
+/// A V2 character card with a greeting as JSON, like a bare card file.
+pub fn card_json(name: &str) -> String {
+    json!({
+        "spec": "chara_card_v2",
+        "spec_version": "2.0",
+        "data": { "name": name, "description": "", "first_mes": "Hello!" }
+    })
+    .to_string()
+}
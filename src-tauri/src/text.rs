@@ -0,0 +1,45 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+/// The tags models wrap their reasoning in.
+const REASONING_TAGS: [&str; 3] = ["think", "thinking", "reasoning"];
+
+/// Removes reasoning blocks, including an unterminated one at the start that was cut off
+/// by the template.
+pub fn strip_reasoning(text: &str) -> String {
+    static BLOCK: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(?s)<(think|thinking|reasoning)>.*?</(think|thinking|reasoning)>")
+            .expect("valid pattern")
+    });
+    let text = BLOCK.replace_all(text, "");
+    let end = REASONING_TAGS
+        .iter()
+        .filter_map(|tag| {
+            let tag = format!("</{tag}>");
+            text.find(&tag).map(|start| start + tag.len())
+        })
+        .min();
+    let text = match end {
+        Some(end) => &text[end..],
+        None => &text,
+    };
+    text.trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::strip_reasoning;
+
+    #[test]
+    fn test_strip_reasoning() {
+        assert_eq!(strip_reasoning("Let me see.</think> Hi"), "Hi");
+        assert_eq!(strip_reasoning("Let me see.</thinking> Hi"), "Hi");
+        assert_eq!(strip_reasoning("Let me see.</reasoning>\n\nHi"), "Hi");
+        assert_eq!(
+            strip_reasoning("<thinking>a</thinking>b<think>c</think>"),
+            "b"
+        );
+        assert_eq!(strip_reasoning("No thoughts."), "No thoughts.");
+    }
+}
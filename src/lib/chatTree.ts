@@ -0,0 +1,205 @@
+import type {
+  ChatBranch,
+  ChatContent,
+  ChatHistoryItem,
+  ChatMessage,
+  ChatSummary,
+  MessageRole,
+} from "./storage";
+
+/**
+ * The messages of a chat as a tree. Alternative replies are siblings with the same parent,
+ * and `head` is the leaf of the branch that is shown and continued.
+ */
+export interface ChatTree {
+  messages: ChatMessage[];
+  branches: ChatBranch[];
+  head: string | null;
+  /** Summaries of older messages, written by the backend's `update_memory`. */
+  summaries: ChatSummary[];
+}
+
+export function emptyTree(): ChatTree {
+  return { messages: [], branches: [], head: null, summaries: [] };
+}
+
+export function findMessage(tree: ChatTree, id: string): ChatMessage | undefined {
+  return tree.messages.find((message) => message.id === id);
+}
+
+export function childrenOf(tree: ChatTree, parentId: string | null): ChatMessage[] {
+  return tree.messages.filter((message) => message.parentId === parentId);
+}
+
+/** The message and its alternatives, i.e. all messages with the same parent and role. */
+export function alternativesOf(tree: ChatTree, id: string): ChatMessage[] {
+  const message = findMessage(tree, id);
+  if (!message) {
+    return [];
+  }
+  return childrenOf(tree, message.parentId).filter((sibling) => sibling.role === message.role);
+}
+
+/** The messages from the start of the chat up to and including `id`. */
+export function pathTo(tree: ChatTree, id: string | null): ChatMessage[] {
+  const path: ChatMessage[] = [];
+  let current = id ? findMessage(tree, id) : undefined;
+  while (current) {
+    path.push(current);
+    current = current.parentId ? findMessage(tree, current.parentId) : undefined;
+  }
+  return path.reverse();
+}
+
+/** The checked out branch, from the start of the chat to `head`. */
+export function historyOf(tree: ChatTree): ChatMessage[] {
+  return pathTo(tree, tree.head);
+}
+
+/**
+ * Adds a message after `head` and checks it out. Branches that ended at `head` move along.
+ * In group chats `characterId` is the character that wrote an assistant message.
+ */
+export function appendMessage(
+  tree: ChatTree,
+  role: MessageRole,
+  content: ChatContent,
+  characterId?: string,
+): string {
+  const id = crypto.randomUUID();
+  for (const branch of tree.branches) {
+    if (branch.leafId === tree.head) {
+      branch.leafId = id;
+    }
+  }
+  tree.messages.push({ id, parentId: tree.head, role, characterId, ...content });
+  tree.head = id;
+  return id;
+}
+
+/** Adds an alternative to a message, like a regenerated reply, and checks it out. */
+export function addAlternative(tree: ChatTree, id: string, content: ChatContent): string {
+  const message = findMessage(tree, id);
+  if (!message) {
+    throw new Error(`no message with id '${id}' in chat`);
+  }
+  const alternativeId = crypto.randomUUID();
+  tree.messages.push({
+    id: alternativeId,
+    parentId: message.parentId,
+    role: message.role,
+    characterId: message.characterId,
+    ...content,
+  });
+  tree.head = alternativeId;
+  return alternativeId;
+}
+
+/** The end of the branch through `id`, following the most recent reply at every step. */
+function latestLeaf(tree: ChatTree, id: string): string {
+  let current = id;
+  for (;;) {
+    const children = childrenOf(tree, current);
+    if (children.length === 0) {
+      return current;
+    }
+    current = children[children.length - 1].id;
+  }
+}
+
+/** Checks out the branch through `id`, bringing back the conversation that followed it. */
+export function checkout(tree: ChatTree, id: string) {
+  tree.head = latestLeaf(tree, id);
+}
+
+/** Names the branch through `id`, so it can be checked out by name later. */
+export function createBranch(tree: ChatTree, name: string, id: string) {
+  if (tree.branches.some((branch) => branch.name === name)) {
+    throw new Error(`a branch named '${name}' already exists`);
+  }
+  tree.branches.push({ name, leafId: latestLeaf(tree, id) });
+}
+
+/**
+ * Deletes a message. A message with alternatives is deleted along with its replies and
+ * another alternative is checked out, otherwise its replies move up to its parent.
+ */
+export function deleteMessage(tree: ChatTree, id: string) {
+  const message = findMessage(tree, id);
+  if (!message) {
+    return;
+  }
+
+  const alternatives = alternativesOf(tree, id).filter((other) => other.id !== id);
+  if (alternatives.length > 0) {
+    const removed = new Set<string>();
+    const collect = (id: string) => {
+      removed.add(id);
+      childrenOf(tree, id).forEach((child) => collect(child.id));
+    };
+    collect(id);
+    tree.messages = tree.messages.filter((message) => !removed.has(message.id));
+    tree.branches = tree.branches.filter((branch) => !removed.has(branch.leafId));
+    tree.summaries = tree.summaries.filter((summary) => !removed.has(summary.end));
+    if (tree.head && removed.has(tree.head)) {
+      checkout(tree, alternatives[alternatives.length - 1].id);
+    }
+  } else {
+    // summaries of the message are out of date
+    tree.summaries = tree.summaries.filter((summary) => !covers(tree, summary, id));
+    for (const child of childrenOf(tree, id)) {
+      child.parentId = message.parentId;
+    }
+    for (const branch of tree.branches) {
+      if (branch.leafId === id && message.parentId) {
+        branch.leafId = message.parentId;
+      }
+    }
+    tree.messages = tree.messages.filter((other) => other.id !== id);
+    if (tree.head === id) {
+      tree.head = message.parentId;
+    }
+  }
+}
+
+/** Whether the summary covers the message with `id`. */
+function covers(tree: ChatTree, summary: ChatSummary, id: string): boolean {
+  const path = pathTo(tree, summary.end).map((message) => message.id);
+  const index = path.indexOf(id);
+  return index >= 0 && path.indexOf(summary.start) <= index;
+}
+
+/** A tree with the conversation up to and including `id`, without other branches. */
+export function forkAt(tree: ChatTree, id: string): ChatTree {
+  return {
+    messages: pathTo(tree, id).map((message) => ({ ...message })),
+    branches: [],
+    head: id,
+    summaries: tree.summaries
+      .filter((summary) => pathTo(tree, id).some((message) => message.id === summary.end))
+      .map((summary) => ({ ...summary })),
+  };
+}
+
+/**
+ * Builds the tree of a chat that was stored as a list. The chosen reply of every item
+ * continues the conversation, and its alternatives become branches that end there. The ids
+ * match the ones the backend gives linear chats.
+ */
+export function fromLinearHistory(history: ChatHistoryItem[]): ChatTree {
+  const messages: ChatMessage[] = [];
+  let parentId: string | null = null;
+  for (const [index, item] of history.entries()) {
+    let chosen: string | null = null;
+    for (const [answer, content] of item.content.entries()) {
+      const id = `${index}-${answer}`;
+      if (answer === item.chosenAnswer) {
+        chosen = id;
+      }
+      messages.push({ id, parentId, role: item.role, ...content });
+    }
+    parentId = chosen ?? parentId;
+  }
+
+  return { messages, branches: [], head: parentId, summaries: [] };
+}
@@ -1,4 +1,6 @@
-import { MessageRole, type Character, type Chat, type ChatHistoryItem } from "./storage";
+import { MessageRole, type Character, type Chat } from "./storage";
+import { addAlternative, appendMessage, emptyTree, historyOf, type ChatTree } from "./chatTree";
+import { convertFileSrc } from "@tauri-apps/api/core";
 import { DateTime } from "luxon";
 
 const numberFormat = new Intl.NumberFormat("en-US");
@@ -29,6 +31,27 @@ export function formatNumber(n: number): string {
   return numberFormat.format(n);
 }
 
+/**
+ * The URL of the smallest thumbnail of the character's image that is at least `size` pixels
+ * large, served from the asset store. Characters imported before the asset store keep their
+ * inline image.
+ */
+export function avatarUrl(
+  character: Pick<Character, "image" | "imageBase64">,
+  size: number,
+  cropped = false,
+): string {
+  const image = character.image;
+  if (!image) {
+    return character.imageBase64;
+  }
+
+  const thumbnail = image.thumbnails
+    .filter((thumbnail) => thumbnail.cropped === cropped && thumbnail.size >= size)
+    .sort((a, b) => a.size - b.size)[0];
+  return convertFileSrc(thumbnail?.id ?? image.original, "erpy-asset");
+}
+
 export function substituteParams(
   content: string,
   userName: string,
@@ -48,7 +71,7 @@ export function getInitialChatHistory(
   character: Character,
   userName: string,
   modelId: string,
-): ChatHistoryItem[] {
+): ChatTree {
   const systemPrompts = [
     character.systemPrompt,
     character.personality,
@@ -59,38 +82,24 @@ export function getInitialChatHistory(
     systemMessage += msg + "\n\n";
   }
 
-  const messages: ChatHistoryItem[] = [
-    {
-      role: MessageRole.System,
-      content: [
-        {
-          content: systemMessage,
-          timestamp: new Date(),
-          modelId,
-        },
-      ],
-      chosenAnswer: 0,
-    },
-  ];
-
-  messages.push({
-    role: MessageRole.Assistant,
-    content: character.firstMessages.map((content) => ({
-      content,
-      timestamp: new Date(),
-      modelId,
-    })),
-    chosenAnswer: 0,
+  const content = (text: string) => ({
+    content: substituteParams(text, userName, character.name, text),
+    timestamp: new Date(),
+    modelId,
   });
 
-  return messages.map((message) => ({
-    ...message,
-    content: message.content.map((content) => ({
-      content: substituteParams(content.content, userName, character.name, content.content),
-      timestamp: content.timestamp,
-      modelId,
-    })),
-  }));
+  const tree = emptyTree();
+  appendMessage(tree, MessageRole.System, content(systemMessage));
+  const [greeting, ...alternatives] = character.firstMessages;
+  if (greeting !== undefined) {
+    const greetingId = appendMessage(tree, MessageRole.Assistant, content(greeting));
+    for (const alternative of alternatives) {
+      addAlternative(tree, greetingId, content(alternative));
+    }
+    tree.head = greetingId;
+  }
+
+  return tree;
 }
 
 export function pluralize(count: number, singular: string, plural: string) {
@@ -120,7 +129,7 @@ export function truncate(str: string | undefined | null, maxLen: number): string
 export function getChatTitle(chat: Chat): string {
   return (
     truncate(chat.title, 40) ||
-    truncate(chat.history.at(2)?.content.at(0)?.content, 40) ||
+    truncate(historyOf(chat).at(2)?.content, 40) ||
     "Untitled"
   );
 }
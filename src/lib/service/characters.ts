@@ -1,25 +1,53 @@
-import { type Character, type NewCharacter, type ErpyStorage } from "$lib/storage";
-import type { CharacterInformation } from "$lib/types";
+import {
+  type Character,
+  type Config,
+  type NewCharacter,
+  type ErpyStorage,
+  type LorebookId,
+} from "$lib/storage";
+import type { CharacterInformation, Lorebook } from "$lib/types";
 import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
 
-export async function createCharactersFromPngs(
+function readBase64(file: File): Promise<string> {
+  const reader = new FileReader();
+  reader.readAsDataURL(file);
+  return new Promise<string>((resolve) => {
+    reader.onload = () => {
+      const dataUrl = reader.result as string;
+      resolve(dataUrl.slice(dataUrl.indexOf(",") + 1));
+    };
+  });
+}
+
+export async function createCharactersFromFiles(
   files: FileList,
   storage: ErpyStorage,
+  config: Config,
 ): Promise<Character[]> {
-  const pngs = await Promise.all(
-    Array.from(files).map(async (file) => {
-      const reader = new FileReader();
-      reader.readAsDataURL(file);
-      return new Promise<string>((resolve) => {
-        reader.onload = () => {
-          const string = (reader.result as string).replace("data:image/png;base64,", "");
-          resolve(string);
-        };
-      });
-    }),
-  );
+  const all = Array.from(files);
+  const hasExtension = (extension: string) => (file: File) =>
+    file.name.toLowerCase().endsWith(extension);
+  const pngs = await Promise.all(all.filter(hasExtension(".png")).map(readBase64));
+  const archives = await Promise.all(all.filter(hasExtension(".charx")).map(readBase64));
+  const jsonFiles = await Promise.all(all.filter(hasExtension(".json")).map((f) => f.text()));
 
-  const characterPayloads: CharacterInformation[] = await invoke("upload_character_pngs", { pngs });
+  const characterPayloads: CharacterInformation[] = [];
+  if (pngs.length > 0) {
+    characterPayloads.push(
+      ...(await invoke<CharacterInformation[]>("upload_character_pngs", { config, pngs })),
+    );
+  }
+  if (archives.length > 0) {
+    characterPayloads.push(
+      ...(await invoke<CharacterInformation[]>("upload_character_charx", { config, archives })),
+    );
+  }
+  for (const json of jsonFiles) {
+    characterPayloads.push(
+      await invoke<CharacterInformation>("import_character_json", { config, json }),
+    );
+  }
 
   const newCharacters: NewCharacter[] = [];
   for (let i = 0; i < characterPayloads.length; i++) {
@@ -38,16 +66,91 @@ export async function createCharactersFromPngs(
 export async function createCharacterFromUrls(
   urls: string[],
   storage: ErpyStorage,
+  config: Config,
 ): Promise<Character[]> {
   const data = await Promise.all(
-    urls.map((url) => invoke<CharacterInformation>("fetch_character", { characterUrl: url })),
+    urls.map((url) =>
+      invoke<CharacterInformation[]>("fetch_character", { config, characterUrl: url }),
+    ),
   );
 
   return storage.persistCharacters(
-    data.map((character) => ({
+    data.flat().map((character) => ({
       imageBase64: character.image_base64,
       payload: character,
       url: null,
     })),
   );
 }
+
+export async function createLorebooksFromFiles(
+  files: FileList,
+  storage: ErpyStorage,
+): Promise<LorebookId[]> {
+  const ids: LorebookId[] = [];
+  for (const file of Array.from(files)) {
+    const json = await file.text();
+    const name = file.name.replace(/\.(json|lorebook)$/i, "");
+    const lorebook = await invoke<Lorebook>("import_lorebook", { json, name });
+    ids.push(await storage.persistLorebook(lorebook));
+  }
+
+  return ids;
+}
+
+export type FileImportStatus =
+  | { type: "character"; name: string }
+  | { type: "lorebook"; name: string }
+  | { type: "duplicate"; duplicateOf: string | null }
+  | { type: "failed"; error: string };
+
+export type FileImportResult = { path: string } & FileImportStatus;
+
+interface DirectoryImport {
+  characters: { path: string; contentHash: string; character: CharacterInformation }[];
+  lorebooks: { path: string; contentHash: string; lorebook: Lorebook }[];
+  files: FileImportResult[];
+}
+
+export interface ImportProgress {
+  processed: number;
+  total: number;
+  path: string;
+}
+
+export async function importCharacterDirectory(
+  path: string,
+  storage: ErpyStorage,
+  config: Config,
+  onProgress: (progress: ImportProgress) => void,
+): Promise<FileImportResult[]> {
+  const existing = [...(await storage.getAllCharacters()), ...(await storage.getAllLorebooks())];
+  const knownHashes = existing.flatMap((item) => (item.contentHash ? [item.contentHash] : []));
+
+  const unlisten = await listen<ImportProgress>("import_progress", (event) =>
+    onProgress(event.payload),
+  );
+  try {
+    const result = await invoke<DirectoryImport>("import_character_directory", {
+      config,
+      path,
+      knownHashes,
+    });
+
+    await storage.persistCharacters(
+      result.characters.map(({ character, contentHash }) => ({
+        payload: character,
+        url: null,
+        imageBase64: character.image_base64,
+        contentHash,
+      })),
+    );
+    for (const { lorebook, contentHash } of result.lorebooks) {
+      await storage.persistLorebook(lorebook, contentHash);
+    }
+
+    return result.files;
+  } finally {
+    unlisten();
+  }
+}
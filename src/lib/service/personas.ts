@@ -0,0 +1,46 @@
+import type { Persona, PersonaLock } from "$lib/types";
+import { convertFileSrc, invoke } from "@tauri-apps/api/core";
+
+function readDataUrl(file: File): Promise<string> {
+  const reader = new FileReader();
+  reader.readAsDataURL(file);
+  return new Promise<string>((resolve) => {
+    reader.onload = () => resolve(reader.result as string);
+  });
+}
+
+export function newPersona(name: string): Persona {
+  return {
+    id: "",
+    name,
+    description: "",
+    avatar: null,
+    isDefault: false,
+    lockedCharacters: [],
+    lockedChats: [],
+  };
+}
+
+/** Saves the persona, with `avatar` as its new avatar if given. */
+export async function savePersona(persona: Persona, avatar?: File): Promise<Persona> {
+  return await invoke<Persona>("save_persona", {
+    persona,
+    avatar: avatar ? await readDataUrl(avatar) : null,
+  });
+}
+
+export async function deletePersona(id: string): Promise<void> {
+  await invoke("delete_persona", { id });
+}
+
+export async function setPersonaLock(
+  id: string,
+  lock: PersonaLock,
+  locked: boolean,
+): Promise<Persona> {
+  return await invoke<Persona>("set_persona_lock", { id, lock, locked });
+}
+
+export function personaAvatarUrl(persona: Persona): string | null {
+  return persona.avatar ? convertFileSrc(persona.avatar, "erpy-asset") : null;
+}
@@ -0,0 +1,64 @@
+import type { Character, Chat, MessageRole } from "$lib/storage";
+import { toBackendChat } from "$lib/types";
+import { invoke } from "@tauri-apps/api/core";
+
+export interface SearchQuery {
+  query: string;
+  characterId?: string | null;
+  from?: string | null;
+  to?: string | null;
+  archived?: boolean | null;
+  role?: MessageRole | null;
+  limit?: number;
+}
+
+export interface SearchHit {
+  chatId: string;
+  messageId: string;
+  title: string;
+  characterId: string;
+  role: MessageRole;
+  timestamp: string;
+  score: number;
+  snippet: string;
+  /** Ranges of the matched terms in the snippet. */
+  highlights: [number, number][];
+}
+
+/**
+ * Adds new and changed messages of the chats to the search index. With `all`, chats that
+ * aren't among them are removed from it.
+ */
+export async function indexChats(
+  chats: Chat[],
+  characters: Character[],
+  all = false,
+): Promise<void> {
+  const characterNames = Object.fromEntries(characters.map((c) => [c.id, c.name]));
+  await invoke("index_chats", {
+    chats: chats.map((chat) => toBackendChat(chat)),
+    characterNames,
+    all,
+  });
+}
+
+export async function searchChats(query: SearchQuery): Promise<SearchHit[]> {
+  return await invoke<SearchHit[]>("search_chats", { query });
+}
+
+/** Splits the snippet of a hit into parts, with the matched terms marked. */
+export function snippetParts(hit: SearchHit): { text: string; highlighted: boolean }[] {
+  const parts = [];
+  let position = 0;
+  for (const [start, end] of hit.highlights) {
+    if (start > position) {
+      parts.push({ text: hit.snippet.slice(position, start), highlighted: false });
+    }
+    parts.push({ text: hit.snippet.slice(start, end), highlighted: true });
+    position = end;
+  }
+  if (position < hit.snippet.length) {
+    parts.push({ text: hit.snippet.slice(position), highlighted: false });
+  }
+  return parts;
+}
@@ -1,4 +1,4 @@
-import type { CharacterInformation } from "$lib/types";
+import type { CharacterImage, CharacterInformation, Lorebook } from "$lib/types";
 import * as S from "@effect/schema/Schema";
 import {
   cast,
@@ -13,6 +13,7 @@ import {
   type Unsubscribe,
 } from "@evolu/common";
 import { createEvolu } from "@evolu/common-web";
+import { fromLinearHistory, type ChatTree } from "./chatTree";
 import { log } from "./log";
 
 const ConfigId = id("config");
@@ -33,6 +34,8 @@ export interface LlmSettings {
   topP: number | null;
   seed: number | null;
   stripThinkingTags: boolean | null;
+  /** How many answers are generated for a message, each a swipe of the reply. */
+  choices?: number | null;
 }
 
 export interface NotificationSettings {
@@ -44,6 +47,24 @@ export interface TtsSettings {
   apiUrl: string | null;
 }
 
+export enum EmbeddingBackend {
+  OpenAi = "open-ai",
+  Ollama = "ollama",
+  Hashing = "hashing",
+}
+
+export interface EmbeddingSettings {
+  enabled: boolean;
+  backend: EmbeddingBackend;
+  apiUrl: string | null;
+  apiKey: string | null;
+  model: string | null;
+  topK: number;
+  tokenBudget: number;
+  minDistance: number;
+  template: string;
+}
+
 export interface ExperimentalSettings {
   localLlm: boolean;
   textToSpeech: boolean;
@@ -57,6 +78,7 @@ export interface Config {
   llm: LlmSettings;
   tts: TtsSettings;
   experimental: ExperimentalSettings;
+  embeddings: EmbeddingSettings;
 }
 
 const ConfigTable = table({
@@ -80,6 +102,7 @@ const ConfigTable = table({
       topP: S.NullOr(S.Number),
       seed: S.NullOr(S.Number),
       stripThinkingTags: S.NullOr(S.Boolean),
+      choices: S.optional(S.NullOr(S.Number)),
     }),
     tts: S.Struct({
       enabled: S.Boolean,
@@ -89,9 +112,36 @@ const ConfigTable = table({
       localLlm: S.Boolean,
       textToSpeech: S.Boolean,
     }),
+    embeddings: S.optional(
+      S.Struct({
+        enabled: S.Boolean,
+        backend: S.Enums(EmbeddingBackend),
+        apiUrl: S.NullOr(S.String),
+        apiKey: S.NullOr(S.String),
+        model: S.NullOr(S.String),
+        topK: S.Number,
+        tokenBudget: S.Number,
+        minDistance: S.Number,
+        template: S.String,
+      }),
+    ),
   }),
 });
 
+function defaultEmbeddingSettings(): EmbeddingSettings {
+  return {
+    enabled: false,
+    backend: EmbeddingBackend.Hashing,
+    apiUrl: null,
+    apiKey: null,
+    model: null,
+    topK: 4,
+    tokenBudget: 400,
+    minDistance: 10,
+    template: "Earlier in the story:\n{{memories}}",
+  };
+}
+
 type ConfigRow = typeof ConfigTable.Type;
 
 function convertConfig(config: Nullable<ConfigRow>): Config {
@@ -122,6 +172,7 @@ function convertConfig(config: Nullable<ConfigRow>): Config {
       localLlm: config.data?.experimental?.localLlm ?? false,
       textToSpeech: config.data?.experimental?.textToSpeech ?? false,
     },
+    embeddings: config.data?.embeddings ?? defaultEmbeddingSettings(),
   };
 }
 
@@ -130,6 +181,70 @@ export type CharacterId = typeof CharacterId.Type;
 
 type Nullable<T> = { [K in keyof T]: T[K] | null };
 
+const CharacterCardFields = S.Struct({
+  scenario: S.String,
+  example_dialogues: S.String,
+  post_history_instructions: S.String,
+  creator: S.String,
+  creator_notes: S.String,
+  creator_notes_multilingual: S.Record(S.String, S.String),
+  character_version: S.String,
+  nickname: S.NullOr(S.String),
+  group_only_greetings: S.Array(S.String),
+  source: S.Array(S.String),
+  creation_date: S.NullOr(S.Number),
+  modification_date: S.NullOr(S.Number),
+  assets: S.Array(
+    S.Struct({
+      type: S.String,
+      uri: S.String,
+      name: S.String,
+      ext: S.String,
+    }),
+  ),
+  character_book: S.Unknown,
+  extensions: S.Record(S.String, S.Unknown),
+});
+
+/** The parts of a V2/V3 character card that don't have a column of their own. */
+export type CharacterCardFields = typeof CharacterCardFields.Type;
+
+function convertCardFields(card: Partial<CharacterCardFields> | null): CharacterCardFields {
+  return {
+    scenario: card?.scenario ?? "",
+    example_dialogues: card?.example_dialogues ?? "",
+    post_history_instructions: card?.post_history_instructions ?? "",
+    creator: card?.creator ?? "",
+    creator_notes: card?.creator_notes ?? "",
+    creator_notes_multilingual: card?.creator_notes_multilingual ?? {},
+    character_version: card?.character_version ?? "",
+    nickname: card?.nickname ?? null,
+    group_only_greetings: card?.group_only_greetings ?? [],
+    source: card?.source ?? [],
+    creation_date: card?.creation_date ?? null,
+    modification_date: card?.modification_date ?? null,
+    assets: card?.assets ?? [],
+    character_book: card?.character_book ?? null,
+    extensions: card?.extensions ?? {},
+  };
+}
+
+const CharacterImageSchema = S.Struct({
+  original: S.String,
+  width: S.Number,
+  height: S.Number,
+  focal_point: S.Struct({ x: S.Number, y: S.Number }),
+  thumbnails: S.Array(
+    S.Struct({
+      id: S.String,
+      size: S.Number,
+      width: S.Number,
+      height: S.Number,
+      cropped: S.Boolean,
+    }),
+  ),
+});
+
 const CharactersTable = table({
   id: CharacterId,
   url: S.NonEmptyString,
@@ -141,6 +256,11 @@ const CharactersTable = table({
   systemPrompt: S.NonEmptyString,
   avatar: S.String,
   imageBase64: S.String,
+  /** The image in the asset store, which replaces `imageBase64` for newly imported characters. */
+  image: S.NullOr(CharacterImageSchema),
+  card: S.NullOr(CharacterCardFields),
+  /** SHA-256 of the imported file, used to skip files that were imported before. */
+  contentHash: S.NullOr(S.String),
 });
 
 export type CharacterRow = typeof CharactersTable.Type;
@@ -156,6 +276,9 @@ export interface Character {
   systemPrompt: string;
   avatar: string;
   imageBase64: string;
+  image: CharacterImage | null;
+  card: CharacterCardFields;
+  contentHash: string | null;
   chatCount?: number;
 }
 
@@ -171,6 +294,9 @@ function convertCharacter(character: Nullable<CharacterRow & { chatCount: number
     systemPrompt: character.systemPrompt!,
     avatar: character.avatar!,
     imageBase64: character.imageBase64!,
+    image: (character.image as CharacterImage | null) ?? null,
+    card: convertCardFields(character.card),
+    contentHash: character.contentHash,
     chatCount: character.chatCount ?? undefined,
   };
 }
@@ -184,24 +310,95 @@ export enum MessageRole {
   System = "system",
 }
 
+/** How the character that replies next in a group chat is picked. */
+export enum SpeakerStrategy {
+  RoundRobin = "round-robin",
+  Natural = "natural",
+  Random = "random",
+  Manual = "manual",
+}
+
+/** Which character definitions the prompt of a group chat is built from. */
+export enum GroupPromptMode {
+  Swap = "swap",
+  Merge = "merge",
+}
+
+const GenerationMetadataSchema = S.Struct({
+  backend: S.String,
+  sampler: S.Struct({
+    maxTokens: S.NullOr(S.Number),
+    temperature: S.NullOr(S.Number),
+    frequencyPenalty: S.NullOr(S.Number),
+    presencePenalty: S.NullOr(S.Number),
+    repeatPenalty: S.NullOr(S.Number),
+    topP: S.NullOr(S.Number),
+  }),
+  seed: S.NullOr(S.Number),
+  promptTokens: S.Number,
+  completionTokens: S.Number,
+  finishReason: S.NullOr(S.String),
+  latencyMs: S.Number,
+  lorebookEntries: S.Array(
+    S.Struct({ lorebook: S.String, id: S.NullOr(S.Number), name: S.String }),
+  ),
+  summaries: S.Array(S.String),
+});
+
+/** The settings and statistics of a generated message, see `GenerationMetadata` in Rust. */
+export type GenerationMetadata = typeof GenerationMetadataSchema.Type;
+
+const ChatContentFields = {
+  content: S.String,
+  timestamp: SqliteDate,
+  modelId: S.NonEmptyString,
+  /** How the message was generated, for messages written by the model. */
+  generation: S.optional(S.NullOr(GenerationMetadataSchema)),
+};
+
 const ChatsTable = table({
   id: ChatId,
   title: S.String,
   characterId: CharacterId,
   archived: SqliteBoolean,
-  history: S.Array(
-    S.Struct({
-      role: S.Enums(MessageRole),
-      chosenAnswer: S.Number,
-      content: S.Array(
-        S.Struct({
-          content: S.String,
-          timestamp: SqliteDate,
-          modelId: S.NonEmptyString,
-        }),
-      ),
-    }),
+  /** The messages of chats created before chats became trees, see `messages`. */
+  history: S.NullOr(
+    S.Array(
+      S.Struct({
+        role: S.Enums(MessageRole),
+        chosenAnswer: S.Number,
+        content: S.Array(S.Struct(ChatContentFields)),
+      }),
+    ),
+  ),
+  messages: S.NullOr(
+    S.Array(
+      S.Struct({
+        id: S.String,
+        parentId: S.NullOr(S.String),
+        role: S.Enums(MessageRole),
+        characterId: S.optional(S.NullOr(S.String)),
+        ...ChatContentFields,
+      }),
+    ),
+  ),
+  branches: S.NullOr(S.Array(S.Struct({ name: S.String, leafId: S.String }))),
+  head: S.NullOr(S.String),
+  summaries: S.NullOr(
+    S.Array(
+      S.Struct({
+        id: S.String,
+        start: S.String,
+        end: S.String,
+        level: S.Number,
+        content: S.String,
+      }),
+    ),
   ),
+  /** Everyone in a group chat. Chats without participants are with `characterId` only. */
+  participants: S.NullOr(S.Array(S.Struct({ characterId: S.String, muted: S.Boolean }))),
+  speakerStrategy: S.NullOr(S.Enums(SpeakerStrategy)),
+  groupPrompt: S.NullOr(S.Enums(GroupPromptMode)),
 });
 
 export type ChatRow = typeof ChatsTable.Type;
@@ -210,25 +407,92 @@ export interface ChatContent {
   content: string;
   timestamp: Date;
   modelId: string;
+  generation?: GenerationMetadata | null;
 }
 
+/** A message of a chat that was stored as a list, with its alternatives in `content`. */
 export interface ChatHistoryItem {
   role: MessageRole;
   chosenAnswer: number;
   content: ChatContent[];
 }
 
-export interface Chat {
+export interface ChatMessage extends ChatContent {
+  id: string;
+  parentId: string | null;
+  role: MessageRole;
+  /** The character that wrote an assistant message. */
+  characterId?: string | null;
+}
+
+export interface ChatBranch {
+  name: string;
+  leafId: string;
+}
+
+/** A summary of the messages from `start` to `end`, see `ChatSummary` in `erpy-types`. */
+export interface ChatSummary {
+  id: string;
+  start: string;
+  end: string;
+  level: number;
+  content: string;
+}
+
+export interface Participant {
+  characterId: string;
+  muted: boolean;
+}
+
+export interface ChatGroup {
+  participants: Participant[];
+  speakerStrategy: SpeakerStrategy;
+  groupPrompt: GroupPromptMode;
+}
+
+export interface Chat extends ChatTree, ChatGroup {
   id: ChatId;
   createdAt: Date;
   updatedAt: Date;
   title: string | null;
   characterId: CharacterId;
   archived: boolean;
-  history: ChatHistoryItem[];
   isDeleted: boolean;
 }
 
+function convertTree(chat: Nullable<ChatRow>): ChatTree {
+  if (!chat.messages) {
+    return fromLinearHistory(
+      (chat.history ?? []).map((item) => ({
+        role: item.role,
+        chosenAnswer: item.chosenAnswer,
+        content: item.content.map((content) => ({
+          content: content.content,
+          timestamp: cast(content.timestamp),
+          modelId: content.modelId,
+          generation: content.generation,
+        })),
+      })),
+    );
+  }
+
+  return {
+    messages: chat.messages.map((message) => ({
+      id: message.id,
+      parentId: message.parentId,
+      role: message.role,
+      characterId: message.characterId,
+      content: message.content,
+      timestamp: cast(message.timestamp),
+      modelId: message.modelId,
+      generation: message.generation,
+    })),
+    branches: (chat.branches ?? []).map((branch) => ({ ...branch })),
+    head: chat.head,
+    summaries: (chat.summaries ?? []).map((summary) => ({ ...summary })),
+  };
+}
+
 function convertChat(chat: Nullable<ChatRow>): Chat {
   return {
     // required
@@ -238,35 +502,68 @@ function convertChat(chat: Nullable<ChatRow>): Chat {
     createdAt: cast(chat.createdAt!),
     updatedAt: cast(chat.updatedAt!),
     archived: cast(chat.archived ?? SqliteBoolean.make(0)),
-    history: (chat.history ?? []).map((item) => ({
-      role: item.role,
-      chosenAnswer: item.chosenAnswer,
-      content: item.content.map((content) => ({
-        content: content.content,
-        timestamp: cast(content.timestamp),
-        modelId: content.modelId,
-      })),
-    })),
+    ...convertTree(chat),
+    participants: chat.participants?.map((participant) => ({ ...participant })) ?? [
+      { characterId: chat.characterId!, muted: false },
+    ],
+    speakerStrategy: chat.speakerStrategy ?? SpeakerStrategy.RoundRobin,
+    groupPrompt: chat.groupPrompt ?? GroupPromptMode.Swap,
     isDeleted: cast(chat.isDeleted ?? SqliteBoolean.make(0)),
   };
 }
 
-function convertHistory(history: ChatHistoryItem[]) {
-  return history.map((item) => ({
-    role: item.role,
-    chosenAnswer: item.chosenAnswer,
-    content: item.content.map((content) => ({
-      content: content.content,
-      timestamp: cast(content.timestamp),
-      modelId: content.modelId,
+function convertMessages(tree: ChatTree) {
+  return {
+    messages: tree.messages.map((message) => ({
+      id: message.id,
+      parentId: message.parentId,
+      role: message.role,
+      characterId: message.characterId,
+      content: message.content,
+      timestamp: cast(message.timestamp),
+      modelId: message.modelId,
+      generation: message.generation,
     })),
-  }));
+    branches: tree.branches.map((branch) => ({ ...branch })),
+    head: tree.head,
+    summaries: tree.summaries.map((summary) => ({ ...summary })),
+  };
+}
+
+export const LorebookId = id("lorebooks");
+export type LorebookId = typeof LorebookId.Type;
+
+const LorebooksTable = table({
+  id: LorebookId,
+  name: S.String,
+  data: S.Unknown,
+  /** SHA-256 of the imported file, used to skip files that were imported before. */
+  contentHash: S.NullOr(S.String),
+});
+
+export type LorebookRow = typeof LorebooksTable.Type;
+
+export interface StoredLorebook {
+  id: LorebookId;
+  name: string;
+  data: Lorebook;
+  contentHash: string | null;
+}
+
+function convertLorebook(lorebook: Nullable<LorebookRow>): StoredLorebook {
+  return {
+    id: lorebook.id!,
+    name: lorebook.name ?? "",
+    data: lorebook.data as Lorebook,
+    contentHash: lorebook.contentHash ?? null,
+  };
 }
 
 const Database = database({
   characters: CharactersTable,
   chats: ChatsTable,
   config: ConfigTable,
+  lorebooks: LorebooksTable,
 });
 
 type Database = typeof Database.Type;
@@ -275,17 +572,21 @@ export interface ChatContent {
   content: string;
   timestamp: Date;
   modelId: string;
+  generation?: GenerationMetadata | null;
 }
 
 export interface NewChat {
   characterId: CharacterId;
-  data: ChatHistoryItem[];
+  tree: ChatTree;
+  /** The group of a forked group chat. New chats are with `characterId` only. */
+  group?: ChatGroup;
 }
 
 export interface NewCharacter {
   url: string | null;
   payload: CharacterInformation;
-  imageBase64: string;
+  imageBase64: string | null;
+  contentHash?: string | null;
 }
 
 export class ErpyStorage {
@@ -385,7 +686,10 @@ export class ErpyStorage {
         firstMessages: character.payload.first_messages,
         tags: character.payload.tags,
         systemPrompt: character.payload.system_prompt,
-        imageBase64: character.imageBase64,
+        imageBase64: character.imageBase64 ?? "",
+        image: character.payload.image ?? null,
+        card: convertCardFields(character.payload),
+        contentHash: character.contentHash ?? null,
       };
       const data = this.#evolu.create("characters", toInsert);
       inserted.push({
@@ -401,15 +705,27 @@ export class ErpyStorage {
     const data = this.#evolu.create("chats", {
       archived: false,
       characterId: chat.characterId,
-      history: convertHistory(chat.data),
+      ...convertMessages(chat.tree),
       title: "",
+      participants: chat.group?.participants.map((participant) => ({ ...participant })),
+      speakerStrategy: chat.group?.speakerStrategy,
+      groupPrompt: chat.group?.groupPrompt,
     });
 
     return data.id;
   }
 
-  async updateChat(id: ChatId, history: ChatHistoryItem[]): Promise<void> {
-    this.#evolu.update("chats", { id, history: convertHistory(history) });
+  async updateChat(id: ChatId, tree: ChatTree): Promise<void> {
+    this.#evolu.update("chats", { id, ...convertMessages(tree) });
+  }
+
+  updateChatGroup(id: ChatId, group: ChatGroup) {
+    this.#evolu.update("chats", {
+      id,
+      participants: group.participants.map((participant) => ({ ...participant })),
+      speakerStrategy: group.speakerStrategy,
+      groupPrompt: group.groupPrompt,
+    });
   }
 
   async getAllChats(): Promise<Chat[]> {
@@ -457,6 +773,26 @@ export class ErpyStorage {
     this.#evolu.update("chats", { id: ChatId.make(chatId), archived });
   }
 
+  async persistLorebook(
+    lorebook: Lorebook,
+    contentHash: string | null = null,
+  ): Promise<LorebookId> {
+    const data = this.#evolu.create("lorebooks", {
+      name: lorebook.name,
+      data: lorebook,
+      contentHash,
+    });
+    return data.id;
+  }
+
+  async getAllLorebooks(): Promise<StoredLorebook[]> {
+    const query = this.#evolu.createQuery((db) =>
+      db.selectFrom("lorebooks").where("isDeleted", "is not", cast(true)).selectAll(),
+    );
+    const data = await this.#evolu.loadQuery(query);
+    return data.rows.map(convertLorebook);
+  }
+
   async getConfig(): Promise<Config> {
     const query = this.#evolu.createQuery((db) => db.selectFrom("config").selectAll());
     const data = await this.#evolu.loadQuery(query);
@@ -485,6 +821,7 @@ export class ErpyStorage {
         userName: config.userName,
         tts: config.tts,
         experimental: config.experimental,
+        embeddings: config.embeddings,
       },
     });
   }
@@ -524,5 +861,6 @@ function getDefaultConfig(): Config {
       localLlm: false,
       textToSpeech: false,
     },
+    embeddings: defaultEmbeddingSettings(),
   };
 }
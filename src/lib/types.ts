@@ -1,11 +1,14 @@
-import type { ChatHistoryItem, MessageRole } from "./storage";
+import type { ChatTree } from "./chatTree";
+import { MessageRole, type Character, type Chat } from "./storage";
 
 export interface CompletionResponse {
   choices: CompletionChoice[];
 }
 
 export interface CompletionChoice {
+  index: number;
   delta: DeltaContent;
+  finishReason: string | null;
 }
 
 export interface DeltaContent {
@@ -17,26 +20,176 @@ export interface MessageHistoryItem {
   content: string;
 }
 
+/** A lorebook entry that was inserted into the prompt. */
+export interface InjectedEntry {
+  lorebook: string;
+  id: number | null;
+  name: string;
+}
+
+/** The messages sent to the model, and the lorebook entries inserted into them. */
+export interface Prompt {
+  messages: MessageHistoryItem[];
+  lorebookEntries: InjectedEntry[];
+  /** The ids of the chat summaries in the prompt. */
+  summaries: string[];
+  /** The newest message that was left out of the prompt to stay within the token budget. */
+  lastOmittedMessage: string | null;
+}
+
+export interface FocalPoint {
+  x: number;
+  y: number;
+}
+
+export interface Thumbnail {
+  id: string;
+  size: number;
+  width: number;
+  height: number;
+  cropped: boolean;
+}
+
+export interface CharacterImage {
+  original: string;
+  width: number;
+  height: number;
+  focal_point: FocalPoint;
+  thumbnails: Thumbnail[];
+}
+
 export interface CharacterInformation {
+  schema_version?: number;
   name: string;
   description: string;
   personality: string;
   first_messages: string[];
   tags: string[];
   system_prompt: string;
+  scenario?: string;
+  example_dialogues?: string;
+  post_history_instructions?: string;
+  creator?: string;
+  creator_notes?: string;
+  creator_notes_multilingual?: Record<string, string>;
+  character_version?: string;
+  nickname?: string | null;
+  group_only_greetings?: string[];
+  source?: string[];
+  creation_date?: number | null;
+  modification_date?: number | null;
+  assets?: CharacterAsset[];
+  character_book?: Lorebook | null;
+  extensions?: Record<string, unknown>;
   avatar?: string;
-  image_base64: string;
+  image?: CharacterImage | null;
+  /** The inline image of characters imported before images were kept in the asset store. */
+  image_base64: string | null;
+}
+
+export interface CharacterAsset {
+  type: string;
+  uri: string;
+  name: string;
+  ext: string;
+}
+
+export type SelectiveLogic = "and_any" | "not_all" | "not_any" | "and_all";
+
+export type LorebookPosition =
+  | "before_character"
+  | "after_character"
+  | "before_author_note"
+  | "after_author_note"
+  | "at_depth"
+  | "before_examples"
+  | "after_examples";
+
+export interface LorebookEntry {
+  id: number | null;
+  name: string;
+  keys: string[];
+  secondary_keys: string[];
+  selective: boolean;
+  selective_logic: SelectiveLogic;
+  content: string;
+  enabled: boolean;
+  constant: boolean;
+  insertion_order: number;
+  priority: number | null;
+  position: LorebookPosition;
+  depth: number | null;
+  probability: number | null;
+  case_sensitive: boolean | null;
+  match_whole_words: boolean | null;
+  use_regex: boolean;
+  scan_depth: number | null;
+  sticky: number | null;
+  cooldown: number | null;
+  delay: number | null;
+  exclude_recursion: boolean;
+  prevent_recursion: boolean;
+  extensions: Record<string, unknown>;
+}
+
+export interface Lorebook {
+  name: string;
+  description: string;
+  scan_depth: number | null;
+  token_budget: number | null;
+  recursive_scanning: boolean;
+  entries: LorebookEntry[];
+  extensions: Record<string, unknown>;
+}
+
+export function toCharacterInformation(character: Character): CharacterInformation {
+  return {
+    name: character.name,
+    description: character.description,
+    personality: character.personality,
+    first_messages: character.firstMessages,
+    tags: character.tags,
+    system_prompt: character.systemPrompt,
+    ...character.card,
+    avatar: character.avatar,
+    image: character.image,
+    image_base64: "",
+  };
+}
+
+/** The version of the chat documents the backend reads, see `Versioned` in `erpy-types`. */
+export const CHAT_SCHEMA_VERSION = 4;
+
+/** The chat in the shape the backend expects, with the messages of `tree`. */
+export function toBackendChat(chat: Chat, tree: ChatTree = chat) {
+  return {
+    schemaVersion: CHAT_SCHEMA_VERSION,
+    id: chat.id,
+    title: chat.title ?? "",
+    characterId: chat.characterId,
+    archived: chat.archived,
+    messages: tree.messages.map((message) => ({
+      ...message,
+      characterId:
+        message.characterId ??
+        (message.role === MessageRole.Assistant ? chat.characterId : undefined),
+    })),
+    branches: tree.branches,
+    head: tree.head,
+    summaries: tree.summaries,
+    participants: chat.participants,
+    speakerStrategy: chat.speakerStrategy,
+    groupPrompt: chat.groupPrompt,
+  };
 }
 
-export function toApiRequest(chat: ChatHistoryItem[]): MessageHistoryItem[] {
-  return chat
-    .map((item) => {
-      return {
-        role: item.role,
-        content: item.content[item.chosenAnswer].content,
-      };
-    })
-    .filter((i) => i.content.length > 0);
+/** A character in the shape of the backend's `Character`, e.g. for group chats. */
+export function toBackendCharacter(character: Character) {
+  return {
+    id: character.id,
+    url: character.url,
+    payload: toCharacterInformation(character),
+  };
 }
 
 export type LoadModel =
@@ -62,3 +215,33 @@ export type ConnectionTestResult =
       type: "failure";
       error: string;
     };
+
+/** Who the user is in a chat, see `Persona` in `erpy-types`. */
+export interface Persona {
+  id: string;
+  name: string;
+  description: string;
+  /** The asset id of the avatar. */
+  avatar: string | null;
+  isDefault: boolean;
+  lockedCharacters: string[];
+  lockedChats: string[];
+}
+
+export type PersonaLock = { type: "character"; id: string } | { type: "chat"; id: string };
+
+export type ChatExportFormat = "markdown" | "html" | "text" | "silly-tavern";
+
+export const EXPORT_EXTENSIONS: Record<ChatExportFormat, string> = {
+  markdown: "md",
+  html: "html",
+  text: "txt",
+  "silly-tavern": "jsonl",
+};
+
+/** See `ExportOptions` in the backend. */
+export interface ChatExportOptions {
+  format: ChatExportFormat;
+  includeSwipes: boolean;
+  stripReasoning: boolean;
+}
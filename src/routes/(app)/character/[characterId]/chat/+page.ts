@@ -17,7 +17,7 @@ export const load = async (event) => {
 
     const id = await storage.saveNewChat({
       characterId,
-      data: getInitialChatHistory(character, config.userName, activeModel!),
+      tree: getInitialChatHistory(character, config.userName, activeModel!),
     });
     redirect(301, `/character/${characterId}/chat/${id}`);
   } else {
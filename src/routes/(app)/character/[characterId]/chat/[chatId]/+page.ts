@@ -1,5 +1,7 @@
 import { CharacterId, ChatId } from "$lib/storage";
+import { toBackendChat, type Persona } from "$lib/types";
 import { error } from "@sveltejs/kit";
+import { invoke } from "@tauri-apps/api/core";
 
 export async function load(event) {
   const { storage } = await event.parent();
@@ -7,6 +9,7 @@ export async function load(event) {
   const chatId = ChatId.make(event.params.chatId);
   const character = await storage.getCharacter(characterId);
   const allChats = await storage.getChatsForCharacter(characterId);
+  const characters = await storage.getAllCharacters();
 
   const chat = await storage.getChatById(chatId);
   if (chat === null) {
@@ -14,10 +17,15 @@ export async function load(event) {
   } else if (character === null) {
     error(404, `Character with ID ${characterId} not found`);
   } else {
+    const personas = await invoke<Persona[]>("list_personas");
+    const persona = await invoke<Persona | null>("resolve_persona", { chat: toBackendChat(chat) });
     return {
       character,
       chat,
       allChats,
+      characters,
+      personas,
+      persona,
     };
   }
 }
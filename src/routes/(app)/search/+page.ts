@@ -0,0 +1,10 @@
+import { indexChats } from "$lib/service/search";
+
+export const load = async (event) => {
+  const { storage } = await event.parent();
+  const chats = await storage.getAllChats();
+  const characters = await storage.getAllCharacters();
+  await indexChats(chats, characters, true);
+
+  return { chats, characters };
+};
@@ -1,3 +1,7 @@
+import type { Persona } from "$lib/types";
+import { invoke } from "@tauri-apps/api/core";
+
 export const load = async () => {
-  return {};
+  const personas = await invoke<Persona[]>("list_personas");
+  return { personas };
 };